@@ -3,11 +3,6 @@
 //   a simple file or environment variables to configure email/password.
 use anyhow::Result;
 use uuid::Uuid;
-use argon2::{
-    password_hash::{PasswordHasher, SaltString},
-    Argon2,
-};
-use rand::RngCore;
 use crate::state::{AppState};
 
 /// Ensure an admin account exists in storage.
@@ -25,14 +20,15 @@ use crate::state::{AppState};
 /// Notes:
 /// - The default password value is intended only for local development. Always
 ///   set a secure password (via file or env) for any real environment.
-/// - Argon2 with a random salt provides strong password hashing.
+/// - The password is hashed like any other (`auth::PasswordPolicy::hash`), but
+///   the policy's rules for new passwords are not applied to it.
 pub fn seed_admin(state: &AppState) -> Result<()> {
     use anyhow::Context;
     use std::fs as stdfs;
     let seed_path: String = std::env::var("ADMIN_SEED_FILE").unwrap_or_else(|_| "/config/admin.seed".to_string());
     // Parse seed file into (username, password, email). Any missing values
     // will be handled by fallbacks below.
-    let (file_username, file_password, file_email) = (|| {
+    let (file_username, file_password, file_email) = {
         match stdfs::read_to_string(&seed_path) {
             Ok(text) => {
                 let mut u: Option<String> = None;
@@ -55,7 +51,7 @@ pub fn seed_admin(state: &AppState) -> Result<()> {
             }
             Err(_) => (None, None, None),
         }
-    })();
+    };
 
     // Fall back to a sane default username and admin email if not configured.
     let username: String = file_username.unwrap_or_else(|| "admin".to_string());
@@ -99,13 +95,8 @@ pub fn seed_admin(state: &AppState) -> Result<()> {
             let user_obj = serde_json::json!({ "id": user_id, "email": admin_email, "username": username, "created_at": chrono::Utc::now().timestamp(), "role": "admin" });
             state.storage.put_user(&user_id, &user_obj).context("put_user(admin)")?;
 
-            // Generate a random salt: this makes identical passwords hash
-            // differently across users and time (prevents rainbow table reuse).
-            let mut salt_bytes: [u8; 16] = [0u8; 16];
-            rand::rng().fill_bytes(&mut salt_bytes);
-            let salt: SaltString = SaltString::encode_b64(&salt_bytes).map_err(|e| anyhow::anyhow!(e.to_string()))?;
-            let argon2: Argon2<'_> = Argon2::default();
-            let pwd_hash = argon2.hash_password(admin_password.as_bytes(), &salt).map_err(|e| anyhow::anyhow!(e.to_string()))?.to_string();
+            // Argon2id with a random salt and the configured cost (see `auth::PasswordPolicy`)
+            let pwd_hash: String = state.passwords.hash(&admin_password).context("hashing admin password")?;
             let cred_obj = serde_json::json!({ "user_id": user_id, "password_hash": pwd_hash, "created_at": chrono::Utc::now().timestamp() });
             state.storage.put_credentials(&admin_email, &cred_obj).context("put_credentials(admin)")?;
             tracing::info!("admin seed: created admin user {}", admin_email);
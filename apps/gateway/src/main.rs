@@ -1,6 +1,6 @@
 // Gateway entrypoint (Axum + Tokio)
 //
-// This file wires up shared services (storage/publisher/presence/rate),
+// This file wires up shared services (storage/publisher/presence/rate/discovery),
 // builds the HTTP router from the route modules, attaches middleware layers
 // (rate limit, CORS, trace, timeout, compression, body limit), and starts
 // listening for requests with graceful shutdown.
@@ -13,7 +13,7 @@
 //   across tasks/threads safely.
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{Router, extract::DefaultBodyLimit};
 use axum::middleware::{from_fn, from_fn_with_state};
 use tower_http::{trace::TraceLayer, cors::{CorsLayer, AllowOrigin}, compression::CompressionLayer};
@@ -31,25 +31,124 @@ mod middleware;
 mod routes;
 mod init;
 
-use crate::state::AppState;
+use crate::state::{AppState, LockoutConfig, MailConfig, WsConfig, token_policy_from_env};
 use crate::middleware as gw_mw;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
+    // JWT signing keys (`AUTH_JWT_KEYS_DIR`, see crates/auth/src/keys.rs). Without one
+    // startup fails, unless `APP_ENV=dev` where an ephemeral key is generated.
+    let keyring: &auth::Keyring = auth::init_keyring().context("loading JWT signing keys")?;
+    tracing::info!(kid = %keyring.active().kid, alg = ?keyring.active().alg, "JWT signing key");
+
     // 1) Construct shared services (our app "state"). These are placed into
     //    an `AppState` later and cloned into handlers via `State(AppState)`.
     let storage: Arc<Storage> = Arc::new(Storage::new("./data")?);
+    // Service discovery (`DISCOVER_BACKEND`, see discover::Backend::from_env): either the
+    // static catalog file, reloaded on change and health-checked here, or Consul. The
+    // registry is kept current in the background either way.
+    let discovery: Arc<discover::Registry> = Arc::new(discover::Registry::new());
+    let backend: Arc<discover::Backend> = Arc::new(discover::Backend::from_env()?);
+    if let Err(e) = backend.start(&discovery).await { tracing::warn!("service discovery not loaded yet: {:#}", e); }
+    // The bus publisher binds locally: `NNG_PUB_ADDR`, else the default. Discovery only
+    // picks the addresses we dial (the websocket subscribers, see routes/ws.rs).
     let nng_addr: String = std::env::var("NNG_PUB_ADDR").unwrap_or_else(|_| "tcp://127.0.0.1:7777".to_string());
     let publisher: Arc<Publisher> = Arc::new(Publisher::bind(&nng_addr)?);
+    // Other gateways reach it at `BUS_ADVERTISE_ADDR` (e.g. "tcp://10.0.0.5:7777"),
+    // which is what gets registered; without it nothing is registered.
+    if let Ok(advertise) = std::env::var("BUS_ADVERTISE_ADDR") {
+        register_bus_publisher(&backend, &discovery, &advertise);
+    }
+    // Presence drops connections not heard from within the timeout, so it has to
+    // outlast the websocket keepalive (checked by `WsConfig::from_env`).
+    let ws = WsConfig::from_env().context("configuring websocket keepalive")?;
+    let presence_timeout: u64 = ws.presence_timeout.as_secs();
     let presence: Arc<presence::PresenceManager> = Arc::new(presence::PresenceManager::new(
-        Arc::clone(&storage), Arc::clone(&publisher), 30, 60,
+        Arc::clone(&storage), Arc::clone(&publisher), (presence_timeout / 2).clamp(1, 30), presence_timeout,
     )?);
-    let rate_limiter: Arc<rate::RateLimiter> = Arc::new(rate::RateLimiter::new(5, 1.0));
+    if let Some(idle) = std::env::var("PRESENCE_IDLE_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+        presence.set_idle_timeout(idle);
+    }
+    // Rate limit policies: a JSON file from `RATE_POLICY_FILE` (see crates/rate/src/policy.rs),
+    // otherwise the built-in defaults. An invalid file fails startup.
+    let mut policies: rate::Policies = match std::env::var("RATE_POLICY_FILE") {
+        Ok(path) => {
+            let json: String = std::fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+            rate::Policies::from_json(&json).with_context(|| format!("loading rate policies from {}", path))?
+        }
+        Err(_) => rate::Policies::defaults(),
+    };
+    // With several gateways, one hosts the rate service (`RATE_SERVICE_BIND`, a ZeroMQ
+    // endpoint such as `tcp://0.0.0.0:7790`) and the others charge it (`RATE_SERVICE_ADDR`,
+    // e.g. `tcp://gateway-1:7790`), leasing a few units at a time for `RATE_LEASE_MS`
+    // (default 1000). All of them must load the same policies.
+    let rate_bind: Option<String> = std::env::var("RATE_SERVICE_BIND").ok();
+    if let (None, Ok(addr)) = (&rate_bind, std::env::var("RATE_SERVICE_ADDR")) {
+        let mut remote: rate::Remote = rate::Remote::new(addr);
+        if let Some(ms) = std::env::var("RATE_LEASE_MS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            remote = remote.with_lease_ttl(Duration::from_millis(ms));
+        }
+        policies = policies.with_remote(remote);
+    }
+    let rate_limiter: Arc<rate::Policies> = Arc::new(policies);
+    if let Some(bind) = &rate_bind {
+        rate::shared::serve(bind, Arc::clone(&rate_limiter)).with_context(|| format!("binding rate service at {}", bind))?;
+        tracing::info!(addr = %bind, "rate service listening");
+    }
+    // Drop idle rate limiter state in the background; active keys are bounded per budget.
+    {
+        let rate_limiter: Arc<rate::Policies> = Arc::clone(&rate_limiter);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                tick.tick().await;
+                let removed: usize = rate_limiter.sweep();
+                if removed > 0 { tracing::debug!(removed, "rate limiter sweep"); }
+            }
+        });
+    }
 
     // Bundle the services into our state struct
-    let state = AppState { publisher, storage, presence, rate: rate_limiter, nng_addr: nng_addr.clone() };
+    let lockout = LockoutConfig::from_env();
+    let tokens = token_policy_from_env();
+    let oauth = Arc::new(auth::OAuthRegistry::from_env().context("configuring OAuth providers")?);
+    let passkeys = auth::Passkeys::from_env().context("configuring passkeys")?.map(Arc::new);
+    let mail = MailConfig::from_env().context("configuring email")?;
+    if mail.is_none() { tracing::warn!("no mailer configured (MAIL_SMTP_URL or MAIL_DIR); password reset and email verification are disabled"); }
+    let passwords = Arc::new(auth::PasswordPolicy::from_env().context("configuring the password policy")?);
+    tracing::info!(min_len = passwords.min_len, breached = passwords.breached_len(), "password policy");
+    let state = AppState { publisher, storage, presence, rate: rate_limiter, discovery, nng_addr: nng_addr.clone(), ws, lockout, tokens, oauth, passkeys, mail, passwords };
+
+    // Drop expired refresh tokens, sessions, abandoned OAuth/passkey/MFA ceremonies and
+    // emailed tokens once an hour.
+    {
+        let storage: Arc<Storage> = Arc::clone(&state.storage);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                tick.tick().await;
+                match storage.purge_refresh_tokens(chrono::Utc::now().timestamp()) {
+                    Ok(0) => {}
+                    Ok(removed) => tracing::debug!(removed, "expired refresh tokens purged"),
+                    Err(e) => tracing::warn!("refresh token purge failed: {:?}", e),
+                }
+                if let Err(e) = storage.purge_oauth_states(chrono::Utc::now().timestamp()) {
+                    tracing::warn!("oauth state purge failed: {:?}", e);
+                }
+                if let Err(e) = storage.purge_webauthn_states(chrono::Utc::now().timestamp()) {
+                    tracing::warn!("webauthn state purge failed: {:?}", e);
+                }
+                if let Err(e) = storage.purge_mfa_challenges(chrono::Utc::now().timestamp()) {
+                    tracing::warn!("mfa challenge purge failed: {:?}", e);
+                }
+                if let Err(e) = storage.purge_account_tokens(chrono::Utc::now().timestamp()) {
+                    tracing::warn!("account token purge failed: {:?}", e);
+                }
+            }
+        });
+    }
 
     // 2) Ensure a usable admin account exists (dev/prod friendly)
     if let Err(e) = init::seed_admin(&state) { tracing::error!("admin seed failed: {:?}", e); }
@@ -102,7 +201,7 @@ async fn main() -> Result<()> {
         .layer(HandleErrorLayer::new(handle_timeout_error))
         .layer(TimeoutLayer::new(Duration::from_secs(10)));
 
-    let body_limit: usize = std::env::var("BODY_LIMIT_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(1 * 1024 * 1024);
+    let body_limit: usize = std::env::var("BODY_LIMIT_BYTES").ok().and_then(|s| s.parse().ok()).unwrap_or(1024 * 1024);
     let mut app: Router<_> = base
         // Policy layers (outer): rate limiting and CORS
         .layer(from_fn_with_state(state.clone(), gw_mw::rate_limit_middleware))
@@ -127,7 +226,7 @@ async fn main() -> Result<()> {
     };
 
     let presence_for_shutdown: Arc<presence::PresenceManager> = Arc::clone(&state.presence);
-    axum::serve(listener, app)
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(async move {
             let _ = signal::ctrl_c().await;
             tracing::info!("shutdown signal received");
@@ -137,3 +236,40 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Register this process's bus publisher as a "bus" instance with a TTL and keep
+/// it alive with heartbeats. `advertise` must be a `tcp://host:port` address other
+/// processes can dial; anything else, loopback included, is logged and skipped.
+fn register_bus_publisher(backend: &Arc<discover::Backend>, discovery: &Arc<discover::Registry>, advertise: &str) {
+    let Some((host, port)) = advertise.strip_prefix("tcp://").and_then(|hp| hp.rsplit_once(':')) else {
+        tracing::warn!("BUS_ADVERTISE_ADDR {:?} is not tcp://host:port, not registering the bus", advertise);
+        return;
+    };
+    let host: &str = host.trim_matches(['[', ']']);
+    let Ok(port) = port.parse::<u16>() else {
+        tracing::warn!("BUS_ADVERTISE_ADDR {:?} has an invalid port, not registering the bus", advertise);
+        return;
+    };
+    if host == "localhost" || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified()) {
+        tracing::warn!("BUS_ADVERTISE_ADDR {:?} is not reachable from other hosts, not registering the bus", advertise);
+        return;
+    }
+    let entry = discover::ServiceEntry { name: "bus".to_string(), address: host.to_string(), port, meta: None };
+    let id: String = format!("bus-{}", uuid::Uuid::new_v4());
+    let ttl: Duration = Duration::from_secs(30);
+    let backend: Arc<discover::Backend> = Arc::clone(backend);
+    let discovery: Arc<discover::Registry> = Arc::clone(discovery);
+    tokio::spawn(async move {
+        let mut registered: bool = false;
+        let mut tick = tokio::time::interval(ttl / 3);
+        loop {
+            tick.tick().await;
+            if registered && backend.heartbeat(&discovery, &id).await.is_ok() { continue; }
+            // first round, or we were dropped (e.g. suspended past the TTL, or Consul restarted)
+            match backend.register(&discovery, &id, entry.clone(), ttl).await {
+                Ok(()) => registered = true,
+                Err(e) => tracing::warn!("registering bus publisher failed: {:#}", e),
+            }
+        }
+    });
+}
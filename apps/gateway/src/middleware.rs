@@ -1,8 +1,9 @@
 // Cross-cutting middleware
 //
 // These functions run "around" handlers to apply consistent behavior:
-// - rate_limit_middleware: derives a per-user or anonymous rate key and throttles
-//   requests using an in-process token bucket.
+// - rate_limit_middleware: matches each request against the rate limit policies
+//   (route, method, role, anon vs. authenticated, IP) and throttles it against
+//   the chosen budget, emitting `RateLimit-*` headers and `Retry-After` on 429.
 // - csrf_middleware: enforces the double-submit cookie pattern for unsafe
 //   methods (POST/PUT/PATCH/DELETE) when a csrf cookie is present.
 // - log_post_body_middleware: dev-only, logs POST request/response bodies up to
@@ -16,24 +17,37 @@ use axum::{
     http::{HeaderMap, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
-    extract::{State},
+    extract::{State, ConnectInfo},
 };
+use std::net::SocketAddr;
 use axum::body::Body;
-use crate::state::{AppState, extract_token};
+use crate::state::{AppState, extract_token, verify_token};
 
 use http_body_util::BodyExt as _; // for .collect()
 use bytes::Bytes;
 use axum::http::Method;
 
-/// Rate limiting middleware (token bucket per key)
+/// Resolve the client address: the TCP peer, or behind a trusted proxy the
+/// first untrusted hop of `Forwarded` / `X-Forwarded-For` (see `rate::Clients`).
+pub fn client_ip(state: &AppState, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<std::net::IpAddr> {
+    let header = |name: &str| headers.get(name).and_then(|hv| hv.to_str().ok());
+    peer.map(|peer| state.rate.clients().resolve(peer.ip(), header("forwarded"), header("x-forwarded-for")))
+}
+
+/// Rate limiting middleware (declarative policies)
 ///
 /// What this does:
-/// - Derives a rate key (usually the authenticated user id from a verified
-///   JWT; otherwise falls back to the string "anon").
-/// - Skips throttling for a small set of endpoints (auth, logs, health, ws)
-///   to avoid interfering with session flows and WebSocket upgrades.
-/// - Checks an in-process token bucket and returns HTTP 429 if the client
-///   exceeds the configured rate.
+/// - Identifies the caller: the authenticated user id from a verified JWT
+///   (plus their stored role when a policy matches on roles) and the client IP.
+///   Behind a trusted proxy (Traefik) the IP comes from `Forwarded` /
+///   `X-Forwarded-For`; otherwise it is the TCP peer.
+/// - Refuses deny-listed clients with 403 and lets allow-listed ones through
+///   without limits.
+/// - Asks the policy set (`state.rate`) which budget, key and cost apply to
+///   this path and method. Exempt routes (health, ws, session endpoints in the
+///   defaults) pass straight through.
+/// - Returns HTTP 429 with `Retry-After` if the budget is exhausted;
+///   `RateLimit-*` headers report the remaining quota either way.
 ///
 /// Why it's safe:
 /// - Verifying the JWT before using its `sub` (subject/user id) prevents
@@ -45,34 +59,62 @@ pub async fn rate_limit_middleware(
     next: Next,
 ) -> Response {
     // Try to read Authorization: Bearer <token> or session cookie and
-    // verify the JWT to get a stable user id. Otherwise default to "anon".
-    let rate_key: String = extract_token(req.headers())
-        .and_then(|tok: String| auth::verify_jwt(&tok).ok().map(|data| data.claims.sub))
-        .unwrap_or_else(|| "anon".to_string());
-
-    // Exempt common auth and health endpoints from throttling to keep the
-    // UX smooth during login/signup/logout and health checks.
-    if let Some(path) = req.uri().path().strip_prefix("") {
-        if path.starts_with("/api/auth/me")
-            || path.starts_with("/api/auth/login")
-            || path.starts_with("/api/auth/logout")
-            || path.starts_with("/api/auth/signup")
-            || path.starts_with("/api/auth/check_username")
-            || path.starts_with("/api/frontend-logs")
-            || path.starts_with("/healthz")
-            || path.starts_with("/ws")
-        {
-            let resp: hyper::Response<Body> = next.run(req).await;
-            return resp;
+    // verify the JWT to get a stable user id.
+    let user_id: Option<String> = extract_token(req.headers())
+        .and_then(|tok: String| verify_token(&state, &tok).ok().map(|claims| claims.sub));
+    let role: Option<String> = match &user_id {
+        Some(id) if state.rate.needs_role() => state.storage.get_user(id).ok().flatten()
+            .and_then(|u| u.get("role").and_then(|r| r.as_str()).map(str::to_string)),
+        _ => None,
+    };
+    let ip: Option<std::net::IpAddr> = client_ip(&state, req.headers(), req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ci| ci.0));
+    match ip.map(|ip| state.rate.clients().access(ip)) {
+        Some(rate::Access::Deny) => {
+            return (StatusCode::FORBIDDEN, axum::Json(serde_json::json!({ "message": "forbidden" }))).into_response();
         }
+        Some(rate::Access::Allow) => return next.run(req).await,
+        _ => {}
     }
 
-    // Consume a token; deny if no capacity is left.
-    if !state.rate.allow(&rate_key) {
-        return (StatusCode::TOO_MANY_REQUESTS, "rate limit").into_response();
+    let info = rate::RequestInfo {
+        path: req.uri().path(),
+        method: req.method().as_str(),
+        user_id: user_id.as_deref(),
+        role: role.as_deref(),
+        ip,
+    };
+    let Some(outcome) = state.rate.charge(&info).await else {
+        return next.run(req).await;
+    };
+    if !outcome.decision.allowed {
+        tracing::debug!(budget = %outcome.budget, key = %outcome.key, "rate limited");
+        return too_many_requests(&outcome.decision);
     }
 
-    next.run(req).await
+    let mut resp: Response = next.run(req).await;
+    apply_rate_headers(resp.headers_mut(), &outcome.decision);
+    resp
+}
+
+/// Set `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (seconds,
+/// rounded up) from a decision, plus `Retry-After` when it was denied.
+pub fn apply_rate_headers(headers: &mut HeaderMap, d: &rate::Decision) {
+    let secs = |t: std::time::Duration| t.as_secs() + (t.subsec_nanos() > 0) as u64;
+    headers.insert("ratelimit-limit", d.limit.into());
+    headers.insert("ratelimit-remaining", d.remaining.into());
+    headers.insert("ratelimit-reset", secs(d.reset_after).into());
+    if let Some(retry) = d.retry_after {
+        headers.insert(axum::http::header::RETRY_AFTER, secs(retry).into());
+    }
+}
+
+/// 429 response for a denied decision, with rate limit headers and a JSON body.
+pub fn too_many_requests(d: &rate::Decision) -> Response {
+    let retry_after: Option<u64> = d.retry_after.map(|t| t.as_secs() + (t.subsec_nanos() > 0) as u64);
+    let body = serde_json::json!({ "message": "rate limit exceeded", "retry_after": retry_after });
+    let mut resp: Response = (StatusCode::TOO_MANY_REQUESTS, axum::Json(body)).into_response();
+    apply_rate_headers(resp.headers_mut(), d);
+    resp
 }
 
 /// CSRF protection middleware (double-submit cookie pattern)
@@ -102,11 +144,7 @@ pub async fn csrf_middleware(req: axum::http::Request<Body>, next: Next) -> Resp
         .and_then(|cookie_header| {
             cookie_header.split(';').find_map(|kv: &str| {
                 let kv: &str = kv.trim();
-                if let Some(v) = kv.strip_prefix("csrfToken=") {
-                    Some(v.to_string())
-                } else {
-                    None
-                }
+                kv.strip_prefix("csrfToken=").map(|v| v.to_string())
             })
         });
 
@@ -148,10 +186,7 @@ pub async fn log_post_body_middleware(req: axum::http::Request<Body>, next: Next
         } else {
             (collected.clone(), false)
         };
-        let body_str = match std::str::from_utf8(&to_log) {
-            Ok(s) => s,
-            Err(_) => "[binary body]",
-        };
+        let body_str = std::str::from_utf8(&to_log).unwrap_or("[binary body]");
         if truncated {
             tracing::info!(target: "post_body", path = %path, bytes = collected.len(), body = %body_str, "POST payload (truncated)");
         } else {
@@ -173,10 +208,7 @@ pub async fn log_post_body_middleware(req: axum::http::Request<Body>, next: Next
         } else {
             (res_bytes.clone(), false)
         };
-        let res_body_str = match std::str::from_utf8(&res_to_log) {
-            Ok(s) => s,
-            Err(_) => "[binary body]",
-        };
+        let res_body_str = std::str::from_utf8(&res_to_log).unwrap_or("[binary body]");
         if res_trunc {
             tracing::info!(target: "post_body", path = %path, status = status, bytes = res_bytes.len(), body = %res_body_str, "POST response (truncated)");
         } else {
@@ -0,0 +1,262 @@
+// Password change and reset, email verification (links sent by email)
+//
+// - POST /api/auth/password/forgot { email } emails a password reset link if
+//   the address has a password account. The answer (202) is the same either
+//   way and the email goes out in the background, so this can't be used to
+//   find out which addresses are registered.
+// - POST /api/auth/password/reset { token, password } sets the new password,
+//   signs out every session of the account and clears its sign-in lockout.
+//   Two-factor authentication stays on.
+// - POST /api/auth/password/change { currentPassword, newPassword } (signed
+//   in) replaces the password after checking the current one, which counts
+//   towards the sign-in lockout when wrong. The caller's other sessions are
+//   signed out. New passwords, here and on reset, must pass the password
+//   policy (`auth::PasswordPolicy`).
+// - POST /api/auth/email/verify/send (signed in) emails a link to verify the
+//   caller's address; signup sends one too.
+// - POST /api/auth/email/verify { token } marks the address verified
+//   (`email_verified` on the user record; a password reset does as well).
+//
+// Links open the web app: `<APP_BASE_URL>/reset-password?token=..` and
+// `<APP_BASE_URL>/verify-email?token=..`, which post the token back here.
+// Tokens are single-use, expire, and are stored hashed (see
+// `auth::issue_account_token`). Without a mailer (`state::MailConfig`) the
+// routes that send email return 503.
+use axum::{Router, routing::post, extract::{State, ConnectInfo}, Json, middleware};
+use axum::http::{StatusCode, HeaderMap};
+use crate::middleware as gw_mw;
+use crate::routes::auth::{check_lockout, check_new_password, current_claims, hash_password, lockout_keys, note_failure, verify_password};
+use crate::state::{AppState, MailConfig, audit, internal, message, require_user};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// Routes reached from an email link or the sign-in page (no CSRF, like the login).
+pub fn public() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/password/forgot", post(password_forgot))
+        .route("/api/auth/password/reset", post(password_reset))
+        .route("/api/auth/email/verify", post(email_verify))
+}
+
+/// Routes for signed-in users (CSRF-protected).
+pub fn protected() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/password/change", post(password_change))
+        .route("/api/auth/email/verify/send", post(email_verify_send))
+        .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
+}
+
+#[derive(Deserialize)]
+struct Forgot { email: String }
+
+#[derive(Deserialize)]
+struct Reset { token: String, password: String }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Change { current_password: String, new_password: String }
+
+#[derive(Deserialize)]
+struct Verify { token: String }
+
+fn mail_config(state: &AppState) -> Result<MailConfig, (StatusCode, String)> {
+    state.mail.clone().ok_or(message(StatusCode::SERVICE_UNAVAILABLE, "email is not configured"))
+}
+
+async fn deliver(mail: &MailConfig, email: mail::Email) {
+    if let Err(e) = mail.mailer.send(&email).await {
+        tracing::warn!(to = %email.to, subject = %email.subject, "sending email failed: {:#}", e);
+    }
+}
+
+/// Email `email` a link to verify it for `user_id`, in the background. Does
+/// nothing without a mailer; failures are logged.
+pub(crate) fn send_verification_email(state: &AppState, user_id: &str, email: &str) {
+    let Some(mail) = state.mail.clone() else { return };
+    let token: String = match auth::issue_account_token(&state.storage, auth::AccountTokenPurpose::VerifyEmail, user_id, email, chrono::Utc::now().timestamp()) {
+        Ok(token) => token,
+        Err(e) => { tracing::error!("issuing email verification token failed: {:?}", e); return; }
+    };
+    let text: String = format!(
+        "Open this link within two days to confirm {} for your account:\n\n{}/verify-email?token={}\n\nIf you didn't sign up, you can ignore this email.\n",
+        email, mail.app_url, token,
+    );
+    let email = mail::Email { to: email.to_string(), subject: "Confirm your email address".into(), text };
+    tokio::spawn(async move { deliver(&mail, email).await });
+}
+
+/// Set `email_verified` on the user if `email` is still their address. Returns whether it was.
+fn mark_email_verified(state: &AppState, user_id: &str, email: &str) -> anyhow::Result<bool> {
+    let Some(mut user) = state.storage.get_user(user_id)? else { return Ok(false) };
+    if !user.get("email").and_then(|v| v.as_str()).is_some_and(|e| e.eq_ignore_ascii_case(email)) { return Ok(false); }
+    let Some(obj) = user.as_object_mut() else { return Ok(false) };
+    obj.insert("email_verified".into(), serde_json::json!(true));
+    obj.insert("email_verified_at".into(), serde_json::json!(chrono::Utc::now().timestamp()));
+    state.storage.put_user(user_id, &user)?;
+    Ok(true)
+}
+
+/// POST /api/auth/password/forgot
+async fn password_forgot(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Forgot>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let mail: MailConfig = mail_config(&state)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let email: String = body.email.trim().to_lowercase();
+    tokio::spawn(async move {
+        let user_id: String = match state.storage.get_credentials(&email) {
+            Ok(Some(cred)) => match cred.get("user_id").and_then(|v| v.as_str()) { Some(id) => id.to_string(), None => return },
+            Ok(None) => return,
+            Err(e) => { tracing::error!("looking up credentials for a password reset failed: {:?}", e); return; }
+        };
+        let token: String = match auth::issue_account_token(&state.storage, auth::AccountTokenPurpose::PasswordReset, &user_id, &email, chrono::Utc::now().timestamp()) {
+            Ok(token) => token,
+            Err(e) => { tracing::error!("issuing password reset token failed: {:?}", e); return; }
+        };
+        audit(&state, "password_reset_requested", None, Some(&user_id), ip, serde_json::Value::Null);
+        let text: String = format!(
+            "Someone (hopefully you) asked to reset the password for {}.\n\nOpen this link within an hour to choose a new one:\n\n{}/reset-password?token={}\n\nIf it wasn't you, ignore this email; your password stays the same.\n",
+            email, mail.app_url, token,
+        );
+        deliver(&mail, mail::Email { to: email, subject: "Reset your password".into(), text }).await;
+    });
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "message": "if the address has an account, a reset link is on its way" }))))
+}
+
+/// POST /api/auth/password/reset
+async fn password_reset(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Reset>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let now: i64 = chrono::Utc::now().timestamp();
+    let invalid = || message(StatusCode::BAD_REQUEST, "the reset link is invalid or has expired");
+    // Check the password against the policy first so a rejected one doesn't use up the link
+    let pending: auth::AccountToken = auth::peek_account_token(&state.storage, auth::AccountTokenPurpose::PasswordReset, &body.token, now)
+        .map_err(internal)?
+        .ok_or_else(invalid)?;
+    let username: Option<String> = state.storage.get_user(&pending.user_id).map_err(internal)?
+        .and_then(|u| u.get("username").and_then(|v| v.as_str()).map(str::to_string));
+    check_new_password(&state, &body.password, &[&pending.email, username.as_deref().unwrap_or_default()])?;
+    let token: auth::AccountToken = auth::redeem_account_token(&state.storage, auth::AccountTokenPurpose::PasswordReset, &body.token, now)
+        .map_err(internal)?
+        .ok_or_else(invalid)?;
+
+    let pwd_hash: String = hash_password(&state, body.password).await?;
+    let updated = state.storage.update_credentials(&token.email, |cred| {
+        if cred.get("user_id").and_then(|v| v.as_str()) != Some(token.user_id.as_str()) {
+            anyhow::bail!("credentials belong to another account");
+        }
+        let obj = cred.as_object_mut().ok_or_else(|| anyhow::anyhow!("malformed credentials"))?;
+        obj.insert("password_hash".into(), serde_json::json!(pwd_hash));
+        obj.insert("password_changed_at".into(), serde_json::json!(now));
+        Ok(())
+    });
+    match updated {
+        Ok(Some(())) => {}
+        Ok(None) | Err(_) => return Err(invalid()),
+    }
+
+    // Whoever knew the old password is signed out, and the owner isn't locked out
+    let revoked: usize = state.storage.revoke_user_sessions(&token.user_id, None, "password_reset", now).map_err(internal)?;
+    if let Some((key, _)) = lockout_keys(&state, Some(&token.email), None).first() {
+        if let Err(e) = state.storage.clear_login_failures(key) { tracing::error!("clearing login failures failed: {:?}", e); }
+    }
+    // The link arrived, so the address works
+    if let Err(e) = mark_email_verified(&state, &token.user_id, &token.email) { tracing::error!("marking email verified failed: {:?}", e); }
+    audit(&state, "password_reset", Some(&token.user_id), Some(&token.user_id), ip, serde_json::json!({ "sessions_revoked": revoked }));
+    Ok(Json(serde_json::json!({ "reset": true, "sessionsRevoked": revoked })))
+}
+
+/// POST /api/auth/password/change
+async fn password_change(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Change>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let claims: auth::Claims = current_claims(&state, &headers)?;
+    let user: serde_json::Value = state.storage.get_user(&claims.sub).map_err(internal)?.ok_or(message(StatusCode::NOT_FOUND, "user not found"))?;
+    let email: String = user.get("email").and_then(|v| v.as_str()).unwrap_or_default().to_lowercase();
+    let cred: serde_json::Value = state.storage.get_credentials(&email).map_err(internal)?
+        .filter(|c| c.get("user_id").and_then(|v| v.as_str()) == Some(claims.sub.as_str()))
+        .ok_or(message(StatusCode::BAD_REQUEST, "the account has no password; reset it by email to set one"))?;
+    let old_hash: String = cred.get("password_hash").and_then(|v| v.as_str())
+        .ok_or(message(StatusCode::BAD_REQUEST, "the account has no password; reset it by email to set one"))?
+        .to_string();
+
+    // A wrong current password counts like a failed sign-in, so a stolen
+    // session can't be used to guess it
+    let keys = lockout_keys(&state, Some(&email), ip);
+    check_lockout(&state, &keys)?;
+    if verify_password(&state, body.current_password.clone(), old_hash.clone()).await? == auth::PasswordCheck::Mismatch {
+        note_failure(&state, &keys, "password_change_failed", &email, ip);
+        return Err(message(StatusCode::UNAUTHORIZED, "the current password is wrong"));
+    }
+    if body.new_password == body.current_password {
+        return Err(message(StatusCode::BAD_REQUEST, "the new password must differ from the current one"));
+    }
+    let username: &str = user.get("username").and_then(|v| v.as_str()).unwrap_or_default();
+    check_new_password(&state, &body.new_password, &[&email, username])?;
+
+    let now: i64 = chrono::Utc::now().timestamp();
+    let pwd_hash: String = hash_password(&state, body.new_password).await?;
+    // Only replace the hash we checked, in case the password changed meanwhile
+    let updated = state.storage.update_credentials(&email, |cred| {
+        if cred.get("password_hash").and_then(|v| v.as_str()) != Some(old_hash.as_str()) { return Ok(false); }
+        let obj = cred.as_object_mut().ok_or_else(|| anyhow::anyhow!("malformed credentials"))?;
+        obj.insert("password_hash".into(), serde_json::json!(pwd_hash));
+        obj.insert("password_changed_at".into(), serde_json::json!(now));
+        Ok(true)
+    }).map_err(internal)?;
+    if updated != Some(true) {
+        return Err(message(StatusCode::CONFLICT, "the password was changed meanwhile, try again"));
+    }
+    if let Err(e) = state.storage.clear_login_failures(&keys[0].0) { tracing::error!("clearing login failures failed: {:?}", e); }
+
+    // Other sessions may belong to whoever knew the old password
+    let revoked: usize = state.storage.revoke_user_sessions(&claims.sub, claims.sid.as_deref(), "password_changed", now).map_err(internal)?;
+    audit(&state, "password_changed", Some(&claims.sub), Some(&claims.sub), ip, serde_json::json!({ "sessions_revoked": revoked }));
+    Ok(Json(serde_json::json!({ "changed": true, "sessionsRevoked": revoked })))
+}
+
+/// POST /api/auth/email/verify
+async fn email_verify(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Verify>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let token: auth::AccountToken = auth::redeem_account_token(&state.storage, auth::AccountTokenPurpose::VerifyEmail, &body.token, chrono::Utc::now().timestamp())
+        .map_err(internal)?
+        .ok_or(message(StatusCode::BAD_REQUEST, "the verification link is invalid or has expired"))?;
+    if !mark_email_verified(&state, &token.user_id, &token.email).map_err(internal)? {
+        return Err(message(StatusCode::BAD_REQUEST, "the account's email address changed after this link was sent"));
+    }
+    audit(&state, "email_verified", Some(&token.user_id), Some(&token.user_id), ip, serde_json::json!({ "email": token.email }));
+    Ok(Json(serde_json::json!({ "verified": true, "email": token.email })))
+}
+
+/// POST /api/auth/email/verify/send
+async fn email_verify_send(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    mail_config(&state)?;
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| message(status, &e.message))?;
+    let user: serde_json::Value = state.storage.get_user(&user_id).map_err(internal)?.ok_or(message(StatusCode::NOT_FOUND, "user not found"))?;
+    if user.get("email_verified").and_then(|v| v.as_bool()) == Some(true) {
+        return Err(message(StatusCode::CONFLICT, "the email address is already verified"));
+    }
+    let email: &str = user.get("email").and_then(|v| v.as_str()).filter(|e| !e.is_empty())
+        .ok_or(message(StatusCode::BAD_REQUEST, "the account has no email address"))?;
+    send_verification_email(&state, &user_id, email);
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "message": "verification link sent" }))))
+}
@@ -4,12 +4,21 @@
 // - POST   /api/admin/users/{id}/promote
 // - POST   /api/admin/users/{id}/demote
 // - DELETE /api/admin/users/{id}
+// - GET    /api/admin/users/{id}/sessions?limit=50 (presence session log, newest first)
+// - GET    /api/admin/stats/dau?days=30 (daily active users, oldest day first)
+// - GET    /api/admin/stats/rate (per-budget rate limiter keys, evictions and denials)
+// - GET    /api/admin/lockouts (accounts/IPs with failed sign-ins, backoff and lockout times)
+// - DELETE /api/admin/lockouts/{key} (clear one, e.g. "ip:203.0.113.7")
+// - POST   /api/admin/users/{id}/unlock (clear the user's account lockout)
+// - GET    /api/admin/audit?limit=100 (security events, newest first)
+// - GET    /api/admin/services?name= (discovered service instances and their health)
 //
 // We apply CSRF protection to unsafe methods via a route layer.
-use axum::{routing::get, routing::post, routing::delete, Router, extract::{State, Path}, Json, middleware};
+use axum::{routing::get, routing::post, routing::delete, Router, extract::{State, Path, Query}, Json, middleware};
+use std::collections::HashMap;
 use crate::middleware as gw_mw;
 use axum::http::{HeaderMap, StatusCode};
-use crate::state::{AppState, require_admin};
+use crate::state::{AppState, audit, require_admin};
 
 /// Build router for admin-only APIs.
 pub fn router() -> Router<AppState> {
@@ -18,6 +27,14 @@ pub fn router() -> Router<AppState> {
         .route("/api/admin/users/{id}/promote", post(api_admin_promote_user))
         .route("/api/admin/users/{id}/demote", post(api_admin_demote_user))
         .route("/api/admin/users/{id}", delete(api_admin_delete_user))
+        .route("/api/admin/users/{id}/sessions", get(api_admin_user_sessions))
+        .route("/api/admin/stats/dau", get(api_admin_dau))
+        .route("/api/admin/stats/rate", get(api_admin_rate_stats))
+        .route("/api/admin/lockouts", get(api_admin_lockouts))
+        .route("/api/admin/lockouts/{key}", delete(api_admin_clear_lockout))
+        .route("/api/admin/users/{id}/unlock", post(api_admin_unlock_user))
+        .route("/api/admin/audit", get(api_admin_audit))
+        .route("/api/admin/services", get(api_admin_services))
         .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
 }
 
@@ -105,3 +122,123 @@ async fn api_admin_delete_user(
     }
     match state.storage.delete_user(&user_id) { Ok(_) => Ok(StatusCode::NO_CONTENT), Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())) }
 }
+
+/// GET /api/admin/users/{id}/sessions — finished connections of a user
+async fn api_admin_user_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let limit: usize = q.get("limit").and_then(|s| s.parse::<usize>().ok()).unwrap_or(50).min(presence::MAX_SESSIONS_PER_USER);
+    match state.presence.sessions(&user_id, limit) {
+        Ok(sessions) => Ok(Json(serde_json::to_value(sessions).unwrap_or_else(|_| serde_json::json!([])))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// GET /api/admin/stats/dau — distinct active users per UTC day (max 365 days)
+async fn api_admin_dau(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let days: u32 = q.get("days").and_then(|s| s.parse::<u32>().ok()).unwrap_or(30).clamp(1, 365);
+    match state.presence.daily_active_users(days) {
+        Ok(counts) => Ok(Json(serde_json::json!(counts.into_iter().map(|(date, users)| serde_json::json!({ "date": date, "users": users })).collect::<Vec<_>>()))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// GET /api/admin/stats/rate — in-memory rate limiter metrics per budget
+async fn api_admin_rate_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let budgets: serde_json::Map<String, serde_json::Value> = state.rate.metrics().into_iter().map(|(name, m)| {
+        (name, serde_json::json!({ "keys": m.keys, "expired": m.expired, "evicted": m.evicted, "allowed": m.allowed, "denied": m.denied }))
+    }).collect();
+    Ok(Json(serde_json::Value::Object(budgets)))
+}
+
+/// GET /api/admin/lockouts — tracked accounts/IPs, locked ones first
+async fn api_admin_lockouts(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let now: i64 = chrono::Utc::now().timestamp();
+    let mut rows: Vec<(String, storage::LoginFailureRecord)> = state.storage.list_login_failures().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    rows.sort_by_key(|(key, r)| (r.locked_until <= now, std::cmp::Reverse(r.last_failed_at), key.clone()));
+    Ok(Json(serde_json::json!(rows.into_iter().map(|(key, r)| serde_json::json!({
+        "key": key,
+        "failures": r.failures,
+        "last_failed_at": r.last_failed_at,
+        "next_attempt_at": r.next_attempt_at,
+        "locked_until": r.locked_until,
+        "locked": r.locked_until > now,
+    })).collect::<Vec<_>>())))
+}
+
+/// DELETE /api/admin/lockouts/{key} — forget failed sign-ins for an account or IP
+async fn api_admin_clear_lockout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let requester_id = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    match state.storage.clear_login_failures(&key) {
+        Ok(true) => {
+            audit(&state, "lockout_cleared", Some(&requester_id), Some(&key), None, serde_json::Value::Null);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "no failed sign-ins for key".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// POST /api/admin/users/{id}/unlock — clear a user's account lockout
+async fn api_admin_unlock_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let requester_id = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let email: String = match state.storage.get_user(&user_id) {
+        Ok(Some(user)) => user.get("email").and_then(|v| v.as_str()).map(|s| s.to_lowercase()).ok_or((StatusCode::BAD_REQUEST, "user has no email".to_string()))?,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "user not found".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    let key: String = format!("account:{}", email);
+    let cleared: bool = state.storage.clear_login_failures(&key).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if cleared { audit(&state, "lockout_cleared", Some(&requester_id), Some(&key), None, serde_json::Value::Null); }
+    Ok(Json(serde_json::json!({ "ok": true, "cleared": cleared })))
+}
+
+/// GET /api/admin/audit — recent security events (max 1000)
+async fn api_admin_audit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let limit: usize = q.get("limit").and_then(|s| s.parse::<usize>().ok()).unwrap_or(100).clamp(1, 1000);
+    match state.storage.list_audit(limit) {
+        Ok(events) => Ok(Json(serde_json::to_value(events).unwrap_or_else(|_| serde_json::json!([])))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// GET /api/admin/services — registry instances (catalog and self-registered), by id
+async fn api_admin_services(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let _ = match require_admin(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    let instances = state.discovery.instances(q.get("name").map(String::as_str));
+    Ok(Json(serde_json::to_value(instances).unwrap_or_else(|_| serde_json::json!([]))))
+}
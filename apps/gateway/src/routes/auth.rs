@@ -1,4 +1,4 @@
-// Auth endpoints (signup/login/me/logout/username check)
+// Auth endpoints (signup/login/refresh/me/logout/sessions/username check)
 //
 // Notes for beginners:
 // - Axum extractors map request pieces into handler arguments. For example,
@@ -6,18 +6,31 @@
 //   cookies; `Json<T>` parses JSON into `T`.
 // - We support tolerant login parsing (JSON, x-www-form-urlencoded, crude
 //   multipart heuristic) to play nice with dev tools and web forms.
-use axum::{Router, routing::{get, post}, extract::{State, Query}, Json, middleware};
+// - Failed logins and signup conflicts are counted per account and per client
+//   IP; repeated failures back off exponentially and then lock the key for a
+//   while (see `auth::LockoutPolicy`). Admins can clear lockouts.
+// - Signing in sets a short-lived access token in the `session` cookie and a
+//   rotating refresh token in the `refresh` cookie (see `auth::refresh_tokens`).
+//   Clients call POST /api/auth/refresh when the access token expires.
+// - Each sign-in is a server-side session (device, IP, last use). Users can
+//   list their sessions and revoke one or all of them; revoked sessions'
+//   access tokens are rejected right away (`state::verify_token`).
+// - When the account has TOTP turned on, a correct password only returns an
+//   MFA challenge (`{ mfaRequired, mfaToken }`); the session cookies come from
+//   POST /api/auth/login/mfa (see routes/totp.rs).
+// - New passwords must pass `state.passwords` (length, not the email or
+//   username, not on the breached list). A password that verifies against a
+//   hash made with older Argon2 parameters is re-hashed during the login.
+use axum::{Router, routing::{get, post, delete}, extract::{State, Query, Path, ConnectInfo}, Json, middleware};
 use crate::middleware as gw_mw;
 use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
-use crate::state::{AppState, is_admin_email, ADMIN_EMAIL};
+use crate::routes::account::send_verification_email;
+use crate::state::{AppState, audit, extract_token, verify_token, is_admin_email, ADMIN_EMAIL};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use axum::http::{StatusCode, HeaderMap};
 use serde::Deserialize;
 use uuid::Uuid;
-use rand::RngCore;
-use argon2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
-};
 
 /// Public auth routes (no CSRF), e.g. login forms.
 pub fn public() -> Router<AppState> {
@@ -29,8 +42,11 @@ pub fn protected() -> Router<AppState> {
     Router::new()
         .route("/api/auth/signup", post(api_signup))
         .route("/api/auth/check_username", get(api_check_username))
+        .route("/api/auth/refresh", post(api_refresh))
         .route("/api/auth/me", get(api_auth_me))
         .route("/api/auth/logout", post(api_logout))
+        .route("/api/auth/sessions", get(api_list_sessions).delete(api_revoke_sessions))
+        .route("/api/auth/sessions/{id}", delete(api_revoke_session))
         .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
 }
 
@@ -52,19 +68,168 @@ enum AuthPayload {
 #[derive(Deserialize)]
 struct SignupPayload { email: String, password: String, username: String }
 
+/// Cookie holding the refresh token. Scoped to the auth endpoints so it isn't
+/// sent with every request.
+const REFRESH_COOKIE: &str = "refresh";
+const REFRESH_COOKIE_PATH: &str = "/api/auth";
+
+/// Optional body for refresh, for clients that keep the refresh token
+/// themselves instead of in a cookie.
+#[derive(Deserialize)]
+struct RefreshPayload { refresh_token: String }
+
+/// Device details recorded on the session: user agent and client IP.
+pub(crate) fn device(headers: &HeaderMap, ip: Option<IpAddr>) -> auth::Device {
+    let user_agent: Option<String> = headers.get("user-agent").and_then(|hv| hv.to_str().ok()).map(|ua| ua.chars().take(256).collect());
+    auth::Device { user_agent, ip: ip.map(|ip| ip.to_string()) }
+}
+
+/// Start a session for `user_id` and set the `session` (access token) and
+/// `refresh` cookies.
+pub(crate) fn start_session(state: &AppState, jar: CookieJar, user_id: &str, device: &auth::Device) -> Result<(CookieJar, auth::IssuedTokens), (StatusCode, String)> {
+    let issued = auth::issue_tokens(&state.storage, &state.tokens, user_id, device, chrono::Utc::now().timestamp())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((set_token_cookies(jar, &issued), issued))
+}
+
+fn set_token_cookies(jar: CookieJar, issued: &auth::IssuedTokens) -> CookieJar {
+    let mut session: Cookie<'static> = Cookie::new("session", issued.access_token.clone());
+    session.set_path("/");
+    session.set_http_only(true);
+    session.set_same_site(SameSite::Lax);
+    let mut refresh: Cookie<'static> = Cookie::new(REFRESH_COOKIE, issued.refresh_token.clone());
+    refresh.set_path(REFRESH_COOKIE_PATH);
+    refresh.set_http_only(true);
+    refresh.set_same_site(SameSite::Strict);
+    refresh.set_max_age(time::Duration::seconds(issued.refresh_expires_at - chrono::Utc::now().timestamp()));
+    jar.add(session).add(refresh)
+}
+
+fn clear_token_cookies(jar: CookieJar) -> CookieJar {
+    let mut session: Cookie<'static> = Cookie::new("session", "");
+    session.set_path("/");
+    let mut refresh: Cookie<'static> = Cookie::new(REFRESH_COOKIE, "");
+    refresh.set_path(REFRESH_COOKIE_PATH);
+    jar.remove(session).remove(refresh)
+}
+
+/// Throttling keys for a sign-in attempt: the account (when known) and the
+/// client IP, grouped like rate limit keys (IPv6 per /64).
+pub(crate) fn lockout_keys(state: &AppState, email: Option<&str>, ip: Option<IpAddr>) -> Vec<(String, auth::LockoutPolicy)> {
+    let mut keys: Vec<(String, auth::LockoutPolicy)> = Vec::new();
+    if let Some(email) = email { keys.push((format!("account:{}", email), state.lockout.account)); }
+    if let Some(ip) = ip { keys.push((format!("ip:{}", state.rate.clients().group(ip)), state.lockout.ip)); }
+    keys
+}
+
+/// Reject the attempt with 429 while any key is backing off or locked out.
+/// Rejected attempts are not counted, so a lockout doesn't extend itself.
+pub(crate) fn check_lockout(state: &AppState, keys: &[(String, auth::LockoutPolicy)]) -> Result<(), (StatusCode, String)> {
+    let now: i64 = chrono::Utc::now().timestamp();
+    for (key, policy) in keys {
+        let rec = state.storage.get_login_failures(key).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if let Some(wait) = rec.and_then(|r| policy.retry_after(&r, now)) {
+            return Err((StatusCode::TOO_MANY_REQUESTS, serde_json::json!({ "message": "too many failed attempts, try again later", "retry_after": wait }).to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Count a failed attempt against every key and audit it, plus any lockout it starts.
+pub(crate) fn note_failure(state: &AppState, keys: &[(String, auth::LockoutPolicy)], event: &str, subject: &str, ip: Option<IpAddr>) {
+    let now: i64 = chrono::Utc::now().timestamp();
+    for (key, policy) in keys {
+        // read-modify-write in one transaction: parallel failures all count
+        let (prev, rec) = match state.storage.update_login_failures(key, |prev| policy.register_failure(prev, now)) {
+            Ok(updated) => updated,
+            Err(e) => { tracing::error!("storing login failure for {} failed: {:?}", key, e); continue; }
+        };
+        let was_locked: bool = prev.as_ref().is_some_and(|r| r.locked_until > now);
+        if rec.locked_until > now && !was_locked {
+            audit(state, "lockout_started", None, Some(key), ip, serde_json::json!({ "failures": rec.failures, "locked_until": rec.locked_until }));
+        }
+    }
+    audit(state, event, None, Some(subject), ip, serde_json::Value::Null);
+}
+
+/// Argon2id hash (random salt, PHC string) of `password` with the cost set by
+/// `state.passwords`. Argon2 is CPU-bound, so it runs on a blocking thread to
+/// avoid stalling the async runtime.
+pub(crate) async fn hash_password(state: &AppState, password: String) -> Result<String, (StatusCode, String)> {
+    let policy: Arc<auth::PasswordPolicy> = Arc::clone(&state.passwords);
+    tokio::task::spawn_blocking(move || policy.hash(&password))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Check `password` against the stored `hash`, on a blocking thread like `hash_password`.
+pub(crate) async fn verify_password(state: &AppState, password: String, hash: String) -> Result<auth::PasswordCheck, (StatusCode, String)> {
+    let policy: Arc<auth::PasswordPolicy> = Arc::clone(&state.passwords);
+    tokio::task::spawn_blocking(move || policy.verify(&password, &hash))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// 400 with the reason when `password` doesn't meet the password policy.
+/// `identifiers` are the account's email and username, which it may not be.
+pub(crate) fn check_new_password(state: &AppState, password: &str, identifiers: &[&str]) -> Result<(), (StatusCode, String)> {
+    state.passwords.check(password, identifiers)
+        .map_err(|e| (StatusCode::BAD_REQUEST, serde_json::json!({ "message": e.to_string() }).to_string()))
+}
+
+/// Store a fresh hash of `password` after it verified against an outdated
+/// `old_hash`, unless the password was changed in the meantime. Failures are
+/// only logged: the sign-in goes ahead either way.
+async fn upgrade_password_hash(state: &AppState, email: &str, password: String, old_hash: &str) {
+    let new_hash: String = match hash_password(state, password).await {
+        Ok(h) => h,
+        Err((_, e)) => { tracing::error!("re-hashing password failed: {}", e); return; }
+    };
+    let updated = state.storage.update_credentials(email, |cred| {
+        if cred.get("password_hash").and_then(|v| v.as_str()) != Some(old_hash) { return Ok(false); }
+        let obj = cred.as_object_mut().ok_or_else(|| anyhow::anyhow!("malformed credentials"))?;
+        obj.insert("password_hash".into(), serde_json::json!(new_hash));
+        Ok(true)
+    });
+    match updated {
+        Ok(Some(true)) => tracing::info!(email = %email, "password hash upgraded to the current Argon2 parameters"),
+        Ok(_) => {}
+        Err(e) => tracing::error!("storing upgraded password hash failed: {:?}", e),
+    }
+}
+
 /// POST /api/auth/signup
 /// Creates a user + Argon2-hashed credentials, sets session cookie, returns basic info.
+/// A link to verify the email address is sent when a mailer is configured.
 async fn api_signup(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     jar: CookieJar,
     Json(payload): Json<SignupPayload>,
 ) -> Result<(CookieJar, Json<serde_json::Value>), (StatusCode, String)> {
+    // 0) Signup conflicts reveal which emails/usernames exist, so they count as
+    //    failures against the client IP like failed logins do.
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let keys = lockout_keys(&state, None, ip);
+    check_lockout(&state, &keys)?;
+
     // 1) Basic input validation (normalize email and enforce minimal length)
     let email: String = payload.email.trim().to_lowercase();
     let password: String = payload.password;
-    if email.is_empty() || password.len() < 8 { return Err((StatusCode::BAD_REQUEST, "invalid email or password".into())); }
+    if email.is_empty() { return Err((StatusCode::BAD_REQUEST, "invalid email or password".into())); }
+    check_new_password(&state, &password, &[&email, payload.username.trim()])?;
     // Disallow duplicate email registrations
-    match state.storage.get_credentials(&email) { Ok(Some(_)) => return Err((StatusCode::CONFLICT, "email already registered".into())), Ok(None) => {}, Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), }
+    match state.storage.get_credentials(&email) {
+        Ok(Some(_)) => {
+            note_failure(&state, &keys, "signup_failed", &email, ip);
+            return Err((StatusCode::CONFLICT, "email already registered".into()));
+        }
+        Ok(None) => {}
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
 
     // 2) Validate and enforce unique username (case-insensitive)
     let u_trim: &str = payload.username.trim();
@@ -72,6 +237,7 @@ async fn api_signup(
     match state.storage.list_users() {
         Ok(users) => {
             if users.iter().any(|usr: &serde_json::Value| usr.get("username").and_then(|v| v.as_str()).map(|s| s.eq_ignore_ascii_case(u_trim)).unwrap_or(false)) {
+                note_failure(&state, &keys, "signup_failed", &email, ip);
                 return Err((StatusCode::CONFLICT, "username already taken".into()));
             }
         }
@@ -81,42 +247,18 @@ async fn api_signup(
     // 3) Create user record with role derived from email (admin/user)
     let user_id: String = Uuid::new_v4().to_string();
     let role: &'static str = if is_admin_email(&email) { "admin" } else { "user" };
-    let user_obj = serde_json::json!({ "id": user_id, "email": email, "username": u_trim.to_string(), "created_at": chrono::Utc::now().timestamp(), "role": role });
+    let user_obj = serde_json::json!({ "id": user_id, "email": email, "username": u_trim.to_string(), "created_at": chrono::Utc::now().timestamp(), "role": role, "email_verified": false });
     if let Err(e) = state.storage.put_user(&user_id, &user_obj) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); }
 
     // 4) Hash password with Argon2 using a random salt, then store credentials
-    //    Argon2 is CPU-bound; run it on a blocking thread to avoid stalling the async runtime.
-    let mut salt_bytes: [u8; 16] = [0u8; 16];
-    rand::rng().fill_bytes(&mut salt_bytes);
-    let salt: SaltString = SaltString::encode_b64(&salt_bytes).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let pwd_hash: String = match tokio::task::spawn_blocking({
-        let password = password.clone();
-        let salt = salt;
-        move || {
-            let argon2: Argon2<'_> = Argon2::default();
-            argon2
-                .hash_password(password.as_bytes(), &salt)
-                .map(|ph| ph.to_string())
-                .map_err(|e| e.to_string())
-        }
-    })
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    {
-        Ok(h) => h,
-        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
-    };
+    let pwd_hash: String = hash_password(&state, password).await?;
     let cred_obj = serde_json::json!({ "user_id": user_id, "password_hash": pwd_hash, "created_at": chrono::Utc::now().timestamp() });
     if let Err(e) = state.storage.put_credentials(&email, &cred_obj) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); }
+    send_verification_email(&state, &user_id, &email);
 
-    // 5) Issue a signed JWT and set it in an HttpOnly, same-site cookie named `session`
-    let token: String = match auth::create_jwt(&user_id, 3600 * 24 * 7) { Ok(t) => t, Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
-    let mut cookie: Cookie<'_> = Cookie::new("session", token);
-    cookie.set_path("/");
-    cookie.set_http_only(true);
-    cookie.set_same_site(SameSite::Lax);
-    let jar: CookieJar = jar.add(cookie);
-    Ok((jar, Json(serde_json::json!({ "userId": user_id, "email": email }))))
+    // 5) Sign in: access token in the HttpOnly `session` cookie, refresh token in `refresh`
+    let (jar, issued) = start_session(&state, jar, &user_id, &device(&headers, ip))?;
+    Ok((jar, Json(serde_json::json!({ "userId": user_id, "email": email, "expiresIn": issued.access_expires_in }))))
 }
 
 /// GET /api/auth/check_username?u=<name>
@@ -141,13 +283,19 @@ async fn api_check_username(
 }
 
 /// POST /api/auth/login
-/// Validates credentials and sets `session` cookie with a signed JWT.
+/// Validates credentials and sets the `session` and `refresh` cookies. Attempts
+/// are refused with 429 while the account or client IP is locked out.
 async fn api_login(
     State(state): State<AppState>,
     jar: CookieJar,
     req: axum::http::Request<axum::body::Body>,
 ) -> Result<(CookieJar, Json<serde_json::Value>), (StatusCode, String)> {
     use axum::http::header::CONTENT_TYPE;
+    // 0) Refuse early if this client is already locked out
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, req.headers(), req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ci| ci.0));
+    check_lockout(&state, &lockout_keys(&state, None, ip))?;
+    let device: auth::Device = device(req.headers(), ip);
+
     // 1) Tolerant parsing. We accept JSON, URL-encoded, or a simple multipart
     //    heuristic (for dev). We convert all of them into an AuthPayload.
     let ct: String = req.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("").to_lowercase();
@@ -197,16 +345,29 @@ async fn api_login(
                 }),
                 Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
             };
-            let email = match email_opt { Some(e) => e, None if uname.eq_ignore_ascii_case("admin") => ADMIN_EMAIL.to_string(), None => return Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string())), };
+            let email = match email_opt {
+                Some(e) => e,
+                None if uname.eq_ignore_ascii_case("admin") => ADMIN_EMAIL.to_string(),
+                None => {
+                    note_failure(&state, &lockout_keys(&state, None, ip), "login_failed", &uname.to_lowercase(), ip);
+                    return Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string()));
+                }
+            };
             (email, password)
         }
     };
+    // Unknown emails are tracked too, so lockouts don't reveal which accounts exist
+    let keys = lockout_keys(&state, Some(&email), ip);
+    check_lockout(&state, &keys)?;
 
     // 3) Authenticate by verifying the Argon2 password hash stored in credentials
     tracing::debug!(content_type = %ct, has_email = %(!email.is_empty()), "api_login invoked");
     let creds_val: serde_json::Value = match state.storage.get_credentials(&email) {
         Ok(Some(v)) => v,
-        Ok(None) => return Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string())),
+        Ok(None) => {
+            note_failure(&state, &keys, "login_failed", &email, ip);
+            return Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string()));
+        }
         Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     };
 
@@ -217,29 +378,18 @@ async fn api_login(
         .and_then(|v| v.as_str())
         .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "malformed credentials".to_string()))?
         .to_string();
-    // Argon2 verify is CPU-bound; offload to a blocking thread and operate purely on owned data
-    let verify_res: Result<bool, String> = tokio::task::spawn_blocking({
-        let password = password.clone();
-        let stored_hash_string = stored_hash_string.clone();
-        move || {
-            let parsed_hash: PasswordHash<'_> = PasswordHash::new(&stored_hash_string)
-                .map_err(|e| e.to_string())?;
-            Ok(Argon2::default()
-                .verify_password(password.as_bytes(), &parsed_hash)
-                .is_ok())
-        }
-    })
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    match verify_res {
-        Ok(true) => {}
-        Ok(false) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                serde_json::json!({ "message": "invalid credentials" }).to_string(),
-            ));
-        }
-        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    // Argon2 verify is CPU-bound and runs on a blocking thread
+    let check: auth::PasswordCheck = verify_password(&state, password.clone(), stored_hash_string.clone()).await?;
+    if check == auth::PasswordCheck::Mismatch {
+        note_failure(&state, &keys, "login_failed", &email, ip);
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            serde_json::json!({ "message": "invalid credentials" }).to_string(),
+        ));
+    }
+    // Hashes made with older Argon2 parameters are replaced while we have the password
+    if check == auth::PasswordCheck::Outdated {
+        upgrade_password_hash(&state, &email, password, &stored_hash_string).await;
     }
 
     // 4) Ensure a user record exists for this credentials entry (safety for seeded admin)
@@ -254,20 +404,151 @@ async fn api_login(
         Err(_) => {}
     }
 
-    // 5) Issue new JWT and set as session cookie
-    let token: String = match auth::create_jwt(&user_id, 3600 * 24 * 7) { Ok(t) => t, Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
-    let mut cookie: Cookie<'_> = Cookie::new("session", token);
-    cookie.set_path("/"); cookie.set_http_only(true); cookie.set_same_site(SameSite::Lax);
-    let jar: CookieJar = jar.add(cookie);
-    Ok((jar, Json(serde_json::json!({ "userId": user_id, "email": email }))))
+    // 5) Accounts with two-factor authentication get a challenge instead of a
+    //    session, answered at POST /api/auth/login/mfa. Their failures are only
+    //    cleared once that succeeds, so the password alone can't reset them.
+    if auth::totp_enabled(&creds_val) {
+        let mfa_token: String = auth::create_mfa_challenge(&state.storage, &user_id, &email, chrono::Utc::now().timestamp())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok((jar, Json(serde_json::json!({ "mfaRequired": true, "mfaToken": mfa_token, "expiresIn": auth::MFA_CHALLENGE_TTL_SECS }))));
+    }
+
+    // A successful login clears the account's failures (the IP's decay on their own)
+    if let Err(e) = state.storage.clear_login_failures(&keys[0].0) { tracing::error!("clearing login failures failed: {:?}", e); }
+
+    // 6) Start a new session and set the session cookies
+    let (jar, issued) = start_session(&state, jar, &user_id, &device)?;
+    Ok((jar, Json(serde_json::json!({ "userId": user_id, "email": email, "expiresIn": issued.access_expires_in }))))
+}
+
+/// POST /api/auth/refresh
+/// Exchanges the refresh token (from the `refresh` cookie, or a JSON body
+/// `{ "refresh_token": ... }`) for a new access token and refresh token. A
+/// refresh token works once; replaying one revokes its session. Tokens
+/// passed in the body are returned in the body, cookie tokens in cookies.
+async fn api_refresh(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    body: axum::body::Bytes,
+) -> Result<(CookieJar, Json<serde_json::Value>), (StatusCode, String)> {
+    let from_body: Option<String> = if body.is_empty() { None } else {
+        Some(serde_json::from_slice::<RefreshPayload>(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?.refresh_token)
+    };
+    let token: String = match from_body.clone().or_else(|| jar.get(REFRESH_COOKIE).map(|c| c.value().to_string())) {
+        Some(t) if !t.is_empty() => t,
+        _ => return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "missing refresh token" }).to_string())),
+    };
+    let now: i64 = chrono::Utc::now().timestamp();
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let outcome = auth::refresh_tokens(&state.storage, &state.tokens, &token, &device(&headers, ip), now).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let issued: auth::IssuedTokens = match outcome {
+        auth::Refreshed::Issued(issued) => issued,
+        auth::Refreshed::AlreadyRotated => {
+            return Err((StatusCode::CONFLICT, serde_json::json!({ "message": "refresh token already used, retry with the latest one" }).to_string()));
+        }
+        auth::Refreshed::Reused { user_id, session_id } => {
+            tracing::warn!(user_id = %user_id, session_id = %session_id, "refresh token reuse, session revoked");
+            audit(&state, "refresh_token_reused", Some(&user_id), Some(&session_id), ip, serde_json::Value::Null);
+            return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid refresh token" }).to_string()));
+        }
+        auth::Refreshed::Invalid => {
+            return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid refresh token" }).to_string()));
+        }
+    };
+    if from_body.is_some() {
+        return Ok((jar, Json(serde_json::json!({
+            "userId": issued.user_id,
+            "access_token": issued.access_token,
+            "expires_in": issued.access_expires_in,
+            "refresh_token": issued.refresh_token,
+        }))));
+    }
+    let jar: CookieJar = set_token_cookies(jar, &issued);
+    Ok((jar, Json(serde_json::json!({ "userId": issued.user_id, "expiresIn": issued.access_expires_in }))))
+}
+
+/// POST /api/auth/logout — revokes the current session (found through the
+/// refresh token, or else the access token) and removes the `session` and
+/// `refresh` cookies
+async fn api_logout(State(state): State<AppState>, headers: HeaderMap, jar: CookieJar) -> (CookieJar, StatusCode) {
+    let now: i64 = chrono::Utc::now().timestamp();
+    let revoked = match jar.get(REFRESH_COOKIE).map(|c| c.value().to_string()).filter(|t| !t.is_empty()) {
+        Some(token) => auth::revoke_refresh_token(&state.storage, &token, "logout", now).map(|_| ()),
+        None => match extract_token(&headers).and_then(|t| verify_token(&state, &t).ok()).and_then(|c| c.sid) {
+            Some(sid) => state.storage.revoke_session(&sid, "logout", now).map(|_| ()),
+            None => Ok(()),
+        },
+    };
+    if let Err(e) = revoked { tracing::error!("revoking session on logout failed: {:?}", e); }
+    (clear_token_cookies(jar), StatusCode::NO_CONTENT)
+}
+
+/// Claims of the caller's verified access token, or 401.
+pub(crate) fn current_claims(state: &AppState, headers: &HeaderMap) -> Result<auth::Claims, (StatusCode, String)> {
+    let token: String = extract_token(headers).ok_or((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "missing token" }).to_string()))?;
+    verify_token(state, &token).map_err(|_| (StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid token" }).to_string()))
+}
+
+/// GET /api/auth/sessions — the caller's live sessions, newest first, with
+/// `current` marking the one making the request
+async fn api_list_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let claims: auth::Claims = current_claims(&state, &headers)?;
+    let now: i64 = chrono::Utc::now().timestamp();
+    let sessions = state.storage.list_sessions(&claims.sub).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let list: Vec<serde_json::Value> = sessions.into_iter().rev()
+        .filter(|(_, s)| s.revoked_at.is_none() && s.expires_at > now)
+        .map(|(id, s)| serde_json::json!({
+            "id": id,
+            "current": claims.sid.as_deref() == Some(id.as_str()),
+            "created_at": s.created_at,
+            "last_used_at": s.last_used_at,
+            "expires_at": s.expires_at,
+            "user_agent": s.user_agent,
+            "ip": s.ip,
+        }))
+        .collect();
+    Ok(Json(serde_json::json!({ "sessions": list })))
 }
 
-/// POST /api/auth/logout — removes the `session` cookie
-async fn api_logout(jar: CookieJar) -> (CookieJar, StatusCode) {
-    let mut cookie: Cookie<'_> = Cookie::new("session", "");
-    cookie.set_path("/");
-    let jar: CookieJar = jar.remove(cookie);
-    (jar, StatusCode::NO_CONTENT)
+/// DELETE /api/auth/sessions/{id} — revoke one of the caller's sessions
+async fn api_revoke_session(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let claims: auth::Claims = current_claims(&state, &headers)?;
+    let owned: bool = matches!(state.storage.get_session(&id), Ok(Some(s)) if s.user_id == claims.sub);
+    if !owned { return Err((StatusCode::NOT_FOUND, serde_json::json!({ "message": "session not found" }).to_string())); }
+    let revoked: bool = state.storage.revoke_session(&id, "user_revoked", chrono::Utc::now().timestamp()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if revoked {
+        audit(&state, "session_revoked", Some(&claims.sub), Some(&id), gw_mw::client_ip(&state, &headers, Some(peer)), serde_json::Value::Null);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct RevokeAllQuery { #[serde(default)] keep_current: bool }
+
+/// DELETE /api/auth/sessions[?keep_current=true] — revoke all of the caller's
+/// sessions, or all but the one making the request
+async fn api_revoke_sessions(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(q): Query<RevokeAllQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let claims: auth::Claims = current_claims(&state, &headers)?;
+    let keep: Option<&str> = if q.keep_current { claims.sid.as_deref() } else { None };
+    let revoked: usize = state.storage.revoke_user_sessions(&claims.sub, keep, "user_revoked_all", chrono::Utc::now().timestamp())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    audit(&state, "sessions_revoked", Some(&claims.sub), Some(&claims.sub), gw_mw::client_ip(&state, &headers, Some(peer)), serde_json::json!({ "revoked": revoked, "kept_current": keep.is_some() }));
+    Ok(Json(serde_json::json!({ "revoked": revoked })))
 }
 
 /// GET /api/auth/me — resolves current user via Bearer or session cookie
@@ -284,7 +565,7 @@ async fn api_auth_me(
     } else { None };
     let token: String = match token { Some(t) => t, None => return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "missing token" }).to_string())), };
     // 2) Verify JWT and load user document
-    let user_id: String = match auth::verify_jwt(&token) { Ok(data) => data.claims.sub, Err(_) => return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid token" }).to_string())), };
+    let user_id: String = match verify_token(&state, &token) { Ok(claims) => claims.sub, Err(_) => return Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid token" }).to_string())), };
     match state.storage.get_user(&user_id) {
         Ok(Some(val)) => Ok(Json(val)),
         Ok(None) => Err((StatusCode::NOT_FOUND, serde_json::json!({ "message": "user not found" }).to_string())),
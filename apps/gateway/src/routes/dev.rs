@@ -8,11 +8,7 @@ use axum::http::StatusCode;
 use serde_json::json;
 use uuid::Uuid;
 use crate::state::{AppState, ADMIN_EMAIL, is_admin_email};
-use argon2::{
-    password_hash::{PasswordHasher, SaltString},
-    Argon2,
-};
-use rand::RngCore;
+use crate::routes::auth::hash_password;
 
 /// Build router for dev-only helpers (unsafe for prod).
 pub fn router() -> Router<AppState> {
@@ -49,11 +45,8 @@ async fn dev_login_post(
         let role: &'static str = if is_admin_email(&email) { "admin" } else { "user" };
         let user_obj: serde_json::Value = json!({ "id": user_id, "email": email, "created_at": chrono::Utc::now().timestamp(), "role": role });
         state.storage.put_user(&user_id, &user_obj)?;
-        let access: String = auth::create_jwt(&user_id, 3600)?;
-        let refresh_id: String = Uuid::new_v4().to_string();
-        let expiry: i64 = chrono::Utc::now().checked_add_signed(chrono::Duration::days(30)).unwrap().timestamp();
-        state.storage.create_refresh_token(&refresh_id, &user_id, expiry)?;
-        Ok(json!({ "access_token": access, "refresh_token": refresh_id, "user_id": user_id }))
+        let issued: auth::IssuedTokens = auth::issue_tokens(&state.storage, &state.tokens, &user_id, &auth::Device::default(), chrono::Utc::now().timestamp())?;
+        Ok(json!({ "access_token": issued.access_token, "expires_in": issued.access_expires_in, "refresh_token": issued.refresh_token, "user_id": user_id }))
     })().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     Ok(Json(resp_value))
 }
@@ -68,7 +61,8 @@ async fn api_dev_promote(
     let cred = match state.storage.get_credentials(&email) { Ok(Some(v)) => v, Ok(None) => return Err((StatusCode::NOT_FOUND, "credentials not found".to_string())), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
     let user_id: String = cred.get("user_id").and_then(|v| v.as_str()).ok_or((StatusCode::INTERNAL_SERVER_ERROR, "malformed credentials".to_string()))?.to_string();
     match state.storage.get_user(&user_id) {
-        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); } if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
+        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); }
+            if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
         Ok(None) => Err((StatusCode::NOT_FOUND, "user not found".to_string())),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
@@ -84,7 +78,8 @@ async fn api_dev_promote_get(
     let cred = match state.storage.get_credentials(&email) { Ok(Some(v)) => v, Ok(None) => return Err((StatusCode::NOT_FOUND, "credentials not found".to_string())), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
     let user_id: String = cred.get("user_id").and_then(|v| v.as_str()).ok_or((StatusCode::INTERNAL_SERVER_ERROR, "malformed credentials".to_string()))?.to_string();
     match state.storage.get_user(&user_id) {
-        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); } if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
+        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); }
+            if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
         Ok(None) => Err((StatusCode::NOT_FOUND, "user not found".to_string())),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
@@ -99,7 +94,8 @@ async fn api_dev_promote_by(
     let cred = match state.storage.get_credentials(&email) { Ok(Some(v)) => v, Ok(None) => return Err((StatusCode::NOT_FOUND, "credentials not found".to_string())), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
     let user_id: String = cred.get("user_id").and_then(|v| v.as_str()).ok_or((StatusCode::INTERNAL_SERVER_ERROR, "malformed credentials".to_string()))?.to_string();
     match state.storage.get_user(&user_id) {
-        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); } if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
+        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); }
+            if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true }))) }
         Ok(None) => Err((StatusCode::NOT_FOUND, "user not found".to_string())),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
@@ -111,7 +107,8 @@ async fn api_dev_promote_user_by_id(
     Path(user_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     match state.storage.get_user(&user_id) {
-        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); } if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true, "promoted": user_id }))) }
+        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); }
+            if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true, "promoted": user_id }))) }
         Ok(None) => Err((StatusCode::NOT_FOUND, "user not found".to_string())),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
@@ -179,7 +176,8 @@ async fn api_dev_promote_now(
     let cred: serde_json::Value = match state.storage.get_credentials(&email) { Ok(Some(v)) => v, Ok(None) => return Err((StatusCode::NOT_FOUND, "credentials not found".to_string())), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
     let user_id: String = cred.get("user_id").and_then(|v: &serde_json::Value| v.as_str()).ok_or((StatusCode::INTERNAL_SERVER_ERROR, "malformed credentials".to_string()))?.to_string();
     match state.storage.get_user(&user_id) {
-        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); } if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true, "promoted": email }))) }
+        Ok(Some(mut user)) => { if let Some(obj) = user.as_object_mut() { obj.insert("role".to_string(), serde_json::Value::String("admin".to_string())); }
+            if let Err(e) = state.storage.put_user(&user_id, &user) { return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())); } Ok(Json(json!({ "ok": true, "promoted": email }))) }
         Ok(None) => Err((StatusCode::NOT_FOUND, "user not found".to_string())),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
@@ -207,11 +205,12 @@ async fn api_dev_clear_buckets(
 
 /// POST /auth/dev/inspect_token — verify and return JWT claims
 async fn api_dev_inspect_token(
+    State(state): State<AppState>,
     Json(payload): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let token: String = payload.get("token").and_then(|v| v.as_str()).ok_or((StatusCode::BAD_REQUEST, "missing token".to_string()))?.to_string();
-    match auth::verify_jwt(&token) {
-        Ok(data) => Ok(Json(json!({ "ok": true, "claims": { "sub": data.claims.sub, "iat": data.claims.iat, "exp": data.claims.exp, "iss": data.claims.iss, "aud": data.claims.aud } }))),
+    match crate::state::verify_token(&state, &token) {
+        Ok(claims) => Ok(Json(json!({ "ok": true, "claims": { "sub": claims.sub, "iat": claims.iat, "exp": claims.exp, "iss": claims.iss, "aud": claims.aud, "sid": claims.sid } }))),
         Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
     }
 }
@@ -232,11 +231,7 @@ async fn api_dev_set_password(
         id
     } };
 
-    let mut salt_bytes: [u8; 16] = [0u8; 16];
-    rand::rng().fill_bytes(&mut salt_bytes);
-    let salt: SaltString = SaltString::encode_b64(&salt_bytes).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let argon2: Argon2<'_> = Argon2::default();
-    let pwd_hash: String = match argon2.hash_password(password.as_bytes(), &salt) { Ok(ph) => ph.to_string(), Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())), };
+    let pwd_hash: String = hash_password(&state, password.to_string()).await?;
     let cred_obj: serde_json::Value = serde_json::json!({ "user_id": user_id, "password_hash": pwd_hash, "created_at": chrono::Utc::now().timestamp() });
     state.storage.put_credentials(&email, &cred_obj).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     Ok(Json(json!({ "ok": true, "email": email })))
@@ -7,6 +7,10 @@ use axum::Router;
 use crate::state::AppState;
 
 pub mod auth;
+pub mod account;
+pub mod oauth;
+pub mod webauthn;
+pub mod totp;
 pub mod admin;
 pub mod dev;
 pub mod rooms;
@@ -14,6 +18,8 @@ pub mod ws;
 pub mod logs;
 pub mod root;
 pub mod push;
+pub mod presence;
+pub mod users;
 
 /// Merge all route groups into a single router.
 pub fn all() -> Router<AppState> {
@@ -22,9 +28,18 @@ pub fn all() -> Router<AppState> {
         .merge(push::router())
         .merge(ws::router())
         .merge(rooms::router())
+        .merge(presence::router())
+        .merge(users::router())
         .merge(logs::router())
         .merge(dev::router())
         .merge(auth::public())
         .merge(auth::protected())
+        .merge(account::public())
+        .merge(account::protected())
+        .merge(oauth::router())
+        .merge(webauthn::public())
+        .merge(webauthn::protected())
+        .merge(totp::public())
+        .merge(totp::protected())
         .merge(admin::router())
 }
@@ -0,0 +1,172 @@
+// OAuth / OpenID Connect sign-in (any provider in `state.oauth`: Google,
+// GitHub, GitLab, Microsoft or a configured OIDC issuer)
+//
+// - GET /api/auth/oauth/providers lists the configured providers for the
+//   login page.
+// - GET /api/auth/oauth/{provider}/start?redirect_to=/profile sends the browser
+//   to the provider. State, nonce and the PKCE verifier are stored server-side
+//   (see `auth::OAuthClient`), so no cookie is needed to finish the flow.
+// - GET /api/auth/oauth/{provider}/callback?code=..&state=.. verifies the
+//   identity, finds or creates the user, starts a session like a password
+//   login and redirects to `redirect_to`.
+// - Accounts are matched by the `provider:subject` link first. A new identity
+//   is linked to an existing account with the same email only when the provider
+//   says the email is verified; an unverified match is refused rather than
+//   creating a second account for the same address.
+// - These are plain browser navigations (GET), so they sit outside the CSRF
+//   layer; the single-use `state` plays that role for the callback.
+use axum::{Router, routing::get, extract::{State, Query, Path, ConnectInfo}, response::Redirect, Json};
+use axum::http::{StatusCode, HeaderMap};
+use axum_extra::extract::cookie::CookieJar;
+use crate::middleware as gw_mw;
+use crate::routes::auth::{device, start_session};
+use crate::state::{AppState, audit, internal, is_admin_email};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Build router for OAuth sign-in.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/oauth/providers", get(oauth_providers))
+        .route("/api/auth/oauth/{provider}/start", get(oauth_start))
+        .route("/api/auth/oauth/{provider}/callback", get(oauth_callback))
+}
+
+#[derive(Deserialize)]
+struct StartQuery { redirect_to: Option<String> }
+
+/// Provider's redirect back to us. `error` is set instead of `code` when the
+/// user declined or the provider failed.
+#[derive(Deserialize)]
+struct CallbackQuery { code: Option<String>, state: Option<String>, error: Option<String> }
+
+fn provider(state: &AppState, name: &str) -> Result<Arc<auth::OAuthClient>, (StatusCode, String)> {
+    state.oauth.get(name).ok_or((StatusCode::NOT_FOUND, format!("unknown sign-in provider {:?}", name)))
+}
+
+/// Only same-site paths, so the callback can't be used as an open redirect.
+fn safe_redirect(to: Option<&str>) -> String {
+    match to {
+        Some(p) if p.starts_with('/') && !p.starts_with("//") && !p.contains('\\') => p.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// GET /api/auth/oauth/providers
+async fn oauth_providers(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let providers: Vec<serde_json::Value> = state.oauth.names().into_iter()
+        .map(|name| serde_json::json!({ "name": name, "start": format!("/api/auth/oauth/{}/start", name) }))
+        .collect();
+    Json(serde_json::json!({ "providers": providers }))
+}
+
+/// GET /api/auth/oauth/{provider}/start
+async fn oauth_start(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(q): Query<StartQuery>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let client = provider(&state, &name)?;
+    let redirect_to: String = safe_redirect(q.redirect_to.as_deref());
+    let url: String = client.start(&state.storage, &redirect_to, chrono::Utc::now().timestamp()).await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    Ok(Redirect::to(&url))
+}
+
+/// GET /api/auth/oauth/{provider}/callback
+async fn oauth_callback(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Query(q): Query<CallbackQuery>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Result<(CookieJar, Redirect), (StatusCode, String)> {
+    let client = provider(&state, &name)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    if let Some(err) = q.error {
+        return Err((StatusCode::BAD_REQUEST, format!("sign-in with {} failed: {}", name, err)));
+    }
+    let (Some(code), Some(oauth_state)) = (q.code, q.state) else {
+        return Err((StatusCode::BAD_REQUEST, "missing code or state".into()));
+    };
+
+    // 1) Consume the state and verify the identity
+    let identity: auth::OAuthIdentity = match client.callback(&state.storage, &oauth_state, &code, chrono::Utc::now().timestamp()).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            audit(&state, "oauth_failed", None, None, ip, serde_json::json!({ "provider": name, "error": e.to_string() }));
+            return Err((StatusCode::UNAUTHORIZED, "sign-in failed".into()));
+        }
+    };
+
+    // 2) Find the account: existing link, else verified email, else a new user
+    let user_id: String = match state.storage.find_user_by_oauth(&identity.provider, &identity.subject).map_err(internal)? {
+        Some(user_id) => user_id,
+        None => {
+            let user_id: String = match existing_user_by_email(&state, identity.email.as_deref())? {
+                Some(_) if !identity.email_verified => {
+                    return Err((StatusCode::CONFLICT, "an account with this email exists; sign in with it and link the provider".into()));
+                }
+                Some(user_id) => user_id,
+                None => create_user(&state, &identity)?,
+            };
+            state.storage.link_oauth(&identity.provider, &identity.subject, &user_id).map_err(internal)?;
+            audit(&state, "oauth_linked", Some(&user_id), Some(&user_id), ip, serde_json::json!({ "provider": identity.provider }));
+            user_id
+        }
+    };
+
+    // 3) Sign in and go where the user was headed
+    let (jar, _) = start_session(&state, jar, &user_id, &device(&headers, ip))?;
+    audit(&state, "oauth_login", Some(&user_id), Some(&user_id), ip, serde_json::json!({ "provider": identity.provider }));
+    Ok((jar, Redirect::to(&identity.redirect_to)))
+}
+
+/// User id of the account registered with `email`, by password or an earlier OAuth sign-in.
+fn existing_user_by_email(state: &AppState, email: Option<&str>) -> Result<Option<String>, (StatusCode, String)> {
+    let Some(email) = email.filter(|e| !e.is_empty()) else { return Ok(None) };
+    if let Some(cred) = state.storage.get_credentials(email).map_err(internal)? {
+        if let Some(user_id) = cred.get("user_id").and_then(|v| v.as_str()) {
+            return Ok(Some(user_id.to_string()));
+        }
+    }
+    let users = state.storage.list_users().map_err(internal)?;
+    Ok(users.iter()
+        .find(|u| u.get("email").and_then(|v| v.as_str()).is_some_and(|e| e.eq_ignore_ascii_case(email)))
+        .and_then(|u| u.get("id").and_then(|v| v.as_str()).map(str::to_string)))
+}
+
+/// New passwordless user for a first-time OAuth sign-in, with a username
+/// suggested by the provider (or the email), made unique with a suffix.
+fn create_user(state: &AppState, identity: &auth::OAuthIdentity) -> Result<String, (StatusCode, String)> {
+    let base: String = identity.username.as_deref()
+        .or_else(|| identity.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or("user")
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+        .take(24)
+        .collect();
+    let base: String = if base.chars().count() < 2 { "user".to_string() } else { base };
+    let users = state.storage.list_users().map_err(internal)?;
+    let taken = |name: &str| users.iter().any(|u| u.get("username").and_then(|v| v.as_str()).is_some_and(|s| s.eq_ignore_ascii_case(name)));
+    let username: String = std::iter::once(base.clone())
+        .chain((2..).map(|n| format!("{}{}", base, n)))
+        .find(|name| !taken(name))
+        .unwrap_or(base);
+
+    let user_id: String = Uuid::new_v4().to_string();
+    // Only a verified address grants the admin role
+    let role: &'static str = match &identity.email {
+        Some(email) if identity.email_verified && is_admin_email(email) => "admin",
+        _ => "user",
+    };
+    let user_obj = serde_json::json!({
+        "id": user_id, "email": identity.email, "username": username,
+        "created_at": chrono::Utc::now().timestamp(), "role": role, "email_verified": identity.email_verified,
+    });
+    state.storage.put_user(&user_id, &user_obj).map_err(internal)?;
+    Ok(user_id)
+}
@@ -0,0 +1,71 @@
+// Presence status endpoints
+//
+// - GET /api/presence/me: the caller's own status, text and live connections.
+// - PUT /api/presence/me: set online/away/busy/invisible and custom status text
+//   (`{ "status": "busy", "text": "In a meeting", "text_ttl_secs": 3600 }`;
+//   an empty text clears it). Changes fan out on `presence/status`.
+// - GET /api/presence?users=a,b,c: public status for up to 100 users.
+//   Invisible users are reported as offline.
+use axum::{routing::get, Router, extract::{State, Query}, Json, middleware};
+use crate::middleware as gw_mw;
+use axum::http::{HeaderMap, StatusCode};
+use std::collections::HashMap;
+use crate::state::{AppState, require_user};
+
+/// Maximum number of users accepted by `GET /api/presence`.
+const MAX_USERS_PER_QUERY: usize = 100;
+
+/// Build router for presence APIs.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/presence/me", get(get_my_presence).put(put_my_presence))
+        .route("/api/presence", get(get_presence))
+        .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
+}
+
+/// GET /api/presence/me
+async fn get_my_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user_id = match require_user(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    match state.presence.user_presence(&user_id) {
+        Ok(p) => Ok(Json(serde_json::to_value(&p).unwrap_or_else(|_| serde_json::json!({})))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// PUT /api/presence/me
+async fn put_my_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(update): Json<presence::StatusUpdate>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user_id = match require_user(&state, &headers) { Ok(id) => id, Err((status, e)) => return Err((status, e.message)) };
+    if let Err(e) = update.validate() { return Err((StatusCode::BAD_REQUEST, e.to_string())); }
+    match state.presence.set_status(&user_id, update) {
+        Ok(p) => Ok(Json(serde_json::to_value(&p).unwrap_or_else(|_| serde_json::json!({})))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// GET /api/presence?users=a,b,c
+async fn get_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Err((status, e)) = require_user(&state, &headers) { return Err((status, e.message)); }
+    let users: Vec<&str> = q.get("users").map(|s| s.split(',').map(str::trim).filter(|u| !u.is_empty()).collect()).unwrap_or_default();
+    if users.is_empty() { return Err((StatusCode::BAD_REQUEST, "users query parameter required".to_string())); }
+    if users.len() > MAX_USERS_PER_QUERY { return Err((StatusCode::BAD_REQUEST, format!("at most {} users per query", MAX_USERS_PER_QUERY))); }
+
+    let mut out: Vec<serde_json::Value> = Vec::with_capacity(users.len());
+    for user_id in users {
+        match state.presence.user_presence(user_id) {
+            Ok(p) => out.push(p.public()),
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        }
+    }
+    Ok(Json(serde_json::json!(out)))
+}
@@ -34,7 +34,7 @@ pub fn router() -> Router<AppState> {
 async fn subscribe(State(state): State<AppState>, headers: HeaderMap, Json(sub): Json<PushSubscription>) -> axum::response::Response {
     use axum::response::IntoResponse;
     // Try to map subscription to a user (if authorized)
-    let user_id = crate::state::extract_token(&headers).and_then(|t| auth::verify_jwt(&t).ok().map(|d| d.claims.sub));
+    let user_id = crate::state::extract_token(&headers).and_then(|t| crate::state::verify_token(&state, &t).ok().map(|c| c.sub));
     let obj = serde_json::json!({
         "endpoint": sub.endpoint,
         "keys": { "p256dh": sub.keys.p256dh, "auth": sub.keys.auth },
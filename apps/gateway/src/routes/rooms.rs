@@ -2,17 +2,20 @@
 //
 // - GET /rooms/{room}/history: read stored messages with simple pagination.
 // - POST /rooms/{room}/messages: append a message to storage and publish to
-//   the room topic (pub/sub). We rate-limit per authenticated user or "anon".
+//   the room topic (pub/sub). Rate limited by the middleware's "messages" budget.
+// - GET /rooms/{room}/members/online (signed in): public presence of users
+//   connected to the room over /ws. Invisible users are not listed.
 use axum::{routing::get, routing::post, Router, extract::{State, Path, Query}, Json};
 use axum::http::StatusCode;
 use std::collections::HashMap;
-use crate::state::AppState;
+use crate::state::{AppState, require_user};
 
 /// Build router for room history + message APIs.
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/rooms/{room}/history", get(get_room_history))
         .route("/rooms/{room}/messages", post(post_room_message))
+        .route("/rooms/{room}/members/online", get(get_room_members_online))
 }
 
 /// GET /rooms/{room}/history
@@ -29,15 +32,29 @@ async fn get_room_history(
     let limit: usize = q.get("limit").and_then(|s| s.parse::<usize>().ok()).unwrap_or(50);
 
     // Delegate to the rooms crate which reads messages from storage
-    match rooms::fetch_history(&room, after_ts, limit, &*state.storage) {
+    match rooms::fetch_history(&room, after_ts, limit, &state.storage) {
         Ok(msgs) => Ok(Json(serde_json::to_value(&msgs).unwrap_or_else(|_| serde_json::json!([])))),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
 }
 
+/// GET /rooms/{room}/members/online
+async fn get_room_members_online(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Err((status, e)) = require_user(&state, &headers) { return Err((status, e.message)); }
+    match state.presence.room_members_online(&room) {
+        Ok(members) => Ok(Json(serde_json::json!(members.iter().map(|p| p.public()).collect::<Vec<_>>()))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
 /// POST /rooms/{room}/messages
 /// Persists a message (arbitrary JSON) and publishes it to subscribers.
-/// Rate-limited by user-id (derived from Bearer token) or "anon".
+/// Rate-limited by the "messages" policy budget in the rate middleware; a
+/// per-user counter is persisted for metrics.
 async fn post_room_message(
     State(state): State<AppState>,
     Path(room): Path<String>,
@@ -54,17 +71,24 @@ async fn post_room_message(
             } else { None }
         });
 
-    // Derive the per-user rate key: JWT sub or "anon"
+    // Derive the per-user metrics key: JWT sub or "anon"
     let rate_key: String = if let Some(token) = token_opt {
-        match auth::verify_jwt(&token) { Ok(data) => data.claims.sub, Err(_) => "anon".to_string() }
+        match crate::state::verify_token(&state, &token) { Ok(claims) => claims.sub, Err(_) => "anon".to_string() }
     } else { "anon".to_string() };
 
-    // Enforce rate limiting; persist a counter for basic metrics
-    if !state.rate.allow(&rate_key) { return Err((StatusCode::TOO_MANY_REQUESTS, "rate limit".to_string())); }
+    // Persist a counter for basic metrics
     let _ = state.storage.incr_rate_counter(&rate_key, 1);
 
+    // Propagate the caller's W3C trace context onto the bus, or start a new trace
+    let traceparent: String = headers
+        .get("traceparent")
+        .and_then(|hv| hv.to_str().ok())
+        .filter(|tp| bus::envelope::is_valid_traceparent(tp))
+        .map(str::to_string)
+        .unwrap_or_else(bus::envelope::new_traceparent);
+
     // Persist + publish via rooms helper; return the stored record
-    match rooms::send_message(&room, payload, &*state.storage, &*state.publisher) {
+    match rooms::send_message_traced(&room, payload, &state.storage, &state.publisher, Some(&traceparent)) {
         Ok(rec) => Ok(Json(serde_json::to_value(&rec).unwrap_or_else(|_| serde_json::json!({})))),
         Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
     }
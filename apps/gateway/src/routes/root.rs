@@ -1,7 +1,9 @@
 // Root and health endpoints
 // - `/` responds with a simple HTML string (smoke-test endpoint)
 // - `/healthz` is used by orchestrators/containers to check liveness
-use axum::{routing::get, Router, response::Html};
+// - `/.well-known/jwks.json` publishes the public JWT signing keys
+use axum::{routing::get, Router, response::Html, Json, http::StatusCode};
+use serde_json::Value;
 use crate::state::AppState;
 
 /// Build the router for root/health endpoints.
@@ -9,6 +11,7 @@ pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", get(root))
         .route("/healthz", get(|| async { "ok" }))
+        .route("/.well-known/jwks.json", get(jwks))
 }
 
 /// GET /
@@ -16,3 +19,11 @@ pub fn router() -> Router<AppState> {
 async fn root() -> Html<&'static str> {
     Html("Gateway up")
 }
+
+/// GET /.well-known/jwks.json
+/// Every key in the JWT keyring (the active one and those still verifying
+/// older tokens), so other services can verify our access tokens.
+async fn jwks() -> Result<Json<Value>, (StatusCode, String)> {
+    let keyring = auth::keyring().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(keyring.jwks()))
+}
@@ -0,0 +1,185 @@
+// TOTP two-factor authentication (authenticator apps) and the second sign-in step
+//
+// - POST /api/auth/login/mfa { mfaToken, code } answers the challenge that
+//   /api/auth/login returns for accounts with TOTP, with a current code or a
+//   recovery code, and signs in like a password login (session + refresh
+//   cookies). Wrong codes count towards the account's and the client IP's
+//   sign-in lockout; a challenge takes a few tries and lasts five minutes.
+// - GET /api/auth/totp -> { enabled, pending, recoveryCodesLeft }
+// - POST /api/auth/totp/enroll -> { secret, otpauthUri } starts enrolment;
+//   POST /api/auth/totp/confirm { code } turns it on and returns the recovery
+//   codes, which are not shown again.
+// - POST /api/auth/totp/recovery-codes { code } replaces the recovery codes;
+//   POST /api/auth/totp/disable { code } turns TOTP off. Both want a current
+//   code (or a recovery code), not just the session.
+//
+// TOTP protects the password sign-in, so it needs an account with a password;
+// passkey and OAuth sign-ins don't ask for it. The secret, the last used time
+// step and the hashed recovery codes live in the credentials record (see
+// `auth::TotpRecord`). `TOTP_ISSUER` names the app in authenticator apps.
+use axum::{Router, routing::{get, post}, extract::{State, ConnectInfo}, Json, middleware};
+use axum::http::{StatusCode, HeaderMap};
+use axum_extra::extract::cookie::CookieJar;
+use crate::middleware as gw_mw;
+use crate::routes::auth::{device, start_session, lockout_keys, check_lockout, note_failure};
+use crate::state::{AppState, audit, internal, message, require_user};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// Second sign-in step (no CSRF, like the password login).
+pub fn public() -> Router<AppState> {
+    Router::new().route("/api/auth/login/mfa", post(login_mfa))
+}
+
+/// Enrolment and management for signed-in users (CSRF-protected).
+pub fn protected() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/totp", get(totp_status))
+        .route("/api/auth/totp/enroll", post(totp_enroll))
+        .route("/api/auth/totp/confirm", post(totp_confirm))
+        .route("/api/auth/totp/recovery-codes", post(totp_recovery_codes))
+        .route("/api/auth/totp/disable", post(totp_disable))
+        .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MfaLogin { mfa_token: String, code: String }
+
+#[derive(Deserialize)]
+struct Code { code: String }
+
+/// POST /api/auth/login/mfa
+async fn login_mfa(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Json(body): Json<MfaLogin>,
+) -> Result<(CookieJar, Json<serde_json::Value>), (StatusCode, String)> {
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let now: i64 = chrono::Utc::now().timestamp();
+    // The challenge names the account, so a locked account can't be guessed at here either
+    let email: Option<String> = auth::mfa_challenge_email(&state.storage, &body.mfa_token, now).map_err(internal)?;
+    check_lockout(&state, &lockout_keys(&state, email.as_deref(), ip))?;
+    match auth::verify_mfa_challenge(&state.storage, &body.mfa_token, &body.code, now).map_err(internal)? {
+        auth::MfaOutcome::Passed { user_id, email, factor, recovery_codes_left } => {
+            let keys = lockout_keys(&state, Some(&email), ip);
+            if let Err(e) = state.storage.clear_login_failures(&keys[0].0) { tracing::error!("clearing login failures failed: {:?}", e); }
+            let (jar, issued) = start_session(&state, jar, &user_id, &device(&headers, ip))?;
+            let method: &str = match factor { auth::SecondFactor::Totp => "totp", auth::SecondFactor::RecoveryCode => "recovery_code" };
+            audit(&state, "mfa_login", Some(&user_id), Some(&user_id), ip, serde_json::json!({ "method": method, "recovery_codes_left": recovery_codes_left }));
+            Ok((jar, Json(serde_json::json!({
+                "userId": user_id, "email": email, "expiresIn": issued.access_expires_in, "recoveryCodesLeft": recovery_codes_left,
+            }))))
+        }
+        auth::MfaOutcome::Failed { email, attempts_left } => {
+            note_failure(&state, &lockout_keys(&state, Some(&email), ip), "mfa_failed", &email, ip);
+            Err((StatusCode::UNAUTHORIZED, serde_json::json!({ "message": "invalid code", "attempts_left": attempts_left }).to_string()))
+        }
+        auth::MfaOutcome::Expired => {
+            note_failure(&state, &lockout_keys(&state, None, ip), "mfa_failed", "expired challenge", ip);
+            Err(message(StatusCode::UNAUTHORIZED, "sign-in expired, enter your password again"))
+        }
+    }
+}
+
+/// The caller's user id and the email of their password account, or 400 for
+/// accounts without a password (OAuth or passkey only).
+fn password_account(state: &AppState, headers: &HeaderMap) -> Result<(String, String, serde_json::Value), (StatusCode, String)> {
+    let user_id: String = require_user(state, headers).map_err(|(status, e)| message(status, &e.message))?;
+    let user: serde_json::Value = state.storage.get_user(&user_id).map_err(internal)?.ok_or(message(StatusCode::NOT_FOUND, "user not found"))?;
+    let email: String = user.get("email").and_then(|v| v.as_str()).unwrap_or_default().to_lowercase();
+    let cred: Option<serde_json::Value> = state.storage.get_credentials(&email).map_err(internal)?
+        .filter(|c| c.get("user_id").and_then(|v| v.as_str()) == Some(user_id.as_str()));
+    let cred = cred.ok_or(message(StatusCode::BAD_REQUEST, "two-factor authentication needs an account with a password"))?;
+    Ok((user_id, email, cred))
+}
+
+/// Check a code for a management action, counting wrong ones like failed sign-ins.
+fn require_code(state: &AppState, email: &str, code: &str, ip: Option<IpAddr>) -> Result<(), (StatusCode, String)> {
+    let keys = lockout_keys(state, Some(email), ip);
+    check_lockout(state, &keys)?;
+    match auth::verify_second_factor(&state.storage, email, code, chrono::Utc::now().timestamp()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => {
+            note_failure(state, &keys, "mfa_failed", email, ip);
+            Err(message(StatusCode::UNAUTHORIZED, "invalid code"))
+        }
+        Err(e) => Err(message(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+/// GET /api/auth/totp
+async fn totp_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, _, cred) = password_account(&state, &headers)?;
+    let rec: Option<auth::TotpRecord> = auth::totp_record(&cred);
+    Ok(Json(serde_json::json!({
+        "enabled": rec.as_ref().is_some_and(|r| r.enabled_at.is_some()),
+        "pending": rec.as_ref().is_some_and(|r| r.enabled_at.is_none()),
+        "enabledAt": rec.as_ref().and_then(|r| r.enabled_at),
+        "recoveryCodesLeft": rec.as_ref().filter(|r| r.enabled_at.is_some()).map(|r| r.recovery_codes.len()),
+    })))
+}
+
+/// POST /api/auth/totp/enroll
+async fn totp_enroll(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (_, email, _) = password_account(&state, &headers)?;
+    let secret: String = auth::begin_totp_enrollment(&state.storage, &email, chrono::Utc::now().timestamp())
+        .map_err(|e| message(StatusCode::CONFLICT, &e.to_string()))?;
+    let issuer: String = std::env::var("TOTP_ISSUER").ok().filter(|v| !v.trim().is_empty()).unwrap_or_else(|| "Stack".to_string());
+    let uri: String = auth::totp_provisioning_uri(&issuer, &email, &secret);
+    Ok(Json(serde_json::json!({ "secret": secret, "otpauthUri": uri })))
+}
+
+/// POST /api/auth/totp/confirm
+async fn totp_confirm(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Code>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (user_id, email, _) = password_account(&state, &headers)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let codes: Vec<String> = auth::confirm_totp_enrollment(&state.storage, &email, &body.code, chrono::Utc::now().timestamp())
+        .map_err(|e| message(StatusCode::BAD_REQUEST, &e.to_string()))?
+        .ok_or(message(StatusCode::BAD_REQUEST, "invalid code, check the authenticator app's clock"))?;
+    audit(&state, "totp_enabled", Some(&user_id), Some(&user_id), ip, serde_json::Value::Null);
+    Ok(Json(serde_json::json!({ "recoveryCodes": codes })))
+}
+
+/// POST /api/auth/totp/recovery-codes
+async fn totp_recovery_codes(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Code>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (user_id, email, _) = password_account(&state, &headers)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    require_code(&state, &email, &body.code, ip)?;
+    let codes: Vec<String> = auth::regenerate_recovery_codes(&state.storage, &email).map_err(internal)?;
+    audit(&state, "recovery_codes_regenerated", Some(&user_id), Some(&user_id), ip, serde_json::Value::Null);
+    Ok(Json(serde_json::json!({ "recoveryCodes": codes })))
+}
+
+/// POST /api/auth/totp/disable
+async fn totp_disable(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<Code>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let (user_id, email, _) = password_account(&state, &headers)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    require_code(&state, &email, &body.code, ip)?;
+    auth::disable_totp(&state.storage, &email).map_err(internal)?;
+    audit(&state, "totp_disabled", Some(&user_id), Some(&user_id), ip, serde_json::Value::Null);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,35 @@
+// User endpoints
+//
+// - GET /api/users/{id}/last-seen: whether a user is online and when they were
+//   last seen (`{ "online": false, "last_seen": 1700000000, "last_seen_ago": "3h ago" }`).
+//   Invisible users are reported as offline with no last-seen time.
+use axum::{routing::get, Router, extract::{State, Path}, Json};
+use axum::http::{HeaderMap, StatusCode};
+use crate::state::{AppState, require_user};
+
+/// Build router for user APIs.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/users/{id}/last-seen", get(get_last_seen))
+}
+
+/// GET /api/users/{id}/last-seen
+async fn get_last_seen(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if let Err((status, e)) = require_user(&state, &headers) { return Err((status, e.message)); }
+    match state.presence.user_presence(&user_id) {
+        Ok(p) => {
+            let public = p.public();
+            Ok(Json(serde_json::json!({
+                "user_id": user_id,
+                "online": public["status"] != "offline",
+                "last_seen": public["last_seen"],
+                "last_seen_ago": public["last_seen_ago"],
+            })))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
@@ -0,0 +1,176 @@
+// Passkey (WebAuthn) endpoints
+//
+// Each ceremony is two calls: `start` returns the options for the browser's
+// `navigator.credentials.create()` / `.get()` plus an id, `finish` sends the
+// browser's answer back with that id. Verification (challenge, origin,
+// signature, counter) happens in `auth::Passkeys`.
+//
+// - POST /api/auth/webauthn/register/start (signed in) -> { regId, publicKey }
+// - POST /api/auth/webauthn/register/finish { regId, credential, name? } -> { id }
+// - POST /api/auth/webauthn/login/start -> { authId, publicKey }
+//   Always a discoverable (username-less) challenge: any of the user's passkeys
+//   can answer, and nothing about an account is revealed before sign-in.
+// - POST /api/auth/webauthn/login/finish { authId, credential } signs in like
+//   a password login (session + refresh cookies). Failures count towards the
+//   client IP's sign-in lockout.
+// - GET /api/auth/webauthn/credentials lists the caller's passkeys (nickname,
+//   created, last used); PATCH /api/auth/webauthn/credentials/{id} { name }
+//   renames one and DELETE removes it.
+//
+// Disabled (404) unless a relying party is configured (`WEBAUTHN_RP_ID`, or dev mode).
+use axum::{Router, routing::{get, patch, post}, extract::{State, ConnectInfo, Path}, Json, middleware};
+use axum::http::{StatusCode, HeaderMap};
+use axum_extra::extract::cookie::CookieJar;
+use crate::middleware as gw_mw;
+use crate::routes::auth::{device, start_session, lockout_keys, check_lockout, note_failure};
+use crate::state::{AppState, audit, internal, require_user};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Sign-in ceremony routes (no CSRF, like the password login).
+pub fn public() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/webauthn/login/start", post(login_start))
+        .route("/api/auth/webauthn/login/finish", post(login_finish))
+}
+
+/// Registration and management routes for signed-in users (CSRF-protected).
+pub fn protected() -> Router<AppState> {
+    Router::new()
+        .route("/api/auth/webauthn/register/start", post(register_start))
+        .route("/api/auth/webauthn/register/finish", post(register_finish))
+        .route("/api/auth/webauthn/credentials", get(list_credentials))
+        .route("/api/auth/webauthn/credentials/{id}", patch(rename_credential).delete(delete_credential))
+        .route_layer(middleware::from_fn(gw_mw::csrf_middleware))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterFinish { reg_id: String, credential: serde_json::Value, name: Option<String> }
+
+#[derive(Deserialize)]
+struct Rename { name: String }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginFinish { auth_id: String, credential: serde_json::Value }
+
+fn passkeys(state: &AppState) -> Result<Arc<auth::Passkeys>, (StatusCode, String)> {
+    state.passkeys.clone().ok_or((StatusCode::NOT_FOUND, "passkeys are not enabled".into()))
+}
+
+/// POST /api/auth/webauthn/register/start
+async fn register_start(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let passkeys = passkeys(&state)?;
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| (status, e.message))?;
+    let user: serde_json::Value = state.storage.get_user(&user_id).map_err(internal)?.ok_or((StatusCode::NOT_FOUND, "user not found".into()))?;
+    let username: &str = user.get("username").and_then(|v| v.as_str()).unwrap_or(&user_id);
+    let display: &str = user.get("email").and_then(|v| v.as_str()).unwrap_or(username);
+    let (reg_id, mut options) = passkeys.begin_registration(&state.storage, &user_id, username, display, chrono::Utc::now().timestamp())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    options["regId"] = serde_json::json!(reg_id);
+    Ok(Json(options))
+}
+
+/// POST /api/auth/webauthn/register/finish
+async fn register_finish(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<RegisterFinish>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let passkeys = passkeys(&state)?;
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| (status, e.message))?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let cred_id: String = passkeys
+        .finish_registration(&state.storage, &user_id, &body.reg_id, &body.credential, body.name.as_deref(), chrono::Utc::now().timestamp())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("passkey not registered: {}", e)))?;
+    audit(&state, "passkey_registered", Some(&user_id), Some(&user_id), ip, serde_json::json!({ "credential": cred_id }));
+    Ok(Json(serde_json::json!({ "id": cred_id })))
+}
+
+/// GET /api/auth/webauthn/credentials
+async fn list_credentials(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| (status, e.message))?;
+    let mut records = auth::list_passkeys(&state.storage, &user_id).map_err(internal)?;
+    records.sort_by_key(|(_, rec)| rec.created_at);
+    let credentials: Vec<serde_json::Value> = records.iter()
+        .map(|(id, rec)| serde_json::json!({ "id": id, "name": rec.name, "createdAt": rec.created_at, "lastUsedAt": rec.last_used_at }))
+        .collect();
+    Ok(Json(serde_json::json!({ "credentials": credentials })))
+}
+
+/// PATCH /api/auth/webauthn/credentials/{id}
+async fn rename_credential(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(cred_id): Path<String>,
+    Json(body): Json<Rename>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| (status, e.message))?;
+    if body.name.trim().is_empty() { return Err((StatusCode::BAD_REQUEST, "name must not be empty".into())); }
+    match auth::rename_passkey(&state.storage, &user_id, &cred_id, &body.name).map_err(internal)? {
+        true => Ok(Json(serde_json::json!({ "ok": true }))),
+        false => Err((StatusCode::NOT_FOUND, "passkey not found".into())),
+    }
+}
+
+/// DELETE /api/auth/webauthn/credentials/{id}
+async fn delete_credential(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cred_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let user_id: String = require_user(&state, &headers).map_err(|(status, e)| (status, e.message))?;
+    if !auth::delete_passkey(&state.storage, &user_id, &cred_id).map_err(internal)? {
+        return Err((StatusCode::NOT_FOUND, "passkey not found".into()));
+    }
+    audit(&state, "passkey_removed", Some(&user_id), Some(&user_id), gw_mw::client_ip(&state, &headers, Some(peer)), serde_json::json!({ "credential": cred_id }));
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+/// POST /api/auth/webauthn/login/start
+/// Any body is ignored. Offering an account's passkeys (`allowCredentials`)
+/// would tell anyone asking whether that account exists and has passkeys, so
+/// the challenge is always discoverable.
+async fn login_start(State(state): State<AppState>) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let passkeys = passkeys(&state)?;
+    let (auth_id, mut options) = passkeys.begin_authentication(&state.storage, None, chrono::Utc::now().timestamp())
+        .map_err(internal)?;
+    options["authId"] = serde_json::json!(auth_id);
+    Ok(Json(options))
+}
+
+/// POST /api/auth/webauthn/login/finish
+async fn login_finish(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Json(body): Json<LoginFinish>,
+) -> Result<(CookieJar, Json<serde_json::Value>), (StatusCode, String)> {
+    let passkeys = passkeys(&state)?;
+    let ip: Option<IpAddr> = gw_mw::client_ip(&state, &headers, Some(peer));
+    let keys = lockout_keys(&state, None, ip);
+    check_lockout(&state, &keys)?;
+    let user_id: String = match passkeys.finish_authentication(&state.storage, &body.auth_id, &body.credential, chrono::Utc::now().timestamp()) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            tracing::debug!("passkey sign-in rejected: {:?}", e);
+            note_failure(&state, &keys, "passkey_login_failed", "passkey", ip);
+            return Err((StatusCode::UNAUTHORIZED, "invalid passkey".into()));
+        }
+    };
+    let (jar, issued) = start_session(&state, jar, &user_id, &device(&headers, ip))?;
+    audit(&state, "passkey_login", Some(&user_id), Some(&user_id), ip, serde_json::json!({}));
+    Ok((jar, Json(serde_json::json!({ "userId": user_id, "expiresIn": issued.access_expires_in }))))
+}
+
@@ -2,12 +2,17 @@
 //
 // Flow:
 // 1) HTTP GET upgrades to WebSocket via `WebSocketUpgrade`.
-// 2) We associate a user id (from Bearer token or anonymous) and create a
-//    presence heartbeat.
+// 2) We associate a user id (from Bearer token or anonymous) and register a
+//    presence connection for this socket (device from `device=` or User-Agent).
 // 3) We subscribe to a pub/sub topic for the room and forward messages to the
 //    WebSocket, while also reading incoming messages and publishing them.
+// 4) The server pings every `WS_PING_INTERVAL_SECS`; pongs refresh the presence
+//    heartbeat and a socket missing `WS_MAX_MISSED_PONGS` pongs in a row is closed,
+//    as is one whose presence connection was dropped in the meantime.
 use axum::{Router, routing::get, extract::{State, Query}, response::IntoResponse};
-use axum::extract::ws::{WebSocketUpgrade, Message};
+use axum::extract::ws::{WebSocketUpgrade, Message, CloseFrame, close_code};
+use bytes::Bytes;
+use std::time::Duration;
 use crate::state::AppState;
 use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
@@ -16,13 +21,15 @@ use serde::Deserialize;
 struct RoomQuery {
     room: Option<String>,
     token: Option<String>,
+    device: Option<String>,
 }
 
 /// Build router for WebSocket upgrades.
 ///
 /// The `/ws` endpoint accepts a standard WebSocket upgrade request. Clients can
-/// include `room=<name>` and `token=<jwt>` as query params. The token can also
-/// be supplied via the `Authorization: Bearer <jwt>` header.
+/// include `room=<name>`, `token=<jwt>` and `device=web|desktop|mobile` as
+/// query params. The token can also be supplied via the `Authorization:
+/// Bearer <jwt>` header.
 pub fn router() -> Router<AppState> {
     Router::new().route("/ws", get(ws_handler))
 }
@@ -32,7 +39,7 @@ pub fn router() -> Router<AppState> {
 /// Steps:
 /// 1) Resolve the `room` name (defaults to "general").
 /// 2) Read a JWT token from either query `token` or `Authorization` header.
-/// 3) Verify token and create a presence heartbeat (or anonymous heartbeat).
+/// 3) Verify token and pick the device kind for presence.
 /// 4) Accept the upgrade and move the work into `ws_connect`.
 async fn ws_handler(
     ws: WebSocketUpgrade,
@@ -50,63 +57,214 @@ async fn ws_handler(
             })
     });
 
-    // Determine the user id for presence tracking. If JWT verification fails
-    // or no token is provided, we record an anonymous connection.
-    let user_id: String = if let Some(token) = token_opt {
-        match auth::verify_jwt(&token) {
-            Ok(data) => {
-                let sub: String = data.claims.sub;
-                match state.presence.heartbeat(Some(sub.clone())) { Ok(id) => id, Err(_) => "unknown".to_string() }
-            }
-            Err(_) => match state.presence.heartbeat(None) { Ok(id) => id, Err(_) => "unknown".to_string() }
-        }
-    } else { match state.presence.heartbeat(None) { Ok(id) => id, Err(_) => "unknown".to_string() } };
+    // Resolve the identity and device for this socket's presence connection.
+    // If JWT verification fails or no token is provided, the connection is anonymous.
+    let device: presence::DeviceKind = match q.device.as_deref() {
+        Some(d) => presence::DeviceKind::parse(d),
+        None => headers
+            .get("user-agent")
+            .and_then(|hv| hv.to_str().ok())
+            .map(presence::DeviceKind::from_user_agent)
+            .unwrap_or(presence::DeviceKind::Unknown),
+    };
+    let user_id: Option<String> = token_opt.and_then(|token| crate::state::verify_token(&state, &token).ok()).map(|claims| claims.sub);
 
-    ws.on_upgrade(move |socket: axum::extract::ws::WebSocket| ws_connect(socket, state, room, user_id))
+    ws.on_upgrade(move |socket: axum::extract::ws::WebSocket| ws_connect(socket, state, room, user_id, device))
 }
 
 /// Actual WebSocket connection handler that runs until the socket closes.
 ///
 /// Responsibilities:
-/// - Subscribe to the NNG pub/sub topic for the room and forward published
-///   messages to the client.
+/// - Register a presence connection for this socket (one per tab/device).
+/// - Subscribe to the NNG pub/sub topic for the room on a bus publisher found
+///   through service discovery (else our own) and forward published messages
+///   to the client.
 /// - Read text messages from the client, persist and publish them using
 ///   the `rooms::send_message` helper.
-/// - On disconnect, abort the forwarding task and mark the user offline.
-async fn ws_connect(socket: axum::extract::ws::WebSocket, state: AppState, room: String, user_id: String) {
+/// - Ping the client periodically; pongs keep the presence connection alive.
+/// - On disconnect, abort the forwarding task and drop this presence connection.
+async fn ws_connect(
+    socket: axum::extract::ws::WebSocket,
+    state: AppState,
+    room: String,
+    user_id: Option<String>,
+    device: presence::DeviceKind,
+) {
+    let conn: Option<presence::Connection> = match state.presence.connect(user_id, device) {
+        Ok(c) => Some(c),
+        Err(e) => { tracing::warn!("presence connect failed: {:?}", e); None }
+    };
     let topic: String = format!("room/{}", room);
-    let nng_addr: String = state.nng_addr.clone();
+    let nng_addr: String = bus_addr(&state);
 
     let subscriber: bus::pubsub::Subscriber = match bus::pubsub::Subscriber::connect(&nng_addr, &topic) {
         Ok(s) => s,
-        Err(e) => { tracing::error!("failed to create nng subscriber for {}: {:?}", topic, e); return; }
+        Err(e) => {
+            tracing::error!("failed to create nng subscriber for {}: {:?}", topic, e);
+            if let Some(c) = &conn { let _ = state.presence.disconnect(c); }
+            return;
+        }
     };
 
     let (mut ws_writer, mut ws_reader) = socket.split();
-    let mut sub_rx: tokio::sync::mpsc::Receiver<(String, Vec<u8>)> = subscriber.into_receiver();
-    // Forward task: reads from pub/sub and pushes to the websocket writer.
-    let forward_task = tokio::spawn(async move {
-        while let Some((_topic, payload)) = sub_rx.recv().await {
-            if let Ok(txt) = String::from_utf8(payload) {
-                if ws_writer.send(Message::Text(txt.into())).await.is_err() { break; }
-            }
+    let mut sub_rx = subscriber.into_deliveries();
+    if let Some(c) = &conn {
+        if let Err(e) = state.presence.join_room(c, &room) { tracing::warn!("presence join failed: {:?}", e); }
+    }
+    // Writer task: the only owner of the socket sink. Forwards pub/sub
+    // deliveries and control frames (pings, close) queued by the read loop.
+    let (out_tx, mut out_rx) = tokio::sync::mpsc::channel::<Message>(16);
+    let mut writer_task = tokio::spawn(async move {
+        loop {
+            let msg: Message = tokio::select! {
+                d = sub_rx.recv() => match d.and_then(delivery_text) {
+                    Some(text) => Message::Text(text.into()),
+                    None if sub_rx.is_closed() => break,
+                    None => continue,
+                },
+                m = out_rx.recv() => match m { Some(m) => m, None => break },
+            };
+            let closing = matches!(msg, Message::Close(_));
+            if ws_writer.send(msg).await.is_err() || closing { break; }
         }
     });
 
-    // Read loop: drain client messages. In this basic example we only handle
-    // text frames. A real app would also manage pings, binary and backpressure.
-    while let Some(Ok(msg)) = ws_reader.next().await {
-        match msg {
-            Message::Text(text) => {
-                let body = serde_json::json!({ "text": text.to_string() });
-                let _ = rooms::send_message(&room, body, &state.storage, &state.publisher);
+    // Read loop: client frames and the ping timer. Any frame from the client
+    // proves the socket is alive; text frames also count as user activity.
+    // A socket that leaves `max_missed_pongs` pings in a row unanswered is closed.
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + state.ws.ping_interval, state.ws.ping_interval);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut keepalive = Keepalive::new(state.ws.max_missed_pongs);
+    loop {
+        tokio::select! {
+            msg = ws_reader.next() => {
+                let msg = match msg { Some(Ok(m)) => m, _ => break };
+                keepalive.frame();
+                match msg {
+                    Message::Text(text) => {
+                        let body = serde_json::json!({ "text": text.to_string() });
+                        let _ = rooms::send_message(&room, body, &state.storage, &state.publisher);
+                        if conn.as_ref().is_some_and(|c| matches!(state.presence.activity(c), Ok(false))) {
+                            let _ = out_tx.send(Message::Close(Some(CloseFrame { code: close_code::AWAY, reason: "presence expired".into() }))).await;
+                            break;
+                        }
+                    }
+                    // Pings are answered by the socket itself. A connection presence
+                    // already dropped (swept as stale) is closed so the client
+                    // reconnects and registers again.
+                    Message::Pong(_) | Message::Ping(_) => {
+                        if conn.as_ref().is_some_and(|c| matches!(state.presence.heartbeat(c), Ok(false))) {
+                            let _ = out_tx.send(Message::Close(Some(CloseFrame { code: close_code::AWAY, reason: "presence expired".into() }))).await;
+                            break;
+                        }
+                    }
+                    Message::Binary(_) => {}
+                    Message::Close(_) => break,
+                }
             }
-            Message::Close(_) => break,
-            _ => break,
+            _ = ping.tick() => {
+                if keepalive.tick() == Tick::TimedOut {
+                    tracing::info!(room = %room, missed = keepalive.missed, "closing websocket after missed pongs");
+                    let frame = CloseFrame { code: close_code::AWAY, reason: "ping timeout".into() };
+                    let _ = out_tx.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+                if out_tx.send(Message::Ping(Bytes::new())).await.is_err() { break; }
+            }
+        }
+    }
+
+    // Cleanup: let the writer flush a queued close frame, stop it, and drop this
+    // connection, which also leaves the room; the user only goes offline if it
+    // was their last one (best-effort).
+    drop(out_tx);
+    if tokio::time::timeout(Duration::from_secs(1), &mut writer_task).await.is_err() {
+        writer_task.abort();
+    }
+    if let Some(c) = &conn { let _ = state.presence.disconnect(c); }
+}
+
+/// Bus publisher for a new subscriber to dial: a healthy "bus" instance from
+/// service discovery (round-robin), else our own publisher.
+fn bus_addr(state: &AppState) -> String {
+    state.discovery.resolve("bus").map(|s| s.tcp_url()).unwrap_or_else(|| state.nng_addr.clone())
+}
+
+/// Unanswered-ping count of one socket.
+struct Keepalive {
+    missed: u32,
+    max_missed: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Tick {
+    Ping,
+    TimedOut,
+}
+
+impl Keepalive {
+    fn new(max_missed: u32) -> Self {
+        Self { missed: 0, max_missed }
+    }
+
+    /// Any frame from the client proves the socket is alive.
+    fn frame(&mut self) {
+        self.missed = 0;
+    }
+
+    /// The ping timer fired: ping again, or give up once `max_missed` pings
+    /// in a row went unanswered.
+    fn tick(&mut self) -> Tick {
+        if self.missed >= self.max_missed {
+            return Tick::TimedOut;
         }
+        self.missed += 1;
+        Tick::Ping
     }
+}
 
-    // Cleanup: stop forwarder and mark user offline (best-effort).
-    forward_task.abort();
-    if user_id != "unknown" { let _ = state.presence.mark_offline(&user_id); }
+/// Text frame for a pub/sub delivery. Capnp join/leave envelopes are
+/// translated to JSON; other binary payloads are not forwarded.
+fn delivery_text(d: bus::pubsub::Delivery) -> Option<String> {
+    if d.headers.is_capnp() {
+        let m = presence::RoomMembership::decode(&d.payload)?;
+        return Some(serde_json::json!({ "type": if m.left { "leave" } else { "join" }, "room": m.room, "user_id": m.user_id }).to_string());
+    }
+    String::from_utf8(d.payload).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::WsConfig;
+
+    #[test]
+    fn keepalive_times_out_after_missed_pongs() {
+        let mut k = Keepalive::new(2);
+        // an answered ping resets the count, however long the socket lives
+        for _ in 0..10 {
+            assert_eq!(k.tick(), Tick::Ping);
+            k.frame();
+        }
+        assert_eq!((k.tick(), k.tick()), (Tick::Ping, Tick::Ping));
+        assert_eq!(k.tick(), Tick::TimedOut);
+
+        let mut k = Keepalive::new(2);
+        assert_eq!((k.tick(), k.tick()), (Tick::Ping, Tick::Ping));
+        k.frame();
+        assert_eq!(k.tick(), Tick::Ping, "a late pong still counts");
+    }
+
+    #[test]
+    fn keepalive_must_fit_in_presence_timeout() {
+        let config = |ping: u64, missed: u32, timeout: u64| WsConfig {
+            ping_interval: Duration::from_secs(ping),
+            max_missed_pongs: missed,
+            presence_timeout: Duration::from_secs(timeout),
+        };
+        assert!(config(20, 2, 60).validate().is_ok());
+        assert!(config(60, 2, 60).validate().is_err(), "healthy sockets would be swept between pongs");
+        assert!(config(20, 3, 60).validate().is_err());
+        assert!(config(30, 3, 120).validate().is_ok());
+    }
 }
@@ -2,16 +2,17 @@
 //
 // This module defines:
 // - AppState: the shared services the app uses (storage, pub/sub, presence,
-//   rate limiting, and a cached NNG address). We store them in Arc<> so they
+//   rate limit policies, the service registry, and a cached NNG address). We store them in Arc<> so they
 //   can be cheaply cloned and used by async handlers across threads.
-// - Small, centralized helpers used by multiple modules (admin check, token
-//   extraction, and an ApiError JSON shape).
+// - Small, centralized helpers used by multiple modules (user/admin checks,
+//   token extraction, audit logging, and an ApiError JSON shape).
 //
 // If you're new to Axum: `State(AppState)` is an extractor that injects a clone
 // of AppState into handlers. You construct it once in main and attach to the
 // router with `.with_state(state.clone())`.
 
 use std::sync::Arc;
+use std::time::Duration;
 use axum::http::{HeaderMap, StatusCode};
 use serde::Serialize;
 
@@ -20,7 +21,7 @@ use storage::Storage;
 
 // Exposed so routes can access presence/rate types without re-importing here
 pub use presence::PresenceManager;
-pub use rate::RateLimiter;
+pub use rate::Policies;
 
 // A constant used in dev helpers for the administrator email address.
 /// Default admin email used in dev helpers and seeding logic.
@@ -39,9 +40,134 @@ pub struct AppState {
     pub publisher: Arc<Publisher>,
     pub storage: Arc<Storage>,
     pub presence: Arc<PresenceManager>,
-    pub rate: Arc<RateLimiter>,
-    // NNG publisher address (moved into shared state so we don't read env on every connection)
+    pub rate: Arc<Policies>,
+    pub discovery: Arc<discover::Registry>,
+    // Address our NNG publisher is bound to; subscribers dial it unless discovery
+    // knows a "bus" instance (see routes/ws.rs)
     pub nng_addr: String,
+    pub ws: WsConfig,
+    pub lockout: LockoutConfig,
+    pub tokens: auth::TokenPolicy,
+    /// OAuth / OpenID Connect sign-in providers (see `auth::OAuthRegistry::from_env`).
+    pub oauth: Arc<auth::OAuthRegistry>,
+    /// Passkey (WebAuthn) relying party; `None` when not configured (see `auth::Passkeys::from_env`).
+    pub passkeys: Option<Arc<auth::Passkeys>>,
+    /// Account emails (verification, password reset); `None` when no mailer is configured.
+    pub mail: Option<MailConfig>,
+    /// Rules for new passwords and the Argon2 cost of password hashes (see `auth::PasswordPolicy::from_env`).
+    pub passwords: Arc<auth::PasswordPolicy>,
+}
+
+/// How account emails are sent and where their links point.
+#[derive(Clone)]
+pub struct MailConfig {
+    pub mailer: Arc<dyn mail::Mailer>,
+    /// Web app origin the links in emails open, e.g. `https://chat.example.com`.
+    pub app_url: String,
+}
+
+impl MailConfig {
+    /// The mailer from `mail::from_env` and `APP_BASE_URL` for links, which is
+    /// required outside dev mode (there it defaults to the Vite dev server).
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let dev: bool = auth::dev_mode();
+        let Some(mailer) = mail::from_env(dev)? else { return Ok(None) };
+        let app_url: String = match std::env::var("APP_BASE_URL").ok().filter(|v| !v.trim().is_empty()) {
+            Some(url) => url.trim().trim_end_matches('/').to_string(),
+            None if dev => "http://localhost:5173".to_string(),
+            None => anyhow::bail!("APP_BASE_URL is required to send account emails"),
+        };
+        Ok(Some(Self { mailer, app_url }))
+    }
+}
+
+/// WebSocket keepalive settings for `/ws`, and how long presence keeps a
+/// connection without hearing from it.
+#[derive(Clone, Copy, Debug)]
+pub struct WsConfig {
+    /// How often the server pings each socket.
+    pub ping_interval: Duration,
+    /// Consecutive unanswered pings after which a socket is considered dead and closed.
+    pub max_missed_pongs: u32,
+    /// Silence after which the presence sweeper drops a connection.
+    pub presence_timeout: Duration,
+}
+
+impl WsConfig {
+    /// Read `WS_PING_INTERVAL_SECS` (default 20), `WS_MAX_MISSED_PONGS` (default 2)
+    /// and `PRESENCE_TIMEOUT_SECS` (default 60). Fails when a socket could
+    /// still be open, with its pings unanswered, after presence dropped it.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let ping_secs: u64 = std::env::var("WS_PING_INTERVAL_SECS").ok().and_then(|s| s.parse().ok()).filter(|s| *s > 0).unwrap_or(20);
+        let max_missed_pongs: u32 = std::env::var("WS_MAX_MISSED_PONGS").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).unwrap_or(2);
+        let timeout_secs: u64 = std::env::var("PRESENCE_TIMEOUT_SECS").ok().and_then(|s| s.parse().ok()).filter(|s| *s > 0).unwrap_or(60);
+        let config = Self { ping_interval: Duration::from_secs(ping_secs), max_missed_pongs, presence_timeout: Duration::from_secs(timeout_secs) };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// A live socket pongs once per ping interval and a dead one is closed
+    /// after `max_missed_pongs + 1` intervals; both must fit in the presence
+    /// timeout, or healthy sockets flap offline between pongs.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let close_after: Duration = self.ping_interval * (self.max_missed_pongs + 1);
+        if close_after > self.presence_timeout {
+            anyhow::bail!(
+                "WS_PING_INTERVAL_SECS ({}) x (WS_MAX_MISSED_PONGS ({}) + 1) must not exceed PRESENCE_TIMEOUT_SECS ({})",
+                self.ping_interval.as_secs(), self.max_missed_pongs, self.presence_timeout.as_secs(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Failed sign-in throttling for `/api/auth/login` and `/api/auth/signup`.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutConfig {
+    pub account: auth::LockoutPolicy,
+    pub ip: auth::LockoutPolicy,
+}
+
+impl LockoutConfig {
+    /// Defaults from `auth::LockoutPolicy`; `LOGIN_LOCK_AFTER` and `LOGIN_LOCK_SECS`
+    /// override the per-account lockout threshold and duration.
+    pub fn from_env() -> Self {
+        let mut account = auth::LockoutPolicy::account();
+        if let Some(n) = std::env::var("LOGIN_LOCK_AFTER").ok().and_then(|s| s.parse::<u32>().ok()).filter(|n| *n > 0) {
+            account.lock_after = n;
+            account.free_attempts = account.free_attempts.min(n);
+        }
+        if let Some(secs) = std::env::var("LOGIN_LOCK_SECS").ok().and_then(|s| s.parse::<i64>().ok()).filter(|s| *s > 0) {
+            account.lock_secs = secs;
+        }
+        Self { account, ip: auth::LockoutPolicy::ip() }
+    }
+}
+
+/// Access and refresh token lifetimes: defaults from `auth::TokenPolicy`, with
+/// `AUTH_ACCESS_TTL_SECS` and `AUTH_REFRESH_TTL_SECS` as overrides.
+pub fn token_policy_from_env() -> auth::TokenPolicy {
+    let secs = |name: &str| std::env::var(name).ok().and_then(|s| s.parse::<i64>().ok()).filter(|s| *s > 0);
+    let mut policy = auth::TokenPolicy::default();
+    if let Some(ttl) = secs("AUTH_ACCESS_TTL_SECS") { policy.access_ttl_secs = ttl; }
+    if let Some(ttl) = secs("AUTH_REFRESH_TTL_SECS") { policy.refresh_ttl_secs = ttl; }
+    policy
+}
+
+/// Record a security event in the audit log. Failures are logged, not returned,
+/// so auditing never breaks the request that triggered it.
+pub fn audit(state: &AppState, event: &str, actor: Option<&str>, subject: Option<&str>, ip: Option<std::net::IpAddr>, detail: serde_json::Value) {
+    let rec = storage::AuditRecord {
+        at: chrono::Utc::now().timestamp(),
+        event: event.to_string(),
+        actor: actor.map(str::to_string),
+        subject: subject.map(str::to_string),
+        ip: ip.map(|ip| ip.to_string()),
+        detail,
+    };
+    if let Err(e) = state.storage.append_audit(&rec) {
+        tracing::error!(event, "audit append failed: {:?}", e);
+    }
 }
 
 // Token extraction helpers: centralize header/cookie parsing so we don't duplicate logic.
@@ -67,13 +193,9 @@ fn cookie_session_token(headers: &HeaderMap) -> Option<String> {
         .and_then(|cookie_header: &str| {
             cookie_header.split(';').find_map(|kv: &str| {
                 let kv: &str = kv.trim();
-                if let Some(v) = kv.strip_prefix("session=") {
-                    Some(v.to_string())
-                } else if let Some(v) = kv.strip_prefix("session_token=") {
-                    Some(v.to_string())
-                } else {
-                    None
-                }
+                kv.strip_prefix("session=")
+                    .or_else(|| kv.strip_prefix("session_token="))
+                    .map(|v| v.to_string())
             })
         })
 }
@@ -89,15 +211,38 @@ pub struct ApiError {
     pub message: String,
 }
 
+/// Error for handlers returning `(StatusCode, String)`: `status` with a
+/// `{ "message": msg }` body.
+pub fn message(status: StatusCode, msg: &str) -> (StatusCode, String) {
+    (status, serde_json::json!({ "message": msg }).to_string())
+}
+
+/// 500 with the error's text, for handlers returning `(StatusCode, String)`.
+pub fn internal(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// Verify an access token: signature, expiry, and that its session (`sid`)
+/// hasn't been revoked. Use this rather than `auth::verify_jwt` directly.
+pub fn verify_token(state: &AppState, token: &str) -> anyhow::Result<auth::Claims> {
+    auth::verify_session_jwt(&state.storage, token).map(|data| data.claims)
+}
+
+/// Centralized authentication helper.
+/// Returns Ok(user_id) from a verified Bearer/cookie token, or Err((StatusCode, ApiError)).
+pub fn require_user(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, ApiError)> {
+    let token: String = extract_token(headers)
+        .ok_or((StatusCode::UNAUTHORIZED, ApiError { message: "missing authorization".into() }))?;
+    verify_token(state, &token)
+        .map(|claims| claims.sub)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, ApiError { message: "invalid token".into() }))
+}
+
 /// Centralized admin requirement helper.
 /// Returns Ok(requester_id) or Err((StatusCode, ApiError)) suitable for returning from handlers.
 pub fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, ApiError)> {
     // Extract token and verify
-    let token: String = extract_token(headers)
-        .ok_or((StatusCode::UNAUTHORIZED, ApiError { message: "missing authorization".into() }))?;
-    let data = auth::verify_jwt(&token)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, ApiError { message: "invalid token".into() }))?;
-    let requester_id: String = data.claims.sub;
+    let requester_id: String = require_user(state, headers)?;
 
     // Ensure stored user has role "admin"
     match state.storage.get_user(&requester_id) {
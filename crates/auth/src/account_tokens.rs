@@ -0,0 +1,110 @@
+//! Single-use tokens sent by email: password resets and email verification.
+//!
+//! A token is 32 random bytes (base64url) that only ever exists in the email;
+//! storage keeps its SHA-256 with the purpose, the account and an expiry.
+//! Redeeming removes the token whether or not it is still good, and issuing
+//! a new one for the same account and purpose revokes the older ones, so only
+//! the latest email works.
+use anyhow::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use storage::Storage;
+
+use crate::sha256_hex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountTokenPurpose {
+    PasswordReset,
+    VerifyEmail,
+}
+
+impl AccountTokenPurpose {
+    /// An hour to reset a password, two days to verify an email address.
+    pub fn ttl_secs(self) -> i64 {
+        match self {
+            Self::PasswordReset => 3600,
+            Self::VerifyEmail => 2 * 86400,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::PasswordReset => "password_reset",
+            Self::VerifyEmail => "verify_email",
+        }
+    }
+}
+
+/// What a token was issued for, as stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountToken {
+    pub purpose: AccountTokenPurpose,
+    pub user_id: String,
+    /// Address the token was sent to.
+    pub email: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+/// New token for `purpose` on `user_id`, replacing any it already had.
+pub fn issue_account_token(storage: &Storage, purpose: AccountTokenPurpose, user_id: &str, email: &str, now: i64) -> Result<String> {
+    storage.delete_account_tokens(user_id, purpose.as_str())?;
+    let mut raw = [0u8; 32];
+    rand::rng().fill_bytes(&mut raw);
+    let token = URL_SAFE_NO_PAD.encode(raw);
+    let rec = AccountToken {
+        purpose,
+        user_id: user_id.to_string(),
+        email: email.to_string(),
+        created_at: now,
+        expires_at: now + purpose.ttl_secs(),
+    };
+    storage.put_account_token(&sha256_hex(&token), &serde_json::to_value(&rec)?)?;
+    Ok(token)
+}
+
+/// Like `redeem_account_token` without using the token up, to look at whom
+/// it is for before acting on it.
+pub fn peek_account_token(storage: &Storage, purpose: AccountTokenPurpose, token: &str, now: i64) -> Result<Option<AccountToken>> {
+    let Some(v) = storage.get_account_token(&sha256_hex(token.trim()))? else { return Ok(None) };
+    Ok(serde_json::from_value::<AccountToken>(v).ok().filter(|t| t.purpose == purpose && t.expires_at > now))
+}
+
+/// Use up `token`. Returns what it was issued for if it is known, unexpired
+/// and for `purpose`.
+pub fn redeem_account_token(storage: &Storage, purpose: AccountTokenPurpose, token: &str, now: i64) -> Result<Option<AccountToken>> {
+    let Some(v) = storage.take_account_token(&sha256_hex(token.trim()))? else { return Ok(None) };
+    Ok(serde_json::from_value::<AccountToken>(v).ok().filter(|t| t.purpose == purpose && t.expires_at > now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_use_expiry_and_replacement() {
+        let (_dir, storage) = crate::test_storage();
+        let reset = AccountTokenPurpose::PasswordReset;
+
+        let first = issue_account_token(&storage, reset, "u1", "a@example.com", 1000).unwrap();
+        let verify = issue_account_token(&storage, AccountTokenPurpose::VerifyEmail, "u1", "a@example.com", 1000).unwrap();
+        let second = issue_account_token(&storage, reset, "u1", "a@example.com", 1010).unwrap();
+        assert!(redeem_account_token(&storage, reset, &first, 1020).unwrap().is_none(), "replaced by the newer token");
+        assert!(peek_account_token(&storage, reset, &second, 1020).unwrap().is_some());
+        let t = redeem_account_token(&storage, reset, &second, 1020).unwrap().unwrap();
+        assert_eq!((t.user_id.as_str(), t.email.as_str(), t.expires_at), ("u1", "a@example.com", 1010 + 3600));
+        assert!(redeem_account_token(&storage, reset, &second, 1021).unwrap().is_none(), "single use");
+        assert!(peek_account_token(&storage, reset, &second, 1021).unwrap().is_none());
+
+        // other purposes are untouched by a reset, but don't work as one
+        assert!(redeem_account_token(&storage, reset, &verify, 1030).unwrap().is_none());
+        let late = issue_account_token(&storage, AccountTokenPurpose::VerifyEmail, "u1", "a@example.com", 1000).unwrap();
+        assert!(redeem_account_token(&storage, AccountTokenPurpose::VerifyEmail, &late, 1000 + 2 * 86400).unwrap().is_none(), "expired");
+
+        issue_account_token(&storage, reset, "u2", "b@example.com", 1000).unwrap();
+        assert_eq!(storage.purge_account_tokens(1000 + 3600).unwrap(), 1);
+    }
+}
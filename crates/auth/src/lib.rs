@@ -1,10 +1,29 @@
 ﻿use anyhow::Result;
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey, TokenData, Algorithm};
+use anyhow::anyhow;
+use jsonwebtoken::{encode, decode, decode_header, Header, Validation, TokenData};
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+mod account_tokens;
+pub use account_tokens::*;
+mod keys;
+pub use keys::*;
+mod lockout;
+pub use lockout::*;
+#[cfg(feature = "with-oauth")]
 mod oauth;
+#[cfg(feature = "with-oauth")]
 pub use oauth::*;
+#[cfg(feature = "with-oauth")]
+mod oauth_config;
+#[cfg(feature = "with-oauth")]
+pub use oauth_config::*;
+mod password;
+pub use password::*;
+mod refresh;
+pub use refresh::*;
+mod totp;
+pub use totp::*;
 #[cfg(feature = "with-webauthn")]
 mod webauthn;
 #[cfg(feature = "with-webauthn")]
@@ -17,15 +36,23 @@ pub struct Claims {
     pub exp: usize,
     pub iss: Option<String>,
     pub aud: Option<String>,
+    /// Session the token was issued for (see `storage::SessionRecord`). Tokens
+    /// with a session stop working as soon as it is revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
 }
 
-/// Read JWT secret from env or return a default (dev only).
-fn jwt_secret() -> String {
-    std::env::var("AUTH_JWT_SECRET").unwrap_or_else(|_| "stack-dev-secret".to_string())
+/// Lowercase hex SHA-256 of `s`, the storage key of opaque tokens.
+pub(crate) fn sha256_hex(s: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(s.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-/// Create a signed JWT (HS256) for `user_id` with ttl seconds.
-pub fn create_jwt(user_id: &str, ttl_secs: usize) -> Result<String> {
+/// Create a JWT for `user_id` bound to session `sid`, valid for ttl seconds
+/// and signed by the active key of the keyring (RS256 or EdDSA, with its `kid`
+/// in the header). Every access token has a session so it can be revoked.
+pub fn create_session_jwt(user_id: &str, sid: &str, ttl_secs: usize) -> Result<String> {
+    let key = keyring()?.active();
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
     let claims = Claims {
         sub: user_id.to_string(),
@@ -33,20 +60,47 @@ pub fn create_jwt(user_id: &str, ttl_secs: usize) -> Result<String> {
         exp: now + ttl_secs,
         iss: Some("stack".to_string()),
         aud: Some("stack-web".to_string()),
+        sid: Some(sid.to_string()),
     };
-    let header = Header::default();
-    let token = encode(&header, &claims, &EncodingKey::from_secret(jwt_secret().as_bytes()))?;
+    let mut header = Header::new(key.alg);
+    header.kid = Some(key.kid.clone());
+    let token = encode(&header, &claims, key.encoding())?;
     Ok(token)
 }
 
-/// Verify a JWT and return its claims if valid.
+/// Verify a JWT and return its claims if valid. The key is picked by the
+/// token's `kid`, so tokens signed by a rotated-out key that is still in the
+/// keyring keep working until they expire.
 pub fn verify_jwt(token: &str) -> Result<TokenData<Claims>> {
-    // Use an explicit Validation that checks expiry and accepts the audience/issuer we mint.
-    let mut validation = Validation::new(Algorithm::HS256);
+    let kid = decode_header(token)?.kid.ok_or_else(|| anyhow!("token has no kid"))?;
+    let key = keyring()?.get(&kid).ok_or_else(|| anyhow!("unknown signing key {:?}", kid))?;
+    // Only accept the algorithm of the key the token names, check expiry, and
+    // accept the audience and issuer we set when creating tokens.
+    let mut validation = Validation::new(key.alg);
     validation.validate_exp = true;
-    // Accept the audience and issuer we set when creating tokens.
     validation.set_audience(&["stack-web"]);
     validation.set_issuer(&["stack"]);
-    let data = decode::<Claims>(token, &DecodingKey::from_secret(jwt_secret().as_bytes()), &validation)?;
+    let data = decode::<Claims>(token, key.decoding(), &validation)?;
     Ok(data)
 }
+
+/// `verify_jwt`, plus a check that the token names a session (`sid`) that
+/// exists and hasn't been revoked. Tokens without one are refused, as they
+/// could never be revoked.
+pub fn verify_session_jwt(storage: &storage::Storage, token: &str) -> Result<TokenData<Claims>> {
+    let data = verify_jwt(token)?;
+    let sid = data.claims.sid.as_deref().ok_or_else(|| anyhow!("token has no session"))?;
+    match storage.get_session(sid)? {
+        Some(session) if session.revoked_at.is_none() && session.user_id == data.claims.sub => Ok(data),
+        _ => Err(anyhow!("session revoked")),
+    }
+}
+
+/// Storage in a temporary directory for tests, removed when the returned guard
+/// drops (also when an assertion panics).
+#[cfg(test)]
+pub(crate) fn test_storage() -> (tempfile::TempDir, storage::Storage) {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = storage::Storage::new(dir.path()).unwrap();
+    (dir, storage)
+}
@@ -0,0 +1,79 @@
+//! Failed sign-in throttling for accounts and client IPs.
+//!
+//! Each tracked key has a `LoginFailureRecord`. The first `free_attempts`
+//! failures cost nothing; every failure after that pushes the next allowed
+//! attempt out exponentially (`base_delay_secs`, doubling, capped at
+//! `max_delay_secs`). Reaching `lock_after` failures locks the key for
+//! `lock_secs`. A key with no failure for `window_secs` starts over.
+use storage::LoginFailureRecord;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub free_attempts: u32,
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+    pub lock_after: u32,
+    pub lock_secs: i64,
+    pub window_secs: i64,
+}
+
+impl LockoutPolicy {
+    /// Per-account defaults: 5 free attempts, then 1s doubling up to 60s;
+    /// 10 failures lock the account for 15 minutes.
+    pub fn account() -> Self {
+        Self { free_attempts: 5, base_delay_secs: 1, max_delay_secs: 60, lock_after: 10, lock_secs: 900, window_secs: 900 }
+    }
+
+    /// Per-IP defaults. Looser than per-account since many users can share an
+    /// address behind NAT: 20 free attempts, locked for 15 minutes after 100.
+    pub fn ip() -> Self {
+        Self { free_attempts: 20, base_delay_secs: 1, max_delay_secs: 60, lock_after: 100, lock_secs: 900, window_secs: 900 }
+    }
+
+    /// Seconds until the key may try again at `now`, or `None` if it may try now.
+    pub fn retry_after(&self, rec: &LoginFailureRecord, now: i64) -> Option<i64> {
+        let until = rec.locked_until.max(rec.next_attempt_at);
+        (until > now).then_some(until - now)
+    }
+
+    /// Count a failure at `now` on top of `rec` and return the updated record.
+    pub fn register_failure(&self, rec: Option<LoginFailureRecord>, now: i64) -> LoginFailureRecord {
+        let mut rec = rec
+            .filter(|r| r.locked_until > now || now - r.last_failed_at < self.window_secs)
+            .unwrap_or_default();
+        rec.failures = rec.failures.saturating_add(1);
+        rec.last_failed_at = now;
+        if rec.failures >= self.lock_after {
+            rec.locked_until = now + self.lock_secs;
+        } else if rec.failures > self.free_attempts {
+            let doublings = (rec.failures - self.free_attempts - 1).min(32);
+            rec.next_attempt_at = now + self.base_delay_secs.saturating_mul(1 << doublings).min(self.max_delay_secs);
+        }
+        rec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_then_lockout_then_quiet_period_resets() {
+        let policy = LockoutPolicy { free_attempts: 2, base_delay_secs: 1, max_delay_secs: 4, lock_after: 6, lock_secs: 100, window_secs: 50 };
+        let mut rec = None;
+        let mut waits = Vec::new();
+        for _ in 0..5 {
+            let r = policy.register_failure(rec, 1000);
+            waits.push(policy.retry_after(&r, 1000));
+            rec = Some(r);
+        }
+        assert_eq!(waits, [None, None, Some(1), Some(2), Some(4)]);
+
+        let locked = policy.register_failure(rec, 1000);
+        assert_eq!(policy.retry_after(&locked, 1000), Some(100));
+        // still locked past the window, and another failure keeps it locked
+        assert_eq!(policy.register_failure(Some(locked.clone()), 1060).failures, 7);
+        // after the lock and a quiet window the count starts over
+        assert_eq!(policy.register_failure(Some(locked), 1200).failures, 1);
+    }
+}
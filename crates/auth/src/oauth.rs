@@ -1,115 +1,465 @@
-use anyhow::Result;
+//! OAuth2 / OpenID Connect sign-in (authorization code flow with PKCE).
+//!
+//! `OAuthClient::start` builds the provider's authorization URL and stores the
+//! flow's state (PKCE verifier, nonce, where to send the user afterwards)
+//! under a random `state` value. `OAuthClient::callback` consumes that state,
+//! exchanges the code at the token endpoint and returns the signed-in
+//! identity, mapped to our user fields with the provider's `ClaimMap`.
+//!
+//! For OpenID Connect providers the identity is the ID token, verified
+//! against the provider's published keys (JWKS) and checked for issuer,
+//! audience, expiry and nonce. Endpoints come from the discovery document
+//! (`<issuer>/.well-known/openid-configuration`), fetched once; the JWKS is
+//! cached for `JWKS_TTL` and refetched early when a token names an unknown
+//! key, which is how providers rotate. Plain OAuth2 providers (GitHub) are
+//! asked for the profile with the access token instead.
+//!
+//! The authorization URL and the code exchange use the `oauth2` crate.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine as _;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use oauth2::basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType};
+use oauth2::{
+    AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet, ExtraTokenFields, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, StandardRevocableToken, StandardTokenResponse, TokenResponse, TokenUrl,
+};
 use rand::RngCore;
-use reqwest::Client as HttpClient;
-use serde_json::json;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use storage::Storage;
-use uuid::Uuid;
-use url::Url;
-
-/// Create a Google OAuth2 authorization URL and persist PKCE + redirect state.
-/// Returns (auth_url, state, pkce_verifier)
-/// This is a Phase-A, lightweight implementation that builds the URL manually
-/// and stores the transient state in `storage`.
-pub fn create_login_url_and_store(
-    google_client_id: &str,
-    _google_client_secret: &str,
-    redirect_url: &str,
-    storage: &Storage,
-    redirect_to: &str,
-) -> Result<(String, String, String)> {
-    // generate PKCE verifier (high-entropy) and challenge (sha256, base64-url)
-    let mut verifier_bytes = [0u8; 32];
-    let mut rng = rand::rng();
-    rng.fill_bytes(&mut verifier_bytes);
-    let pkce_verifier = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&verifier_bytes);
-
-    // create code challenge (sha256)
-    use sha2::{Digest, Sha256};
-    let mut hasher = Sha256::new();
-    hasher.update(pkce_verifier.as_bytes());
-    let challenge = hasher.finalize();
-    let pkce_challenge = URL_SAFE_NO_PAD.encode(&challenge);
-
-    // state (CSRF)
-    // generate a UUID-like random state using the same RNG (avoid relying on `Uuid::new_v4`)
-    let high = rng.next_u64() as u128;
-    let low = rng.next_u64() as u128;
-    let uuid_u128 = (high << 64) | low;
-    let state = Uuid::from_u128(uuid_u128).to_string();
-
-    // build auth url
-    let mut url = Url::parse("https://accounts.google.com/o/oauth2/v2/auth")?;
-    url.query_pairs_mut()
-        .append_pair("client_id", google_client_id)
-        .append_pair("redirect_uri", redirect_url)
-        .append_pair("response_type", "code")
-        .append_pair("scope", "openid email profile")
-        .append_pair("state", &state)
-        .append_pair("code_challenge", &pkce_challenge)
-        .append_pair("code_challenge_method", "S256");
-
-    // persist minimal state: provider, pkce_verifier, redirect_to
-    let state_json = json!({
-        "provider": "google",
-        "pkce_verifier": pkce_verifier,
-        "redirect_to": redirect_to
-    });
-    storage.put_oauth_state(&state, &state_json)?;
-
-    Ok((url.to_string(), state, pkce_verifier))
+
+use crate::{ClaimMap, Endpoints, ProviderConfig, GOOGLE_ISSUER};
+
+/// How long a sign-in may take between `start` and `callback`.
+pub const STATE_TTL_SECS: i64 = 600;
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+/// Don't refetch the JWKS for unknown kids more often than this.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+/// Clock skew allowed on `exp`/`iat`.
+const LEEWAY_SECS: u64 = 60;
+/// Placeholder for the tenant in multi-tenant issuers (Microsoft "common").
+const TENANT_PLACEHOLDER: &str = "{tenantid}";
+
+/// The parts of a discovery document we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// A verified sign-in.
+#[derive(Debug, Clone)]
+pub struct OAuthIdentity {
+    pub provider: String,
+    /// Stable user id at the provider.
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// Suggested username (not necessarily unique or valid here).
+    pub username: Option<String>,
+    /// Where the user asked to go after signing in, as passed to `start`.
+    pub redirect_to: String,
+}
+
+/// Token response fields beyond the OAuth2 basics: the OIDC ID token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IdTokenFields {
+    id_token: Option<String>,
 }
 
-/// Exchange an authorization code directly against Google's token endpoint and
-/// return the subject (sub) from the ID token if present.
-///
-/// Note: This is a lightweight Phase-A helper. Production code must validate
-/// the ID token signature and claims according to OIDC rules.
-pub async fn exchange_code_for_google_subject(
-    google_client_id: &str,
-    google_client_secret: &str,
-    redirect_url: &str,
-    code: &str,
-    pkce_verifier: &str,
-) -> Result<String> {
-    let http = HttpClient::builder()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-
-    let params = [
-        ("grant_type", "authorization_code"),
-        ("code", code),
-        ("client_id", google_client_id),
-        ("client_secret", google_client_secret),
-        ("redirect_uri", redirect_url),
-        ("code_verifier", pkce_verifier),
-    ];
-
-    let resp = http
-        .post("https://oauth2.googleapis.com/token")
-        .form(&params)
-        .send()
-        .await?
-        .error_for_status()?;
-
-    let body: Value = resp.json().await?;
-    if let Some(id_token) = body.get("id_token").and_then(|v| v.as_str()) {
-        // parse payload (unsafe: no signature validation)
-        let parts: Vec<&str> = id_token.split('.').collect();
-        if parts.len() >= 2 {
-            let payload = URL_SAFE_NO_PAD.decode(parts[1])?;
-            let v: Value = serde_json::from_slice(&payload)?;
-            if let Some(sub) = v.get("sub").and_then(|s| s.as_str()) {
-                return Ok(sub.to_string());
+impl ExtraTokenFields for IdTokenFields {}
+
+type TokenResp = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+type Client = oauth2::Client<
+    BasicErrorResponse,
+    TokenResp,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+>;
+
+pub struct OAuthClient {
+    config: ProviderConfig,
+    http: reqwest::Client,
+    discovery: Mutex<Option<Discovery>>,
+    jwks: Mutex<Option<(Instant, JwkSet)>>,
+}
+
+impl OAuthClient {
+    pub fn new(config: ProviderConfig) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            // The oauth2 crate requires this: following redirects would leak the code
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(Duration::from_secs(10))
+            // GitHub's API refuses requests without one
+            .user_agent(concat!("stack-auth/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self { config, http, discovery: Mutex::new(None), jwks: Mutex::new(None) })
+    }
+
+    pub fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    /// The provider's discovery document, fetched on first use. Its issuer
+    /// must be the configured one. `None` for plain OAuth2 providers.
+    pub async fn discovery(&self) -> Result<Option<Discovery>> {
+        let Endpoints::Oidc { issuer } = &self.config.endpoints else { return Ok(None) };
+        if let Some(d) = self.discovery.lock().unwrap().clone() {
+            return Ok(Some(d));
+        }
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+        let doc: Discovery = self.http.get(&url).send().await?.error_for_status()?.json().await.with_context(|| format!("reading {}", url))?;
+        if !issuer_matches(&doc.issuer, issuer) {
+            bail!("discovery issuer {:?} does not match {:?}", doc.issuer, issuer);
+        }
+        *self.discovery.lock().unwrap() = Some(doc.clone());
+        Ok(Some(doc))
+    }
+
+    async fn oauth2_client(&self) -> Result<(Client, Option<Discovery>)> {
+        let discovery = self.discovery().await?;
+        let (auth_url, token_url) = match (&self.config.endpoints, &discovery) {
+            (Endpoints::OAuth2 { authorize_url, token_url, .. }, _) => (authorize_url.clone(), token_url.clone()),
+            (Endpoints::Oidc { .. }, Some(d)) => (d.authorization_endpoint.clone(), d.token_endpoint.clone()),
+            (Endpoints::Oidc { .. }, None) => unreachable!("OIDC providers always have a discovery document"),
+        };
+        let mut client = oauth2::Client::new(ClientId::new(self.config.client_id.clone()))
+            .set_auth_uri(AuthUrl::new(auth_url)?)
+            .set_token_uri(TokenUrl::new(token_url)?)
+            .set_redirect_uri(RedirectUrl::new(self.config.redirect_url.clone())?)
+            // Credentials in the form body work with every provider; not all take Basic auth
+            .set_auth_type(AuthType::RequestBody);
+        if let Some(secret) = &self.config.client_secret {
+            client = client.set_client_secret(ClientSecret::new(secret.clone()));
+        }
+        Ok((client, discovery))
+    }
+
+    /// Begin a sign-in: store the flow state and return the URL to send the
+    /// user to. `redirect_to` is handed back by `callback`.
+    pub async fn start(&self, storage: &Storage, redirect_to: &str, now: i64) -> Result<String> {
+        let (client, discovery) = self.oauth2_client().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let nonce = random_token();
+        let mut request = client
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(self.config.scopes.iter().cloned().map(Scope::new))
+            .set_pkce_challenge(pkce_challenge);
+        if discovery.is_some() {
+            request = request.add_extra_param("nonce", nonce.clone());
+        }
+        let (url, state) = request.url();
+
+        let state_json = json!({
+            "provider": self.config.name,
+            "pkce_verifier": pkce_verifier.secret(),
+            "nonce": nonce,
+            "redirect_to": redirect_to,
+            "expires_at": now + STATE_TTL_SECS,
+        });
+        storage.put_oauth_state(state.secret(), &state_json)?;
+        Ok(url.to_string())
+    }
+
+    /// Finish a sign-in: consume `state` (each state works once), exchange
+    /// `code` and fetch or verify the identity.
+    pub async fn callback(&self, storage: &Storage, state: &str, code: &str, now: i64) -> Result<OAuthIdentity> {
+        let saved = storage.get_oauth_state(state)?.ok_or_else(|| anyhow!("unknown or used state"))?;
+        storage.delete_oauth_state(state)?;
+        let field = |name: &str| saved.get(name).and_then(|v| v.as_str()).map(str::to_string);
+        if field("provider").as_deref() != Some(self.config.name.as_str()) {
+            bail!("state belongs to another provider");
+        }
+        if saved.get("expires_at").and_then(|v| v.as_i64()).is_none_or(|at| at <= now) {
+            bail!("sign-in took too long, start again");
+        }
+        let pkce_verifier = field("pkce_verifier").ok_or_else(|| anyhow!("malformed state"))?;
+        let nonce = field("nonce").ok_or_else(|| anyhow!("malformed state"))?;
+        let redirect_to = field("redirect_to").unwrap_or_else(|| "/".to_string());
+
+        let (client, discovery) = self.oauth2_client().await?;
+        let token: TokenResp = client
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(&self.http)
+            .await
+            .map_err(|e| anyhow!("token exchange failed: {}", describe_token_error(&e)))?;
+
+        let claims: Value = match (&self.config.endpoints, discovery) {
+            (Endpoints::Oidc { .. }, Some(discovery)) => {
+                let id_token = token.extra_fields().id_token.as_deref().ok_or_else(|| anyhow!("token response has no id_token"))?;
+                self.verify_id_token(&discovery, id_token, &nonce).await?
+            }
+            (Endpoints::OAuth2 { userinfo_url, emails_url, .. }, _) => {
+                let access_token = token.access_token().secret();
+                let mut profile: Value = self.http.get(userinfo_url).bearer_auth(access_token).send().await?.error_for_status()?.json().await?;
+                if let Some(emails_url) = emails_url {
+                    let emails: Value = self.http.get(emails_url).bearer_auth(access_token).send().await?.error_for_status()?.json().await?;
+                    apply_primary_email(&mut profile, &emails, &self.config.claims);
+                }
+                profile
             }
+            (Endpoints::Oidc { .. }, None) => unreachable!("OIDC providers always have a discovery document"),
+        };
+        map_identity(&self.config, &claims, redirect_to)
+    }
+
+    async fn verify_id_token(&self, discovery: &Discovery, id_token: &str, nonce: &str) -> Result<Value> {
+        let header = decode_header(id_token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA
+        ) {
+            bail!("ID token algorithm {:?} not accepted", header.alg);
+        }
+        let key = self.decoding_key(discovery, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = LEEWAY_SECS;
+        validation.set_audience(&[&self.config.client_id]);
+        // The issuer is checked below, multi-tenant issuers depend on the token's `tid`
+        validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
+        let claims = decode::<Value>(id_token, &key, &validation).context("invalid ID token")?.claims;
+
+        let str_claim = |name: &str| claims.get(name).and_then(|v| v.as_str());
+        let iss = str_claim("iss").unwrap_or_default();
+        let expected = match str_claim("tid") {
+            Some(tid) if discovery.issuer.contains(TENANT_PLACEHOLDER) => discovery.issuer.replace(TENANT_PLACEHOLDER, tid),
+            _ => discovery.issuer.clone(),
+        };
+        // Google documents both forms
+        let google_alt = discovery.issuer == GOOGLE_ISSUER && iss == "accounts.google.com";
+        if iss.trim_end_matches('/') != expected.trim_end_matches('/') && !google_alt {
+            bail!("ID token issuer {:?} is not {:?}", iss, expected);
+        }
+        if str_claim("nonce") != Some(nonce) {
+            bail!("ID token nonce mismatch");
         }
+        if str_claim("azp").is_some_and(|azp| azp != self.config.client_id) {
+            bail!("ID token issued to another client");
+        }
+        Ok(claims)
+    }
+
+    /// Key for `kid` from the cached JWKS, refetching it when stale or when
+    /// the kid is unknown.
+    async fn decoding_key(&self, discovery: &Discovery, kid: Option<&str>) -> Result<DecodingKey> {
+        let cached = self.jwks.lock().unwrap().clone();
+        if let Some((fetched, set)) = &cached {
+            if fetched.elapsed() < JWKS_TTL {
+                if let Some(key) = pick_key(set, kid)? {
+                    return Ok(key);
+                }
+                if fetched.elapsed() < JWKS_MIN_REFRESH {
+                    bail!("no signing key {:?} in the provider's JWKS", kid);
+                }
+            }
+        }
+        let set: JwkSet = self.http.get(&discovery.jwks_uri).send().await?.error_for_status()?.json().await.context("reading JWKS")?;
+        let key = pick_key(&set, kid)?;
+        *self.jwks.lock().unwrap() = Some((Instant::now(), set));
+        key.ok_or_else(|| anyhow!("no signing key {:?} in the provider's JWKS", kid))
     }
+}
+
+/// Same issuer, where a `{tenantid}` segment in the discovered one stands for
+/// the configured tenant ("common", "organizations").
+fn issuer_matches(discovered: &str, configured: &str) -> bool {
+    let discovered: Vec<&str> = discovered.trim_end_matches('/').split('/').collect();
+    let configured: Vec<&str> = configured.trim_end_matches('/').split('/').collect();
+    discovered.len() == configured.len() && discovered.iter().zip(&configured).all(|(d, c)| d == c || *d == TENANT_PLACEHOLDER)
+}
 
-    if let Some(at) = body.get("access_token").and_then(|v| v.as_str()) {
-        return Ok(at.to_string());
+/// Key named `kid`, or the only key when the token names none. Symmetric keys are refused.
+fn pick_key(set: &JwkSet, kid: Option<&str>) -> Result<Option<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => set.find(kid),
+        None if set.keys.len() == 1 => set.keys.first(),
+        None => None,
+    };
+    match jwk {
+        Some(jwk) if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) => bail!("symmetric key in JWKS"),
+        Some(jwk) => Ok(Some(DecodingKey::from_jwk(jwk)?)),
+        None => Ok(None),
     }
+}
+
+/// Replace the profile's email with the primary verified one from a
+/// GitHub-style email list, marking it verified.
+fn apply_primary_email(profile: &mut Value, emails: &Value, claims: &ClaimMap) {
+    let primary = emails.as_array().into_iter().flatten().find(|e| e["primary"] == true && e["verified"] == true);
+    if let (Some(email), Some(obj)) = (primary.and_then(|e| e["email"].as_str()), profile.as_object_mut()) {
+        obj.insert(claims.email.clone(), json!(email));
+        obj.insert("email_verified".into(), json!(true));
+    }
+}
+
+/// Our user fields from the provider's claims.
+fn map_identity(config: &ProviderConfig, claims: &Value, redirect_to: String) -> Result<OAuthIdentity> {
+    let text = |name: &str| match claims.get(name) {
+        Some(Value::String(s)) if !s.is_empty() => Some(s.clone()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    let map = &config.claims;
+    let subject = text(&map.subject).ok_or_else(|| anyhow!("{} returned no {:?}", config.name, map.subject))?;
+    // The emails list vouches for its address even where the profile can't
+    let verified_claim = if matches!(config.endpoints, Endpoints::OAuth2 { emails_url: Some(_), .. }) { Some("email_verified") } else { map.email_verified.as_deref() };
+    let email_verified = match verified_claim.and_then(|name| claims.get(name)) {
+        Some(Value::Bool(b)) => *b,
+        // Some providers send "true"/"false" as strings
+        Some(Value::String(s)) => s == "true",
+        _ => false,
+    };
+    Ok(OAuthIdentity {
+        provider: config.name.clone(),
+        subject,
+        email: text(&map.email).map(|e| e.trim().to_lowercase()),
+        email_verified,
+        username: map.username.iter().find_map(|name| text(name)),
+        redirect_to,
+    })
+}
+
+fn describe_token_error<E: std::error::Error>(e: &oauth2::RequestTokenError<E, BasicErrorResponse>) -> String {
+    match e {
+        oauth2::RequestTokenError::ServerResponse(r) => r.to_string(),
+        other => {
+            let mut msg = other.to_string();
+            let mut source = std::error::Error::source(other);
+            while let Some(s) = source {
+                msg = format!("{}: {}", msg, s);
+                source = s.source();
+            }
+            msg
+        }
+    }
+}
 
-    anyhow::bail!("token response missing id_token and access_token");
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SigningKey;
+    use axum::{routing::{get, post}, Json, Router};
+    use std::sync::Arc;
+    use url::Url;
+
+    struct Idp {
+        base: String,
+        key: SigningKey,
+        nonce: Mutex<String>,
+    }
+
+    /// An OIDC issuer that is also a GitHub-like OAuth2 service.
+    async fn mock_idp() -> Arc<Idp> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let idp = Arc::new(Idp { base, key: SigningKey::generate("idp-1").unwrap(), nonce: Mutex::new(String::new()) });
+        let (d, j, t) = (Arc::clone(&idp), Arc::clone(&idp), Arc::clone(&idp));
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(move || async move {
+                Json(json!({
+                    "issuer": d.base,
+                    "authorization_endpoint": format!("{}/authorize", d.base),
+                    "token_endpoint": format!("{}/token", d.base),
+                    "jwks_uri": format!("{}/jwks", d.base),
+                }))
+            }))
+            .route("/jwks", get(move || async move { Json(json!({ "keys": [j.key.jwk()] })) }))
+            .route("/token", post(move || async move {
+                let now = unix_now();
+                let claims = json!({
+                    "iss": t.base, "aud": "client-1", "sub": "idp-user-7", "iat": now, "exp": now + 300,
+                    "nonce": *t.nonce.lock().unwrap(), "email": "Ann@Example.com", "email_verified": true, "name": "Ann",
+                });
+                let mut header = jsonwebtoken::Header::new(t.key.alg);
+                header.kid = Some(t.key.kid.clone());
+                let id_token = jsonwebtoken::encode(&header, &claims, t.key.encoding()).unwrap();
+                Json(json!({ "access_token": "at", "token_type": "bearer", "id_token": id_token }))
+            }))
+            .route("/user", get(|| async { Json(json!({ "id": 583231, "login": "octocat", "email": null })) }))
+            .route("/user/emails", get(|| async {
+                Json(json!([
+                    { "email": "old@example.com", "primary": false, "verified": true },
+                    { "email": "octo@example.com", "primary": true, "verified": true },
+                ]))
+            }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        idp
+    }
+
+    fn unix_now() -> i64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn query(url: &str, name: &str) -> String {
+        Url::parse(url).unwrap().query_pairs().find(|(k, _)| k == name).unwrap().1.into_owned()
+    }
+
+    #[tokio::test]
+    async fn code_flow_against_mock_idp() {
+        let idp = mock_idp().await;
+        let (_dir, storage) = crate::test_storage();
+        let client = OAuthClient::new(ProviderConfig::oidc("mock", &idp.base, "client-1", None, "http://app/cb")).unwrap();
+        let now = unix_now();
+
+        let url = client.start(&storage, "/profile", now).await.unwrap();
+        assert!(url.starts_with(&format!("{}/authorize?", idp.base)));
+        let state = query(&url, "state");
+        *idp.nonce.lock().unwrap() = query(&url, "nonce");
+        let who = client.callback(&storage, &state, "code", now).await.unwrap();
+        assert_eq!((who.subject.as_str(), who.email.as_deref(), who.email_verified), ("idp-user-7", Some("ann@example.com"), true));
+        assert_eq!((who.username.as_deref(), who.redirect_to.as_str()), (Some("Ann"), "/profile"));
+        assert!(client.callback(&storage, &state, "code", now).await.is_err(), "state is single use");
+
+        // an ID token minted for another sign-in's nonce is refused
+        let state = query(&client.start(&storage, "/", now).await.unwrap(), "state");
+        *idp.nonce.lock().unwrap() = "someone-else".to_string();
+        assert!(client.callback(&storage, &state, "code", now).await.unwrap_err().to_string().contains("nonce"));
+
+        // expired state
+        let state = query(&client.start(&storage, "/", now).await.unwrap(), "state");
+        assert!(client.callback(&storage, &state, "code", now + STATE_TTL_SECS).await.is_err());
+
+        // plain OAuth2 with the GitHub preset's claim mapping
+        let mut github = ProviderConfig::preset("github", None, "client-1", Some("secret"), "http://app/cb").unwrap();
+        github.endpoints = Endpoints::OAuth2 {
+            authorize_url: format!("{}/authorize", idp.base),
+            token_url: format!("{}/token", idp.base),
+            userinfo_url: format!("{}/user", idp.base),
+            emails_url: Some(format!("{}/user/emails", idp.base)),
+        };
+        let client = OAuthClient::new(github).unwrap();
+        let url = client.start(&storage, "/", now).await.unwrap();
+        assert!(!url.contains("nonce="));
+        let who = client.callback(&storage, &query(&url, "state"), "code", now).await.unwrap();
+        assert_eq!((who.provider.as_str(), who.subject.as_str(), who.username.as_deref()), ("github", "583231", Some("octocat")));
+        assert_eq!((who.email.as_deref(), who.email_verified), (Some("octo@example.com"), true));
+    }
+
+    #[test]
+    fn multi_tenant_issuer() {
+        assert!(issuer_matches("https://login.microsoftonline.com/{tenantid}/v2.0", "https://login.microsoftonline.com/common/v2.0"));
+        assert!(!issuer_matches("https://login.microsoftonline.com/{tenantid}/v2.0", "https://evil.example.com/common/v2.0"));
+        assert!(issuer_matches("https://gitlab.com/", "https://gitlab.com"));
+    }
 }
@@ -0,0 +1,315 @@
+//! OAuth sign-in providers and where their configuration comes from.
+//!
+//! A provider is either an OpenID Connect issuer (endpoints from discovery,
+//! identity from the verified ID token) or a plain OAuth2 service (identity
+//! from a userinfo endpoint, e.g. GitHub). Presets fill in the endpoints,
+//! scopes and claim mapping of the common ones: `google`, `github`, `gitlab`
+//! and `microsoft`; anything else is configured by hand.
+//!
+//! Providers come from a JSON file (`OAUTH_PROVIDERS_FILE`):
+//!
+//! ```json
+//! {
+//!   "providers": [
+//!     { "name": "github", "preset": "github", "client_id": "Iv1.abc" },
+//!     { "name": "gitlab", "preset": "gitlab", "issuer": "https://git.example.com", "client_id": "..." },
+//!     { "name": "corp", "issuer": "https://sso.example.com", "client_id": "...",
+//!       "claims": { "username": ["upn"], "email_verified": null } }
+//!   ]
+//! }
+//! ```
+//!
+//! and from the environment: each preset name, and each name listed in
+//! `OAUTH_PROVIDERS` (comma separated), is enabled by `OAUTH_<NAME>_CLIENT_ID`,
+//! with `OAUTH_<NAME>_CLIENT_SECRET`, `OAUTH_<NAME>_REDIRECT_URL`,
+//! `OAUTH_<NAME>_ISSUER` (a self-hosted GitLab, a mock IdP) and
+//! `OAUTH_MICROSOFT_TENANT` alongside. A file entry without a secret reads
+//! `OAUTH_<NAME>_CLIENT_SECRET`, so secrets can stay out of the file. Without
+//! a redirect URL a provider calls back to
+//! `$OAUTH_REDIRECT_BASE/api/auth/oauth/<name>/callback`.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::OAuthClient;
+
+pub const GOOGLE_ISSUER: &str = "https://accounts.google.com";
+pub const PRESETS: [&str; 4] = ["google", "github", "gitlab", "microsoft"];
+
+/// Where a provider's endpoints and identity come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoints {
+    /// OpenID Connect: endpoints from `<issuer>/.well-known/openid-configuration`,
+    /// identity from the ID token.
+    Oidc { issuer: String },
+    /// Plain OAuth2: identity from `userinfo_url`, called with the access token.
+    /// `emails_url` lists the account's addresses GitHub-style
+    /// (`[{ "email", "primary", "verified" }]`) when the profile doesn't say
+    /// whether its email is verified.
+    OAuth2 { authorize_url: String, token_url: String, userinfo_url: String, emails_url: Option<String> },
+}
+
+/// Which claims (ID token) or fields (userinfo) hold our user fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClaimMap {
+    /// Stable account id at the provider. Numbers are used as strings.
+    pub subject: String,
+    pub email: String,
+    /// Boolean claim saying the email is verified; `None` when the provider
+    /// doesn't verify emails, so they are never trusted for account linking.
+    pub email_verified: Option<String>,
+    /// Candidates for the username, first present wins.
+    pub username: Vec<String>,
+}
+
+impl Default for ClaimMap {
+    /// Standard OIDC claims.
+    fn default() -> Self {
+        Self {
+            subject: "sub".into(),
+            email: "email".into(),
+            email_verified: Some("email_verified".into()),
+            username: vec!["preferred_username".into(), "name".into()],
+        }
+    }
+}
+
+/// One sign-in provider.
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    /// Name used in routes and account links, e.g. "google".
+    pub name: String,
+    pub endpoints: Endpoints,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Our callback URL, registered with the provider.
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub claims: ClaimMap,
+}
+
+impl ProviderConfig {
+    /// Any OpenID Connect issuer, with the standard scopes and claims.
+    pub fn oidc(name: &str, issuer: &str, client_id: &str, client_secret: Option<&str>, redirect_url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            endpoints: Endpoints::Oidc { issuer: issuer.trim_end_matches('/').to_string() },
+            client_id: client_id.to_string(),
+            client_secret: client_secret.map(str::to_string),
+            redirect_url: redirect_url.to_string(),
+            scopes: vec!["openid".into(), "email".into(), "profile".into()],
+            claims: ClaimMap::default(),
+        }
+    }
+
+    /// A preset (see `PRESETS`) named after itself. `tenant` is the Microsoft
+    /// tenant: its directory id, "organizations" or "common" (the default, any
+    /// work, school or personal account).
+    pub fn preset(preset: &str, tenant: Option<&str>, client_id: &str, client_secret: Option<&str>, redirect_url: &str) -> Result<Self> {
+        let mut config = Self::oidc(preset, GOOGLE_ISSUER, client_id, client_secret, redirect_url);
+        match preset {
+            "google" => config.claims.username = vec!["name".into()],
+            "gitlab" => {
+                config.endpoints = Endpoints::Oidc { issuer: "https://gitlab.com".into() };
+                config.claims.username = vec!["nickname".into(), "preferred_username".into(), "name".into()];
+            }
+            "microsoft" => {
+                let tenant = tenant.unwrap_or("common");
+                config.endpoints = Endpoints::Oidc { issuer: format!("https://login.microsoftonline.com/{}/v2.0", tenant) };
+                // Microsoft doesn't verify the email claim
+                config.claims.email_verified = None;
+            }
+            "github" => {
+                config.endpoints = Endpoints::OAuth2 {
+                    authorize_url: "https://github.com/login/oauth/authorize".into(),
+                    token_url: "https://github.com/login/oauth/access_token".into(),
+                    userinfo_url: "https://api.github.com/user".into(),
+                    emails_url: Some("https://api.github.com/user/emails".into()),
+                };
+                config.scopes = vec!["read:user".into(), "user:email".into()];
+                config.claims = ClaimMap { subject: "id".into(), email: "email".into(), email_verified: None, username: vec!["login".into(), "name".into()] };
+            }
+            other => bail!("unknown OAuth preset {:?} (expected one of {})", other, PRESETS.join(", ")),
+        }
+        Ok(config)
+    }
+}
+
+/// A provider as written in the providers file or assembled from the environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProviderEntry {
+    name: String,
+    preset: Option<String>,
+    tenant: Option<String>,
+    issuer: Option<String>,
+    authorize_url: Option<String>,
+    token_url: Option<String>,
+    userinfo_url: Option<String>,
+    emails_url: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    redirect_url: Option<String>,
+    scopes: Option<Vec<String>>,
+    /// Merged over the preset's (or the standard) mapping.
+    claims: Option<serde_json::Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProvidersFile {
+    providers: Vec<ProviderEntry>,
+}
+
+impl ProviderEntry {
+    fn resolve(self, redirect_base: Option<&str>) -> Result<ProviderConfig> {
+        let name = self.name;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("invalid provider name {:?}", name);
+        }
+        let client_id = self.client_id.ok_or_else(|| anyhow!("{}: client_id is missing", name))?;
+        let redirect_url = match (self.redirect_url, redirect_base) {
+            (Some(url), _) => url,
+            (None, Some(base)) => format!("{}/api/auth/oauth/{}/callback", base.trim_end_matches('/'), name),
+            (None, None) => bail!("{}: no redirect_url (or OAUTH_REDIRECT_BASE)", name),
+        };
+        let mut config = match &self.preset {
+            Some(preset) => ProviderConfig::preset(preset, self.tenant.as_deref(), &client_id, None, &redirect_url)?,
+            None => ProviderConfig::oidc(&name, "", &client_id, None, &redirect_url),
+        };
+        config.name = name.clone();
+        config.client_secret = self.client_secret;
+
+        // Explicit endpoints override the preset's
+        match (self.issuer, self.authorize_url, self.token_url, self.userinfo_url) {
+            (Some(issuer), None, None, None) => config.endpoints = Endpoints::Oidc { issuer: issuer.trim_end_matches('/').to_string() },
+            (None, Some(authorize_url), Some(token_url), Some(userinfo_url)) => {
+                config.endpoints = Endpoints::OAuth2 { authorize_url, token_url, userinfo_url, emails_url: self.emails_url };
+            }
+            (None, None, None, None) if self.preset.is_some() => {}
+            _ => bail!("{}: set either issuer (OpenID Connect) or authorize_url, token_url and userinfo_url (OAuth2)", name),
+        }
+        if let Some(scopes) = self.scopes {
+            config.scopes = scopes;
+        }
+        if let Some(overrides) = self.claims {
+            let Value::Object(mut claims) = serde_json::to_value(&config.claims)? else { unreachable!() };
+            claims.extend(overrides);
+            config.claims = serde_json::from_value(Value::Object(claims)).with_context(|| format!("{}: invalid claims", name))?;
+        }
+        Ok(config)
+    }
+}
+
+/// The configured providers, by name.
+#[derive(Default)]
+pub struct OAuthRegistry {
+    providers: HashMap<String, Arc<OAuthClient>>,
+}
+
+impl OAuthRegistry {
+    pub fn new(configs: Vec<ProviderConfig>) -> Result<Self> {
+        let mut providers = HashMap::new();
+        for config in configs {
+            let name = config.name.clone();
+            if providers.insert(name.clone(), Arc::new(OAuthClient::new(config)?)).is_some() {
+                bail!("OAuth provider {:?} is configured twice", name);
+            }
+        }
+        Ok(Self { providers })
+    }
+
+    /// Providers from a providers file (see the module docs).
+    pub fn from_json(json: &str, redirect_base: Option<&str>) -> Result<Self> {
+        let file: ProvidersFile = serde_json::from_str(json)?;
+        Self::new(file.providers.into_iter().map(|e| e.resolve(redirect_base)).collect::<Result<_>>()?)
+    }
+
+    /// Providers from `OAUTH_PROVIDERS_FILE` and the environment (see the module docs).
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let redirect_base = var("OAUTH_REDIRECT_BASE");
+        let mut entries: Vec<ProviderEntry> = match var("OAUTH_PROVIDERS_FILE") {
+            Some(path) => {
+                let json = std::fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+                serde_json::from_str::<ProvidersFile>(&json).with_context(|| format!("parsing {}", path))?.providers
+            }
+            None => Vec::new(),
+        };
+        let env_key = |name: &str, field: &str| format!("OAUTH_{}_{}", name.to_ascii_uppercase().replace('-', "_"), field);
+        for entry in &mut entries {
+            if entry.client_secret.is_none() {
+                entry.client_secret = var(&env_key(&entry.name, "CLIENT_SECRET"));
+            }
+        }
+        let listed = var("OAUTH_PROVIDERS").unwrap_or_default();
+        let mut names: Vec<&str> = PRESETS.to_vec();
+        names.extend(listed.split(',').map(str::trim).filter(|n| !n.is_empty() && !PRESETS.contains(n)));
+        for name in names {
+            let Some(client_id) = var(&env_key(name, "CLIENT_ID")) else { continue };
+            entries.push(ProviderEntry {
+                name: name.to_string(),
+                preset: PRESETS.contains(&name).then(|| name.to_string()),
+                tenant: (name == "microsoft").then(|| var("OAUTH_MICROSOFT_TENANT")).flatten(),
+                issuer: var(&env_key(name, "ISSUER")),
+                client_id: Some(client_id),
+                client_secret: var(&env_key(name, "CLIENT_SECRET")),
+                redirect_url: var(&env_key(name, "REDIRECT_URL")),
+                ..Default::default()
+            });
+        }
+        let configs = entries.into_iter().map(|e| e.resolve(redirect_base.as_deref())).collect::<Result<Vec<_>>>()?;
+        Self::new(configs)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<OAuthClient>> {
+        self.providers.get(name).cloned()
+    }
+
+    /// Names of the configured providers, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.providers.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_and_overrides() {
+        let registry = OAuthRegistry::from_json(
+            r#"{ "providers": [
+                { "name": "github", "preset": "github", "client_id": "gh" },
+                { "name": "work", "preset": "microsoft", "tenant": "0b9e2a6c-51f4-4f21-9c3e-7d3f1e4b8a10", "client_id": "ms" },
+                { "name": "corp", "issuer": "https://sso.example.com/", "client_id": "c", "redirect_url": "https://app/cb",
+                  "claims": { "username": ["upn"], "email_verified": null } }
+            ] }"#,
+            Some("https://app.example.com/"),
+        )
+        .unwrap();
+        assert_eq!(registry.names(), ["corp", "github", "work"]);
+
+        let github = registry.get("github").unwrap();
+        let github = github.config();
+        assert!(matches!(&github.endpoints, Endpoints::OAuth2 { emails_url: Some(_), .. }));
+        assert_eq!((github.claims.subject.as_str(), github.redirect_url.as_str()), ("id", "https://app.example.com/api/auth/oauth/github/callback"));
+
+        let work = registry.get("work").unwrap();
+        assert_eq!(work.config().endpoints, Endpoints::Oidc { issuer: "https://login.microsoftonline.com/0b9e2a6c-51f4-4f21-9c3e-7d3f1e4b8a10/v2.0".into() });
+
+        let corp = registry.get("corp").unwrap();
+        let corp = corp.config();
+        assert_eq!(corp.endpoints, Endpoints::Oidc { issuer: "https://sso.example.com".into() });
+        assert_eq!((corp.claims.subject.as_str(), corp.claims.email_verified.as_deref(), corp.claims.username.as_slice()), ("sub", None, &["upn".to_string()][..]));
+
+        assert!(OAuthRegistry::from_json(r#"{ "providers": [{ "name": "x", "client_id": "c", "redirect_url": "/cb" }] }"#, None).is_err(), "no endpoints");
+        assert!(OAuthRegistry::from_json(r#"{ "providers": [{ "name": "x", "preset": "okta", "client_id": "c", "redirect_url": "/cb" }] }"#, None).is_err());
+    }
+}
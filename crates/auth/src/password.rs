@@ -0,0 +1,181 @@
+//! Password hashing and the rules new passwords must follow.
+//!
+//! Hashes are Argon2id PHC strings with the cost parameters of the policy.
+//! The parameters are stored in each hash, so old hashes keep verifying when
+//! they are raised; `PasswordPolicy::verify` then reports the hash as
+//! `Outdated` and the caller re-hashes the password it just checked.
+//!
+//! New passwords must be `min_len..=max_len` characters, must not be the
+//! account's email address or username, and must not be on the breached
+//! password list: a local file with one password per line, or SHA-1 hashes in
+//! the Pwned Passwords format (`HEX[:count]`), or a mix.
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    /// Argon2id cost of new hashes.
+    pub argon2: Params,
+    /// SHA-1 of each breached password.
+    breached: HashSet<[u8; 20]>,
+}
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Mismatch,
+    Match,
+    /// Matches, but the hash uses other parameters than the policy: re-hash it.
+    Outdated,
+}
+
+impl Default for PasswordPolicy {
+    /// 8 to 128 characters, Argon2id with 19 MiB, 2 iterations, 1 lane (the
+    /// argon2 crate's defaults), no breached list.
+    fn default() -> Self {
+        Self { min_len: 8, max_len: 128, argon2: Params::default(), breached: HashSet::new() }
+    }
+}
+
+impl PasswordPolicy {
+    /// Defaults overridden by `PASSWORD_MIN_LENGTH`, `PASSWORD_MAX_LENGTH`,
+    /// `PASSWORD_ARGON2_MEMORY_KIB`, `PASSWORD_ARGON2_ITERATIONS`,
+    /// `PASSWORD_ARGON2_PARALLELISM`, plus the list in `PASSWORD_BREACHED_FILE`.
+    pub fn from_env() -> Result<Self> {
+        let num = |name: &str| -> Result<Option<u32>> {
+            match std::env::var(name).ok().filter(|v| !v.trim().is_empty()) {
+                Some(v) => v.trim().parse().map(Some).with_context(|| format!("invalid {}", name)),
+                None => Ok(None),
+            }
+        };
+        let mut policy = Self::default();
+        if let Some(n) = num("PASSWORD_MIN_LENGTH")? { policy.min_len = n as usize; }
+        if let Some(n) = num("PASSWORD_MAX_LENGTH")? { policy.max_len = n as usize; }
+        if policy.min_len == 0 || policy.max_len < policy.min_len {
+            bail!("password length limits {}..={} make no sense", policy.min_len, policy.max_len);
+        }
+        let d = Params::default();
+        policy.argon2 = Params::new(
+            num("PASSWORD_ARGON2_MEMORY_KIB")?.unwrap_or(d.m_cost()),
+            num("PASSWORD_ARGON2_ITERATIONS")?.unwrap_or(d.t_cost()),
+            num("PASSWORD_ARGON2_PARALLELISM")?.unwrap_or(d.p_cost()),
+            None,
+        ).map_err(|e| anyhow!("invalid Argon2 parameters: {}", e))?;
+        if let Some(path) = std::env::var("PASSWORD_BREACHED_FILE").ok().filter(|v| !v.trim().is_empty()) {
+            let text = std::fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+            policy = policy.with_breached_list(&text);
+        }
+        Ok(policy)
+    }
+
+    /// Add the passwords of a breached list (see the module docs for the format).
+    pub fn with_breached_list(mut self, text: &str) -> Self {
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            let entry = line.trim_end_matches('\r');
+            let hex = entry.split_once(':').filter(|(_, n)| n.bytes().all(|b| b.is_ascii_digit())).map_or(entry, |(h, _)| h);
+            let sha1 = match data_encoding::HEXUPPER_PERMISSIVE.decode(hex.as_bytes()) {
+                Ok(bytes) if bytes.len() == 20 => bytes,
+                _ => sha1(entry),
+            };
+            self.breached.insert(sha1.try_into().expect("SHA-1 is 20 bytes"));
+        }
+        self
+    }
+
+    /// Number of passwords on the breached list.
+    pub fn breached_len(&self) -> usize {
+        self.breached.len()
+    }
+
+    /// Check a new password against the policy. `identifiers` are the
+    /// account's email and username. The error message can be shown as is.
+    pub fn check(&self, password: &str, identifiers: &[&str]) -> Result<()> {
+        let len = password.chars().count();
+        if len < self.min_len {
+            bail!("password must be at least {} characters", self.min_len);
+        }
+        if len > self.max_len {
+            bail!("password must be at most {} characters", self.max_len);
+        }
+        let same = |id: &str| !id.is_empty() && password.eq_ignore_ascii_case(id);
+        if identifiers.iter().any(|id| same(id) || id.split_once('@').is_some_and(|(local, _)| same(local))) {
+            bail!("password must not be your email address or username");
+        }
+        let hash: [u8; 20] = sha1(password).try_into().expect("SHA-1 is 20 bytes");
+        if self.breached.contains(&hash) {
+            bail!("this password appears in a list of breached passwords, choose another");
+        }
+        Ok(())
+    }
+
+    /// Argon2id hash of `password` (PHC string) with a random salt. CPU-bound:
+    /// call it off the async runtime.
+    pub fn hash(&self, password: &str) -> Result<String> {
+        let mut salt = [0u8; 16];
+        rand::rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!(e))?;
+        let hash = self.hasher().hash_password(password.as_bytes(), &salt).map_err(|e| anyhow!(e))?;
+        Ok(hash.to_string())
+    }
+
+    /// Check `password` against a stored hash, made with whatever parameters
+    /// it names. CPU-bound like `hash`.
+    pub fn verify(&self, password: &str, stored: &str) -> Result<PasswordCheck> {
+        let parsed = PasswordHash::new(stored).map_err(|e| anyhow!("malformed password hash: {}", e))?;
+        if self.hasher().verify_password(password.as_bytes(), &parsed).is_err() {
+            return Ok(PasswordCheck::Mismatch);
+        }
+        let current = parsed.algorithm == Algorithm::Argon2id.ident()
+            && parsed.version == Some(Version::V0x13.into())
+            && Params::try_from(&parsed).is_ok_and(|p| {
+                (p.m_cost(), p.t_cost(), p.p_cost()) == (self.argon2.m_cost(), self.argon2.t_cost(), self.argon2.p_cost())
+            });
+        Ok(if current { PasswordCheck::Match } else { PasswordCheck::Outdated })
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.argon2.clone())
+    }
+}
+
+fn sha1(s: &str) -> Vec<u8> {
+    digest(&SHA1_FOR_LEGACY_USE_ONLY, s.as_bytes()).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_hashing_and_rehash() {
+        // "password" as SHA-1 in Pwned Passwords format, plus a plain entry
+        let list = "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:9545824\nletmein123\r\n";
+        let policy = PasswordPolicy { argon2: Params::new(64, 1, 1, None).unwrap(), ..PasswordPolicy::default() }.with_breached_list(list);
+        assert_eq!(policy.breached_len(), 2);
+        assert!(policy.check("short", &[]).is_err());
+        assert!(policy.check(&"x".repeat(129), &[]).is_err());
+        assert!(policy.check("letmein123", &[]).is_err());
+        assert!(policy.check("Alice.Smith", &["alice.smith@example.com", "alice"]).is_err());
+        assert!(policy.check("correct horse battery", &["alice.smith@example.com", "alice"]).is_ok());
+        // the list is exact: other casings aren't flagged
+        assert!(PasswordPolicy::default().with_breached_list(list).check("password", &[]).is_err());
+        assert!(PasswordPolicy::default().with_breached_list(list).check("Password", &[]).is_ok());
+
+        let hash = policy.hash("correct horse battery").unwrap();
+        assert_eq!(policy.verify("correct horse battery", &hash).unwrap(), PasswordCheck::Match);
+        assert_eq!(policy.verify("wrong horse battery", &hash).unwrap(), PasswordCheck::Mismatch);
+        // raising the cost keeps old hashes working but flags them
+        let stronger = PasswordPolicy { argon2: Params::new(128, 1, 1, None).unwrap(), ..policy.clone() };
+        assert_eq!(stronger.verify("correct horse battery", &hash).unwrap(), PasswordCheck::Outdated);
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, policy.argon2.clone())
+            .hash_password(b"correct horse battery", &SaltString::encode_b64(&[7u8; 16]).unwrap()).unwrap().to_string();
+        assert_eq!(policy.verify("correct horse battery", &argon2i).unwrap(), PasswordCheck::Outdated);
+        assert!(policy.verify("x", "not a hash").is_err());
+    }
+}
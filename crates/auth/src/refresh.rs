@@ -0,0 +1,177 @@
+//! Sessions: short-lived access tokens with rotating refresh tokens.
+//!
+//! Signing in starts a session (`storage::SessionRecord`). Its access tokens
+//! carry the session id in the `sid` claim and its refresh tokens form a
+//! family: each one is opaque (random, stored hashed) and can be exchanged
+//! exactly once for a new access token and a new refresh token. Presenting a
+//! token that was already exchanged means it leaked, or the client is
+//! replaying it, so the session is revoked and both the thief and the
+//! legitimate client have to sign in again. The exception is a token reused
+//! within `reuse_grace_secs` of its exchange, which is what two tabs
+//! refreshing at once look like; that is refused without revoking anything.
+//!
+//! Revoking a session (sign-out, "sign out other devices", reuse) stops its
+//! refresh tokens at once and its access tokens wherever they are checked
+//! with `verify_session_jwt`.
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use storage::{RefreshTokenRecord, SessionRecord, Storage};
+
+use crate::{create_session_jwt, sha256_hex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenPolicy {
+    pub access_ttl_secs: i64,
+    /// Lifetime of each refresh token; a session stays alive while it keeps rotating.
+    pub refresh_ttl_secs: i64,
+    pub reuse_grace_secs: i64,
+}
+
+impl Default for TokenPolicy {
+    /// 15 minute access tokens, 30 day refresh tokens, 10s grace for concurrent refreshes.
+    fn default() -> Self {
+        Self { access_ttl_secs: 900, refresh_ttl_secs: 30 * 86400, reuse_grace_secs: 10 }
+    }
+}
+
+/// Where a session is used from, shown when listing sessions.
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// An access token and the refresh token to renew it with.
+#[derive(Debug, Clone)]
+pub struct IssuedTokens {
+    pub user_id: String,
+    pub session_id: String,
+    pub access_token: String,
+    pub access_expires_in: i64,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+}
+
+/// Outcome of presenting a refresh token.
+#[derive(Debug)]
+pub enum Refreshed {
+    Issued(IssuedTokens),
+    /// Unknown, expired, or its session was revoked.
+    Invalid,
+    /// Already exchanged moments ago (concurrent refresh); retry with the newer token.
+    AlreadyRotated,
+    /// Already exchanged earlier: the session has been revoked.
+    Reused { user_id: String, session_id: String },
+}
+
+/// Storage key of a refresh token.
+pub fn hash_refresh_token(token: &str) -> String {
+    sha256_hex(token)
+}
+
+/// Start a session for `user_id` on `device` (a sign-in) and issue its first tokens.
+pub fn issue_tokens(storage: &Storage, policy: &TokenPolicy, user_id: &str, device: &Device, now: i64) -> Result<IssuedTokens> {
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let session = SessionRecord {
+        user_id: user_id.to_string(),
+        created_at: now,
+        last_used_at: now,
+        expires_at: now + policy.refresh_ttl_secs,
+        user_agent: device.user_agent.clone(),
+        ip: device.ip.clone(),
+        revoked_at: None,
+        revoked_reason: None,
+    };
+    storage.put_session(&session_id, &session)?;
+    mint(storage, policy, user_id, &session_id, now)
+}
+
+/// Exchange `token` for new tokens, rotating it within its session.
+pub fn refresh_tokens(storage: &Storage, policy: &TokenPolicy, token: &str, device: &Device, now: i64) -> Result<Refreshed> {
+    let Some(rec) = storage.use_refresh_token(&hash_refresh_token(token), now)? else { return Ok(Refreshed::Invalid) };
+    let Some(session) = storage.get_session(&rec.session_id)?.filter(|s| s.revoked_at.is_none()) else {
+        return Ok(Refreshed::Invalid);
+    };
+    if let Some(used_at) = rec.used_at {
+        if now - used_at <= policy.reuse_grace_secs {
+            return Ok(Refreshed::AlreadyRotated);
+        }
+        storage.revoke_session(&rec.session_id, "reuse_detected", now)?;
+        return Ok(Refreshed::Reused { user_id: rec.user_id, session_id: rec.session_id });
+    }
+    if rec.expires_at <= now {
+        return Ok(Refreshed::Invalid);
+    }
+    let issued = mint(storage, policy, &rec.user_id, &rec.session_id, now)?;
+    let session = SessionRecord {
+        last_used_at: now,
+        expires_at: issued.refresh_expires_at,
+        user_agent: device.user_agent.clone().or(session.user_agent),
+        ip: device.ip.clone().or(session.ip),
+        ..session
+    };
+    storage.put_session(&rec.session_id, &session)?;
+    Ok(Refreshed::Issued(issued))
+}
+
+/// Revoke the session of `token` (sign-out). Returns the session's user if it was live.
+pub fn revoke_refresh_token(storage: &Storage, token: &str, reason: &str, now: i64) -> Result<Option<String>> {
+    let Some(rec) = storage.get_refresh_token(&hash_refresh_token(token))? else { return Ok(None) };
+    Ok(storage.revoke_session(&rec.session_id, reason, now)?.then_some(rec.user_id))
+}
+
+fn mint(storage: &Storage, policy: &TokenPolicy, user_id: &str, session_id: &str, now: i64) -> Result<IssuedTokens> {
+    let ttl = usize::try_from(policy.access_ttl_secs).map_err(|_| anyhow!("invalid access token ttl"))?;
+    let access_token = create_session_jwt(user_id, session_id, ttl)?;
+    let mut raw = [0u8; 32];
+    rand::rng().fill_bytes(&mut raw);
+    let refresh_token = URL_SAFE_NO_PAD.encode(raw);
+    let rec = RefreshTokenRecord {
+        user_id: user_id.to_string(),
+        session_id: session_id.to_string(),
+        issued_at: now,
+        expires_at: now + policy.refresh_ttl_secs,
+        used_at: None,
+    };
+    storage.create_refresh_token(&hash_refresh_token(&refresh_token), &rec)?;
+    Ok(IssuedTokens {
+        user_id: user_id.to_string(),
+        session_id: session_id.to_string(),
+        access_token,
+        access_expires_in: policy.access_ttl_secs,
+        refresh_token,
+        refresh_expires_at: rec.expires_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{install_keyring, verify_session_jwt, Keyring, SigningKey};
+
+    #[test]
+    fn rotation_and_reuse_revokes_session() {
+        let _ = install_keyring(Keyring::new(vec![SigningKey::generate("test").unwrap()], None).unwrap());
+        let (_dir, storage) = crate::test_storage();
+        let policy = TokenPolicy { access_ttl_secs: 60, refresh_ttl_secs: 100, reuse_grace_secs: 5 };
+        let device = Device::default();
+
+        let first = issue_tokens(&storage, &policy, "u1", &device, 1000).unwrap();
+        let Refreshed::Issued(second) = refresh_tokens(&storage, &policy, &first.refresh_token, &device, 1010).unwrap() else { panic!("not rotated") };
+        assert_eq!(second.session_id, first.session_id);
+        // a second tab racing the first gets told to retry, nothing is revoked
+        assert!(matches!(refresh_tokens(&storage, &policy, &first.refresh_token, &device, 1012).unwrap(), Refreshed::AlreadyRotated));
+        assert!(verify_session_jwt(&storage, &second.access_token).is_ok());
+
+        // replaying the old token later kills the session, including the newest tokens
+        assert!(matches!(refresh_tokens(&storage, &policy, &first.refresh_token, &device, 1030).unwrap(), Refreshed::Reused { .. }));
+        assert!(matches!(refresh_tokens(&storage, &policy, &second.refresh_token, &device, 1031).unwrap(), Refreshed::Invalid));
+        assert!(verify_session_jwt(&storage, &second.access_token).is_err());
+
+        let other = issue_tokens(&storage, &policy, "u1", &device, 1040).unwrap();
+        assert!(matches!(refresh_tokens(&storage, &policy, &other.refresh_token, &device, 1140).unwrap(), Refreshed::Invalid), "expired");
+        assert_eq!(storage.purge_refresh_tokens(1200).unwrap(), 3);
+    }
+}
@@ -0,0 +1,340 @@
+//! TOTP two-factor authentication (RFC 6238) with one-time recovery codes.
+//!
+//! An account's second factor lives in its credentials record under `totp`
+//! (`TotpRecord`): the shared secret, when enrolment was confirmed, the last
+//! time step a code was accepted for, and SHA-256 hashes of the unused
+//! recovery codes.
+//!
+//! Enrolment takes two steps so a mistyped secret can't lock anyone out:
+//! `begin_totp_enrollment` stores a pending secret (shown to the user, or as
+//! an `otpauth://` URI for authenticator apps), and `confirm_totp_enrollment`
+//! turns it on once a code from the app checks out. Confirming returns the
+//! recovery codes; they are only ever shown then.
+//!
+//! Codes are accepted one step either side of the current one (clock skew),
+//! and only for a step later than the last accepted one, so a code can't be
+//! used twice, not even within its 30 seconds.
+//!
+//! A password sign-in on an account with TOTP gets an MFA challenge instead
+//! of a session: an opaque token (stored hashed) that `verify_mfa_challenge`
+//! exchanges, together with a code, for the user to sign in. It expires after
+//! `MFA_CHALLENGE_TTL_SECS` or `MFA_CHALLENGE_ATTEMPTS` wrong codes.
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use data_encoding::BASE32_NOPAD;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use storage::Storage;
+
+use crate::sha256_hex;
+
+pub const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Steps accepted either side of the current one.
+const TOTP_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+pub const MFA_CHALLENGE_TTL_SECS: i64 = 300;
+pub const MFA_CHALLENGE_ATTEMPTS: u32 = 5;
+
+/// TOTP state of an account, stored in its credentials record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpRecord {
+    /// Shared secret, base32 as in the provisioning URI.
+    pub secret: String,
+    pub created_at: i64,
+    /// When enrolment was confirmed; `None` while it is pending.
+    #[serde(default)]
+    pub enabled_at: Option<i64>,
+    /// Last time step a code was accepted for.
+    #[serde(default)]
+    pub last_step: i64,
+    /// SHA-256 (hex) of each unused recovery code.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
+/// How the second factor was proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode,
+}
+
+/// Outcome of answering an MFA challenge.
+#[derive(Debug)]
+pub enum MfaOutcome {
+    /// Code accepted and challenge used up: sign `user_id` in.
+    Passed { user_id: String, email: String, factor: SecondFactor, recovery_codes_left: usize },
+    /// Wrong code; the challenge takes `attempts_left` more.
+    Failed { email: String, attempts_left: u32 },
+    /// Unknown, expired or out of attempts: sign in with the password again.
+    Expired,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MfaChallenge {
+    user_id: String,
+    email: String,
+    attempts: u32,
+    expires_at: i64,
+}
+
+/// TOTP state of a credentials record, pending or enabled.
+pub fn totp_record(cred: &Value) -> Option<TotpRecord> {
+    cred.get("totp").and_then(|v| serde_json::from_value(v.clone()).ok())
+}
+
+/// True when the credentials record has TOTP turned on.
+pub fn totp_enabled(cred: &Value) -> bool {
+    totp_record(cred).is_some_and(|t| t.enabled_at.is_some())
+}
+
+/// `otpauth://` URI for authenticator apps (usually shown as a QR code).
+pub fn totp_provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let enc = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        enc(issuer), enc(account), secret, enc(issuer), TOTP_DIGITS, TOTP_STEP_SECS
+    )
+}
+
+/// The code for time step `step`: HOTP (RFC 4226) over the step counter.
+pub fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let mac = tag.as_ref();
+    let offset = usize::from(mac[mac.len() - 1] & 0x0f);
+    let bin = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+    bin % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Start (or restart) enrolment for `email` and return the new base32 secret.
+/// Fails if TOTP is already on.
+pub fn begin_totp_enrollment(storage: &Storage, email: &str, now: i64) -> Result<String> {
+    let mut raw = [0u8; 20];
+    rand::rng().fill_bytes(&mut raw);
+    let secret = BASE32_NOPAD.encode(&raw);
+    update(storage, email, |cred| {
+        if totp_enabled(cred) {
+            return Err(anyhow!("two-factor authentication is already enabled"));
+        }
+        let rec = TotpRecord { secret: secret.clone(), created_at: now, enabled_at: None, last_step: 0, recovery_codes: Vec::new() };
+        store(cred, Some(&rec))?;
+        Ok(secret)
+    })
+}
+
+/// Turn TOTP on with a code from the authenticator app. Returns the recovery
+/// codes, or `None` if the code is wrong.
+pub fn confirm_totp_enrollment(storage: &Storage, email: &str, code: &str, now: i64) -> Result<Option<Vec<String>>> {
+    update(storage, email, |cred| {
+        let mut rec = totp_record(cred)
+            .filter(|t| t.enabled_at.is_none())
+            .ok_or_else(|| anyhow!("no two-factor enrolment in progress"))?;
+        let Some(step) = matching_step(&rec, code, now)? else { return Ok(None) };
+        let codes = new_recovery_codes();
+        rec.enabled_at = Some(now);
+        rec.last_step = step;
+        rec.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        store(cred, Some(&rec))?;
+        Ok(Some(codes))
+    })
+}
+
+/// Check a TOTP code or a recovery code for `email` and use it up. Returns
+/// how it was proven and the recovery codes left, or `None` if it doesn't match.
+pub fn verify_second_factor(storage: &Storage, email: &str, code: &str, now: i64) -> Result<Option<(SecondFactor, usize)>> {
+    update(storage, email, |cred| {
+        let mut rec = enabled(cred)?;
+        let factor = match matching_step(&rec, code, now)? {
+            Some(step) => {
+                rec.last_step = step;
+                SecondFactor::Totp
+            }
+            None => {
+                let hash = hash_recovery_code(code);
+                let Some(i) = rec.recovery_codes.iter().position(|h| *h == hash) else { return Ok(None) };
+                rec.recovery_codes.remove(i);
+                SecondFactor::RecoveryCode
+            }
+        };
+        store(cred, Some(&rec))?;
+        Ok(Some((factor, rec.recovery_codes.len())))
+    })
+}
+
+/// Replace the recovery codes of `email` with a new set and return it.
+pub fn regenerate_recovery_codes(storage: &Storage, email: &str) -> Result<Vec<String>> {
+    update(storage, email, |cred| {
+        let mut rec = enabled(cred)?;
+        let codes = new_recovery_codes();
+        rec.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        store(cred, Some(&rec))?;
+        Ok(codes)
+    })
+}
+
+/// Turn TOTP off (or drop a pending enrolment). Returns whether it was on.
+pub fn disable_totp(storage: &Storage, email: &str) -> Result<bool> {
+    update(storage, email, |cred| {
+        let was_enabled = totp_enabled(cred);
+        store(cred, None)?;
+        Ok(was_enabled)
+    })
+}
+
+/// Challenge for a password sign-in on `user_id` that still needs its second
+/// factor. Returns the token to answer it with.
+pub fn create_mfa_challenge(storage: &Storage, user_id: &str, email: &str, now: i64) -> Result<String> {
+    let mut raw = [0u8; 32];
+    rand::rng().fill_bytes(&mut raw);
+    let token = URL_SAFE_NO_PAD.encode(raw);
+    let challenge = MfaChallenge { user_id: user_id.to_string(), email: email.to_string(), attempts: 0, expires_at: now + MFA_CHALLENGE_TTL_SECS };
+    storage.put_mfa_challenge(&sha256_hex(&token), &serde_json::to_value(&challenge)?)?;
+    Ok(token)
+}
+
+/// Email of the account the open challenge `token` is for, to check that
+/// account's sign-in lockout before answering it.
+pub fn mfa_challenge_email(storage: &Storage, token: &str, now: i64) -> Result<Option<String>> {
+    let Some(v) = storage.get_mfa_challenge(&sha256_hex(token))? else { return Ok(None) };
+    Ok(serde_json::from_value::<MfaChallenge>(v).ok().filter(|c| c.expires_at > now && c.attempts < MFA_CHALLENGE_ATTEMPTS).map(|c| c.email))
+}
+
+/// Answer the challenge `token` with a TOTP or recovery `code`.
+pub fn verify_mfa_challenge(storage: &Storage, token: &str, code: &str, now: i64) -> Result<MfaOutcome> {
+    let key = sha256_hex(token);
+    // Use up an attempt before looking at the code, in the transaction that
+    // checks one is left, so parallel guesses can't share an attempt
+    let challenge = storage.update_mfa_challenge(&key, |v| {
+        let open = serde_json::from_value::<MfaChallenge>(v.clone()).ok().filter(|c| c.expires_at > now && c.attempts < MFA_CHALLENGE_ATTEMPTS);
+        let Some(mut challenge) = open else { return Ok(None) };
+        challenge.attempts += 1;
+        *v = serde_json::to_value(&challenge)?;
+        Ok(Some(challenge))
+    })?;
+    let Some(challenge) = challenge.flatten() else { return Ok(MfaOutcome::Expired) };
+    match verify_second_factor(storage, &challenge.email, code, now)? {
+        Some((factor, recovery_codes_left)) => {
+            storage.delete_mfa_challenge(&key)?;
+            Ok(MfaOutcome::Passed { user_id: challenge.user_id, email: challenge.email, factor, recovery_codes_left })
+        }
+        None => {
+            if challenge.attempts >= MFA_CHALLENGE_ATTEMPTS {
+                storage.delete_mfa_challenge(&key)?;
+            }
+            Ok(MfaOutcome::Failed { email: challenge.email, attempts_left: MFA_CHALLENGE_ATTEMPTS - challenge.attempts })
+        }
+    }
+}
+
+fn update<T>(storage: &Storage, email: &str, f: impl FnOnce(&mut Value) -> Result<T>) -> Result<T> {
+    storage.update_credentials(email, f)?.ok_or_else(|| anyhow!("no password credentials for {}", email))
+}
+
+fn enabled(cred: &Value) -> Result<TotpRecord> {
+    totp_record(cred).filter(|t| t.enabled_at.is_some()).ok_or_else(|| anyhow!("two-factor authentication is not enabled"))
+}
+
+fn store(cred: &mut Value, rec: Option<&TotpRecord>) -> Result<()> {
+    let obj = cred.as_object_mut().ok_or_else(|| anyhow!("malformed credentials"))?;
+    match rec {
+        Some(rec) => obj.insert("totp".to_string(), serde_json::to_value(rec)?),
+        None => obj.remove("totp"),
+    };
+    Ok(())
+}
+
+/// Time step `code` is valid for: within the skew window and after the last used one.
+fn matching_step(rec: &TotpRecord, code: &str, now: i64) -> Result<Option<i64>> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let code: u32 = code.parse()?;
+    let secret = BASE32_NOPAD.decode(rec.secret.as_bytes())?;
+    let current = now.div_euclid(TOTP_STEP_SECS);
+    Ok((current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS).find(|&step| step > rec.last_step && totp_code(&secret, step) == code))
+}
+
+/// Ten codes like `k3j9x-q2m7a` (50 random bits each).
+fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut raw = [0u8; 7];
+            rand::rng().fill_bytes(&mut raw);
+            let s = BASE32_NOPAD.encode(&raw).to_lowercase();
+            format!("{}-{}", &s[..5], &s[5..10])
+        })
+        .collect()
+}
+
+/// Case, dashes and spaces don't matter when typing a recovery code back.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect();
+    sha256_hex(&normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / TOTP_STEP_SECS), 287082);
+        assert_eq!(totp_code(secret, 1111111109 / TOTP_STEP_SECS), 81804);
+        assert_eq!(totp_code(secret, 2000000000 / TOTP_STEP_SECS), 279037);
+    }
+
+    #[test]
+    fn enrolment_replay_and_recovery() {
+        let (_dir, storage) = crate::test_storage();
+        storage.put_credentials("a@example.com", &serde_json::json!({ "user_id": "u1", "password_hash": "x" })).unwrap();
+        let code_at = |secret: &str, now: i64| format!("{:06}", totp_code(&BASE32_NOPAD.decode(secret.as_bytes()).unwrap(), now / TOTP_STEP_SECS));
+
+        let secret = begin_totp_enrollment(&storage, "a@example.com", 1000).unwrap();
+        assert!(!totp_enabled(&storage.get_credentials("a@example.com").unwrap().unwrap()));
+        assert!(confirm_totp_enrollment(&storage, "a@example.com", "000000x", 1000).unwrap().is_none());
+        let codes = confirm_totp_enrollment(&storage, "a@example.com", &code_at(&secret, 1000), 1000).unwrap().unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(begin_totp_enrollment(&storage, "a@example.com", 1001).is_err(), "already enabled");
+
+        // the enrolment code can't be used again, a code from the next step (clock skew) can, once
+        assert!(verify_second_factor(&storage, "a@example.com", &code_at(&secret, 1000), 1005).unwrap().is_none());
+        assert_eq!(verify_second_factor(&storage, "a@example.com", &code_at(&secret, 1030), 1005).unwrap(), Some((SecondFactor::Totp, 10)));
+        assert!(verify_second_factor(&storage, "a@example.com", &code_at(&secret, 1030), 1031).unwrap().is_none());
+        assert!(verify_second_factor(&storage, "a@example.com", &code_at(&secret, 1200), 1100).unwrap().is_none(), "outside the window");
+
+        // challenges: recovery codes work once, wrong codes use up attempts
+        let token = create_mfa_challenge(&storage, "u1", "a@example.com", 2000).unwrap();
+        let upper = codes[0].to_uppercase().replace('-', " ");
+        assert!(matches!(verify_mfa_challenge(&storage, &token, &upper, 2001).unwrap(),
+            MfaOutcome::Passed { factor: SecondFactor::RecoveryCode, recovery_codes_left: 9, .. }));
+        assert!(matches!(verify_mfa_challenge(&storage, &token, &codes[1], 2002).unwrap(), MfaOutcome::Expired), "challenge is single use");
+        let token = create_mfa_challenge(&storage, "u1", "a@example.com", 2000).unwrap();
+        for left in (0..MFA_CHALLENGE_ATTEMPTS).rev() {
+            assert!(matches!(verify_mfa_challenge(&storage, &token, &codes[0], 2001).unwrap(), MfaOutcome::Failed { attempts_left, .. } if attempts_left == left));
+        }
+        assert!(matches!(verify_mfa_challenge(&storage, &token, &codes[1], 2002).unwrap(), MfaOutcome::Expired));
+        let token = create_mfa_challenge(&storage, "u1", "a@example.com", 2000).unwrap();
+        assert_eq!(mfa_challenge_email(&storage, &token, 2001).unwrap().as_deref(), Some("a@example.com"));
+        assert!(mfa_challenge_email(&storage, &token, 2000 + MFA_CHALLENGE_TTL_SECS).unwrap().is_none());
+        assert!(matches!(verify_mfa_challenge(&storage, &token, &codes[1], 2000 + MFA_CHALLENGE_TTL_SECS).unwrap(), MfaOutcome::Expired));
+
+        // parallel guesses get no more attempts than sequential ones
+        let token = create_mfa_challenge(&storage, "u1", "a@example.com", 2000).unwrap();
+        let failed = std::thread::scope(|scope| {
+            let guesses: Vec<_> = (0..3 * MFA_CHALLENGE_ATTEMPTS).map(|_| scope.spawn(|| verify_mfa_challenge(&storage, &token, &codes[0], 2001).unwrap())).collect();
+            guesses.into_iter().map(|g| g.join().unwrap()).filter(|o| matches!(o, MfaOutcome::Failed { .. })).count()
+        });
+        assert_eq!(failed, MFA_CHALLENGE_ATTEMPTS as usize);
+
+        assert!(disable_totp(&storage, "a@example.com").unwrap());
+        assert!(totp_record(&storage.get_credentials("a@example.com").unwrap().unwrap()).is_none());
+    }
+}
@@ -1,252 +1,338 @@
-#![allow(clippy::unused_unit)]
-#![cfg(feature = "with-webauthn")]
-
-use anyhow::{Context, Result};
+//! Passkeys (WebAuthn), verified with webauthn-rs.
+//!
+//! Registration and authentication are two-step ceremonies. `begin_*`
+//! returns the options for `navigator.credentials.create()` / `.get()` and
+//! keeps the ceremony state (challenge, allowed credentials) server-side in
+//! storage under a random id; `finish_*` consumes that state, so each
+//! challenge can be answered once, and verifies the browser's response:
+//! challenge, origin, RP id, user verification, signature, and for
+//! authentication the signature counter, which must increase for
+//! authenticators that keep one (a cloned key shows up as a counter that
+//! didn't).
+//!
+//! Credentials are stored per user under `<user_id>/<cred_id>` (cred_id in
+//! base64url) as a `PasskeyRecord` with a nickname and last-use time. The
+//! WebAuthn user handle is the user id, which must be a UUID; that is what
+//! lets a discoverable passkey name its user when sign-in starts without a
+//! username. Registration asks for discoverable credentials, which platform
+//! authenticators and password managers create; security keys may decline,
+//! and then need the username to sign in.
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine as _;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use uuid::Uuid;
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, DiscoverableKey, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, Url,
+    Webauthn, WebauthnBuilder,
+};
 
 use storage::Storage;
 
-/// Begin a WebAuthn registration for `user_id`.
-/// Returns (reg_id, public_options_json) where `public_options_json` contains
-/// a PublicKeyCredentialCreationOptions-like structure the frontend can use.
-///
-/// This implementation intentionally keeps server-side verification out of scope
-/// for Phase A: it generates a challenge, stores a minimal pending state, and
-/// returns public options as JSON. In Phase B we will replace this with a full
-/// webauthn-rs-backed implementation that validates attestation and counters.
-pub fn begin_registration(
-    storage: &Storage,
-    user_id: &str,
-    display_name: &str,
-    rp_id: &str,
-    rp_name: &str,
-    origin: &str,
-) -> Result<(String, Value)> {
-    let reg_id = Uuid::new_v4().to_string();
-    let challenge_raw = Uuid::new_v4().as_bytes().to_vec();
-    let challenge_b64 = URL_SAFE_NO_PAD.encode(&challenge_raw);
-
-    // Minimal publicKey options structure suitable for most client libraries.
-    let public_key = json!({
-        "challenge": challenge_b64,
-        "rp": {
-            "name": rp_name,
-            "id": rp_id
-        },
-        "user": {
-            // WebAuthn requires user.id as bytes; encode user_id as base64 for demo purposes.
-            "id": URL_SAFE_NO_PAD.encode(user_id.as_bytes()),
-            "name": user_id,
-            "displayName": display_name
-        },
-        "pubKeyCredParams": [
-            { "type": "public-key", "alg": -7 },   // ES256
-            { "type": "public-key", "alg": -257 }  // RS256
-        ],
-        "timeout": 60000,
-        "attestation": "none"
-    });
-
-    // Persist minimal server-side state used to validate the subsequent finish_registration call.
-    let state_json = json!({
-        "reg_id": reg_id,
-        "challenge": challenge_b64,
-        "rp_id": rp_id,
-        "rp_name": rp_name,
-        "origin": origin,
-        "user_id": user_id,
-        "display_name": display_name
-    });
-
-    storage.put_webauthn_reg_state(user_id, &reg_id, &state_json)
-        .with_context(|| "storing webauthn registration state")?;
-
-    Ok((reg_id, json!({ "publicKey": public_key })))
+/// How long a ceremony may take between `begin_*` and `finish_*`.
+pub const CEREMONY_TTL_SECS: i64 = 300;
+/// Auth states aren't tied to a user until the passkey names one, so they
+/// share one storage partition.
+const LOGIN_PARTITION: &str = "login";
+
+/// A registered passkey.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasskeyRecord {
+    pub passkey: Passkey,
+    /// Label shown to the user, e.g. "MacBook".
+    pub name: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+pub struct Passkeys {
+    webauthn: Webauthn,
 }
 
-/// Finish registration: store the credential returned by the client and remove the pending state.
-/// For Phase A we accept the client_provided_cred as-is and store it under a generated or provided cred id.
-pub fn finish_registration(
-    storage: &Storage,
-    user_id: &str,
-    reg_id: &str,
-    client_provided_cred: &Value,
-) -> Result<String> {
-    // Read pending state (optional sanity check)
-    let pending = storage.get_webauthn_reg_state(user_id, reg_id)?;
-    if pending.is_none() {
-        anyhow::bail!("no pending registration state found for user_id={} reg_id={}", user_id, reg_id);
+impl Passkeys {
+    /// Relying party `rp_id` (the site's domain) accepting ceremonies from
+    /// `origins`, which must be on that domain.
+    pub fn new(rp_id: &str, rp_name: &str, origins: &[Url], any_port: bool) -> Result<Self> {
+        let (first, rest) = origins.split_first().ok_or_else(|| anyhow!("no WebAuthn origins"))?;
+        let mut builder = WebauthnBuilder::new(rp_id, first)
+            .with_context(|| format!("origin {} is not on RP id {:?}", first, rp_id))?
+            .rp_name(rp_name)
+            .allow_any_port(any_port)
+            .timeout(Duration::from_secs(CEREMONY_TTL_SECS as u64));
+        for origin in rest {
+            builder = builder.append_allowed_origin(origin);
+        }
+        Ok(Self { webauthn: builder.build()? })
+    }
+
+    /// Relying party from `WEBAUTHN_RP_ID`, `WEBAUTHN_ORIGINS` (comma separated)
+    /// and `WEBAUTHN_RP_NAME`. Unset, dev mode uses `localhost` and the dev
+    /// site's origins on any port; otherwise passkeys are disabled (`None`).
+    pub fn from_env() -> Result<Option<Self>> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let rp_name = var("WEBAUTHN_RP_NAME").unwrap_or_else(|| "Stack".to_string());
+        let Some(rp_id) = var("WEBAUTHN_RP_ID") else {
+            if !crate::dev_mode() {
+                return Ok(None);
+            }
+            let origins = [Url::parse("https://localhost:5173")?, Url::parse("http://localhost:5173")?];
+            return Self::new("localhost", &rp_name, &origins, true).map(Some);
+        };
+        let origins = match var("WEBAUTHN_ORIGINS") {
+            Some(list) => list.split(',').map(|o| Url::parse(o.trim()).with_context(|| format!("invalid WebAuthn origin {:?}", o))).collect::<Result<Vec<_>>>()?,
+            None => vec![Url::parse(&format!("https://{}", rp_id))?],
+        };
+        Self::new(&rp_id, &rp_name, &origins, false).map(Some)
     }
 
-    // Extract an id from the client credential if present, else generate one.
-    let cred_id = client_provided_cred
-        .get("id")
-        .and_then(|v| v.as_str())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    /// Begin registering a passkey for `user_id`. Returns the ceremony id and
+    /// the creation options (`{ "publicKey": ... }`).
+    pub fn begin_registration(&self, storage: &Storage, user_id: &str, user_name: &str, display_name: &str, now: i64) -> Result<(String, Value)> {
+        let handle = user_handle(user_id)?;
+        let existing = list_passkeys(storage, user_id)?.into_iter().map(|(_, rec)| rec.passkey.cred_id().clone()).collect::<Vec<_>>();
+        let (options, state) = self.webauthn.start_passkey_registration(handle, user_name, display_name, Some(existing))?;
+        let reg_id = Uuid::new_v4().to_string();
+        storage.put_webauthn_reg_state(user_id, &reg_id, &json!({ "state": state, "expires_at": now + CEREMONY_TTL_SECS }))?;
+        let mut options = serde_json::to_value(options)?;
+        // webauthn-rs discourages resident keys; ask for one so username-less sign-in works
+        options["publicKey"]["authenticatorSelection"]["residentKey"] = json!("preferred");
+        Ok((reg_id, options))
+    }
 
-    // Persist credential JSON under "<user_id>/<cred_id>"
-    storage.put_webauthn_cred(user_id, &cred_id, client_provided_cred)
-        .with_context(|| "storing webauthn credential")?;
+    /// Verify the browser's response to registration `reg_id` and store the
+    /// passkey. Returns its credential id.
+    pub fn finish_registration(&self, storage: &Storage, user_id: &str, reg_id: &str, credential: &Value, name: Option<&str>, now: i64) -> Result<String> {
+        let saved = storage.get_webauthn_reg_state(user_id, reg_id)?.ok_or_else(|| anyhow!("unknown or finished registration"))?;
+        storage.delete_webauthn_reg_state(user_id, reg_id)?;
+        let state: PasskeyRegistration = live_state(saved, now)?;
+        let credential: RegisterPublicKeyCredential = serde_json::from_value(credential.clone()).context("malformed credential")?;
+        let passkey = self.webauthn.finish_passkey_registration(&credential, &state)?;
+
+        let cred_id = encode_cred_id(passkey.cred_id());
+        let name = name.map(str::trim).filter(|n| !n.is_empty()).unwrap_or("Passkey").chars().take(64).collect();
+        let record = PasskeyRecord { passkey, name, created_at: now, last_used_at: None };
+        storage.put_webauthn_cred(user_id, &cred_id, &serde_json::to_value(&record)?)?;
+        Ok(cred_id)
+    }
 
-    // Remove pending registration state
-    storage.delete_webauthn_reg_state(user_id, reg_id)?;
+    /// Begin a sign-in. With `user_id` the user's passkeys are offered in
+    /// `allowCredentials`, which shows whether the account has passkeys, so
+    /// only pass it for a user who is already known to the caller (e.g.
+    /// signed in). Without it, or if the user has none, any discoverable
+    /// passkey may answer. Returns the ceremony id and the request options
+    /// (`{ "publicKey": ... }`).
+    pub fn begin_authentication(&self, storage: &Storage, user_id: Option<&str>, now: i64) -> Result<(String, Value)> {
+        let passkeys: Vec<Passkey> = match user_id {
+            Some(user_id) => list_passkeys(storage, user_id)?.into_iter().map(|(_, rec)| rec.passkey).collect(),
+            None => Vec::new(),
+        };
+        let (options, state) = if passkeys.is_empty() {
+            let (options, state) = self.webauthn.start_discoverable_authentication()?;
+            (options, json!({ "discoverable": state }))
+        } else {
+            let (options, state) = self.webauthn.start_passkey_authentication(&passkeys)?;
+            (options, json!({ "user_id": user_id, "state": state }))
+        };
+        let auth_id = Uuid::new_v4().to_string();
+        let mut saved = state;
+        saved["expires_at"] = json!(now + CEREMONY_TTL_SECS);
+        storage.put_webauthn_auth_state(LOGIN_PARTITION, &auth_id, &saved)?;
+        Ok((auth_id, serde_json::to_value(options)?))
+    }
 
-    Ok(cred_id)
+    /// Verify the browser's assertion for sign-in `auth_id`, record the
+    /// passkey's new counter and use, and return the signed-in user id.
+    pub fn finish_authentication(&self, storage: &Storage, auth_id: &str, credential: &Value, now: i64) -> Result<String> {
+        let saved = storage.get_webauthn_auth_state(LOGIN_PARTITION, auth_id)?.ok_or_else(|| anyhow!("unknown or finished sign-in"))?;
+        storage.delete_webauthn_auth_state(LOGIN_PARTITION, auth_id)?;
+        if saved.get("expires_at").and_then(|v| v.as_i64()).is_none_or(|at| at <= now) {
+            bail!("sign-in took too long, start again");
+        }
+        let credential: PublicKeyCredential = serde_json::from_value(credential.clone()).context("malformed credential")?;
+
+        let (user_id, mut records, result) = match saved.get("discoverable") {
+            Some(state) => {
+                let state: DiscoverableAuthentication = serde_json::from_value(state.clone())?;
+                let (handle, _) = self.webauthn.identify_discoverable_authentication(&credential)?;
+                let user_id = handle.to_string();
+                let records = list_passkeys(storage, &user_id)?;
+                let keys: Vec<DiscoverableKey> = records.iter().map(|(_, rec)| DiscoverableKey::from(&rec.passkey)).collect();
+                let result = self.webauthn.finish_discoverable_authentication(&credential, state, &keys)?;
+                (user_id, records, result)
+            }
+            None => {
+                let user_id = saved.get("user_id").and_then(|v| v.as_str()).ok_or_else(|| anyhow!("malformed sign-in state"))?.to_string();
+                let state: PasskeyAuthentication = serde_json::from_value(saved.get("state").cloned().unwrap_or_default())?;
+                let result = self.webauthn.finish_passkey_authentication(&credential, &state)?;
+                (user_id.clone(), list_passkeys(storage, &user_id)?, result)
+            }
+        };
+
+        let (cred_id, record) = records
+            .iter_mut()
+            .find(|(_, rec)| rec.passkey.cred_id() == result.cred_id())
+            .ok_or_else(|| anyhow!("passkey was removed"))?;
+        record.passkey.update_credential(&result);
+        record.last_used_at = Some(now);
+        storage.put_webauthn_cred(&user_id, cred_id, &serde_json::to_value(&*record)?)?;
+        Ok(user_id)
+    }
 }
 
-/// Begin an authentication ceremony (assertion request).
-/// Returns (auth_id, public_options_json).
-pub fn begin_authentication(
-    storage: &Storage,
-    user_id: &str,
-    rp_id: &str,
-    origin: &str,
-) -> Result<(String, Value)> {
-    let auth_id = Uuid::new_v4().to_string();
-    let challenge_raw = Uuid::new_v4().as_bytes().to_vec();
-    let challenge_b64 = URL_SAFE_NO_PAD.encode(&challenge_raw);
-
-    // Ideally we'd enumerate stored credentials for the user and fill allowCredentials.
-    // Storage currently exposes get_webauthn_cred(user, cred_id) but not a listing API,
-    // so for Phase A we return empty allowCredentials (letting the client pick).
-    let public_key = json!({
-        "challenge": challenge_b64,
-        "rpId": rp_id,
-        "timeout": 60000,
-        "allowCredentials": [],
-        "userVerification": "preferred"
-    });
-
-    let state_json = json!({
-        "auth_id": auth_id,
-        "challenge": challenge_b64,
-        "rp_id": rp_id,
-        "origin": origin,
-        "user_id": user_id
-    });
-
-    storage.put_webauthn_auth_state(user_id, &auth_id, &state_json)
-        .with_context(|| "storing webauthn auth state")?;
-
-    Ok((auth_id, json!({ "publicKey": public_key })))
+/// A user's passkeys as `(cred_id, record)`. Entries that don't parse (stored
+/// by an older version) are skipped.
+pub fn list_passkeys(storage: &Storage, user_id: &str) -> Result<Vec<(String, PasskeyRecord)>> {
+    Ok(storage
+        .list_webauthn_creds(user_id)?
+        .into_iter()
+        .filter_map(|(id, v)| serde_json::from_value(v).ok().map(|rec| (id, rec)))
+        .collect())
 }
 
-/// Finish authentication: validate the client assertion (lightweight for Phase A) and remove the pending state.
-/// This Phase-A implementation does not perform cryptographic verification; it verifies presence of expected fields
-/// and relies on Phase B to introduce full verification using webauthn-rs.
-pub fn finish_authentication(
-    storage: &Storage,
-    user_id: &str,
-    auth_id: &str,
-    client_assertion: &Value,
-) -> Result<()> {
-    let pending = storage.get_webauthn_auth_state(user_id, auth_id)?;
-    if pending.is_none() {
-        anyhow::bail!("no pending auth state for user_id={} auth_id={}", user_id, auth_id);
+/// Change a passkey's nickname. Returns false if the user has no such passkey.
+pub fn rename_passkey(storage: &Storage, user_id: &str, cred_id: &str, name: &str) -> Result<bool> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("name must not be empty");
     }
+    let Some(value) = storage.get_webauthn_cred(user_id, cred_id)? else { return Ok(false) };
+    let mut record: PasskeyRecord = serde_json::from_value(value)?;
+    record.name = name.chars().take(64).collect();
+    storage.put_webauthn_cred(user_id, cred_id, &serde_json::to_value(&record)?)?;
+    Ok(true)
+}
 
-    // Basic structural checks: ensure client provided an "id" and "response"
-    if client_assertion.get("id").is_none() || client_assertion.get("response").is_none() {
-        anyhow::bail!("malformed client assertion");
-    }
+/// Remove a passkey. Returns false if the user has no such passkey.
+pub fn delete_passkey(storage: &Storage, user_id: &str, cred_id: &str) -> Result<bool> {
+    storage.delete_webauthn_cred(user_id, cred_id)
+}
 
-    // In a full implementation we'd:
-    //  - lookup credential by id
-    //  - verify signature & user presence & counters
-    //  - update stored counter
-    // For Phase A, accept and remove pending state.
-    storage.delete_webauthn_auth_state(user_id, auth_id)?;
+fn user_handle(user_id: &str) -> Result<Uuid> {
+    Uuid::parse_str(user_id).map_err(|_| anyhow!("user id {:?} is not a UUID", user_id))
+}
 
-    Ok(())
+fn encode_cred_id(id: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(id)
+}
+
+fn live_state<T: serde::de::DeserializeOwned>(saved: Value, now: i64) -> Result<T> {
+    if saved.get("expires_at").and_then(|v| v.as_i64()).is_none_or(|at| at <= now) {
+        bail!("ceremony took too long, start again");
+    }
+    Ok(serde_json::from_value(saved.get("state").cloned().unwrap_or_default())?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
-    use uuid::Uuid;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
 
-    #[test]
-    fn registration_state_lifecycle() -> Result<()> {
-        // unique test directory to avoid collisions
-        let path = format!("./data/test-webauthn-{}", Uuid::new_v4());
-        let storage = Storage::new(&path)?;
-
-        let user_id = "test-user";
-        let display = "Test User";
-        let rp_id = "localhost";
-        let rp_name = "Stack Test RP";
-        let origin = "http://localhost:3000";
-
-        // Begin
-        let (reg_id, options) = begin_registration(&storage, user_id, display, rp_id, rp_name, origin)?;
-        assert!(options.get("publicKey").is_some());
-
-        // Ensure pending state exists
-        let pending = storage.get_webauthn_reg_state(user_id, &reg_id)?;
-        assert!(pending.is_some());
-
-        // Finish with a fake client-provided credential
-        let fake_cred = json!({
-            "id": "fake-cred-1",
-            "rawId": "ZmFrZS1yY3QtcmF3",
-            "response": {
-                "attestationObject": {},
-                "clientDataJSON": {}
-            },
-            "type": "public-key"
-        });
-
-        let cred_id = finish_registration(&storage, user_id, &reg_id, &fake_cred)?;
-        assert_eq!(cred_id, "fake-cred-1");
+    fn passkeys() -> Passkeys {
+        Passkeys::new("localhost", "Stack Test RP", &[Url::parse("http://localhost:3000").unwrap()], false).unwrap()
+    }
 
-        // Credential should be stored
-        let stored = storage.get_webauthn_cred(user_id, &cred_id)?;
-        assert!(stored.is_some());
+    #[test]
+    fn registration_rejects_unverified_credentials() -> Result<()> {
+        let (_dir, storage) = crate::test_storage();
+        let user_id = Uuid::new_v4().to_string();
+        let pk = passkeys();
 
-        // cleanup test dir
-        let _ = fs::remove_dir_all(&path);
+        let (reg_id, options) = pk.begin_registration(&storage, &user_id, "test", "Test User", 1000)?;
+        assert_eq!(options["publicKey"]["rp"]["id"], "localhost");
+        assert_eq!(options["publicKey"]["authenticatorSelection"]["residentKey"], "preferred");
+        assert!(storage.get_webauthn_reg_state(&user_id, &reg_id)?.is_some());
 
+        // an unsigned credential with a fake attestation object
+        let fake_cred = json!({
+            "id": "ZmFrZS1jcmVk",
+            "rawId": "ZmFrZS1jcmVk",
+            "response": { "attestationObject": "o2NmbXRkbm9uZQ", "clientDataJSON": "e30" },
+            "type": "public-key",
+            "extensions": {}
+        });
+        assert!(pk.finish_registration(&storage, &user_id, &reg_id, &fake_cred, None, 1001).is_err());
+        assert!(list_passkeys(&storage, &user_id)?.is_empty());
+        // the challenge is spent either way
+        assert!(storage.get_webauthn_reg_state(&user_id, &reg_id)?.is_none());
+        assert!(pk.begin_registration(&storage, "not-a-uuid", "x", "x", 1000).is_err());
+        assert!(!rename_passkey(&storage, &user_id, "ZmFrZS1jcmVk", "Laptop")?);
+        assert!(!delete_passkey(&storage, &user_id, "ZmFrZS1jcmVk")?);
         Ok(())
     }
 
     #[test]
-    fn auth_state_lifecycle() -> Result<()> {
-        let path = format!("./data/test-webauthn-{}", Uuid::new_v4());
-        let storage = Storage::new(&path)?;
-
-        let user_id = "auth-user";
-        let rp_id = "localhost";
-        let origin = "http://localhost:3000";
-
-        let (auth_id, options) = begin_authentication(&storage, user_id, rp_id, origin)?;
-        assert!(options.get("publicKey").is_some());
-
-        let pending = storage.get_webauthn_auth_state(user_id, &auth_id)?;
-        assert!(pending.is_some());
-
-        let fake_assertion = json!({
-            "id": "fake-cred-1",
-            "rawId": "ZmFrZS1yY3Q=",
-            "response": {
-                "clientDataJSON": {},
-                "authenticatorData": {},
-                "signature": "sig"
-            },
-            "type": "public-key"
+    fn authentication_rejects_forged_assertions() -> Result<()> {
+        let (_dir, storage) = crate::test_storage();
+        let pk = passkeys();
+
+        let (auth_id, options) = pk.begin_authentication(&storage, None, 1000)?;
+        assert!(options["publicKey"]["challenge"].is_string());
+        let forged = json!({
+            "id": "ZmFrZS1jcmVk",
+            "rawId": "ZmFrZS1jcmVk",
+            "response": { "authenticatorData": "AAAA", "clientDataJSON": "e30", "signature": "c2ln", "userHandle": null },
+            "type": "public-key",
+            "extensions": {}
         });
+        assert!(pk.finish_authentication(&storage, &auth_id, &forged, 1001).is_err());
+        assert!(pk.finish_authentication(&storage, &auth_id, &forged, 1001).unwrap_err().to_string().contains("unknown"), "single use");
 
-        finish_authentication(&storage, user_id, &auth_id, &fake_assertion)?;
-
-        let pending_after = storage.get_webauthn_auth_state(user_id, &auth_id)?;
-        assert!(pending_after.is_none());
+        let (auth_id, _) = pk.begin_authentication(&storage, None, 1000)?;
+        assert!(pk.finish_authentication(&storage, &auth_id, &forged, 1000 + CEREMONY_TTL_SECS).unwrap_err().to_string().contains("too long"));
+        Ok(())
+    }
 
-        let _ = fs::remove_dir_all(&path);
+    fn stored_counter(storage: &Storage, user_id: &str, cred_id: &str) -> u64 {
+        storage.get_webauthn_cred(user_id, cred_id).unwrap().unwrap()["passkey"]["cred"]["counter"].as_u64().unwrap()
+    }
 
+    #[test]
+    fn software_passkey_signs_in_and_counter_regressions_are_rejected() -> Result<()> {
+        let (_dir, storage) = crate::test_storage();
+        let user_id = Uuid::new_v4().to_string();
+        let pk = passkeys();
+        let origin = Url::parse("http://localhost:3000")?;
+        // true: report user verification, which passkeys require
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        let (reg_id, options) = pk.begin_registration(&storage, &user_id, "test", "Test User", 1000)?;
+        let credential = authenticator.do_registration(origin.clone(), serde_json::from_value(options)?).map_err(|e| anyhow!("{:?}", e))?;
+        let cred_id = pk.finish_registration(&storage, &user_id, &reg_id, &serde_json::to_value(&credential)?, Some("  Laptop "), 1001)?;
+        let passkeys_of_user = list_passkeys(&storage, &user_id)?;
+        assert_eq!((passkeys_of_user.len(), passkeys_of_user[0].1.name.as_str()), (1, "Laptop"));
+
+        let mut sign_in = |now: i64| -> Result<(String, Value)> {
+            let (auth_id, options) = pk.begin_authentication(&storage, Some(&user_id), now)?;
+            let assertion = authenticator.do_authentication(origin.clone(), serde_json::from_value(options)?).map_err(|e| anyhow!("{:?}", e))?;
+            Ok((auth_id, serde_json::to_value(&assertion)?))
+        };
+
+        let (auth_id, assertion) = sign_in(1010)?;
+        assert_eq!(pk.finish_authentication(&storage, &auth_id, &assertion, 1011)?, user_id);
+        let counter = stored_counter(&storage, &user_id, &cred_id);
+        assert!(counter > 0, "the new counter is stored");
+        assert_eq!(list_passkeys(&storage, &user_id)?[0].1.last_used_at, Some(1011));
+        // the same assertion can't be used for another sign-in
+        let (auth_id, _) = pk.begin_authentication(&storage, Some(&user_id), 1020)?;
+        assert!(pk.finish_authentication(&storage, &auth_id, &assertion, 1021).is_err());
+
+        // an authenticator whose counter doesn't move past the stored one looks cloned
+        for ahead in [1, 5] {
+            let mut value = storage.get_webauthn_cred(&user_id, &cred_id)?.unwrap();
+            let next = stored_counter(&storage, &user_id, &cred_id) + ahead;
+            value["passkey"]["cred"]["counter"] = json!(next);
+            storage.put_webauthn_cred(&user_id, &cred_id, &value)?;
+            let (auth_id, assertion) = sign_in(1030)?;
+            assert!(pk.finish_authentication(&storage, &auth_id, &assertion, 1031).is_err(), "counter {} ahead", ahead);
+            assert_eq!(stored_counter(&storage, &user_id, &cred_id), next, "not lowered");
+        }
+
+        assert!(rename_passkey(&storage, &user_id, &cred_id, "Desktop")?);
+        assert!(delete_passkey(&storage, &user_id, &cred_id)?);
+        assert!(list_passkeys(&storage, &user_id)?.is_empty());
         Ok(())
     }
 }
@@ -0,0 +1,255 @@
+/*
+Framed message headers carried by every pub/sub backend.
+
+Each published payload is wrapped as:
+
+  magic "\xB5BUS" | version u8 | header_len u16 BE | fields... | payload
+
+where each field is `tag u8 | len u16 BE | value`. Unknown tags are skipped so
+new headers can be added without breaking older consumers. Bytes that do not
+start with the magic are treated as a legacy (unframed) payload, which keeps
+mixed deployments working while publishers are upgraded.
+
+On nng/zmq the frame is the part after `topic\0`; the in-memory backend carries
+the same frame through its broadcast channel.
+*/
+
+use anyhow::{bail, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_CAPNP: &str = "application/capnp";
+pub const CONTENT_TYPE_OCTET: &str = "application/octet-stream";
+
+const MAGIC: [u8; 4] = [0xB5, b'B', b'U', b'S'];
+const VERSION: u8 = 1;
+const PREFIX_LEN: usize = MAGIC.len() + 1 + 2;
+
+const TAG_CONTENT_TYPE: u8 = 1;
+const TAG_SCHEMA_VERSION: u8 = 2;
+const TAG_MESSAGE_ID: u8 = 3;
+const TAG_PUBLISHED_AT: u8 = 4;
+const TAG_TRACEPARENT: u8 = 5;
+
+/// Per-message metadata. `published_at_ms` is unix milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Headers {
+    pub content_type: String,
+    pub schema_version: u32,
+    pub message_id: String,
+    pub published_at_ms: u64,
+    /// W3C trace context (`traceparent` header value), if the publish is part of a trace.
+    pub traceparent: Option<String>,
+}
+
+impl Headers {
+    /// Fresh headers with a new message id and the current time.
+    pub fn new(content_type: &str) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            schema_version: 0,
+            message_id: uuid::Uuid::new_v4().to_string(),
+            published_at_ms: now_ms(),
+            traceparent: None,
+        }
+    }
+
+    pub fn json() -> Self {
+        Self::new(CONTENT_TYPE_JSON)
+    }
+
+    pub fn capnp() -> Self {
+        Self::new(CONTENT_TYPE_CAPNP)
+    }
+
+    /// Headers reported for payloads published without a frame.
+    pub fn legacy() -> Self {
+        Self {
+            content_type: CONTENT_TYPE_OCTET.to_string(),
+            schema_version: 0,
+            message_id: String::new(),
+            published_at_ms: 0,
+            traceparent: None,
+        }
+    }
+
+    pub fn with_schema_version(mut self, version: u32) -> Self {
+        self.schema_version = version;
+        self
+    }
+
+    /// Attach a trace context. Malformed values are dropped rather than propagated.
+    pub fn with_traceparent(mut self, traceparent: Option<&str>) -> Self {
+        self.traceparent = traceparent.filter(|tp| is_valid_traceparent(tp)).map(str::to_string);
+        self
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.content_type == CONTENT_TYPE_JSON
+    }
+
+    pub fn is_capnp(&self) -> bool {
+        self.content_type == CONTENT_TYPE_CAPNP
+    }
+}
+
+/// Wrap `payload` in a header frame.
+pub fn encode(headers: &Headers, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut fields = Vec::with_capacity(64);
+    put_field(&mut fields, TAG_CONTENT_TYPE, headers.content_type.as_bytes())?;
+    put_field(&mut fields, TAG_SCHEMA_VERSION, &headers.schema_version.to_be_bytes())?;
+    put_field(&mut fields, TAG_MESSAGE_ID, headers.message_id.as_bytes())?;
+    put_field(&mut fields, TAG_PUBLISHED_AT, &headers.published_at_ms.to_be_bytes())?;
+    if let Some(tp) = &headers.traceparent {
+        put_field(&mut fields, TAG_TRACEPARENT, tp.as_bytes())?;
+    }
+    if fields.len() > u16::MAX as usize {
+        bail!("envelope headers too large: {} bytes", fields.len());
+    }
+
+    let mut buf = Vec::with_capacity(PREFIX_LEN + fields.len() + payload.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&fields);
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// Split a frame into headers and payload. Unframed bytes decode as `Headers::legacy()`.
+pub fn decode(bytes: &[u8]) -> Result<(Headers, &[u8])> {
+    if !bytes.starts_with(&MAGIC) {
+        return Ok((Headers::legacy(), bytes));
+    }
+    if bytes.len() < PREFIX_LEN {
+        bail!("truncated envelope prefix");
+    }
+    let version = bytes[MAGIC.len()];
+    if version != VERSION {
+        bail!("unsupported envelope version {}", version);
+    }
+    let header_len = u16::from_be_bytes([bytes[MAGIC.len() + 1], bytes[MAGIC.len() + 2]]) as usize;
+    let Some(mut fields) = bytes.get(PREFIX_LEN..PREFIX_LEN + header_len) else {
+        bail!("truncated envelope headers");
+    };
+    let payload = &bytes[PREFIX_LEN + header_len..];
+
+    let mut headers = Headers::legacy();
+    while !fields.is_empty() {
+        if fields.len() < 3 {
+            bail!("truncated envelope field");
+        }
+        let tag = fields[0];
+        let len = u16::from_be_bytes([fields[1], fields[2]]) as usize;
+        let Some(value) = fields.get(3..3 + len) else {
+            bail!("truncated envelope field {}", tag);
+        };
+        match tag {
+            TAG_CONTENT_TYPE => headers.content_type = utf8(value)?,
+            TAG_SCHEMA_VERSION => headers.schema_version = u32::from_be_bytes(fixed(value)?),
+            TAG_MESSAGE_ID => headers.message_id = utf8(value)?,
+            TAG_PUBLISHED_AT => headers.published_at_ms = u64::from_be_bytes(fixed(value)?),
+            TAG_TRACEPARENT => headers.traceparent = Some(utf8(value)?),
+            _ => {} // newer header; ignore
+        }
+        fields = &fields[3 + len..];
+    }
+    Ok((headers, payload))
+}
+
+/// Check the `00-<trace-id>-<parent-id>-<flags>` shape of a W3C traceparent.
+pub fn is_valid_traceparent(tp: &str) -> bool {
+    let parts: Vec<&str> = tp.split('-').collect();
+    let hex = |s: &str, n: usize| s.len() == n && s.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase());
+    parts.len() == 4
+        && hex(parts[0], 2)
+        && parts[0] != "ff"
+        && hex(parts[1], 32)
+        && parts[1].bytes().any(|b| b != b'0')
+        && hex(parts[2], 16)
+        && parts[2].bytes().any(|b| b != b'0')
+        && hex(parts[3], 2)
+}
+
+/// Start a new sampled trace: `00-<random trace-id>-<random span-id>-01`.
+pub fn new_traceparent() -> String {
+    let trace = uuid::Uuid::new_v4().simple().to_string();
+    let span = uuid::Uuid::new_v4().simple().to_string();
+    format!("00-{}-{}-01", trace, &span[..16])
+}
+
+fn put_field(buf: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<()> {
+    if value.len() > u16::MAX as usize {
+        bail!("envelope header {} too large: {} bytes", tag, value.len());
+    }
+    buf.push(tag);
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+fn utf8(value: &[u8]) -> Result<String> {
+    Ok(std::str::from_utf8(value)?.to_string())
+}
+
+fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N]> {
+    value.try_into().map_err(|_| anyhow::anyhow!("envelope field has {} bytes, expected {}", value.len(), N))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_preserves_headers_and_payload() {
+        let tp = new_traceparent();
+        let headers = Headers::json().with_schema_version(3).with_traceparent(Some(&tp));
+        let frame = encode(&headers, b"{\"a\":1}").unwrap();
+        let (decoded, payload) = decode(&frame).unwrap();
+        assert_eq!(decoded, headers);
+        assert_eq!(payload, b"{\"a\":1}");
+        assert!(decoded.is_json());
+    }
+
+    #[test]
+    fn unframed_bytes_decode_as_legacy() {
+        let (headers, payload) = decode(b"{\"a\":1}").unwrap();
+        assert_eq!(headers, Headers::legacy());
+        assert_eq!(payload, b"{\"a\":1}");
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut frame = encode(&Headers::capnp(), b"xyz").unwrap();
+        // Append an unknown field (tag 200, 2 bytes) to the header block.
+        let len = u16::from_be_bytes([frame[5], frame[6]]) + 5;
+        frame[5..7].copy_from_slice(&len.to_be_bytes());
+        let at = PREFIX_LEN + len as usize - 5;
+        frame.splice(at..at, [200, 0, 2, 9, 9]);
+        let (headers, payload) = decode(&frame).unwrap();
+        assert!(headers.is_capnp());
+        assert_eq!(payload, b"xyz");
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let frame = encode(&Headers::json(), b"").unwrap();
+        assert!(decode(&frame[..frame.len() - 1]).is_err());
+        assert!(decode(&frame[..5]).is_err());
+    }
+
+    #[test]
+    fn traceparent_validation() {
+        assert!(is_valid_traceparent(&new_traceparent()));
+        assert!(is_valid_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert!(!is_valid_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"));
+        assert!(!is_valid_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"));
+        assert!(!is_valid_traceparent("garbage"));
+        let h = Headers::json().with_traceparent(Some("garbage"));
+        assert_eq!(h.traceparent, None);
+    }
+}
@@ -1,5 +1,8 @@
 ﻿pub mod rpc;
 pub mod pubsub;
 pub mod codecs;
+pub mod envelope;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub fn hello() { println!("bus hello"); }
@@ -7,11 +7,17 @@ This file exposes the same public API regardless of feature flags:
   - bind(addr: &str) -> Result<Self>
   - dial(addr: &str) -> Result<Self>
   - publish(&self, topic: &str, payload: &[u8]) -> Result<()>
+  - publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()>
 
 - Subscriber
   - connect(addr: &str, topic: &str) -> Result<Self>
+  - into_deliveries(self) -> mpsc::Receiver<Delivery>
   - into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)>
 
+Every backend carries the envelope frame from `crate::envelope` (content type,
+schema version, message id, publish time, traceparent). On sockets the wire
+format is `topic\0frame`; subscribers strip the frame and hand out a Delivery.
+
 Current state:
 - Default (no feature): in-memory tokio::broadcast-based implementation (suitable for dev/tests)
 - feature = "with-nng": for now we alias to the in-memory implementation as a shim.
@@ -24,8 +30,62 @@ with-nng feature and still work locally.
 use anyhow::Result;
 use tokio::sync::mpsc;
 
+use crate::envelope::{self, Headers};
+
+/// A received message: topic, decoded envelope headers and the inner payload.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub topic: String,
+    pub headers: Headers,
+    pub payload: Vec<u8>,
+}
+
+/// Decode an envelope frame received on `topic`. Malformed frames are logged and dropped.
+fn delivery(topic: String, frame: &[u8]) -> Option<Delivery> {
+    match envelope::decode(frame) {
+        Ok((headers, payload)) => Some(Delivery { topic, headers, payload: payload.to_vec() }),
+        Err(e) => {
+            tracing::warn!(topic = %topic, error = %e, "dropping malformed bus frame");
+            None
+        }
+    }
+}
+
+/// Socket wire format: topic\x00frame
+#[cfg(any(feature = "with-nng", feature = "with-zmq"))]
+fn encode_wire(topic: &str, headers: &Headers, payload: &[u8]) -> Result<Vec<u8>> {
+    let frame = envelope::encode(headers, payload)?;
+    let mut buf = Vec::with_capacity(topic.len() + 1 + frame.len());
+    buf.extend_from_slice(topic.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(&frame);
+    Ok(buf)
+}
+
+/// Split topic\x00frame; no separator means an empty topic.
+#[cfg(any(feature = "with-nng", feature = "with-zmq"))]
+fn decode_wire(msg: &[u8]) -> Option<Delivery> {
+    match msg.iter().position(|&b| b == 0) {
+        Some(pos) => delivery(String::from_utf8_lossy(&msg[..pos]).to_string(), &msg[pos + 1..]),
+        None => delivery(String::new(), msg),
+    }
+}
+
+/// Adapt a Delivery stream to the header-less `(topic, payload)` stream.
+fn strip_headers(mut rx: mpsc::Receiver<Delivery>) -> mpsc::Receiver<(String, Vec<u8>)> {
+    let (tx, out) = mpsc::channel(256);
+    tokio::spawn(async move {
+        while let Some(d) = rx.recv().await {
+            if tx.send((d.topic, d.payload)).await.is_err() {
+                break;
+            }
+        }
+    });
+    out
+}
+
 #[allow(dead_code)]
-mod mem {
+pub(crate) mod mem {
     use super::*;
     use std::collections::HashMap;
     use std::sync::{Mutex, OnceLock};
@@ -51,9 +111,15 @@ mod mem {
             Ok(Self {})
         }
 
-        /// Publish a payload to `topic`.
+        /// Publish a payload to `topic` with default (octet-stream) headers.
         pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+            self.publish_with(topic, &Headers::new(envelope::CONTENT_TYPE_OCTET), payload)
+        }
+
+        /// Publish a payload to `topic` with explicit envelope headers.
+        pub fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
             tracing::debug!(topic = %topic, len = payload.len(), "mem pub: sending payload");
+            let frame = envelope::encode(headers, payload)?;
             let mut map = registry().lock().unwrap();
             let tx = map
                 .entry(topic.to_string())
@@ -63,7 +129,7 @@ mod mem {
             // If there are no subscribers, broadcast::Sender::send returns Err(SendError).
             // For our in-memory dev fallback we treat "no subscribers" as non-fatal and
             // swallow the error so publishers don't fail simply because no one is listening.
-            match tx.send(payload.to_vec()) {
+            match tx.send(frame) {
                 Ok(_) => Ok(()),
                 Err(_send_err) => Ok(()), // no receivers; ignore in dev fallback
             }
@@ -71,7 +137,7 @@ mod mem {
     }
 
     pub struct Subscriber {
-        receiver: mpsc::Receiver<(String, Vec<u8>)>,
+        receiver: mpsc::Receiver<Delivery>,
     }
 
     impl Subscriber {
@@ -92,10 +158,11 @@ mod mem {
             tokio::spawn(async move {
                 loop {
                     match brx.recv().await {
-                        Ok(payload) => {
-                            tracing::debug!(topic = %topic_owned, len = payload.len(), "mem sub: received payload, forwarding");
+                        Ok(frame) => {
+                            tracing::debug!(topic = %topic_owned, len = frame.len(), "mem sub: received payload, forwarding");
+                            let Some(d) = delivery(topic_owned.clone(), &frame) else { continue };
                             // best-effort: if receiver closed, stop the task
-                            if tx.send((topic_owned.clone(), payload)).await.is_err() {
+                            if tx.send(d).await.is_err() {
                                 break;
                             }
                         }
@@ -114,12 +181,17 @@ mod mem {
         }
 
         /// Consume the Subscriber and return the owned receiver for moving into tasks.
-        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn into_deliveries(self) -> mpsc::Receiver<Delivery> {
             self.receiver
         }
 
+        /// Like `into_deliveries` but yields only `(topic, payload)`, dropping headers.
+        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+            strip_headers(self.receiver)
+        }
+
         /// Return the internal receiver to await incoming messages.
-        pub fn receiver(&mut self) -> &mut mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn receiver(&mut self) -> &mut mpsc::Receiver<Delivery> {
             &mut self.receiver
         }
     }
@@ -127,14 +199,13 @@ mod mem {
 
 #[allow(dead_code)]
 #[cfg(feature = "with-nng")]
-mod nng_impl {
+pub(crate) mod nng_impl {
     use super::*;
     use anyhow::Result;
     use nng::{Socket, Protocol, Message};
     use nng::options::Options;
     use nng::options::protocol::pubsub::Subscribe;
     use std::thread;
-    use tokio::runtime::Handle;
 
     #[allow(dead_code)]
     pub struct Publisher {
@@ -156,12 +227,14 @@ mod nng_impl {
             Ok(Self { sock })
         }
 
-        /// Publish a topic + payload as: topic\x00payload
+        /// Publish a payload with default (octet-stream) headers.
         pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
-            let mut buf = Vec::with_capacity(topic.len() + 1 + payload.len());
-            buf.extend_from_slice(topic.as_bytes());
-            buf.push(0);
-            buf.extend_from_slice(payload);
+            self.publish_with(topic, &Headers::new(envelope::CONTENT_TYPE_OCTET), payload)
+        }
+
+        /// Publish as: topic\x00frame
+        pub fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+            let buf = encode_wire(topic, headers, payload)?;
             let msg = Message::from(buf.as_slice());
             self.sock.send(msg).map_err(|(_m, e)| anyhow::anyhow!(e))?;
             Ok(())
@@ -170,13 +243,13 @@ mod nng_impl {
 
     #[allow(dead_code)]
     pub struct Subscriber {
-        receiver: mpsc::Receiver<(String, Vec<u8>)>,
+        receiver: mpsc::Receiver<Delivery>,
     }
 
     impl Subscriber {
         /// Connect to a topic on addr and subscribe to the topic prefix.
         /// This spawns a blocking thread to recv from the native nng socket and forwards
-        /// messages into a tokio mpsc channel using the current runtime handle.
+        /// messages into a tokio mpsc channel.
         pub fn connect(addr: &str, topic: &str) -> Result<Self> {
             let sub_sock = Socket::new(Protocol::Sub0)?;
             sub_sock.dial(addr)?;
@@ -185,41 +258,16 @@ mod nng_impl {
 
             let (tx, rx) = mpsc::channel(256);
 
-            // Capture a handle to the current runtime so the blocking thread can
-            // spawn async tasks to forward into the tokio channel.
-            let handle = Handle::current();
             let sub_thread = sub_sock;
-            let tx_thread = tx.clone();
 
             // Spawn a blocking OS thread to receive messages from the native nng socket.
+            // Forward with blocking_send from this thread so messages keep socket order.
             thread::spawn(move || {
-                loop {
-                    match sub_thread.recv() {
-                        Ok(msg) => {
-                            let msg_bytes = msg.as_slice().to_vec();
-                            // split at first 0x00 separator
-                            if let Some(pos) = msg_bytes.iter().position(|&b| b == 0) {
-                                let topic_bytes = msg_bytes[..pos].to_vec();
-                                let payload = msg_bytes[pos + 1..].to_vec();
-                                let topic_str = String::from_utf8_lossy(&topic_bytes).to_string();
-                                let tx_async = tx_thread.clone();
-                                // forward into tokio mpsc via the runtime
-                                let _ = handle.spawn(async move {
-                                    let _ = tx_async.send((topic_str, payload)).await;
-                                });
-                            } else {
-                                // No separator, treat whole message as payload with empty topic
-                                let payload = msg_bytes;
-                                let tx_async = tx_thread.clone();
-                                let _ = handle.spawn(async move {
-                                    let _ = tx_async.send((String::new(), payload)).await;
-                                });
-                            }
-                        }
-                        Err(_) => {
-                            // socket closed or error; stop thread
-                            break;
-                        }
+                while let Ok(msg) = sub_thread.recv() {
+                    let Some(item) = decode_wire(msg.as_slice()) else { continue };
+                    if tx.blocking_send(item).is_err() {
+                        // receiver dropped; stop thread
+                        break;
                     }
                 }
             });
@@ -228,12 +276,17 @@ mod nng_impl {
         }
 
         /// Consume the Subscriber and return the owned receiver for moving into tasks.
-        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn into_deliveries(self) -> mpsc::Receiver<Delivery> {
             self.receiver
         }
 
+        /// Like `into_deliveries` but yields only `(topic, payload)`, dropping headers.
+        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+            strip_headers(self.receiver)
+        }
+
         /// Return the internal receiver to await incoming messages.
-        pub fn receiver(&mut self) -> &mut mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn receiver(&mut self) -> &mut mpsc::Receiver<Delivery> {
             &mut self.receiver
         }
     }
@@ -241,11 +294,10 @@ mod nng_impl {
 
 #[allow(dead_code)]
 #[cfg(all(feature = "with-zmq", not(feature = "with-nng")))]
-mod zmq_impl {
+pub(crate) mod zmq_impl {
     use super::*;
     use anyhow::Result;
     use std::thread;
-    use tokio::runtime::Handle;
     use zmq::Context as ZmqContext;
 
     #[allow(dead_code)]
@@ -270,12 +322,14 @@ mod zmq_impl {
             Ok(Self { sock })
         }
 
-        /// Publish a topic + payload as: topic\x00payload
+        /// Publish a payload with default (octet-stream) headers.
         pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
-            let mut buf = Vec::with_capacity(topic.len() + 1 + payload.len());
-            buf.extend_from_slice(topic.as_bytes());
-            buf.push(0);
-            buf.extend_from_slice(payload);
+            self.publish_with(topic, &Headers::new(envelope::CONTENT_TYPE_OCTET), payload)
+        }
+
+        /// Publish as: topic\x00frame
+        pub fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+            let buf = encode_wire(topic, headers, payload)?;
             self.sock.send(&buf, 0)?;
             Ok(())
         }
@@ -283,13 +337,13 @@ mod zmq_impl {
 
     #[allow(dead_code)]
     pub struct Subscriber {
-        receiver: mpsc::Receiver<(String, Vec<u8>)>,
+        receiver: mpsc::Receiver<Delivery>,
     }
 
     impl Subscriber {
         /// Connect to a topic on addr and subscribe to the topic prefix.
         /// This spawns a blocking thread to recv from the ZMQ SUB socket and forwards
-        /// messages into a tokio mpsc channel using the current runtime handle.
+        /// messages into a tokio mpsc channel.
         pub fn connect(addr: &str, topic: &str) -> Result<Self> {
             let ctx = ZmqContext::new();
             let sock = ctx.socket(zmq::SUB)?;
@@ -299,35 +353,14 @@ mod zmq_impl {
 
             let (tx, rx) = mpsc::channel(256);
 
-            let handle = Handle::current();
             let sub_thread_sock = sock;
-            let tx_thread = tx.clone();
 
+            // Forward with blocking_send from the recv thread so messages keep socket order.
             thread::spawn(move || {
-                loop {
-                    match sub_thread_sock.recv_bytes(0) {
-                        Ok(msg_bytes) => {
-                            // split at first 0x00 separator
-                            if let Some(pos) = msg_bytes.iter().position(|&b| b == 0) {
-                                let topic_bytes = msg_bytes[..pos].to_vec();
-                                let payload = msg_bytes[pos + 1..].to_vec();
-                                let topic_str = String::from_utf8_lossy(&topic_bytes).to_string();
-                                let tx_async = tx_thread.clone();
-                                let _ = handle.spawn(async move {
-                                    let _ = tx_async.send((topic_str, payload)).await;
-                                });
-                            } else {
-                                // No separator, treat whole message as payload with empty topic
-                                let payload = msg_bytes;
-                                let tx_async = tx_thread.clone();
-                                let _ = handle.spawn(async move {
-                                    let _ = tx_async.send((String::new(), payload)).await;
-                                });
-                            }
-                        }
-                        Err(_) => {
-                            break;
-                        }
+                while let Ok(msg_bytes) = sub_thread_sock.recv_bytes(0) {
+                    let Some(item) = decode_wire(&msg_bytes) else { continue };
+                    if tx.blocking_send(item).is_err() {
+                        break;
                     }
                 }
             });
@@ -336,12 +369,17 @@ mod zmq_impl {
         }
 
         /// Consume the Subscriber and return the owned receiver for moving into tasks.
-        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn into_deliveries(self) -> mpsc::Receiver<Delivery> {
             self.receiver
         }
 
+        /// Like `into_deliveries` but yields only `(topic, payload)`, dropping headers.
+        pub fn into_receiver(self) -> mpsc::Receiver<(String, Vec<u8>)> {
+            strip_headers(self.receiver)
+        }
+
         /// Return the internal receiver to await incoming messages.
-        pub fn receiver(&mut self) -> &mut mpsc::Receiver<(String, Vec<u8>)> {
+        pub fn receiver(&mut self) -> &mut mpsc::Receiver<Delivery> {
             &mut self.receiver
         }
     }
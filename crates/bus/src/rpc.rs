@@ -15,7 +15,8 @@ The implementation uses a global registry mapping addresses -> mpsc::Sender of
 the caller's perspective while remaining async-friendly.
 
 When compiled with feature "with-nng" a native NNG-backed req/rep implementation
-is available (both server and client). The default without the feature remains
+is available (both server and client). Feature "with-zmq", or "rpc-zmq" which
+leaves pub/sub alone, adds the ZeroMQ one. The default without the feature remains
 the in-process mpsc/oneshot registry.
 */
 
@@ -26,11 +27,11 @@ use tokio::sync::{mpsc, oneshot};
 
 type Req = Vec<u8>;
 type Resp = Vec<u8>;
+type Registry = Mutex<HashMap<String, mpsc::Sender<(Req, oneshot::Sender<Resp>)>>>;
 
-static RPC_REGISTRY: OnceLock<Mutex<HashMap<String, mpsc::Sender<(Req, oneshot::Sender<Resp>)>>>> =
-    OnceLock::new();
+static RPC_REGISTRY: OnceLock<Registry> = OnceLock::new();
 
-fn registry() -> &'static Mutex<HashMap<String, mpsc::Sender<(Req, oneshot::Sender<Resp>)>>> {
+fn registry() -> &'static Registry {
     RPC_REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
@@ -74,12 +75,11 @@ where
 /// Send a single request to `addr` and await the response. Returns an error if
 /// the address is not bound or the response channel is closed.
 pub async fn req_once(addr: &str, payload: &[u8]) -> Result<Vec<u8>> {
-    let map = registry().lock().unwrap();
-    let tx = match map.get(addr) {
+    // Clone the sender out so the registry lock is never held across an await.
+    let tx = match registry().lock().unwrap().get(addr) {
         Some(tx) => tx.clone(),
         None => return Err(anyhow!("no rpc server bound at {}", addr)),
     };
-    drop(map);
 
     let (resp_tx, resp_rx) = oneshot::channel();
     // Send request; if send fails, server receiver likely closed.
@@ -182,7 +182,7 @@ mod nng_impl {
         })
         .await?;
 
-        resp.map_err(|e| e.into())
+        resp
     }
 }
 
@@ -190,7 +190,7 @@ mod nng_impl {
 #[cfg(feature = "with-nng")]
 pub use nng_impl::{bind_server as bind_server_nng, req_once as req_once_nng};
 
-#[cfg(feature = "with-zmq")]
+#[cfg(any(feature = "with-zmq", feature = "rpc-zmq"))]
 mod zmq_impl {
     use super::*;
     use anyhow::Result;
@@ -198,6 +198,7 @@ mod zmq_impl {
     use std::pin::Pin;
     use std::sync::Arc;
     use std::thread;
+    use std::time::Duration;
     use tokio::runtime::Handle;
     use tokio::task;
     use zmq::Context as ZmqContext;
@@ -217,7 +218,8 @@ mod zmq_impl {
         }
     }
 
-    /// ZMQ-backed server using REP socket. Spawns a blocking thread that recv()s
+    /// ZMQ-backed server using REP socket. Binds before returning, so a taken
+    /// address is an error, then spawns a blocking thread that recv()s
     /// messages and dispatches to the async handler via the current Tokio runtime.
     pub fn bind_server<F, Fut>(addr: &str, handler: F) -> Result<()>
     where
@@ -228,31 +230,20 @@ mod zmq_impl {
         let handler_arc: Arc<dyn Handler> = Arc::new(handler);
         let handle = Handle::current();
 
-        // Spawn a blocking OS thread and create the ZMQ socket inside it.
-        thread::spawn(move || {
-            let ctx = ZmqContext::new();
-            let sock = match ctx.socket(zmq::REP) {
-                Ok(s) => s,
-                Err(_) => return,
-            };
-            if sock.bind(&addr_owned).is_err() {
-                return;
-            }
+        let ctx = ZmqContext::new();
+        let sock = ctx.socket(zmq::REP)?;
+        sock.bind(&addr_owned)?;
 
-            loop {
-                match sock.recv_bytes(0) {
-                    Ok(msg) => {
-                        let handler = handler_arc.clone();
-                        let fut = handler.call(msg);
-                        // Block on the runtime to execute the async handler and get response bytes.
-                        let resp_bytes = handle.block_on(fut);
-                        // best-effort send; ignore errors
-                        let _ = sock.send(resp_bytes, 0);
-                    }
-                    Err(_) => {
-                        break;
-                    }
-                }
+        // Serve on a blocking OS thread, which owns the context and socket.
+        thread::spawn(move || {
+            let _ctx = ctx;
+            while let Ok(msg) = sock.recv_bytes(0) {
+                let handler = handler_arc.clone();
+                let fut = handler.call(msg);
+                // Block on the runtime to execute the async handler and get response bytes.
+                let resp_bytes = handle.block_on(fut);
+                // best-effort send; ignore errors
+                let _ = sock.send(resp_bytes, 0);
             }
             tracing::info!("zmq rpc server for {} has shut down", addr_owned);
         });
@@ -262,25 +253,45 @@ mod zmq_impl {
 
     /// ZMQ-backed client: perform one request-response using REQ socket.
     pub async fn req_once(addr: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        request(addr, payload, None).await
+    }
+
+    /// Like `req_once`, but give up after `timeout`. ZMQ connects lazily, so
+    /// without a timeout a request to an address nobody serves waits forever.
+    pub async fn req_timeout(addr: &str, payload: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        request(addr, payload, Some(timeout)).await
+    }
+
+    async fn request(addr: &str, payload: &[u8], timeout: Option<Duration>) -> Result<Vec<u8>> {
         let addr_owned = addr.to_string();
         let payload_owned = payload.to_vec();
 
         let resp = task::spawn_blocking(move || -> Result<Vec<u8>> {
             let ctx = ZmqContext::new();
             let sock = ctx.socket(zmq::REQ)?;
+            if let Some(timeout) = timeout {
+                let ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+                sock.set_sndtimeo(ms)?;
+                sock.set_rcvtimeo(ms)?;
+                // don't block the context teardown on a request nobody took
+                sock.set_linger(0)?;
+            }
             sock.connect(&addr_owned)?;
             sock.send(&payload_owned, 0)?;
-            let reply = sock.recv_bytes(0)?;
+            let reply = sock.recv_bytes(0).map_err(|e| match e {
+                zmq::Error::EAGAIN => anyhow!("no reply from {} within {:?}", addr_owned, timeout.unwrap_or_default()),
+                e => e.into(),
+            })?;
             Ok(reply)
         })
         .await?;
 
-        resp.map_err(|e| e.into())
+        resp
     }
 }
 
-#[cfg(feature = "with-zmq")]
-pub use zmq_impl::{bind_server as bind_server_zmq, req_once as req_once_zmq};
+#[cfg(any(feature = "with-zmq", feature = "rpc-zmq"))]
+pub use zmq_impl::{bind_server as bind_server_zmq, req_once as req_once_zmq, req_timeout as req_timeout_zmq};
 
 #[cfg(feature = "with-ipc")]
 mod ipc_impl {
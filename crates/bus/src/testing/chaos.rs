@@ -0,0 +1,437 @@
+/*
+Fault-injecting in-memory transport.
+
+ChaosNet is an isolated in-process network (no global registry, unlike
+`pubsub::mem`) that the conformance scenarios drive like any other backend.
+Every delivery is shaped by a FaultPlan:
+
+- latency + jitter: per-delivery delay. Deliveries on one link stay FIFO, the
+  same guarantee a single TCP stream gives the native transports.
+- loss: probability that one delivery (or one RPC leg) is dropped.
+- link_capacity: bounded per-subscriber queue. When a consumer falls behind the
+  overflow is dropped and counted, which is how slow consumers are modelled.
+
+Randomness comes from a seeded xorshift generator that is only advanced under
+the network lock, so a given plan + publish order always produces the same
+drops and delays. Run tests with `#[tokio::test(start_paused = true)]` to make
+the simulated latency instant and deterministic.
+
+`sever(addr)` drops every live subscription on an address mid-stream (the
+subscriber sees its inbox close), and `unbind(addr)` does the same for RPC.
+*/
+
+use super::{unique_suffix, Inbox, Publish, PubSubTransport, RpcHandler, RpcTransport};
+use crate::envelope::{self, Headers};
+use crate::pubsub::Delivery;
+use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+/// Faults applied to every delivery on a ChaosNet.
+#[derive(Debug, Clone)]
+pub struct FaultPlan {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Drop probability in [0.0, 1.0].
+    pub loss: f64,
+    /// Per-subscriber queue size; deliveries beyond it are dropped.
+    pub link_capacity: usize,
+    pub seed: u64,
+}
+
+impl Default for FaultPlan {
+    /// A reliable, zero-latency network with the same queue depth as `pubsub::mem`.
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            link_capacity: 256,
+            seed: 0x5eed_b055,
+        }
+    }
+}
+
+impl FaultPlan {
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_link_capacity(mut self, capacity: usize) -> Self {
+        self.link_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Counters so tests can assert on what the network actually did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub published: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub overflowed: u64,
+    pub severed: u64,
+    pub rpc_lost: u64,
+}
+
+/// xorshift64*: tiny, dependency-free and good enough for fault rolls.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+type Pending = (Instant, String, Vec<u8>);
+
+struct Link {
+    queue: mpsc::UnboundedSender<Pending>,
+    task: AbortHandle,
+    last_due: Instant,
+}
+
+struct State {
+    rng: Rng,
+    // key: "<addr>\0<topic>"
+    links: HashMap<String, Vec<Link>>,
+    servers: HashMap<String, RpcHandler>,
+    stats: ChaosStats,
+}
+
+impl State {
+    /// Roll one delivery: None if it is lost, otherwise its delay.
+    fn roll(&mut self, plan: &FaultPlan) -> Option<Duration> {
+        if plan.loss > 0.0 && self.rng.next_f64() < plan.loss {
+            return None;
+        }
+        let jitter = if plan.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            plan.jitter.mul_f64(self.rng.next_f64())
+        };
+        Some(plan.latency + jitter)
+    }
+}
+
+fn link_key(addr: &str, topic: &str) -> String {
+    format!("{}\0{}", addr, topic)
+}
+
+/// A fault-injecting in-memory network. Cheap to clone; clones share state.
+#[derive(Clone)]
+pub struct ChaosNet {
+    plan: FaultPlan,
+    state: Arc<Mutex<State>>,
+}
+
+impl ChaosNet {
+    pub fn new(plan: FaultPlan) -> Self {
+        let state = State {
+            rng: Rng::new(plan.seed),
+            links: HashMap::new(),
+            servers: HashMap::new(),
+            stats: ChaosStats::default(),
+        };
+        Self { plan, state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub fn plan(&self) -> &FaultPlan {
+        &self.plan
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Drop every live subscription on `addr`, discarding anything in flight.
+    /// Returns the number of links severed.
+    pub fn sever(&self, addr: &str) -> usize {
+        let prefix = format!("{}\0", addr);
+        let mut st = self.state.lock().unwrap();
+        let keys: Vec<String> = st.links.keys().filter(|k| k.starts_with(&prefix)).cloned().collect();
+        let mut severed = 0;
+        for key in keys {
+            for link in st.links.remove(&key).unwrap_or_default() {
+                link.task.abort();
+                severed += 1;
+            }
+        }
+        st.stats.severed += severed as u64;
+        severed
+    }
+
+    fn publish_to(&self, addr: &str, topic: &str, frame: &[u8]) {
+        let mut st = self.state.lock().unwrap();
+        st.stats.published += 1;
+        let Some(mut links) = st.links.remove(&link_key(addr, topic)) else {
+            return;
+        };
+        // forget links whose subscriber went away
+        links.retain(|l| !l.queue.is_closed());
+        for link in links.iter_mut() {
+            match st.roll(&self.plan) {
+                Some(delay) => {
+                    let due = (Instant::now() + delay).max(link.last_due);
+                    link.last_due = due;
+                    let _ = link.queue.send((due, topic.to_string(), frame.to_vec()));
+                }
+                None => st.stats.lost += 1,
+            }
+        }
+        if !links.is_empty() {
+            st.links.insert(link_key(addr, topic), links);
+        }
+    }
+
+    fn connect(&self, addr: &str, topic: &str) -> Inbox {
+        let (tx, rx) = mpsc::channel(self.plan.link_capacity);
+        let (queue, mut pending) = mpsc::unbounded_channel::<Pending>();
+        let state = self.state.clone();
+
+        // One delivery task per link keeps that link FIFO regardless of jitter.
+        let task = tokio::spawn(async move {
+            while let Some((due, topic, frame)) = pending.recv().await {
+                tokio::time::sleep_until(due).await;
+                let Ok((headers, payload)) = envelope::decode(&frame) else { continue };
+                let res = tx.try_send(Delivery { topic, headers, payload: payload.to_vec() });
+                let mut st = state.lock().unwrap();
+                match res {
+                    Ok(()) => st.stats.delivered += 1,
+                    Err(mpsc::error::TrySendError::Full(_)) => st.stats.overflowed += 1,
+                    Err(mpsc::error::TrySendError::Closed(_)) => break,
+                }
+            }
+        });
+
+        let link = Link { queue, task: task.abort_handle(), last_due: Instant::now() };
+        self.state.lock().unwrap().links.entry(link_key(addr, topic)).or_default().push(link);
+        rx
+    }
+}
+
+/// Publisher bound to one ChaosNet address.
+pub struct ChaosPublisher {
+    net: ChaosNet,
+    addr: String,
+}
+
+impl Publish for ChaosPublisher {
+    fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+        // Carry the same envelope frame the socket backends put on the wire.
+        self.net.publish_to(&self.addr, topic, &envelope::encode(headers, payload)?);
+        Ok(())
+    }
+}
+
+impl PubSubTransport for ChaosNet {
+    fn name(&self) -> &'static str {
+        "chaos"
+    }
+
+    fn endpoint(&self) -> String {
+        format!("chaos://{}", unique_suffix())
+    }
+
+    fn bind(&self, addr: &str) -> Result<Box<dyn Publish>> {
+        Ok(Box::new(ChaosPublisher { net: self.clone(), addr: addr.to_string() }))
+    }
+
+    fn subscribe(&self, addr: &str, topic: &str) -> Result<Inbox> {
+        Ok(self.connect(addr, topic))
+    }
+}
+
+impl RpcTransport for ChaosNet {
+    fn name(&self) -> &'static str {
+        "chaos"
+    }
+
+    fn endpoint(&self) -> String {
+        format!("chaos://{}", unique_suffix())
+    }
+
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()> {
+        let mut st = self.state.lock().unwrap();
+        if st.servers.contains_key(addr) {
+            return Err(anyhow!("address already bound: {}", addr));
+        }
+        st.servers.insert(addr.to_string(), handler);
+        Ok(())
+    }
+
+    /// Both legs are rolled up front so the outcome only depends on call order.
+    /// A lost leg surfaces as an error after the time it would have taken,
+    /// the way a timeout would on a real socket.
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let (handler, out, back) = {
+                let mut st = self.state.lock().unwrap();
+                let handler = st
+                    .servers
+                    .get(addr)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("no rpc server bound at {}", addr))?;
+                let out = st.roll(&self.plan);
+                let back = st.roll(&self.plan);
+                if out.is_none() || back.is_none() {
+                    st.stats.rpc_lost += 1;
+                }
+                (handler, out, back)
+            };
+
+            let Some(out) = out else {
+                tokio::time::sleep(self.plan.latency * 2).await;
+                return Err(anyhow!("rpc request to {} lost", addr));
+            };
+            tokio::time::sleep(out).await;
+            let resp = handler(payload.to_vec()).await;
+            match back {
+                Some(back) => {
+                    tokio::time::sleep(back).await;
+                    Ok(resp)
+                }
+                None => Err(anyhow!("rpc response from {} lost", addr)),
+            }
+        })
+    }
+
+    fn unbind(&self, addr: &str) -> bool {
+        self.state.lock().unwrap().servers.remove(addr).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scenarios::{collect, frame};
+
+    const N: u64 = 200;
+
+    async fn publish_and_collect(net: &ChaosNet) -> Result<Vec<u64>> {
+        let addr = PubSubTransport::endpoint(net);
+        let publisher = net.bind(&addr)?;
+        let mut inbox = net.subscribe(&addr, "chaos/loss")?;
+        for seq in 0..N {
+            publisher.publish("chaos/loss", &frame(seq, 16))?;
+        }
+        Ok(collect(&mut inbox, N as usize, Duration::from_secs(1)).await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_delays_delivery_but_keeps_order() -> Result<()> {
+        let net = ChaosNet::new(
+            FaultPlan::default().with_latency(Duration::from_millis(100), Duration::from_millis(80)),
+        );
+        let addr = PubSubTransport::endpoint(&net);
+        let publisher = net.bind(&addr)?;
+        let mut inbox = net.subscribe(&addr, "chaos/latency")?;
+
+        let start = Instant::now();
+        for seq in 0..N {
+            publisher.publish("chaos/latency", &frame(seq, 16))?;
+        }
+        let got = collect(&mut inbox, N as usize, Duration::from_secs(1)).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(got, (0..N).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loss_is_deterministic_per_seed() -> Result<()> {
+        let plan = FaultPlan::default().with_loss(0.3).with_seed(42);
+        let first_net = ChaosNet::new(plan.clone());
+        let first = publish_and_collect(&first_net).await?;
+        let second = publish_and_collect(&ChaosNet::new(plan.clone())).await?;
+        let other = publish_and_collect(&ChaosNet::new(plan.with_seed(7))).await?;
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert!(first.windows(2).all(|w| w[0] < w[1]), "lossy delivery must stay ordered");
+
+        let stats = first_net.stats();
+        assert_eq!(stats.delivered + stats.lost, N);
+        assert_eq!(stats.delivered, first.len() as u64);
+        assert!(stats.lost > 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_consumer_overflow_is_counted() -> Result<()> {
+        let net = ChaosNet::new(FaultPlan::default().with_link_capacity(8));
+        let addr = PubSubTransport::endpoint(&net);
+        let publisher = net.bind(&addr)?;
+        let mut inbox = net.subscribe(&addr, "chaos/slow")?;
+        for seq in 0..N {
+            publisher.publish("chaos/slow", &frame(seq, 16))?;
+            tokio::task::yield_now().await;
+        }
+        let got = collect(&mut inbox, N as usize, Duration::from_millis(200)).await;
+
+        assert_eq!(got, (0..8).collect::<Vec<_>>());
+        let stats = net.stats();
+        assert_eq!(stats.delivered, 8);
+        assert_eq!(stats.overflowed, N - 8);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sever_closes_subscribers() -> Result<()> {
+        let net = ChaosNet::new(FaultPlan::default().with_latency(Duration::from_millis(50), Duration::ZERO));
+        let addr = PubSubTransport::endpoint(&net);
+        let publisher = net.bind(&addr)?;
+        let mut a = net.subscribe(&addr, "chaos/sever")?;
+        let mut b = net.subscribe(&addr, "chaos/other")?;
+        publisher.publish("chaos/sever", &frame(0, 16))?;
+
+        assert_eq!(net.sever(&addr), 2);
+        // the in-flight message is discarded and the inbox closes
+        assert!(a.recv().await.is_none());
+        assert!(b.recv().await.is_none());
+        // publishing into a severed address is still fine
+        publisher.publish("chaos/sever", &frame(1, 16))?;
+        assert_eq!(net.stats().severed, 2);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpc_loss_surfaces_as_error() -> Result<()> {
+        let net = ChaosNet::new(FaultPlan::default().with_loss(1.0));
+        let addr = RpcTransport::endpoint(&net);
+        net.serve(&addr, crate::testing::handler(|req| async move { req }))?;
+        assert!(net.request(&addr, b"ping").await.is_err());
+        assert_eq!(net.stats().rpc_lost, 1);
+        Ok(())
+    }
+}
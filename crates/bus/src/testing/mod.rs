@@ -0,0 +1,377 @@
+/*
+Transport conformance + chaos harness.
+
+Every bus backend must behave the same from a caller's point of view, so this
+module describes a backend through two small traits and runs one shared set of
+scenarios against each of them:
+
+- PubSubTransport: bind a publisher, subscribe to a topic, hand out fresh
+  endpoints so scenarios never collide.
+- RpcTransport: serve a handler at an address, send a request, unbind.
+
+Adapters are provided for every backend compiled into the crate:
+
+- Mem       — the default in-process broadcast/registry implementation.
+- Zmq / Nng — native socket transports (behind with-zmq / with-nng).
+- Ipc       — interprocess local sockets for RPC (behind with-ipc).
+- ChaosNet  — a fault-injecting in-memory network with deterministic latency,
+  jitter, loss and bounded per-subscriber queues (see `chaos`).
+
+Scenarios live in `scenarios` (ordering, fan-out, slow consumers, large
+payloads, publisher restart, dropped connections, and the RPC equivalents).
+`scenarios::run_pubsub_suite` / `scenarios::run_rpc_suite` run all of them.
+
+The module is compiled for this crate's own tests and, for downstream crates,
+behind the `testing` feature:
+
+    [dev-dependencies]
+    bus = { path = "../bus", features = ["testing"] }
+*/
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::envelope::{self, Headers};
+use crate::pubsub::Delivery;
+
+pub mod chaos;
+pub mod scenarios;
+
+pub use chaos::{ChaosNet, ChaosStats, FaultPlan};
+
+/// Receiving side of a subscription, as handed out by every backend.
+pub type Inbox = mpsc::Receiver<Delivery>;
+
+/// Type-erased async RPC handler so transports can be used as trait objects.
+pub type RpcHandler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Vec<u8>> + Send + Sync>;
+
+/// Wrap an async closure into an `RpcHandler`.
+pub fn handler<F, Fut>(f: F) -> RpcHandler
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Vec<u8>> + Send + 'static,
+{
+    Arc::new(move |req| Box::pin(f(req)))
+}
+
+/// Publishing half of a pub/sub backend.
+pub trait Publish: Send + Sync {
+    fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()>;
+
+    fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_with(topic, &Headers::new(envelope::CONTENT_TYPE_OCTET), payload)
+    }
+}
+
+/// A pub/sub backend under test.
+pub trait PubSubTransport: Send + Sync {
+    /// Short name used in failure messages.
+    fn name(&self) -> &'static str;
+    /// A fresh address no other scenario is using.
+    fn endpoint(&self) -> String;
+    fn bind(&self, addr: &str) -> Result<Box<dyn Publish>>;
+    fn subscribe(&self, addr: &str, topic: &str) -> Result<Inbox>;
+}
+
+/// What an RPC backend is able to do; scenarios skip checks a backend can't support.
+#[derive(Debug, Clone, Copy)]
+pub struct RpcCapabilities {
+    /// The server can be unbound and another one bound at the same address.
+    pub unbind: bool,
+    /// A request to an address with no server fails instead of blocking forever.
+    pub unbound_errors: bool,
+}
+
+/// An RPC backend under test.
+pub trait RpcTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn endpoint(&self) -> String;
+    fn capabilities(&self) -> RpcCapabilities {
+        RpcCapabilities { unbind: true, unbound_errors: true }
+    }
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()>;
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>>;
+    /// Unbind the server at `addr`. Returns true if one was removed.
+    fn unbind(&self, addr: &str) -> bool;
+}
+
+static ENDPOINT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Process-unique suffix for endpoints and topics.
+pub(crate) fn unique_suffix() -> String {
+    format!("{}-{}", std::process::id(), ENDPOINT_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Reserve a free localhost TCP port for socket-based backends.
+#[allow(dead_code)]
+fn free_tcp_endpoint() -> String {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .unwrap_or(0);
+    format!("tcp://127.0.0.1:{}", port)
+}
+
+/// The default in-process backend (`pubsub::mem` + the rpc registry).
+pub struct Mem;
+
+impl Publish for crate::pubsub::mem::Publisher {
+    fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+        crate::pubsub::mem::Publisher::publish_with(self, topic, headers, payload)
+    }
+}
+
+impl PubSubTransport for Mem {
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    fn endpoint(&self) -> String {
+        format!("mem://{}", unique_suffix())
+    }
+
+    fn bind(&self, addr: &str) -> Result<Box<dyn Publish>> {
+        Ok(Box::new(crate::pubsub::mem::Publisher::bind(addr)?))
+    }
+
+    fn subscribe(&self, addr: &str, topic: &str) -> Result<Inbox> {
+        Ok(crate::pubsub::mem::Subscriber::connect(addr, topic)?.into_deliveries())
+    }
+}
+
+impl RpcTransport for Mem {
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    fn endpoint(&self) -> String {
+        format!("mem://{}", unique_suffix())
+    }
+
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()> {
+        crate::rpc::bind_server(addr, move |req| handler(req))
+    }
+
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(crate::rpc::req_once(addr, payload))
+    }
+
+    fn unbind(&self, addr: &str) -> bool {
+        crate::rpc::unbind_server(addr)
+    }
+}
+
+/// ZeroMQ PUB/SUB and REQ/REP (feature "with-zmq").
+#[cfg(feature = "with-zmq")]
+pub struct Zmq;
+
+// zmq sockets are Send but not Sync, so the publisher is serialised behind a lock.
+#[cfg(all(feature = "with-zmq", not(feature = "with-nng")))]
+impl Publish for std::sync::Mutex<crate::pubsub::zmq_impl::Publisher> {
+    fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+        self.lock().unwrap().publish_with(topic, headers, payload)
+    }
+}
+
+#[cfg(all(feature = "with-zmq", not(feature = "with-nng")))]
+impl PubSubTransport for Zmq {
+    fn name(&self) -> &'static str {
+        "zmq"
+    }
+
+    fn endpoint(&self) -> String {
+        free_tcp_endpoint()
+    }
+
+    fn bind(&self, addr: &str) -> Result<Box<dyn Publish>> {
+        Ok(Box::new(std::sync::Mutex::new(crate::pubsub::zmq_impl::Publisher::bind(addr)?)))
+    }
+
+    fn subscribe(&self, addr: &str, topic: &str) -> Result<Inbox> {
+        Ok(crate::pubsub::zmq_impl::Subscriber::connect(addr, topic)?.into_deliveries())
+    }
+}
+
+#[cfg(feature = "with-zmq")]
+impl RpcTransport for Zmq {
+    fn name(&self) -> &'static str {
+        "zmq"
+    }
+
+    fn endpoint(&self) -> String {
+        free_tcp_endpoint()
+    }
+
+    // The REP thread runs until process exit and REQ blocks until a peer shows up.
+    fn capabilities(&self) -> RpcCapabilities {
+        RpcCapabilities { unbind: false, unbound_errors: false }
+    }
+
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()> {
+        crate::rpc::bind_server_zmq(addr, move |req| handler(req))
+    }
+
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(crate::rpc::req_once_zmq(addr, payload))
+    }
+
+    fn unbind(&self, _addr: &str) -> bool {
+        false
+    }
+}
+
+/// NNG PUB/SUB and REQ/REP (feature "with-nng").
+#[cfg(feature = "with-nng")]
+pub struct Nng;
+
+#[cfg(feature = "with-nng")]
+impl Publish for crate::pubsub::nng_impl::Publisher {
+    fn publish_with(&self, topic: &str, headers: &Headers, payload: &[u8]) -> Result<()> {
+        crate::pubsub::nng_impl::Publisher::publish_with(self, topic, headers, payload)
+    }
+}
+
+#[cfg(feature = "with-nng")]
+impl PubSubTransport for Nng {
+    fn name(&self) -> &'static str {
+        "nng"
+    }
+
+    fn endpoint(&self) -> String {
+        free_tcp_endpoint()
+    }
+
+    fn bind(&self, addr: &str) -> Result<Box<dyn Publish>> {
+        Ok(Box::new(crate::pubsub::nng_impl::Publisher::bind(addr)?))
+    }
+
+    fn subscribe(&self, addr: &str, topic: &str) -> Result<Inbox> {
+        Ok(crate::pubsub::nng_impl::Subscriber::connect(addr, topic)?.into_deliveries())
+    }
+}
+
+#[cfg(feature = "with-nng")]
+impl RpcTransport for Nng {
+    fn name(&self) -> &'static str {
+        "nng"
+    }
+
+    fn endpoint(&self) -> String {
+        free_tcp_endpoint()
+    }
+
+    fn capabilities(&self) -> RpcCapabilities {
+        RpcCapabilities { unbind: false, unbound_errors: true }
+    }
+
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()> {
+        crate::rpc::bind_server_nng(addr, move |req| handler(req))
+    }
+
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(crate::rpc::req_once_nng(addr, payload))
+    }
+
+    fn unbind(&self, _addr: &str) -> bool {
+        false
+    }
+}
+
+/// Interprocess local-socket RPC (feature "with-ipc").
+#[cfg(feature = "with-ipc")]
+pub struct Ipc;
+
+#[cfg(feature = "with-ipc")]
+impl RpcTransport for Ipc {
+    fn name(&self) -> &'static str {
+        "ipc"
+    }
+
+    fn endpoint(&self) -> String {
+        format!("bus-conformance-{}", unique_suffix())
+    }
+
+    fn capabilities(&self) -> RpcCapabilities {
+        RpcCapabilities { unbind: false, unbound_errors: true }
+    }
+
+    fn serve(&self, addr: &str, handler: RpcHandler) -> Result<()> {
+        crate::rpc::bind_server_ipc(addr, move |req| handler(req))
+    }
+
+    fn request<'a>(&'a self, addr: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(crate::rpc::req_once_ipc(addr, payload))
+    }
+
+    fn unbind(&self, _addr: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn mem_pubsub_conformance() -> Result<()> {
+        scenarios::run_pubsub_suite(&Mem).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn mem_rpc_conformance() -> Result<()> {
+        scenarios::run_rpc_suite(&Mem).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chaos_reliable_pubsub_conformance() -> Result<()> {
+        scenarios::run_pubsub_suite(&ChaosNet::new(FaultPlan::default())).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chaos_latency_pubsub_conformance() -> Result<()> {
+        let plan = FaultPlan::default()
+            .with_latency(Duration::from_millis(40), Duration::from_millis(25))
+            .with_link_capacity(4096);
+        scenarios::run_pubsub_suite(&ChaosNet::new(plan)).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chaos_rpc_conformance() -> Result<()> {
+        let plan = FaultPlan::default().with_latency(Duration::from_millis(15), Duration::from_millis(10));
+        scenarios::run_rpc_suite(&ChaosNet::new(plan)).await
+    }
+
+    #[cfg(all(feature = "with-zmq", not(feature = "with-nng")))]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn zmq_pubsub_conformance() -> Result<()> {
+        scenarios::run_pubsub_suite(&Zmq).await
+    }
+
+    #[cfg(feature = "with-zmq")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn zmq_rpc_conformance() -> Result<()> {
+        scenarios::run_rpc_suite(&Zmq).await
+    }
+
+    #[cfg(feature = "with-nng")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn nng_pubsub_conformance() -> Result<()> {
+        scenarios::run_pubsub_suite(&Nng).await
+    }
+
+    #[cfg(feature = "with-nng")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn nng_rpc_conformance() -> Result<()> {
+        scenarios::run_rpc_suite(&Nng).await
+    }
+
+    #[cfg(feature = "with-ipc")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn ipc_rpc_conformance() -> Result<()> {
+        scenarios::run_rpc_suite(&Ipc).await
+    }
+}
@@ -0,0 +1,498 @@
+/*
+Conformance scenarios shared by every backend.
+
+Each scenario takes a transport, uses its own fresh endpoint and topic, and
+returns an error describing the first violation it finds. Data frames carry an
+8-byte big-endian sequence number followed by a deterministic filler so
+ordering, gaps and corruption are all detectable. Empty payloads are readiness
+probes and are ignored by the receivers.
+
+Socket transports need a moment before a new subscription or reconnect takes
+effect ("slow joiner"), so scenarios wait for a probe to round-trip before
+publishing data instead of sleeping for a fixed time.
+*/
+
+use super::{handler, Inbox, Publish, PubSubTransport, RpcTransport};
+use crate::envelope::{self, Headers};
+use crate::pubsub::Delivery;
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Idle timeout while waiting for the next expected message.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a subscriber/server may take to become reachable.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Build a data frame of `len` bytes (at least 8) for `seq`.
+pub fn frame(seq: u64, len: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(len.max(8));
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend((8..len).map(|i| (seq as usize).wrapping_add(i) as u8));
+    buf
+}
+
+/// Decode a data frame, verifying its filler. Probes (empty payloads) yield None.
+pub fn parse(payload: &[u8]) -> Result<Option<u64>> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() < 8 {
+        bail!("short frame: {} bytes", payload.len());
+    }
+    let seq = u64::from_be_bytes(payload[..8].try_into().unwrap());
+    if payload != frame(seq, payload.len()).as_slice() {
+        bail!("frame {} corrupted ({} bytes)", seq, payload.len());
+    }
+    Ok(Some(seq))
+}
+
+/// Receive up to `n` data frames, giving up after `idle` without progress.
+/// Probes and corrupted frames are skipped (corruption is reported by `expect_seq`).
+pub async fn collect(inbox: &mut Inbox, n: usize, idle: Duration) -> Vec<u64> {
+    let mut out = Vec::with_capacity(n);
+    while out.len() < n {
+        match timeout(idle, inbox.recv()).await {
+            Ok(Some(d)) => {
+                if let Ok(Some(seq)) = parse(&d.payload) {
+                    out.push(seq);
+                }
+            }
+            _ => break,
+        }
+    }
+    out
+}
+
+/// Wait for the next non-probe payload.
+async fn next_frame(t: &str, what: &str, inbox: &mut Inbox) -> Result<Delivery> {
+    timeout(RECV_TIMEOUT, async {
+        loop {
+            match inbox.recv().await {
+                Some(d) if !d.payload.is_empty() => return Some(d),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("{}: {}: timed out waiting for a frame", t, what))?
+    .ok_or_else(|| anyhow!("{}: {}: inbox closed", t, what))
+}
+
+/// Receive exactly the frames in `expected`, in order.
+async fn expect_seq(t: &str, what: &str, inbox: &mut Inbox, expected: std::ops::Range<u64>) -> Result<()> {
+    for want in expected.clone() {
+        let payload = next_frame(t, what, inbox)
+            .await
+            .with_context(|| format!("expecting frame {} of {:?}", want, expected))?
+            .payload;
+        let got = parse(&payload).with_context(|| format!("{}: {}", t, what))?;
+        if got != Some(want) {
+            bail!("{}: {}: expected frame {}, got {:?}", t, what, want, got);
+        }
+    }
+    Ok(())
+}
+
+/// Run `expect_seq` on a background task so the inbox is drained while publishing.
+fn spawn_expect(
+    t: &'static str,
+    what: &'static str,
+    mut inbox: Inbox,
+    expected: std::ops::Range<u64>,
+) -> tokio::task::JoinHandle<(Result<()>, Inbox)> {
+    tokio::spawn(async move {
+        let res = expect_seq(t, what, &mut inbox, expected).await;
+        (res, inbox)
+    })
+}
+
+/// Publish probes until every inbox has seen one, then drain them.
+async fn await_ready(t: &str, publisher: &dyn Publish, topic: &str, inboxes: &mut [&mut Inbox]) -> Result<()> {
+    for inbox in inboxes.iter_mut() {
+        let ready = timeout(READY_TIMEOUT, async {
+            loop {
+                publisher.publish(topic, &[])?;
+                if let Ok(Some(_)) = timeout(PROBE_INTERVAL, inbox.recv()).await {
+                    return Ok::<_, anyhow::Error>(());
+                }
+            }
+        })
+        .await;
+        match ready {
+            Ok(res) => res?,
+            Err(_) => bail!("{}: subscriber on {} never became ready", t, topic),
+        }
+    }
+    // Late probes are harmless (receivers skip them) but drain what is queued.
+    for inbox in inboxes.iter_mut() {
+        while let Ok(d) = inbox.try_recv() {
+            if !d.payload.is_empty() {
+                bail!("{}: unexpected data frame while probing {}", t, topic);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn publish_range(publisher: &dyn Publish, topic: &str, range: std::ops::Range<u64>, len: usize) -> Result<()> {
+    for seq in range {
+        publisher.publish(topic, &frame(seq, len))?;
+    }
+    Ok(())
+}
+
+fn topic(name: &str) -> String {
+    format!("conformance/{}/{}", name, super::unique_suffix())
+}
+
+/// Bind a publisher, retrying briefly while a previous owner releases the address.
+async fn bind_retry(t: &dyn PubSubTransport, addr: &str) -> Result<Box<dyn Publish>> {
+    let mut last = None;
+    for _ in 0..50 {
+        match t.bind(addr) {
+            Ok(p) => return Ok(p),
+            Err(e) => last = Some(e),
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Err(last.unwrap_or_else(|| anyhow!("bind failed")).context(format!("{}: rebinding {}", t.name(), addr)))
+}
+
+/// Envelope headers arrive unchanged, and plain publishes get a fresh message id.
+pub async fn headers(t: &dyn PubSubTransport) -> Result<()> {
+    let addr = t.endpoint();
+    let topic = topic("headers");
+    let publisher = t.bind(&addr)?;
+    let mut inbox = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut inbox]).await?;
+
+    let sent = Headers::capnp()
+        .with_schema_version(7)
+        .with_traceparent(Some(&envelope::new_traceparent()));
+    publisher.publish_with(&topic, &sent, &frame(0, 32))?;
+    publisher.publish(&topic, &frame(1, 32))?;
+
+    let first = next_frame(t.name(), "headers", &mut inbox).await?;
+    if first.headers != sent {
+        bail!("{}: headers: sent {:?}, got {:?}", t.name(), sent, first.headers);
+    }
+    if first.topic != topic || parse(&first.payload)? != Some(0) {
+        bail!("{}: headers: wrong topic or payload for frame 0", t.name());
+    }
+    let second = next_frame(t.name(), "headers", &mut inbox).await?;
+    if second.headers.content_type != envelope::CONTENT_TYPE_OCTET
+        || second.headers.message_id.is_empty()
+        || second.headers.message_id == first.headers.message_id
+    {
+        bail!("{}: headers: default headers look wrong: {:?}", t.name(), second.headers);
+    }
+    Ok(())
+}
+
+/// A single subscriber sees every frame exactly once and in publish order.
+pub async fn ordering(t: &dyn PubSubTransport) -> Result<()> {
+    const N: u64 = 500;
+    let addr = t.endpoint();
+    let topic = topic("ordering");
+    let publisher = t.bind(&addr)?;
+    let mut inbox = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut inbox]).await?;
+
+    let reader = spawn_expect(t.name(), "ordering", inbox, 0..N);
+    for seq in 0..N {
+        publisher.publish(&topic, &frame(seq, 32))?;
+        if seq % 32 == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+    reader.await?.0
+}
+
+/// Every subscriber on a topic receives the full stream.
+pub async fn fan_out(t: &dyn PubSubTransport) -> Result<()> {
+    const N: u64 = 200;
+    const SUBSCRIBERS: usize = 4;
+    let addr = t.endpoint();
+    let topic = topic("fan-out");
+    let publisher = t.bind(&addr)?;
+    let mut inboxes: Vec<Inbox> = (0..SUBSCRIBERS).map(|_| t.subscribe(&addr, &topic)).collect::<Result<_>>()?;
+    {
+        let mut refs: Vec<&mut Inbox> = inboxes.iter_mut().collect();
+        await_ready(t.name(), publisher.as_ref(), &topic, &mut refs).await?;
+    }
+
+    publish_range(publisher.as_ref(), &topic, 0..N, 32)?;
+    for (i, inbox) in inboxes.iter_mut().enumerate() {
+        expect_seq(t.name(), &format!("fan-out subscriber {}", i), inbox, 0..N).await?;
+    }
+    Ok(())
+}
+
+/// A consumer that stops reading must not stall the publisher or its peers.
+/// Whatever the slow consumer eventually gets must still be in order.
+pub async fn slow_consumer(t: &dyn PubSubTransport) -> Result<()> {
+    const N: u64 = 2000;
+    let addr = t.endpoint();
+    let topic = topic("slow-consumer");
+    let publisher = t.bind(&addr)?;
+    let mut fast = t.subscribe(&addr, &topic)?;
+    let mut slow = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut fast, &mut slow]).await?;
+
+    // Pace the publisher to the fast peer only: it may run at most WINDOW frames
+    // ahead of it, while the slow peer never reads until the end.
+    const WINDOW: u64 = 128;
+    let progress = Arc::new(AtomicU64::new(0));
+    let name = t.name();
+    let seen = progress.clone();
+    let reader = tokio::spawn(async move {
+        for want in 0..N {
+            let payload = next_frame(name, "slow-consumer fast peer", &mut fast).await?.payload;
+            let got = parse(&payload).with_context(|| format!("{}: slow-consumer fast peer", name))?;
+            if got != Some(want) {
+                bail!("{}: slow-consumer fast peer: expected frame {}, got {:?}", name, want, got);
+            }
+            seen.store(want + 1, Ordering::Release);
+        }
+        Ok(())
+    });
+
+    timeout(RECV_TIMEOUT, async {
+        for seq in 0..N {
+            while seq >= progress.load(Ordering::Acquire) + WINDOW {
+                if reader.is_finished() {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            publisher.publish(&topic, &frame(seq, 64))?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await
+    .map_err(|_| anyhow!("{}: slow-consumer: publisher stalled behind a slow subscriber", t.name()))??;
+
+    reader.await??;
+
+    let got = collect(&mut slow, N as usize, Duration::from_millis(500)).await;
+    if got.is_empty() {
+        bail!("{}: slow-consumer: slow subscriber received nothing", t.name());
+    }
+    if let Some(w) = got.windows(2).find(|w| w[0] >= w[1]) {
+        bail!("{}: slow-consumer: slow subscriber saw {} after {}", t.name(), w[1], w[0]);
+    }
+    Ok(())
+}
+
+/// Payloads well above typical MTU/frame sizes arrive intact.
+pub async fn large_payloads(t: &dyn PubSubTransport) -> Result<()> {
+    const SIZES: [usize; 3] = [64 * 1024, 512 * 1024, 1024 * 1024];
+    let addr = t.endpoint();
+    let topic = topic("large-payloads");
+    let publisher = t.bind(&addr)?;
+    let mut inbox = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut inbox]).await?;
+
+    for (seq, len) in SIZES.iter().enumerate() {
+        publisher.publish(&topic, &frame(seq as u64, *len))?;
+    }
+    for (seq, len) in SIZES.iter().enumerate() {
+        let payload = next_frame(t.name(), "large-payloads", &mut inbox).await?.payload;
+        if payload.len() != *len {
+            bail!("{}: large-payloads: expected {} bytes, got {}", t.name(), len, payload.len());
+        }
+        let got = parse(&payload).with_context(|| format!("{}: large-payloads", t.name()))?;
+        if got != Some(seq as u64) {
+            bail!("{}: large-payloads: expected frame {}, got {:?}", t.name(), seq, got);
+        }
+    }
+    Ok(())
+}
+
+/// Existing subscribers pick up a publisher that is restarted on the same address.
+pub async fn publisher_restart(t: &dyn PubSubTransport) -> Result<()> {
+    let addr = t.endpoint();
+    let topic = topic("publisher-restart");
+    let mut inbox = t.subscribe(&addr, &topic)?;
+
+    let first = t.bind(&addr)?;
+    await_ready(t.name(), first.as_ref(), &topic, &mut [&mut inbox]).await?;
+    publish_range(first.as_ref(), &topic, 0..50, 32)?;
+    expect_seq(t.name(), "before restart", &mut inbox, 0..50).await?;
+    drop(first);
+
+    let second = bind_retry(t, &addr).await?;
+    await_ready(t.name(), second.as_ref(), &topic, &mut [&mut inbox]).await?;
+    publish_range(second.as_ref(), &topic, 50..100, 32)?;
+    expect_seq(t.name(), "after restart", &mut inbox, 50..100).await
+}
+
+/// Subscribers that disconnect mid-stream don't affect the publisher or the
+/// remaining subscribers, and a fresh subscriber can join afterwards.
+pub async fn dropped_connections(t: &dyn PubSubTransport) -> Result<()> {
+    let addr = t.endpoint();
+    let topic = topic("dropped-connections");
+    let publisher = t.bind(&addr)?;
+    let mut keep = t.subscribe(&addr, &topic)?;
+    let mut gone = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut keep, &mut gone]).await?;
+
+    publish_range(publisher.as_ref(), &topic, 0..50, 32)?;
+    expect_seq(t.name(), "before drop", &mut gone, 0..50).await?;
+    drop(gone);
+    tokio::task::yield_now().await;
+
+    publish_range(publisher.as_ref(), &topic, 50..100, 32)
+        .with_context(|| format!("{}: publishing after a subscriber dropped", t.name()))?;
+
+    let mut joined = t.subscribe(&addr, &topic)?;
+    await_ready(t.name(), publisher.as_ref(), &topic, &mut [&mut joined]).await?;
+    publish_range(publisher.as_ref(), &topic, 100..150, 32)?;
+
+    expect_seq(t.name(), "surviving subscriber", &mut keep, 0..150).await?;
+    expect_seq(t.name(), "rejoined subscriber", &mut joined, 100..150).await
+}
+
+/// Run every pub/sub scenario against `t`.
+pub async fn run_pubsub_suite(t: &dyn PubSubTransport) -> Result<()> {
+    headers(t).await?;
+    ordering(t).await?;
+    fan_out(t).await?;
+    slow_consumer(t).await?;
+    large_payloads(t).await?;
+    publisher_restart(t).await?;
+    dropped_connections(t).await?;
+    Ok(())
+}
+
+/// Send requests until the server at `addr` answers (servers may bind lazily).
+async fn await_server(t: &dyn RpcTransport, addr: &str) -> Result<()> {
+    timeout(READY_TIMEOUT, async {
+        loop {
+            match timeout(RECV_TIMEOUT, t.request(addr, &[])).await {
+                Ok(Ok(_)) => return,
+                _ => tokio::time::sleep(PROBE_INTERVAL).await,
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("{}: rpc server at {} never became ready", t.name(), addr))
+}
+
+async fn request(t: &dyn RpcTransport, addr: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    timeout(RECV_TIMEOUT, t.request(addr, payload))
+        .await
+        .map_err(|_| anyhow!("{}: rpc request to {} timed out", t.name(), addr))?
+}
+
+fn prefixed(prefix: &'static [u8]) -> super::RpcHandler {
+    handler(move |req: Vec<u8>| async move {
+        let mut resp = prefix.to_vec();
+        resp.extend_from_slice(&req);
+        resp
+    })
+}
+
+/// Sequential requests each get their own response.
+pub async fn rpc_roundtrip(t: &dyn RpcTransport) -> Result<()> {
+    let addr = t.endpoint();
+    t.serve(&addr, prefixed(b"ack:"))?;
+    await_server(t, &addr).await?;
+    for seq in 0..50u64 {
+        let req = frame(seq, 24);
+        let resp = request(t, &addr, &req).await?;
+        if resp[..4] != *b"ack:" || resp[4..] != req[..] {
+            bail!("{}: rpc-roundtrip: wrong response for request {}", t.name(), seq);
+        }
+    }
+    Ok(())
+}
+
+/// Concurrent requests are answered and never cross-wired.
+pub async fn rpc_concurrent(t: &dyn RpcTransport) -> Result<()> {
+    const N: u64 = 32;
+    let addr = t.endpoint();
+    t.serve(&addr, prefixed(b""))?;
+    await_server(t, &addr).await?;
+
+    let reqs: Vec<Vec<u8>> = (0..N).map(|seq| frame(seq, 48)).collect();
+    let resps = futures_util::future::join_all(reqs.iter().map(|r| request(t, &addr, r))).await;
+    for (seq, resp) in resps.into_iter().enumerate() {
+        let resp = resp?;
+        if parse(&resp)? != Some(seq as u64) {
+            bail!("{}: rpc-concurrent: request {} got response {:?}", t.name(), seq, parse(&resp)?);
+        }
+    }
+    Ok(())
+}
+
+/// Megabyte-sized requests and responses survive the transport.
+pub async fn rpc_large_payload(t: &dyn RpcTransport) -> Result<()> {
+    let addr = t.endpoint();
+    t.serve(&addr, prefixed(b""))?;
+    await_server(t, &addr).await?;
+    let req = frame(7, 1024 * 1024);
+    let resp = request(t, &addr, &req).await?;
+    if resp != req {
+        bail!("{}: rpc-large-payload: response differs ({} bytes vs {})", t.name(), resp.len(), req.len());
+    }
+    Ok(())
+}
+
+/// A server can be replaced at the same address and clients follow it.
+pub async fn rpc_server_restart(t: &dyn RpcTransport) -> Result<()> {
+    if !t.capabilities().unbind {
+        return Ok(());
+    }
+    let addr = t.endpoint();
+    t.serve(&addr, prefixed(b"v1:"))?;
+    await_server(t, &addr).await?;
+    if !request(t, &addr, b"x").await?.starts_with(b"v1:") {
+        bail!("{}: rpc-server-restart: first server did not answer", t.name());
+    }
+    if !t.unbind(&addr) {
+        bail!("{}: rpc-server-restart: unbind reported no server", t.name());
+    }
+    t.serve(&addr, prefixed(b"v2:"))?;
+    await_server(t, &addr).await?;
+    if !request(t, &addr, b"x").await?.starts_with(b"v2:") {
+        bail!("{}: rpc-server-restart: restarted server did not answer", t.name());
+    }
+    Ok(())
+}
+
+/// Requests to an address with no server (never bound, or unbound) fail.
+pub async fn rpc_dropped_connection(t: &dyn RpcTransport) -> Result<()> {
+    let caps = t.capabilities();
+    if !caps.unbound_errors {
+        return Ok(());
+    }
+    let addr = t.endpoint();
+    if request(t, &addr, b"x").await.is_ok() {
+        bail!("{}: rpc-dropped-connection: request to unbound {} succeeded", t.name(), addr);
+    }
+    if caps.unbind {
+        t.serve(&addr, prefixed(b""))?;
+        await_server(t, &addr).await?;
+        t.unbind(&addr);
+        if request(t, &addr, b"x").await.is_ok() {
+            bail!("{}: rpc-dropped-connection: request after unbind succeeded", t.name());
+        }
+    }
+    Ok(())
+}
+
+/// Run every RPC scenario against `t`.
+pub async fn run_rpc_suite(t: &dyn RpcTransport) -> Result<()> {
+    rpc_roundtrip(t).await?;
+    rpc_concurrent(t).await?;
+    rpc_large_payload(t).await?;
+    rpc_server_restart(t).await?;
+    rpc_dropped_connection(t).await?;
+    Ok(())
+}
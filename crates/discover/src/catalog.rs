@@ -0,0 +1,57 @@
+//! Static service catalog: a JSON array of `ServiceEntry` on disk.
+//!
+//! `watch` polls the file's modification time and size and reloads the
+//! registry's catalog instances when either changes. A file that fails to parse
+//! is logged and ignored so a half-written edit never empties the catalog; a
+//! deleted file does empty it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::registry::Registry;
+use crate::ServiceEntry;
+
+/// Where `get_services` and the gateway look for the catalog by default.
+pub const DEFAULT_PATH: &str = "./data/discover.json";
+
+/// Parse the catalog at `path`.
+pub fn read(path: &Path) -> Result<Vec<ServiceEntry>> {
+    let s = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Load `path` into `registry` now. A missing file loads an empty catalog.
+pub fn load(registry: &Registry, path: &Path) -> Result<usize> {
+    if !path.exists() {
+        return Ok(registry.load_catalog(Vec::new()));
+    }
+    Ok(registry.load_catalog(read(path)?))
+}
+
+/// Reload `path` into `registry` whenever it changes, checking every `interval`.
+/// Spawn this on the runtime after an initial `load`.
+pub async fn watch(registry: Arc<Registry>, path: PathBuf, interval: Duration) {
+    let mut last = stamp(&path);
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        let current = stamp(&path);
+        if current == last {
+            continue;
+        }
+        last = current;
+        match load(&registry, &path) {
+            Ok(n) => tracing::info!(path = %path.display(), instances = n, "service catalog reloaded"),
+            Err(e) => tracing::warn!(path = %path.display(), "service catalog not reloaded: {:#}", e),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! Consul backend.
+//!
+//! Reads use the HTTP API's blocking queries: each watcher repeats
+//! `GET /v1/health/service/<name>?index=N&wait=..` with the last
+//! `X-Consul-Index`, so a request returns as soon as something changes (or the
+//! wait elapses). Results are cached in the `Registry` with the health Consul
+//! reports, so `resolve` keeps answering from the last known state while Consul
+//! is unreachable; failed queries back off up to `MAX_BACKOFF`.
+//!
+//! Without configured `services`, `/v1/catalog/services` is watched the same
+//! way and a health watcher is started or stopped per service name.
+//!
+//! Writes go to the local agent: `register` adds a service with a TTL check that
+//! `pass` keeps alive, and Consul drops the service if it stays critical.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::registry::{Health, Registry, Source};
+use crate::ServiceEntry;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct ConsulConfig {
+    /// Agent HTTP address, e.g. `http://127.0.0.1:8500`.
+    pub addr: String,
+    /// ACL token sent as `X-Consul-Token`.
+    pub token: Option<String>,
+    pub datacenter: Option<String>,
+    /// Services to watch; all services in the catalog if empty.
+    pub services: Vec<String>,
+    /// How long a blocking query may wait for a change.
+    pub wait: Duration,
+    /// Minimum time between two queries of the same watcher.
+    pub min_interval: Duration,
+}
+
+impl Default for ConsulConfig {
+    fn default() -> Self {
+        Self {
+            addr: "http://127.0.0.1:8500".to_string(),
+            token: None,
+            datacenter: None,
+            services: Vec::new(),
+            wait: Duration::from_secs(60),
+            min_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: Node,
+    service: Service,
+    #[serde(default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    node: String,
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(rename = "ID")]
+    id: String,
+    service: String,
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    meta: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    status: String,
+}
+
+pub struct Consul {
+    cfg: ConsulConfig,
+    http: reqwest::Client,
+}
+
+impl Consul {
+    pub fn new(cfg: ConsulConfig) -> Result<Self> {
+        if !cfg.addr.starts_with("http://") && !cfg.addr.starts_with("https://") {
+            bail!("consul address {:?} must start with http:// or https://", cfg.addr);
+        }
+        Ok(Self { http: reqwest::Client::new(), cfg })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let mut req = self.http.request(method, format!("{}{}", self.cfg.addr.trim_end_matches('/'), path));
+        if let Some(token) = &self.cfg.token {
+            req = req.header("X-Consul-Token", token);
+        }
+        if let Some(dc) = &self.cfg.datacenter {
+            req = req.query(&[("dc", dc)]);
+        }
+        req
+    }
+
+    // GET `path` as a blocking query from `index`; returns the body and the new index.
+    async fn blocking_get(&self, path: &str, index: u64) -> Result<(Vec<u8>, u64)> {
+        let mut req = self.request(reqwest::Method::GET, path);
+        if index > 0 {
+            let wait = format!("{}s", self.cfg.wait.as_secs().max(1));
+            req = req.query(&[("index", index.to_string()), ("wait", wait)]);
+        }
+        // Consul adds up to wait/16 of jitter to the wait
+        let resp = req.timeout(self.cfg.wait + self.cfg.wait / 16 + Duration::from_secs(5)).send().await?;
+        let resp = resp.error_for_status().with_context(|| format!("consul GET {}", path))?;
+        let new_index = resp.headers().get("X-Consul-Index").and_then(|v| v.to_str().ok()).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+        Ok((resp.bytes().await?.to_vec(), new_index))
+    }
+
+    /// Service names in the catalog, and the index to block on next.
+    pub async fn services(&self, index: u64) -> Result<(Vec<String>, u64)> {
+        let (body, index) = self.blocking_get("/v1/catalog/services", index).await?;
+        let map: HashMap<String, serde_json::Value> = serde_json::from_slice(&body).context("parsing catalog services")?;
+        let mut names: Vec<String> = map.into_keys().collect();
+        names.sort();
+        Ok((names, index))
+    }
+
+    /// Instances of `name` as (id, entry, health), and the index to block on next.
+    /// An instance is passing only if all of its checks pass.
+    pub async fn health_service(&self, name: &str, index: u64) -> Result<(Vec<(String, ServiceEntry, Health)>, u64)> {
+        let (body, index) = self.blocking_get(&format!("/v1/health/service/{}", name), index).await?;
+        let entries: Vec<HealthEntry> = serde_json::from_slice(&body).with_context(|| format!("parsing health of {}", name))?;
+        let instances = entries
+            .into_iter()
+            .map(|e| {
+                let health = if e.checks.iter().all(|c| c.status == "passing") { Health::Passing } else { Health::Critical };
+                let address = if e.service.address.is_empty() { e.node.address } else { e.service.address };
+                let id = format!("consul:{}/{}", e.node.node, e.service.id);
+                (id, ServiceEntry { name: e.service.service, address, port: e.service.port, meta: e.service.meta }, health)
+            })
+            .collect();
+        Ok((instances, index))
+    }
+
+    /// Register `entry` with the local agent under `id`, with a TTL check that
+    /// must be passed (see `pass`) at least every `ttl`.
+    pub async fn register(&self, id: &str, entry: &ServiceEntry, ttl: Duration) -> Result<()> {
+        let ttl_secs = ttl.as_secs().max(1);
+        let body = json!({
+            "ID": id,
+            "Name": entry.name,
+            "Address": entry.address,
+            "Port": entry.port,
+            // Consul metadata values must be strings
+            "Meta": entry.meta.as_ref().filter(|m| m.as_object().is_some_and(|o| o.values().all(|v| v.is_string()))),
+            "Check": {
+                "CheckID": format!("service:{}", id),
+                "TTL": format!("{}s", ttl_secs),
+                "DeregisterCriticalServiceAfter": format!("{}s", ttl_secs * crate::registry::DEREGISTER_AFTER_TTLS as u64),
+            },
+        });
+        self.request(reqwest::Method::PUT, "/v1/agent/service/register").json(&body).send().await?.error_for_status().context("consul register")?;
+        Ok(())
+    }
+
+    /// Mark the TTL check of service `id` as passing.
+    pub async fn pass(&self, id: &str) -> Result<()> {
+        let path = format!("/v1/agent/check/pass/service:{}", id);
+        self.request(reqwest::Method::PUT, &path).send().await?.error_for_status().context("consul check pass")?;
+        Ok(())
+    }
+
+    pub async fn deregister(&self, id: &str) -> Result<()> {
+        let path = format!("/v1/agent/service/deregister/{}", id);
+        self.request(reqwest::Method::PUT, &path).send().await?.error_for_status().context("consul deregister")?;
+        Ok(())
+    }
+
+    /// Fetch the configured services once (no blocking), filling `registry`.
+    /// A service whose health query fails is logged and skipped; only a failed
+    /// catalog query is returned as an error.
+    pub async fn sync_once(&self, registry: &Registry) -> Result<()> {
+        let names = if self.cfg.services.is_empty() { self.services(0).await?.0 } else { self.cfg.services.clone() };
+        for name in names {
+            match self.health_service(&name, 0).await {
+                Ok((instances, _)) => registry.replace_service(Source::Consul, &name, instances),
+                Err(e) => tracing::warn!("consul health query for {} failed: {:#}", name, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep `registry` in sync with Consul forever. Spawn this on the runtime.
+    pub async fn watch(self: Arc<Self>, registry: Arc<Registry>) {
+        if !self.cfg.services.is_empty() {
+            let watchers: Vec<JoinHandle<()>> = self.cfg.services.iter().map(|name| tokio::spawn(Arc::clone(&self).watch_service(Arc::clone(&registry), name.clone()))).collect();
+            futures_util::future::join_all(watchers).await;
+            return;
+        }
+
+        let mut watchers: HashMap<String, JoinHandle<()>> = HashMap::new();
+        let mut index = 0;
+        let mut backoff = Backoff::default();
+        loop {
+            match self.services(index).await {
+                Ok((names, next)) => {
+                    backoff.reset();
+                    index = next_index(index, next);
+                    watchers.retain(|name, handle| {
+                        let keep = names.contains(name);
+                        if !keep {
+                            handle.abort();
+                            registry.replace_service(Source::Consul, name, Vec::new());
+                        }
+                        keep
+                    });
+                    for name in names {
+                        watchers.entry(name.clone()).or_insert_with(|| tokio::spawn(Arc::clone(&self).watch_service(Arc::clone(&registry), name)));
+                    }
+                    tokio::time::sleep(self.cfg.min_interval).await;
+                }
+                Err(e) => {
+                    tracing::warn!("consul catalog query failed: {:#}", e);
+                    index = 0;
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    async fn watch_service(self: Arc<Self>, registry: Arc<Registry>, name: String) {
+        let mut index = 0;
+        let mut backoff = Backoff::default();
+        loop {
+            match self.health_service(&name, index).await {
+                Ok((instances, next)) => {
+                    backoff.reset();
+                    // an unchanged index means the wait elapsed without changes
+                    if index == 0 || next != index {
+                        registry.replace_service(Source::Consul, &name, instances);
+                    }
+                    index = next_index(index, next);
+                    tokio::time::sleep(self.cfg.min_interval).await;
+                }
+                // keep serving the cached instances while Consul is away
+                Err(e) => {
+                    tracing::warn!(service = %name, "consul health query failed: {:#}", e);
+                    index = 0;
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+}
+
+/// Consul resets indexes that go backwards (e.g. after a snapshot restore) to 0.
+fn next_index(prev: u64, next: u64) -> u64 {
+    if next < prev { 0 } else { next }
+}
+
+struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self(Duration::from_secs(1))
+    }
+}
+
+impl Backoff {
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    async fn wait(&mut self) {
+        tokio::time::sleep(self.0).await;
+        self.0 = (self.0 * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, put};
+    use axum::{Json, Router};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[derive(Default)]
+    struct Stub {
+        index: u64,
+        passing: bool,
+        registered: Vec<String>,
+    }
+
+    type Shared = Arc<Mutex<Stub>>;
+
+    fn indexed(index: u64, body: serde_json::Value) -> (HeaderMap, Json<serde_json::Value>) {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Consul-Index", index.to_string().parse().unwrap());
+        (headers, Json(body))
+    }
+
+    async fn catalog(State(stub): State<Shared>) -> (HeaderMap, Json<serde_json::Value>) {
+        let index = stub.lock().unwrap().index;
+        indexed(index, json!({ "bus": [], "consul": [] }))
+    }
+
+    // Blocks like Consul while the caller's index is current, up to 2s.
+    // "broken" always fails, like a service whose health query errors.
+    async fn health(State(stub): State<Shared>, Path(name): Path<String>, Query(q): Query<HashMap<String, String>>) -> Result<(HeaderMap, Json<serde_json::Value>), StatusCode> {
+        if name == "broken" {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let seen: u64 = q.get("index").and_then(|s| s.parse().ok()).unwrap_or(0);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while seen > 0 && stub.lock().unwrap().index == seen && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let s = stub.lock().unwrap();
+        let entries = if name == "bus" {
+            let status = if s.passing { "passing" } else { "critical" };
+            json!([{
+                "Node": { "Node": "n1", "Address": "10.0.0.5" },
+                "Service": { "ID": "bus-1", "Service": "bus", "Address": "", "Port": 7777 },
+                "Checks": [{ "Status": "passing" }, { "Status": status }],
+            }])
+        } else {
+            json!([])
+        };
+        Ok(indexed(s.index, entries))
+    }
+
+    async fn register(State(stub): State<Shared>, Json(body): Json<serde_json::Value>) -> StatusCode {
+        stub.lock().unwrap().registered.push(body["ID"].as_str().unwrap_or_default().to_string());
+        StatusCode::OK
+    }
+
+    async fn pass(State(stub): State<Shared>, Path(check): Path<String>) -> StatusCode {
+        let known = stub.lock().unwrap().registered.iter().any(|id| check == format!("service:{}", id));
+        if known { StatusCode::OK } else { StatusCode::NOT_FOUND }
+    }
+
+    async fn deregister(State(stub): State<Shared>, Path(id): Path<String>) -> StatusCode {
+        stub.lock().unwrap().registered.retain(|r| *r != id);
+        StatusCode::OK
+    }
+
+    async fn stub_consul() -> (Shared, Consul) {
+        stub_consul_watching(Vec::new()).await
+    }
+
+    async fn stub_consul_watching(services: Vec<String>) -> (Shared, Consul) {
+        let stub: Shared = Arc::new(Mutex::new(Stub { index: 5, passing: true, registered: Vec::new() }));
+        let app = Router::new()
+            .route("/v1/catalog/services", get(catalog))
+            .route("/v1/health/service/{name}", get(health))
+            .route("/v1/agent/service/register", put(register))
+            .route("/v1/agent/check/pass/{check}", put(pass))
+            .route("/v1/agent/service/deregister/{id}", put(deregister))
+            .with_state(Arc::clone(&stub));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let cfg = ConsulConfig { addr: format!("http://{}", addr), wait: Duration::from_secs(2), min_interval: Duration::from_millis(10), services, ..ConsulConfig::default() };
+        (stub, Consul::new(cfg).unwrap())
+    }
+
+    #[tokio::test]
+    async fn watch_caches_instances_and_follows_health_changes() {
+        let (stub, consul) = stub_consul().await;
+        let registry = Arc::new(Registry::new());
+        let backend = crate::Backend::Consul(Arc::new(consul));
+        backend.start(&registry).await.unwrap();
+        let bus = registry.resolve("bus").unwrap();
+        assert_eq!((bus.address.as_str(), bus.port), ("10.0.0.5", 7777), "node address used when the service has none");
+
+        {
+            let mut s = stub.lock().unwrap();
+            s.passing = false;
+            s.index += 1;
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while registry.resolve("bus").is_some() {
+            assert!(Instant::now() < deadline, "watch did not pick up the failed check");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(registry.instances(Some("bus"))[0].health, Health::Critical);
+    }
+
+    #[tokio::test]
+    async fn sync_skips_services_whose_health_query_fails() {
+        let (_stub, consul) = stub_consul_watching(vec!["broken".to_string(), "bus".to_string()]).await;
+        let registry = Registry::new();
+        consul.sync_once(&registry).await.unwrap();
+        assert!(registry.resolve("bus").is_some());
+        assert!(registry.resolve("broken").is_none());
+    }
+
+    #[tokio::test]
+    async fn agent_registration_with_ttl_checks() {
+        let (_stub, consul) = stub_consul().await;
+        let entry = ServiceEntry { name: "gateway".to_string(), address: "10.0.0.9".to_string(), port: 7000, meta: None };
+        assert!(consul.pass("gw-1").await.is_err());
+        consul.register("gw-1", &entry, Duration::from_secs(30)).await.unwrap();
+        consul.pass("gw-1").await.unwrap();
+        consul.deregister("gw-1").await.unwrap();
+        assert!(consul.pass("gw-1").await.is_err());
+        assert!(Consul::new(ConsulConfig { addr: "127.0.0.1:8500".to_string(), ..ConsulConfig::default() }).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Active health checks for catalog instances.
+//!
+//! Each round opens a TCP connection to every catalog instance (concurrently,
+//! with a timeout) and records the result in the registry. Registered instances
+//! aren't probed: their heartbeats are their health, and each round also
+//! expires the ones that stopped heartbeating.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::net::TcpStream;
+
+use crate::registry::Registry;
+use crate::ServiceEntry;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CheckConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failed probes before an instance is marked critical.
+    pub failures_before_critical: u32,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(10), timeout: Duration::from_secs(2), failures_before_critical: 2 }
+    }
+}
+
+/// Whether a TCP connection to the instance succeeds within `timeout`.
+pub async fn probe(entry: &ServiceEntry, timeout: Duration) -> bool {
+    let connect = TcpStream::connect((entry.address.as_str(), entry.port));
+    matches!(tokio::time::timeout(timeout, connect).await, Ok(Ok(_)))
+}
+
+/// Run one round of checks. Returns the number of instances probed.
+pub async fn check_once(registry: &Registry, cfg: &CheckConfig) -> usize {
+    registry.expire(Instant::now());
+    let targets = registry.catalog_instances();
+    let results = join_all(targets.iter().map(|(_, entry)| probe(entry, cfg.timeout))).await;
+    for ((id, entry), ok) in targets.iter().zip(results) {
+        if !ok {
+            tracing::debug!(service = %entry.name, id = %id, "health check failed");
+        }
+        registry.record_check(id, ok, cfg.failures_before_critical);
+    }
+    targets.len()
+}
+
+/// Check forever every `cfg.interval`. Spawn this on the runtime.
+pub async fn run(registry: Arc<Registry>, cfg: CheckConfig) {
+    let mut tick = tokio::time::interval(cfg.interval);
+    loop {
+        tick.tick().await;
+        check_once(&registry, &cfg).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Health;
+
+    #[tokio::test]
+    async fn probes_mark_unreachable_instances_critical() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap().port();
+        // a port that was free a moment ago is very likely closed
+        let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let entry = |port| ServiceEntry { name: "svc".to_string(), address: "127.0.0.1".to_string(), port, meta: None };
+
+        let reg = Registry::new();
+        reg.load_catalog(vec![entry(up), entry(down)]);
+        let cfg = CheckConfig { timeout: Duration::from_millis(500), failures_before_critical: 1, ..CheckConfig::default() };
+        assert_eq!(check_once(&reg, &cfg).await, 2);
+
+        let health = |port| reg.instances(Some("svc")).into_iter().find(|i| i.entry.port == port).unwrap().health;
+        assert_eq!(health(up), Health::Passing);
+        assert_eq!(health(down), Health::Critical);
+        assert_eq!(reg.resolve("svc").unwrap().port, up);
+    }
+}
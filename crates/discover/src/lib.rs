@@ -1,10 +1,18 @@
-﻿use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::fs;
-use std::path::Path;
+﻿use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod catalog;
+pub mod consul;
+pub mod health;
+pub mod registry;
+
+pub use registry::{Health, Instance, Registry, Source};
 
 /// Service entry returned by discover::get_services()
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServiceEntry {
     pub name: String,
     pub address: String,
@@ -12,19 +20,97 @@ pub struct ServiceEntry {
     pub meta: Option<serde_json::Value>,
 }
 
-/// Try to load service catalog from Consul (feature to be implemented).
-/// For now this function provides a dev-friendly fallback:
+impl ServiceEntry {
+    /// `tcp://address:port`, the form NNG/ZMQ endpoints take.
+    pub fn tcp_url(&self) -> String {
+        if self.address.contains(':') {
+            format!("tcp://[{}]:{}", self.address, self.port)
+        } else {
+            format!("tcp://{}:{}", self.address, self.port)
+        }
+    }
+}
+
+/// Where a `Registry` gets its instances from and where services register.
+pub enum Backend {
+    /// Static catalog file, reloaded on change and health-checked locally;
+    /// registrations stay in the local registry.
+    File { path: PathBuf },
+    /// Consul agent: instances and health come from Consul and registrations go to it.
+    Consul(Arc<consul::Consul>),
+}
+
+impl Backend {
+    /// `DISCOVER_BACKEND` is "file" (default; catalog at `DISCOVER_CATALOG`) or
+    /// "consul" (`CONSUL_HTTP_ADDR`, `CONSUL_HTTP_TOKEN`, and optionally
+    /// `DISCOVER_SERVICES`, a comma-separated list of services to watch).
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        match var("DISCOVER_BACKEND").as_deref().unwrap_or("file") {
+            "file" => Ok(Backend::File { path: var("DISCOVER_CATALOG").unwrap_or_else(|| catalog::DEFAULT_PATH.to_string()).into() }),
+            "consul" => {
+                let mut cfg = consul::ConsulConfig::default();
+                if let Some(addr) = var("CONSUL_HTTP_ADDR") {
+                    // Consul's own tools accept a bare host:port here
+                    cfg.addr = if addr.contains("://") { addr } else { format!("http://{}", addr) };
+                }
+                cfg.token = var("CONSUL_HTTP_TOKEN");
+                cfg.services = var("DISCOVER_SERVICES").map(|s| s.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect()).unwrap_or_default();
+                Ok(Backend::Consul(Arc::new(consul::Consul::new(cfg)?)))
+            }
+            other => bail!("unknown DISCOVER_BACKEND {:?} (expected \"file\" or \"consul\")", other),
+        }
+    }
+
+    /// Fill `registry` and spawn the tasks that keep it current. Errors from the
+    /// initial load are returned, but the background tasks are started anyway.
+    pub async fn start(&self, registry: &Arc<Registry>) -> Result<()> {
+        match self {
+            Backend::File { path } => {
+                let loaded = catalog::load(registry, path);
+                tokio::spawn(catalog::watch(Arc::clone(registry), path.clone(), Duration::from_secs(5)));
+                tokio::spawn(health::run(Arc::clone(registry), health::CheckConfig::default()));
+                loaded.map(|_| ())
+            }
+            Backend::Consul(consul) => {
+                let synced = consul.sync_once(registry).await;
+                tokio::spawn(Arc::clone(consul).watch(Arc::clone(registry)));
+                synced
+            }
+        }
+    }
+
+    /// Register this process as instance `id`, to be kept alive with `heartbeat`
+    /// at least every `ttl`.
+    pub async fn register(&self, registry: &Registry, id: &str, entry: ServiceEntry, ttl: Duration) -> Result<()> {
+        match self {
+            Backend::File { .. } => {
+                registry.register(id, entry, ttl);
+                Ok(())
+            }
+            Backend::Consul(consul) => consul.register(id, &entry, ttl).await,
+        }
+    }
+
+    /// Fails if the instance is no longer known, in which case register again.
+    pub async fn heartbeat(&self, registry: &Registry, id: &str) -> Result<()> {
+        match self {
+            Backend::File { .. } => registry.heartbeat(id),
+            Backend::Consul(consul) => consul.pass(id).await,
+        }
+    }
+}
+
+/// One-shot read of the service catalog, with a dev-friendly fallback:
 /// 1. If the file "./data/discover.json" exists, parse it as an array of ServiceEntry.
 /// 2. Otherwise, return a small hard-coded static catalog.
 ///
-/// This provides a safe local fallback for development and testing while a
-/// full Consul client + cache can be implemented later.
+/// Long-running processes should keep a `Registry` filled by a `Backend`
+/// (file or Consul) instead.
 pub fn get_services() -> Result<Vec<ServiceEntry>> {
-    let path = Path::new("./data/discover.json");
+    let path = Path::new(catalog::DEFAULT_PATH);
     if path.exists() {
-        let s = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
-        let v: Vec<ServiceEntry> = serde_json::from_str(&s).with_context(|| "parsing discover.json")?;
-        return Ok(v);
+        return catalog::read(path);
     }
 
     // Static fallback catalog (development)
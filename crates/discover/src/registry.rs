@@ -0,0 +1,267 @@
+//! In-process service registry.
+//!
+//! Instances come from three places:
+//! - the static catalog (`catalog::read` / `catalog::watch`), whose instances
+//!   are probed by the health checker (`health`);
+//! - services registering themselves with a TTL, which stay passing while they
+//!   heartbeat, turn critical once the TTL lapses and are dropped after
+//!   `DEREGISTER_AFTER_TTLS` TTLs without a heartbeat;
+//! - Consul (`consul`), for which the registry is a local cache of the health
+//!   endpoint, including the health Consul reports.
+//!
+//! `resolve(name)` round-robins over the instances of a service that are not
+//! critical (instances not checked yet are given the benefit of the doubt).
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::ServiceEntry;
+
+/// Registered instances are removed after this many TTLs without a heartbeat.
+pub const DEREGISTER_AFTER_TTLS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    /// Not checked yet.
+    Unknown,
+    Passing,
+    Critical,
+}
+
+/// Where an instance came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Catalog,
+    Registered,
+    Consul,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Instance {
+    pub id: String,
+    #[serde(flatten)]
+    pub entry: ServiceEntry,
+    pub source: Source,
+    pub health: Health,
+    /// Heartbeat TTL of a registered instance.
+    pub ttl_secs: Option<u64>,
+    #[serde(skip)]
+    last_heartbeat: Instant,
+    #[serde(skip)]
+    failed_checks: u32,
+}
+
+impl Instance {
+    fn usable(&self) -> bool {
+        self.health != Health::Critical
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    instances: BTreeMap<String, Instance>,
+    // next round-robin position per service name
+    cursors: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    inner: Mutex<Inner>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register (or re-register) instance `id` of `entry.name`, expected to
+    /// heartbeat at least every `ttl`.
+    pub fn register(&self, id: &str, entry: ServiceEntry, ttl: Duration) {
+        let instance = Instance {
+            id: id.to_string(),
+            entry,
+            source: Source::Registered,
+            health: Health::Passing,
+            ttl_secs: Some(ttl.as_secs().max(1)),
+            last_heartbeat: Instant::now(),
+            failed_checks: 0,
+        };
+        self.inner.lock().unwrap().instances.insert(id.to_string(), instance);
+    }
+
+    /// Keep a registered instance alive. Fails if it is unknown (e.g. already
+    /// deregistered after missing its TTL), in which case it should register again.
+    pub fn heartbeat(&self, id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let instance = inner
+            .instances
+            .get_mut(id)
+            .filter(|i| i.source == Source::Registered)
+            .ok_or_else(|| anyhow!("instance {:?} is not registered", id))?;
+        instance.last_heartbeat = Instant::now();
+        instance.health = Health::Passing;
+        Ok(())
+    }
+
+    /// Remove a registered instance. Returns true if it existed.
+    pub fn deregister(&self, id: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.instances.get(id) {
+            Some(i) if i.source == Source::Registered => inner.instances.remove(id).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Replace the catalog instances with `entries`. Instances that are still
+    /// listed keep their health. Returns the number of catalog instances.
+    pub fn load_catalog(&self, entries: Vec<ServiceEntry>) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let mut fresh: BTreeMap<String, Instance> = BTreeMap::new();
+        for entry in entries {
+            let id = format!("catalog:{}@{}:{}", entry.name, entry.address, entry.port);
+            let previous = inner.instances.get(&id).filter(|i| i.source == Source::Catalog && i.entry == entry);
+            let instance = match previous {
+                Some(p) => p.clone(),
+                None => Instance {
+                    id: id.clone(),
+                    entry,
+                    source: Source::Catalog,
+                    health: Health::Unknown,
+                    ttl_secs: None,
+                    last_heartbeat: Instant::now(),
+                    failed_checks: 0,
+                },
+            };
+            fresh.insert(id, instance);
+        }
+        let count = fresh.len();
+        inner.instances.retain(|_, i| i.source != Source::Catalog);
+        inner.instances.extend(fresh);
+        count
+    }
+
+    /// Replace the `source` instances of service `name` with `instances`
+    /// (id, entry, health), e.g. after a Consul health query returned.
+    pub fn replace_service(&self, source: Source, name: &str, instances: Vec<(String, ServiceEntry, Health)>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.instances.retain(|_, i| !(i.source == source && i.entry.name == name));
+        for (id, entry, health) in instances {
+            let instance = Instance { id: id.clone(), entry, source, health, ttl_secs: None, last_heartbeat: Instant::now(), failed_checks: 0 };
+            inner.instances.insert(id, instance);
+        }
+    }
+
+    /// Mark registered instances whose TTL lapsed at `now` critical and drop
+    /// those silent for `DEREGISTER_AFTER_TTLS` TTLs. Returns how many were dropped.
+    pub fn expire(&self, now: Instant) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.instances.len();
+        inner.instances.retain(|_, i| {
+            let Some(ttl) = i.ttl_secs.map(Duration::from_secs) else { return true };
+            let silent = now.saturating_duration_since(i.last_heartbeat);
+            if silent >= ttl {
+                i.health = Health::Critical;
+            }
+            silent < ttl * DEREGISTER_AFTER_TTLS
+        });
+        before - inner.instances.len()
+    }
+
+    /// Record a health check result for a catalog instance. An instance turns
+    /// critical after `failures_before_critical` consecutive failures and
+    /// passing again on the first success.
+    pub fn record_check(&self, id: &str, ok: bool, failures_before_critical: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(instance) = inner.instances.get_mut(id) else { return };
+        if ok {
+            instance.failed_checks = 0;
+            instance.health = Health::Passing;
+        } else {
+            instance.failed_checks += 1;
+            if instance.failed_checks >= failures_before_critical {
+                instance.health = Health::Critical;
+            }
+        }
+    }
+
+    /// Pick the next usable instance of `name`, round-robin.
+    pub fn resolve(&self, name: &str) -> Option<ServiceEntry> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { instances, cursors } = &mut *inner;
+        let usable: Vec<&Instance> = instances.values().filter(|i| i.entry.name == name && i.usable()).collect();
+        if usable.is_empty() {
+            return None;
+        }
+        let cursor = cursors.entry(name.to_string()).or_insert(0);
+        let picked = usable[*cursor % usable.len()].entry.clone();
+        *cursor = cursor.wrapping_add(1);
+        Some(picked)
+    }
+
+    /// All instances of `name`, or of every service if `None`, ordered by id.
+    pub fn instances(&self, name: Option<&str>) -> Vec<Instance> {
+        let inner = self.inner.lock().unwrap();
+        inner.instances.values().filter(|i| name.is_none_or(|n| i.entry.name == n)).cloned().collect()
+    }
+
+    /// Catalog instances, which the health checker probes.
+    pub(crate) fn catalog_instances(&self) -> Vec<(String, ServiceEntry)> {
+        let inner = self.inner.lock().unwrap();
+        inner.instances.values().filter(|i| i.source == Source::Catalog).map(|i| (i.id.clone(), i.entry.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, port: u16) -> ServiceEntry {
+        ServiceEntry { name: name.to_string(), address: "127.0.0.1".to_string(), port, meta: None }
+    }
+
+    #[test]
+    fn ttl_registrations_expire_and_resolve_skips_critical() {
+        let reg = Registry::new();
+        reg.register("bus-a", entry("bus", 7001), Duration::from_secs(10));
+        reg.register("bus-b", entry("bus", 7002), Duration::from_secs(10));
+        let ports: Vec<u16> = (0..4).map(|_| reg.resolve("bus").unwrap().port).collect();
+        assert_eq!(ports, [7001, 7002, 7001, 7002]);
+
+        let later = Instant::now() + Duration::from_secs(11);
+        reg.heartbeat("bus-b").unwrap();
+        assert_eq!(reg.expire(later), 0);
+        // bus-a missed its TTL (bus-b's heartbeat is also older than 11s by then)
+        assert!(reg.instances(Some("bus")).iter().all(|i| i.health == Health::Critical));
+        assert!(reg.resolve("bus").is_none());
+        reg.heartbeat("bus-b").unwrap();
+        assert_eq!(reg.resolve("bus").unwrap().port, 7002);
+
+        assert_eq!(reg.expire(Instant::now() + Duration::from_secs(31)), 2);
+        assert!(reg.heartbeat("bus-a").is_err());
+        assert!(reg.resolve("missing").is_none());
+    }
+
+    #[test]
+    fn catalog_reload_keeps_health_of_unchanged_instances() {
+        let reg = Registry::new();
+        assert_eq!(reg.load_catalog(vec![entry("push", 7010), entry("push", 7011)]), 2);
+        let id = reg.instances(Some("push"))[0].id.clone();
+        reg.record_check(&id, false, 2);
+        assert_eq!(reg.instances(Some("push"))[0].health, Health::Unknown);
+        reg.record_check(&id, false, 2);
+        assert_eq!(reg.resolve("push").unwrap().port, 7011);
+
+        reg.register("push-dyn", entry("push", 7012), Duration::from_secs(5));
+        reg.load_catalog(vec![entry("push", 7010)]);
+        let all = reg.instances(Some("push"));
+        assert_eq!(all.len(), 2, "7011 dropped, registered instance kept");
+        assert_eq!(all.iter().find(|i| i.id == id).unwrap().health, Health::Critical);
+        assert!(!reg.deregister(&id), "catalog instances can't be deregistered");
+    }
+}
@@ -1,4 +1,6 @@
-﻿use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::{watch, Mutex};
@@ -6,17 +8,238 @@ use tokio::task::JoinHandle;
 use tokio::time;
 use uuid::Uuid;
 
+use bus::codecs::{decode_message, encode_envelope};
+use bus::envelope::Headers;
 use bus::pubsub::Publisher;
-use storage::Storage;
+use proto::message_capnp::envelope;
+use storage::{PresenceConnRecord, PresenceSessionRecord, PresenceStatusRecord, Storage};
 use serde_json::json;
 
-/// Presence manager: heartbeats + sweeper.
-/// - heartbeat(user_id): mark user online (writes storage presence table and publishes presence/online)
-/// - mark_offline(user_id): mark offline (remove presence entry and publish presence/offline)
-/// - background sweeper will check last_seen and publish offline events for stale users.
-pub struct PresenceManager {
+/// Default inactivity (seconds) after which an online user is shown as away.
+pub const DEFAULT_IDLE_SECS: u64 = 300;
+/// Maximum length (chars) of custom status text.
+pub const MAX_STATUS_TEXT: usize = 140;
+/// Finished connections kept per user in the session log.
+pub const MAX_SESSIONS_PER_USER: usize = 200;
+
+/// Kind of client behind a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Web,
+    Desktop,
+    Mobile,
+    Unknown,
+}
+
+impl DeviceKind {
+    /// Parse an explicit client hint ("web", "desktop", "mobile").
+    pub fn parse(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "web" | "browser" => Self::Web,
+            "desktop" => Self::Desktop,
+            "mobile" | "ios" | "android" => Self::Mobile,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Best-effort guess from a User-Agent header.
+    pub fn from_user_agent(ua: &str) -> Self {
+        let ua = ua.to_ascii_lowercase();
+        if ua.contains("tauri") || ua.contains("electron") {
+            Self::Desktop
+        } else if ua.contains("mobile") || ua.contains("android") || ua.contains("iphone") {
+            Self::Mobile
+        } else if ua.contains("mozilla") {
+            Self::Web
+        } else {
+            Self::Unknown
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Web => "web",
+            Self::Desktop => "desktop",
+            Self::Mobile => "mobile",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Presence status. `Invisible` is only ever reported to the user themselves;
+/// everyone else sees `Offline`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Away,
+    Busy,
+    Invisible,
+    Offline,
+}
+
+impl Status {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "online" => Some(Self::Online),
+            "away" => Some(Self::Away),
+            "busy" | "dnd" => Some(Self::Busy),
+            "invisible" => Some(Self::Invisible),
+            "offline" => Some(Self::Offline),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Away => "away",
+            Self::Busy => "busy",
+            Self::Invisible => "invisible",
+            Self::Offline => "offline",
+        }
+    }
+
+    /// What other users are allowed to see.
+    pub fn public(self) -> Self {
+        match self {
+            Self::Invisible => Self::Offline,
+            s => s,
+        }
+    }
+}
+
+/// Handle for one registered connection; pass it back to heartbeat/disconnect.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub user_id: String,
+    pub conn_id: String,
+    pub device: DeviceKind,
+}
+
+/// User-level view aggregated over all of a user's connections.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPresence {
+    pub user_id: String,
+    pub online: bool,
+    /// Effective status (manual status, idle auto-away, or offline).
+    pub status: Status,
+    pub text: Option<String>,
+    pub text_expires_at: Option<i64>,
+    pub last_seen: Option<i64>,
+    /// "3h ago" style rendering of `last_seen`; only set while offline.
+    pub last_seen_ago: Option<String>,
+    pub devices: Vec<DeviceKind>,
+    pub connections: Vec<PresenceConnRecord>,
+}
+
+impl UserPresence {
+    /// The view other users get: invisible shows as offline, and neither
+    /// devices nor status text of hidden users are exposed.
+    pub fn public(&self) -> serde_json::Value {
+        let status = self.status.public();
+        let visible = status != Status::Offline;
+        json!({
+            "user_id": self.user_id,
+            "status": status,
+            "text": if visible { self.text.clone() } else { None },
+            "last_seen": if self.status == Status::Invisible { None } else { self.last_seen },
+            "last_seen_ago": if self.status == Status::Invisible { None } else { self.last_seen_ago.clone() },
+            "devices": if visible { self.devices.clone() } else { Vec::new() },
+        })
+    }
+}
+
+/// A user joining or leaving a room, as carried by the capnp `Join` variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomMembership {
+    pub room: String,
+    pub user_id: String,
+    pub left: bool,
+}
+
+impl RoomMembership {
+    /// Decode a `room/{room}` payload; `None` if it is not a join/leave envelope.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let msg = decode_message(bytes).ok()?;
+        let env = msg.get_root::<envelope::Reader>().ok()?;
+        let envelope::Which::Join(join) = env.which().ok()? else {
+            return None;
+        };
+        let join = join.ok()?;
+        Some(Self {
+            room: env.get_room().ok()?.to_string().ok()?,
+            user_id: join.get_user().ok()?.to_string().ok()?,
+            left: join.get_left(),
+        })
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        encode_envelope(|env| {
+            env.set_id(Uuid::new_v4().as_bytes());
+            env.set_room(self.room.as_str());
+            env.set_server_ts(now_ms);
+            let mut join = env.reborrow().init_join();
+            join.set_user(self.user_id.as_str());
+            join.set_left(self.left);
+        })
+    }
+}
+
+/// Change to a user's manual status. `None` fields are left unchanged; an
+/// empty `text` clears the custom status text.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StatusUpdate {
+    pub status: Option<String>,
+    pub text: Option<String>,
+    /// Custom text expiry, relative to now. Absent means the text never expires.
+    pub text_ttl_secs: Option<u64>,
+}
+
+impl StatusUpdate {
+    /// Reject statuses that can't be set manually and over-long text.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(s) = self.status.as_deref() {
+            match Status::parse(s) {
+                Some(Status::Offline) | None => bail!("invalid status {:?}: expected online, away, busy or invisible", s),
+                Some(_) => {}
+            }
+        }
+        if self.text.as_deref().is_some_and(|t| t.trim().chars().count() > MAX_STATUS_TEXT) {
+            bail!("status text longer than {} characters", MAX_STATUS_TEXT);
+        }
+        Ok(())
+    }
+}
+
+/// State shared between the manager and its sweeper task.
+struct Shared {
     storage: Arc<Storage>,
     publisher: Arc<Publisher>,
+    idle_secs: AtomicU64,
+    // serializes status transitions so each is fanned out once
+    transitions: std::sync::Mutex<()>,
+    // same for room join/leave transitions
+    memberships: std::sync::Mutex<()>,
+}
+
+/// Presence manager: per-connection heartbeats, manual statuses + sweeper.
+/// - connect(user_id, device): register a connection; the user comes online with their first one
+/// - heartbeat(conn): refresh a connection's liveness
+/// - activity(conn): record user activity (resets idle auto-away)
+/// - disconnect(conn): drop a connection; the user goes offline only when it was the last one
+/// - set_status(user_id, update): manual away/busy/invisible and custom status text
+/// - join_room(conn, room) / leave_room(conn, room): room subscriptions of a connection
+/// - background sweeper drops stale connections, applies idle auto-away and expires status text.
+///
+/// Every user-visible transition is published on `presence/status`; coming online
+/// and going offline are additionally published on `presence/online` / `presence/offline`.
+/// A user's first connection joining a room and their last one leaving it are
+/// published on `room/{room}` as a capnp `Join` envelope (`left` set on leave).
+pub struct PresenceManager {
+    shared: Arc<Shared>,
     sweep_handle: Mutex<Option<JoinHandle<()>>>,
     shutdown_tx: watch::Sender<bool>,
 }
@@ -25,24 +248,22 @@ impl PresenceManager {
     /// Create and start a PresenceManager. The sweeper interval and timeout are in seconds.
     pub fn new(storage: Arc<Storage>, publisher: Arc<Publisher>, sweep_interval_secs: u64, timeout_secs: u64) -> Result<Self> {
         let (tx, mut rx) = watch::channel(false);
-        let storage_c = storage.clone();
-        let publisher_c = publisher.clone();
+        let shared = Arc::new(Shared {
+            storage,
+            publisher,
+            idle_secs: AtomicU64::new(DEFAULT_IDLE_SECS),
+            transitions: std::sync::Mutex::new(()),
+            memberships: std::sync::Mutex::new(()),
+        });
+        let shared_c = shared.clone();
 
         let handle = tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(sweep_interval_secs));
             loop {
                 tokio::select! {
                     _ = interval.tick() => {
-                        // Sweep presence
-                        if let Ok(entries) = storage_c.list_presence() {
-                            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
-                            for (user_id, last_seen) in entries {
-                                if now - last_seen > timeout_secs as i64 {
-                                    // mark offline
-                                    let _ = storage_c.set_presence(&user_id, false, now);
-                                    let _ = publisher_c.publish("presence/offline", &serde_json::to_vec(&json!({"user_id": user_id, "last_seen": last_seen})).unwrap_or_default());
-                                }
-                            }
+                        if let Err(e) = shared_c.sweep(timeout_secs as i64) {
+                            tracing::warn!(err = ?e, "presence sweep failed");
                         }
                     }
                     changed = rx.changed() => {
@@ -56,51 +277,218 @@ impl PresenceManager {
         });
 
         Ok(Self {
-            storage,
-            publisher,
+            shared,
             sweep_handle: Mutex::new(Some(handle)),
             shutdown_tx: tx,
         })
     }
 
-    /// Heartbeat for a user_id. Creates a user_id if empty (guest).
-    pub fn heartbeat(&self, user_id: Option<String>) -> Result<String> {
-        // immediate diagnostic so we can see the heartbeat was invoked
-        eprintln!("PRESENCE_DIAG: heartbeat invoked for incoming connection");
-        let id = user_id.unwrap_or_else(|| format!("anon-{}", Uuid::new_v4().to_string()));
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-        self.storage.set_presence(&id, true, now)?;
-        let payload = serde_json::to_vec(&json!({"user_id": id.clone(), "last_seen": now}))?;
-        let res = self.publisher.publish("presence/online", &payload);
-        match res {
-            Ok(()) => {
-                tracing::info!(user = %id, "published presence/online");
-                // diagnostics for smoke: stdout marker and diag topic
-                eprintln!("PRESENCE_DIAG: online {}", id);
-                let _ = self.publisher.publish("presence/diag", &serde_json::to_vec(&json!({"event":"online","user_id": id.clone(), "last_seen": now})).unwrap_or_default());
+    /// Inactivity (seconds) after which an online user is shown as away.
+    pub fn set_idle_timeout(&self, secs: u64) {
+        self.shared.idle_secs.store(secs, Ordering::Relaxed);
+    }
+
+    /// Register a new connection. Creates a user_id if empty (guest).
+    pub fn connect(&self, user_id: Option<String>, device: DeviceKind) -> Result<Connection> {
+        let conn = Connection {
+            user_id: user_id.unwrap_or_else(|| format!("anon-{}", Uuid::new_v4())),
+            conn_id: Uuid::new_v4().to_string(),
+            device,
+        };
+        let now = now_secs()?;
+        let rec = PresenceConnRecord {
+            user_id: conn.user_id.clone(),
+            conn_id: conn.conn_id.clone(),
+            device: device.as_str().to_string(),
+            connected_at: now,
+            last_seen: now,
+            last_active: now,
+            rooms: Vec::new(),
+        };
+        let (count, _) = self.shared.storage.put_presence_conn(&rec)?;
+        self.shared.storage.set_presence(&conn.user_id, true, now)?;
+        self.shared.mark_active(&conn.user_id, now)?;
+        tracing::debug!(user = %conn.user_id, conn = %conn.conn_id, device = device.as_str(), count, "presence connect");
+        self.shared.refresh(&conn.user_id)?;
+        Ok(conn)
+    }
+
+    /// Refresh a connection's liveness. Returns false, and changes nothing, when
+    /// the connection is no longer registered (disconnected, or dropped by the
+    /// sweeper); the caller should close it.
+    pub fn heartbeat(&self, conn: &Connection) -> Result<bool> {
+        self.touch(conn, false)
+    }
+
+    /// Record user activity on a connection (a sent message, input, focus).
+    /// Returns false like `heartbeat` for a connection that is gone.
+    pub fn activity(&self, conn: &Connection) -> Result<bool> {
+        self.touch(conn, true)
+    }
+
+    fn touch(&self, conn: &Connection, active: bool) -> Result<bool> {
+        let now = now_secs()?;
+        let prev = {
+            // under the membership lock so a concurrent join/leave or removal
+            // isn't undone by writing back a stale copy of the record
+            let _guard = self.shared.memberships.lock().unwrap();
+            let Some(mut rec) = self
+                .shared
+                .storage
+                .list_presence_conns_for_user(&conn.user_id)?
+                .into_iter()
+                .find(|c| c.conn_id == conn.conn_id)
+            else {
+                return Ok(false);
+            };
+            let prev_seen = rec.last_seen;
+            rec.last_seen = now;
+            if active {
+                rec.last_active = now;
             }
-            Err(e) => tracing::error!(user = %id, err = ?e, "failed publishing presence/online"),
+            self.shared.storage.put_presence_conn(&rec)?;
+            self.shared.storage.set_presence(&conn.user_id, true, now)?;
+            prev_seen
+        };
+        // long-lived connections count towards every day they span
+        if day(prev) != day(now) {
+            self.shared.mark_active(&conn.user_id, now)?;
         }
-        Ok(id)
+        if active {
+            self.shared.refresh(&conn.user_id)?;
+        }
+        Ok(true)
+    }
+
+    /// Drop one connection; the user goes offline only if it was their last.
+    /// Rooms nobody else of the user is still in are left.
+    pub fn disconnect(&self, conn: &Connection) -> Result<()> {
+        let (remaining, existed) = self.shared.remove_conn(&conn.user_id, &conn.conn_id, now_secs()?)?;
+        tracing::debug!(user = %conn.user_id, conn = %conn.conn_id, remaining, "presence disconnect");
+        if remaining == 0 && existed {
+            self.shared.go_offline(&conn.user_id, now_secs()?)?;
+        }
+        Ok(())
     }
 
-    /// Mark a user offline explicitly.
+    /// Mark a user offline explicitly, dropping all of their connections.
     pub fn mark_offline(&self, user_id: &str) -> Result<()> {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-        self.storage.set_presence(user_id, false, now)?;
-        let payload = serde_json::to_vec(&json!({"user_id": user_id, "last_seen": now}))?;
-        let res = self.publisher.publish("presence/offline", &payload);
-        match res {
-            Ok(()) => {
-                tracing::info!(user = %user_id, "published presence/offline");
-                eprintln!("PRESENCE_DIAG: offline {}", user_id);
-                let _ = self.publisher.publish("presence/diag", &serde_json::to_vec(&json!({"event":"offline","user_id": user_id, "last_seen": now})).unwrap_or_default());
-            }
-            Err(e) => tracing::error!(user = %user_id, err = ?e, "failed publishing presence/offline"),
+        for conn in self.shared.storage.list_presence_conns_for_user(user_id)? {
+            self.shared.remove_conn(user_id, &conn.conn_id, now_secs()?)?;
+        }
+        self.shared.go_offline(user_id, now_secs()?)
+    }
+
+    /// Subscribe a connection to a room. Publishes a join when it is the user's
+    /// first connection in the room.
+    pub fn join_room(&self, conn: &Connection, room: &str) -> Result<()> {
+        let _guard = self.shared.memberships.lock().unwrap();
+        let conns = self.shared.storage.list_presence_conns_for_user(&conn.user_id)?;
+        let Some(mut rec) = conns.iter().find(|c| c.conn_id == conn.conn_id).cloned() else {
+            bail!("connection {} is not registered", conn.conn_id);
+        };
+        if rec.rooms.iter().any(|r| r == room) {
+            return Ok(());
+        }
+        let first = !conns.iter().any(|c| c.rooms.iter().any(|r| r == room));
+        rec.rooms.push(room.to_string());
+        self.shared.storage.put_presence_conn(&rec)?;
+        if first {
+            self.shared.publish_membership(room, &conn.user_id, false)?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe a connection from a room. Publishes a leave when no other
+    /// connection of the user remains in the room.
+    pub fn leave_room(&self, conn: &Connection, room: &str) -> Result<()> {
+        let _guard = self.shared.memberships.lock().unwrap();
+        let conns = self.shared.storage.list_presence_conns_for_user(&conn.user_id)?;
+        let Some(mut rec) = conns.iter().find(|c| c.conn_id == conn.conn_id).cloned() else {
+            return Ok(());
+        };
+        if !rec.rooms.iter().any(|r| r == room) {
+            return Ok(());
+        }
+        rec.rooms.retain(|r| r != room);
+        self.shared.storage.put_presence_conn(&rec)?;
+        let others = conns.iter().any(|c| c.conn_id != conn.conn_id && c.rooms.iter().any(|r| r == room));
+        if !others {
+            self.shared.publish_membership(room, &conn.user_id, true)?;
         }
         Ok(())
     }
 
+    /// Public presence of users with a live connection in `room`, sorted by user id.
+    /// Invisible users are left out.
+    pub fn room_members_online(&self, room: &str) -> Result<Vec<UserPresence>> {
+        let now = now_secs()?;
+        let mut users: Vec<String> = self
+            .shared
+            .storage
+            .list_presence_conns()?
+            .into_iter()
+            .filter(|c| c.rooms.iter().any(|r| r == room))
+            .map(|c| c.user_id)
+            .collect();
+        users.sort();
+        users.dedup();
+        let mut out = Vec::with_capacity(users.len());
+        for user_id in users {
+            let view = self.shared.view(&user_id, now)?;
+            if view.status.public() != Status::Offline {
+                out.push(view);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Set a user's manual status and/or custom status text.
+    pub fn set_status(&self, user_id: &str, update: StatusUpdate) -> Result<UserPresence> {
+        update.validate()?;
+        let now = now_secs()?;
+        let mut rec = self.shared.storage.get_presence_status(user_id)?.unwrap_or_else(default_status);
+        if let Some(status) = update.status.as_deref().and_then(Status::parse) {
+            rec.status = status.as_str().to_string();
+        }
+        if let Some(text) = update.text {
+            let text = text.trim();
+            if text.is_empty() {
+                rec.text = None;
+                rec.text_expires_at = None;
+            } else {
+                rec.text = Some(text.to_string());
+                rec.text_expires_at = update.text_ttl_secs.map(|ttl| now + ttl as i64);
+            }
+        }
+        rec.updated_at = now;
+        self.shared.storage.put_presence_status(user_id, &rec)?;
+        self.shared.refresh(user_id)
+    }
+
+    /// Aggregate status of a user across their connections.
+    pub fn user_presence(&self, user_id: &str) -> Result<UserPresence> {
+        self.shared.view(user_id, now_secs()?)
+    }
+
+    /// A user's finished connections, newest first.
+    pub fn sessions(&self, user_id: &str, limit: usize) -> Result<Vec<PresenceSessionRecord>> {
+        self.shared.storage.list_presence_sessions(user_id, limit)
+    }
+
+    /// Distinct active users per UTC day for the last `days` days, oldest first,
+    /// as ("YYYY-MM-DD", count) pairs. Today is included.
+    pub fn daily_active_users(&self, days: u32) -> Result<Vec<(String, usize)>> {
+        let now = now_secs()?;
+        let mut out = Vec::with_capacity(days as usize);
+        for back in (0..days as i64).rev() {
+            let d = day(now - back * 86_400);
+            let n = self.shared.storage.count_daily_active(&d)?;
+            out.push((d, n));
+        }
+        Ok(out)
+    }
+
     /// Shutdown the sweeper and background tasks. Accepts Arc<Self> so callers can invoke it
     /// without needing mutable ownership.
     pub async fn shutdown(self: Arc<Self>) {
@@ -111,3 +499,340 @@ impl PresenceManager {
         }
     }
 }
+
+impl Shared {
+    /// Compute the aggregated view of a user at `now`.
+    fn view(&self, user_id: &str, now: i64) -> Result<UserPresence> {
+        let connections = self.storage.list_presence_conns_for_user(user_id)?;
+        let manual = self.storage.get_presence_status(user_id)?.unwrap_or_else(default_status);
+        let mut devices: Vec<DeviceKind> = connections.iter().map(|c| DeviceKind::parse(&c.device)).collect();
+        devices.sort_by_key(|d| d.as_str());
+        devices.dedup();
+        let last_seen = connections
+            .iter()
+            .map(|c| c.last_seen)
+            .max()
+            .or(self.storage.get_presence(user_id)?);
+
+        let idle = self.idle_secs.load(Ordering::Relaxed) as i64;
+        let last_active = connections.iter().map(|c| c.last_active.max(c.connected_at)).max();
+        let manual_status = Status::parse(&manual.status).unwrap_or(Status::Online);
+        let status = match last_active {
+            None => Status::Offline,
+            Some(t) if manual_status == Status::Online && now - t >= idle => Status::Away,
+            Some(_) => manual_status,
+        };
+        let text_live = manual.text_expires_at.is_none_or(|exp| exp > now);
+        let online = !connections.is_empty();
+
+        Ok(UserPresence {
+            user_id: user_id.to_string(),
+            online,
+            status,
+            text: manual.text.filter(|_| text_live),
+            text_expires_at: manual.text_expires_at.filter(|_| text_live),
+            last_seen,
+            last_seen_ago: last_seen.filter(|_| !online).map(|t| format_ago(now - t)),
+            devices,
+            connections,
+        })
+    }
+
+    /// Re-evaluate a user and fan out any change in what others can see.
+    fn refresh(&self, user_id: &str) -> Result<UserPresence> {
+        let _guard = self.transitions.lock().unwrap();
+        let now = now_secs()?;
+        let view = self.view(user_id, now)?;
+        let public = view.public();
+        let status = view.status.public();
+        let signature = format!("{}|{}", status.as_str(), public["text"].as_str().unwrap_or(""));
+
+        let mut rec = self.storage.get_presence_status(user_id)?.unwrap_or_else(default_status);
+        let before = rec.published.clone().unwrap_or_else(|| "offline|".to_string());
+        if before == signature {
+            return Ok(view);
+        }
+        let was_offline = before.starts_with("offline|");
+        rec.published = Some(signature);
+        self.storage.put_presence_status(user_id, &rec)?;
+
+        let last_seen = view.last_seen.unwrap_or(now);
+        publish(&self.publisher, "presence/status", &public);
+        if was_offline && status != Status::Offline {
+            publish(&self.publisher, "presence/online", &json!({"user_id": user_id, "last_seen": last_seen, "devices": view.devices}));
+            tracing::info!(user = %user_id, "published presence/online");
+        } else if !was_offline && status == Status::Offline {
+            publish(&self.publisher, "presence/offline", &json!({"user_id": user_id, "last_seen": last_seen}));
+            tracing::info!(user = %user_id, "published presence/offline");
+        }
+        Ok(view)
+    }
+
+    /// Remove a connection, log it as a finished session ending at `ended_at` and
+    /// publish leaves for rooms the user no longer has any connection in.
+    /// Returns `remove_presence_conn`'s (remaining, existed).
+    fn remove_conn(&self, user_id: &str, conn_id: &str, ended_at: i64) -> Result<(usize, bool)> {
+        let _guard = self.memberships.lock().unwrap();
+        let conns = self.storage.list_presence_conns_for_user(user_id)?;
+        let res = self.storage.remove_presence_conn(user_id, conn_id)?;
+        if let Some(gone) = conns.iter().find(|c| c.conn_id == conn_id) {
+            let session = PresenceSessionRecord {
+                user_id: user_id.to_string(),
+                conn_id: conn_id.to_string(),
+                device: gone.device.clone(),
+                started_at: gone.connected_at,
+                ended_at: ended_at.max(gone.connected_at),
+            };
+            self.storage.append_presence_session(&session, MAX_SESSIONS_PER_USER)?;
+            for room in &gone.rooms {
+                if !conns.iter().any(|c| c.conn_id != conn_id && c.rooms.contains(room)) {
+                    self.publish_membership(room, user_id, true)?;
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Publish a join/leave on `room/{room}`, unless the user is invisible.
+    fn publish_membership(&self, room: &str, user_id: &str, left: bool) -> Result<()> {
+        let invisible = self
+            .storage
+            .get_presence_status(user_id)?
+            .is_some_and(|s| Status::parse(&s.status) == Some(Status::Invisible));
+        if invisible {
+            return Ok(());
+        }
+        let event = RoomMembership { room: room.to_string(), user_id: user_id.to_string(), left };
+        let topic = format!("room/{}", room);
+        if let Err(e) = self.publisher.publish_with(&topic, &Headers::capnp(), &event.encode()?) {
+            tracing::error!(topic = %topic, err = ?e, "failed publishing room membership");
+        }
+        Ok(())
+    }
+
+    fn mark_active(&self, user_id: &str, now: i64) -> Result<()> {
+        self.storage.mark_daily_active(&day(now), user_id)?;
+        Ok(())
+    }
+
+    fn go_offline(&self, user_id: &str, last_seen: i64) -> Result<()> {
+        self.storage.set_presence(user_id, false, last_seen)?;
+        self.refresh(user_id)?;
+        Ok(())
+    }
+
+    /// Drop connections idle for longer than `timeout`, take users with none left
+    /// offline, and re-evaluate everyone else for idle/away and expired status text.
+    fn sweep(&self, timeout: i64) -> Result<()> {
+        let now = now_secs()?;
+        let mut live: Vec<String> = Vec::new();
+        for conn in self.storage.list_presence_conns()? {
+            if now - conn.last_seen > timeout {
+                let (remaining, existed) = self.remove_conn(&conn.user_id, &conn.conn_id, conn.last_seen)?;
+                if remaining == 0 && existed {
+                    self.go_offline(&conn.user_id, conn.last_seen)?;
+                }
+            } else if !live.contains(&conn.user_id) {
+                live.push(conn.user_id);
+            }
+        }
+        for user_id in &live {
+            self.refresh(user_id)?;
+        }
+        // User-level rows without any connection (e.g. written before per-connection tracking).
+        for (user_id, last_seen) in self.storage.list_presence()? {
+            if now - last_seen > timeout && self.storage.list_presence_conns_for_user(&user_id)?.is_empty() {
+                self.go_offline(&user_id, last_seen)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_status() -> PresenceStatusRecord {
+    PresenceStatusRecord { status: Status::Online.as_str().to_string(), ..Default::default() }
+}
+
+fn publish(publisher: &Publisher, topic: &str, value: &serde_json::Value) {
+    let payload = serde_json::to_vec(value).unwrap_or_default();
+    if let Err(e) = publisher.publish_with(topic, &Headers::json(), &payload) {
+        tracing::error!(topic = %topic, err = ?e, "failed publishing presence event");
+    }
+}
+
+/// Render an elapsed time in seconds as "just now", "5m ago", "3h ago" or "2d ago".
+pub fn format_ago(secs: i64) -> String {
+    match secs.max(0) {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3_600 => format!("{}m ago", s / 60),
+        s if s < 86_400 => format!("{}h ago", s / 3_600),
+        s => format!("{}d ago", s / 86_400),
+    }
+}
+
+/// UTC calendar day ("YYYY-MM-DD") of a unix timestamp in seconds.
+fn day(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0).map(|d| d.format("%Y-%m-%d").to_string()).unwrap_or_default()
+}
+
+fn now_secs() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn user_stays_online_until_last_connection_closes() -> Result<()> {
+        let path = format!("./data/test-presence-{}", Uuid::new_v4());
+        let storage = Arc::new(Storage::new(&path)?);
+        let publisher = Arc::new(Publisher::bind("inproc://presence-test")?);
+        let mut online = bus::pubsub::Subscriber::connect("inproc://presence-test", "presence/online")?.into_deliveries();
+        let mut offline = bus::pubsub::Subscriber::connect("inproc://presence-test", "presence/offline")?.into_deliveries();
+        let pm = PresenceManager::new(storage, publisher, 3600, 3600)?;
+
+        let user = format!("u-{}", Uuid::new_v4());
+        let tabs: Vec<Connection> = (0..3).map(|_| pm.connect(Some(user.clone()), DeviceKind::Web)).collect::<Result<_>>()?;
+        let phone = pm.connect(Some(user.clone()), DeviceKind::Mobile)?;
+        let status = pm.user_presence(&user)?;
+        assert!(status.online);
+        assert_eq!(status.connections.len(), 4);
+        assert_eq!(status.devices, vec![DeviceKind::Mobile, DeviceKind::Web]);
+
+        for tab in &tabs {
+            pm.disconnect(tab)?;
+            assert!(pm.user_presence(&user)?.online);
+        }
+        pm.disconnect(&phone)?;
+        let gone = pm.user_presence(&user)?;
+        assert!(!gone.online);
+        assert!(gone.last_seen.is_some(), "last seen survives going offline");
+        assert_eq!(gone.last_seen_ago.as_deref(), Some("just now"));
+        let sessions = pm.sessions(&user, 10)?;
+        assert_eq!(sessions.len(), 4);
+        assert!(sessions.iter().all(|s| s.ended_at >= s.started_at));
+        let dau = pm.daily_active_users(2)?;
+        assert_eq!(dau.len(), 2);
+        assert!(dau[1].1 >= 1, "today counts this user");
+        // a late heartbeat from a closed connection doesn't bring it back
+        assert!(!pm.heartbeat(&phone)?);
+        assert!(!pm.user_presence(&user)?.online);
+
+        let mine = |d: &bus::pubsub::Delivery| serde_json::from_slice::<serde_json::Value>(&d.payload).ok().and_then(|v| v["user_id"].as_str().map(|u| u == user)).unwrap_or(false);
+        // let the bus forwarders drain before counting events
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut onlines = 0;
+        while let Ok(d) = online.try_recv() {
+            onlines += mine(&d) as usize;
+        }
+        let mut offlines = 0;
+        while let Ok(d) = offline.try_recv() {
+            offlines += mine(&d) as usize;
+        }
+        assert_eq!((onlines, offlines), (1, 1));
+
+        Arc::new(pm).shutdown().await;
+        let _ = fs::remove_dir_all(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn format_ago_units() {
+        assert_eq!(format_ago(5), "just now");
+        assert_eq!(format_ago(125), "2m ago");
+        assert_eq!(format_ago(3 * 3_600 + 10), "3h ago");
+        assert_eq!(format_ago(2 * 86_400), "2d ago");
+        assert_eq!(day(0), "1970-01-01");
+    }
+
+    #[tokio::test]
+    async fn room_join_leave_and_members() -> Result<()> {
+        let path = format!("./data/test-presence-{}", Uuid::new_v4());
+        let storage = Arc::new(Storage::new(&path)?);
+        let publisher = Arc::new(Publisher::bind("inproc://presence-test")?);
+        let room = format!("r-{}", Uuid::new_v4());
+        let mut events = bus::pubsub::Subscriber::connect("inproc://presence-test", &format!("room/{}", room))?.into_deliveries();
+        let pm = PresenceManager::new(storage, publisher, 3600, 3600)?;
+
+        let (alice, bob) = (format!("a-{}", Uuid::new_v4()), format!("b-{}", Uuid::new_v4()));
+        let tab1 = pm.connect(Some(alice.clone()), DeviceKind::Web)?;
+        let tab2 = pm.connect(Some(alice.clone()), DeviceKind::Web)?;
+        let phone = pm.connect(Some(bob.clone()), DeviceKind::Mobile)?;
+        pm.join_room(&tab1, &room)?;
+        pm.join_room(&tab2, &room)?;
+        pm.join_room(&phone, &room)?;
+        let members: Vec<String> = pm.room_members_online(&room)?.into_iter().map(|p| p.user_id).collect();
+        let mut expected = vec![alice.clone(), bob.clone()];
+        expected.sort();
+        assert_eq!(members, expected);
+
+        // invisible users are neither listed nor announced
+        pm.set_status(&bob, StatusUpdate { status: Some("invisible".into()), ..Default::default() })?;
+        assert_eq!(pm.room_members_online(&room)?.len(), 1);
+        pm.disconnect(&phone)?;
+        // a late pong from a closed connection doesn't bring it back
+        assert!(!pm.heartbeat(&phone)?);
+        assert!(pm.user_presence(&bob)?.connections.is_empty());
+        // heartbeats keep the connection's rooms
+        assert!(pm.heartbeat(&tab1)? && pm.activity(&tab2)?);
+        assert_eq!(pm.room_members_online(&room)?.len(), 1);
+
+        pm.leave_room(&tab1, &room)?;
+        pm.disconnect(&tab2)?;
+        assert!(pm.room_members_online(&room)?.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut seen = Vec::new();
+        while let Ok(d) = events.try_recv() {
+            assert!(d.headers.is_capnp());
+            let m = RoomMembership::decode(&d.payload).expect("membership envelope");
+            assert_eq!(m.room, room);
+            seen.push((m.user_id, m.left));
+        }
+        assert_eq!(seen, vec![(alice.clone(), false), (bob.clone(), false), (alice.clone(), true)]);
+
+        Arc::new(pm).shutdown().await;
+        let _ = fs::remove_dir_all(&path);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manual_status_idle_and_text_expiry() -> Result<()> {
+        let path = format!("./data/test-presence-{}", Uuid::new_v4());
+        let storage = Arc::new(Storage::new(&path)?);
+        let publisher = Arc::new(Publisher::bind("inproc://presence-test")?);
+        let pm = PresenceManager::new(storage, publisher, 3600, 3600)?;
+        let user = format!("u-{}", Uuid::new_v4());
+        let conn = pm.connect(Some(user.clone()), DeviceKind::Desktop)?;
+        assert_eq!(pm.user_presence(&user)?.status, Status::Online);
+
+        let busy = pm.set_status(&user, StatusUpdate { status: Some("busy".into()), text: Some("In a meeting".into()), text_ttl_secs: None })?;
+        assert_eq!(busy.status, Status::Busy);
+        assert_eq!(busy.public()["text"], "In a meeting");
+
+        let hidden = pm.set_status(&user, StatusUpdate { status: Some("invisible".into()), ..Default::default() })?;
+        assert_eq!(hidden.status, Status::Invisible);
+        assert_eq!(hidden.public()["status"], "offline");
+        assert!(hidden.public()["text"].is_null());
+        assert!(pm.set_status(&user, StatusUpdate { status: Some("offline".into()), ..Default::default() }).is_err());
+
+        // idle auto-away applies only to a manual "online"
+        pm.set_status(&user, StatusUpdate { status: Some("online".into()), text: Some("brb".into()), text_ttl_secs: Some(0) })?;
+        pm.set_idle_timeout(0);
+        let idle = pm.user_presence(&user)?;
+        assert_eq!(idle.status, Status::Away);
+        assert_eq!(idle.text, None, "expired text is hidden");
+        pm.set_idle_timeout(DEFAULT_IDLE_SECS);
+        pm.activity(&conn)?;
+        assert_eq!(pm.user_presence(&user)?.status, Status::Online);
+
+        pm.disconnect(&conn)?;
+        assert_eq!(pm.user_presence(&user)?.status, Status::Offline);
+
+        Arc::new(pm).shutdown().await;
+        let _ = fs::remove_dir_all(&path);
+        Ok(())
+    }
+}
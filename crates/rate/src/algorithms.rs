@@ -0,0 +1,411 @@
+//! Rate limiting algorithms.
+//!
+//! - `TokenBucket`: bursts up to `capacity`, refilled continuously.
+//! - `Gcra`: generic cell rate algorithm; the same shape as a token bucket but
+//!   with a single timestamp of state per key.
+//! - `SlidingWindowLog`: at most `limit` requests in any `window`; exact, but
+//!   keeps one timestamp per admitted unit.
+//! - `FixedWindow`: at most `limit` requests per window starting at the key's
+//!   first request; cheapest, but allows up to 2x `limit` across a boundary.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::{Algorithm, Decision, Keyed, StoreLimits, StoreStats};
+
+/// Algorithm names accepted by `Kind::parse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    TokenBucket,
+    Gcra,
+    SlidingWindow,
+    FixedWindow,
+}
+
+impl Kind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "token_bucket" | "bucket" => Some(Self::TokenBucket),
+            "gcra" => Some(Self::Gcra),
+            "sliding_window" | "sliding_window_log" => Some(Self::SlidingWindow),
+            "fixed_window" => Some(Self::FixedWindow),
+            _ => None,
+        }
+    }
+
+    /// Build the algorithm allowing `limit` requests per `period`.
+    pub fn build(self, limit: u32, period: Duration) -> Box<dyn Algorithm> {
+        self.build_with(limit, period, StoreLimits::default())
+    }
+
+    /// Like `build`, with explicit limits for the per-key store.
+    pub fn build_with(self, limit: u32, period: Duration, store: StoreLimits) -> Box<dyn Algorithm> {
+        match self {
+            Self::TokenBucket => Box::new(TokenBucket::new(limit, limit as f64 / period.as_secs_f64().max(f64::EPSILON)).with_store_limits(store)),
+            Self::Gcra => Box::new(Gcra::new(limit, period).with_store_limits(store)),
+            Self::SlidingWindow => Box::new(SlidingWindowLog::new(limit, period).with_store_limits(store)),
+            Self::FixedWindow => Box::new(FixedWindow::new(limit, period).with_store_limits(store)),
+        }
+    }
+}
+
+impl Algorithm for Box<dyn Algorithm> {
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision {
+        (**self).check_at(key, cost, now)
+    }
+
+    fn reset(&self) {
+        (**self).reset()
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        (**self).sweep(now)
+    }
+
+    fn stats(&self) -> StoreStats {
+        (**self).stats()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket: `capacity` tokens, refilled at `refill_per_sec`.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Keyed<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        // a bucket idle long enough to refill completely is the same as a new one
+        let full = refill_time(capacity as f64, refill_per_sec);
+        Self { capacity: capacity as f64, refill_per_sec, buckets: Keyed::new(full) }
+    }
+
+    pub fn with_store_limits(self, limits: StoreLimits) -> Self {
+        Self { buckets: Keyed::with_limits(self.buckets.idle_ttl(), limits), ..self }
+    }
+
+    fn secs_for(&self, tokens: f64) -> Duration {
+        refill_time(tokens, self.refill_per_sec)
+    }
+}
+
+fn refill_time(tokens: f64, refill_per_sec: f64) -> Duration {
+    if tokens <= 0.0 {
+        Duration::ZERO
+    } else if refill_per_sec <= 0.0 {
+        Duration::MAX
+    } else {
+        Duration::from_secs_f64(tokens / refill_per_sec)
+    }
+}
+
+impl Algorithm for TokenBucket {
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision {
+        let cost = cost as f64;
+        self.buckets.with(
+            key,
+            now,
+            || Bucket { tokens: self.capacity, last: now },
+            |b| {
+                let elapsed = now.saturating_duration_since(b.last).as_secs_f64();
+                b.tokens = (b.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+                b.last = b.last.max(now);
+                let allowed = b.tokens >= cost;
+                if allowed {
+                    b.tokens -= cost;
+                }
+                Decision {
+                    allowed,
+                    limit: self.capacity as u32,
+                    remaining: b.tokens.floor() as u32,
+                    reset_after: self.secs_for(self.capacity - b.tokens),
+                    retry_after: (!allowed).then(|| self.secs_for(cost - b.tokens)),
+                }
+            },
+        )
+    }
+
+    fn reset(&self) {
+        self.buckets.clear();
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        self.buckets.sweep(now)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.buckets.stats()
+    }
+}
+
+/// GCRA: `limit` requests per `period`, with bursts of up to `limit`. State is
+/// the theoretical arrival time (TAT) of the next request.
+pub struct Gcra {
+    limit: u32,
+    period: Duration,
+    // emission interval: period / limit
+    interval: Duration,
+    tats: Keyed<Instant>,
+}
+
+impl Gcra {
+    pub fn new(limit: u32, period: Duration) -> Self {
+        let limit = limit.max(1);
+        Self { limit, period, interval: period / limit, tats: Keyed::new(period) }
+    }
+
+    pub fn with_store_limits(self, limits: StoreLimits) -> Self {
+        Self { tats: Keyed::with_limits(self.period, limits), ..self }
+    }
+}
+
+impl Algorithm for Gcra {
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision {
+        self.tats.with(
+            key,
+            now,
+            || now,
+            |tat| {
+                let start = (*tat).max(now);
+                let new_tat = start + self.interval * cost;
+                // earliest time at which new_tat fits within the burst tolerance
+                let allow_at = new_tat.checked_sub(self.period).unwrap_or(now);
+                let allowed = allow_at <= now;
+                if allowed {
+                    *tat = new_tat;
+                }
+                let backlog = tat.saturating_duration_since(now);
+                let free = self.period.saturating_sub(backlog);
+                Decision {
+                    allowed,
+                    limit: self.limit,
+                    remaining: (free.as_nanos() / self.interval.as_nanos().max(1)) as u32,
+                    reset_after: backlog,
+                    retry_after: (!allowed).then(|| allow_at.saturating_duration_since(now)),
+                }
+            },
+        )
+    }
+
+    fn reset(&self) {
+        self.tats.clear();
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        self.tats.sweep(now)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.tats.stats()
+    }
+}
+
+/// Sliding-window log: at most `limit` units in any trailing `window`.
+pub struct SlidingWindowLog {
+    limit: u32,
+    window: Duration,
+    logs: Keyed<VecDeque<Instant>>,
+}
+
+impl SlidingWindowLog {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window, logs: Keyed::new(window) }
+    }
+
+    pub fn with_store_limits(self, limits: StoreLimits) -> Self {
+        Self { logs: Keyed::with_limits(self.window, limits), ..self }
+    }
+}
+
+impl Algorithm for SlidingWindowLog {
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision {
+        self.logs.with(
+            key,
+            now,
+            VecDeque::new,
+            |log| {
+                while log.front().is_some_and(|t| now.saturating_duration_since(*t) >= self.window) {
+                    log.pop_front();
+                }
+                let used = log.len() as u32;
+                // checked: a cost near u32::MAX must not wrap around into an allow
+                let allowed = used.checked_add(cost).is_some_and(|total| total <= self.limit);
+                if allowed {
+                    log.extend(std::iter::repeat_n(now, cost as usize));
+                }
+                let expires = |t: &Instant| (*t + self.window).saturating_duration_since(now);
+                // denied: wait until enough of the oldest entries age out
+                let retry_after = (!allowed).then(|| {
+                    let need = used.saturating_add(cost).saturating_sub(self.limit) as usize;
+                    if cost > self.limit { Duration::MAX } else { log.get(need - 1).map(expires).unwrap_or(Duration::ZERO) }
+                });
+                Decision {
+                    allowed,
+                    limit: self.limit,
+                    remaining: self.limit.saturating_sub(log.len() as u32),
+                    reset_after: log.back().map(expires).unwrap_or(Duration::ZERO),
+                    retry_after,
+                }
+            },
+        )
+    }
+
+    fn reset(&self) {
+        self.logs.clear();
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        self.logs.sweep(now)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.logs.stats()
+    }
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// Fixed-window counter: at most `limit` units per `window`.
+pub struct FixedWindow {
+    limit: u32,
+    window: Duration,
+    windows: Keyed<Window>,
+}
+
+impl FixedWindow {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window, windows: Keyed::new(window) }
+    }
+
+    pub fn with_store_limits(self, limits: StoreLimits) -> Self {
+        Self { windows: Keyed::with_limits(self.window, limits), ..self }
+    }
+}
+
+impl Algorithm for FixedWindow {
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision {
+        self.windows.with(
+            key,
+            now,
+            || Window { start: now, count: 0 },
+            |w| {
+                if now.saturating_duration_since(w.start) >= self.window {
+                    w.start = now;
+                    w.count = 0;
+                }
+                let allowed = w.count.checked_add(cost).is_some_and(|total| total <= self.limit);
+                if allowed {
+                    w.count += cost;
+                }
+                let reset_after = (w.start + self.window).saturating_duration_since(now);
+                Decision {
+                    allowed,
+                    limit: self.limit,
+                    remaining: self.limit.saturating_sub(w.count),
+                    reset_after,
+                    retry_after: (!allowed).then_some(reset_after),
+                }
+            },
+        )
+    }
+
+    fn reset(&self) {
+        self.windows.clear();
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        self.windows.sweep(now)
+    }
+
+    fn stats(&self) -> StoreStats {
+        self.windows.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admitted(alg: &dyn Algorithm, at: Instant, n: u32) -> u32 {
+        (0..n).filter(|_| alg.check_at("k", 1, at).allowed).count() as u32
+    }
+
+    #[test]
+    fn every_algorithm_admits_limit_then_recovers() {
+        let t0 = Instant::now();
+        for kind in [Kind::TokenBucket, Kind::Gcra, Kind::SlidingWindow, Kind::FixedWindow] {
+            let alg = kind.build(5, Duration::from_secs(10));
+            assert_eq!(admitted(alg.as_ref(), t0, 10), 5, "{:?}", kind);
+            let denied = alg.check_at("k", 1, t0);
+            assert!(!denied.allowed);
+            assert_eq!(denied.remaining, 0, "{:?}", kind);
+            let retry = denied.retry_after.expect("retry_after on deny");
+            assert!(retry > Duration::ZERO && retry <= Duration::from_secs(10), "{:?}: {:?}", kind, retry);
+            assert!(alg.check_at("k", 1, t0 + retry).allowed, "{:?} allows after retry_after", kind);
+            // other keys are independent
+            assert!(alg.check_at("other", 1, t0).allowed);
+        }
+    }
+
+    #[test]
+    fn gcra_spaces_requests_after_burst() {
+        let t0 = Instant::now();
+        let gcra = Gcra::new(10, Duration::from_secs(1));
+        assert_eq!(admitted(&gcra, t0, 10), 10);
+        let d = gcra.check_at("k", 1, t0);
+        assert_eq!(d.retry_after, Some(Duration::from_millis(100)));
+        assert_eq!(d.reset_after, Duration::from_secs(1));
+        let later = gcra.check_at("k", 1, t0 + Duration::from_millis(100));
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 0);
+    }
+
+    #[test]
+    fn sliding_window_has_no_boundary_burst() {
+        let t0 = Instant::now();
+        let window = Duration::from_secs(60);
+        let sliding = SlidingWindowLog::new(4, window);
+        let fixed = FixedWindow::new(4, window);
+        for alg in [&sliding as &dyn Algorithm, &fixed] {
+            assert_eq!(admitted(alg, t0, 1), 1);
+            assert_eq!(admitted(alg, t0 + Duration::from_secs(59), 4), 3);
+        }
+        // just after the fixed window rolls over it admits a full new window;
+        // the sliding log still counts the three requests from a second ago
+        let t1 = t0 + Duration::from_secs(61);
+        assert_eq!(admitted(&fixed, t1, 4), 4);
+        assert_eq!(admitted(&sliding, t1, 4), 1);
+    }
+
+    #[test]
+    fn cost_weights_consume_multiple_units() {
+        let t0 = Instant::now();
+        let bucket = TokenBucket::new(10, 1.0);
+        let d = bucket.check_at("k", 4, t0);
+        assert!(d.allowed);
+        assert_eq!(d.remaining, 6);
+        assert!(!bucket.check_at("k", 7, t0).allowed);
+        assert_eq!(bucket.check_at("k", 7, t0).retry_after, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn huge_costs_are_denied() {
+        let t0 = Instant::now();
+        for kind in [Kind::TokenBucket, Kind::Gcra, Kind::SlidingWindow, Kind::FixedWindow] {
+            let alg = kind.build(5, Duration::from_secs(10));
+            assert!(alg.check_at("k", 1, t0).allowed);
+            // 1 + u32::MAX would wrap to 0 in the counting algorithms
+            assert!(!alg.check_at("k", u32::MAX, t0).allowed, "{:?}", kind);
+            assert_eq!(admitted(alg.as_ref(), t0, 10), 4, "{:?}: nothing was used up", kind);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Client address handling for IP-keyed limits.
+//!
+//! - The client IP is the TCP peer unless the peer is a trusted proxy, in which
+//!   case the `Forwarded` (RFC 7239) or `X-Forwarded-For` chain is walked from
+//!   the right and the first untrusted hop wins. Entries left of it are
+//!   client-supplied and ignored, so they cannot be spoofed.
+//! - IPv6 clients are grouped by prefix (a /64 by default) since a single host
+//!   usually controls a whole /64; IPv4-mapped addresses count as IPv4.
+//! - Allow-listed clients skip rate limiting; deny-listed ones are refused.
+//!   Deny wins when both match.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::policy::Cidr;
+
+/// `clients` section of the policy file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Proxies whose forwarding headers are believed. Defaults to loopback and
+    /// private ranges, where Traefik reaches us on the container network.
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Prefix length IPv6 clients are grouped by.
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self { trusted_proxies: default_trusted_proxies(), allow: Vec::new(), deny: Vec::new(), ipv6_prefix: default_ipv6_prefix() }
+    }
+}
+
+fn default_trusted_proxies() -> Vec<String> {
+    ["127.0.0.0/8", "::1", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"].map(String::from).to_vec()
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+/// How a client address is treated by the allow/deny lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Not rate limited.
+    Allow,
+    Deny,
+    /// Subject to the normal policies.
+    Limited,
+}
+
+/// Compiled `ClientConfig`.
+#[derive(Debug, Clone)]
+pub struct Clients {
+    trusted: Vec<Cidr>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    ipv6_prefix: u8,
+}
+
+impl Clients {
+    pub fn from_config(cfg: &ClientConfig) -> Result<Self> {
+        if cfg.ipv6_prefix == 0 || cfg.ipv6_prefix > 128 {
+            bail!("ipv6_prefix must be between 1 and 128");
+        }
+        let parse = |list: &[String]| list.iter().map(|s| Cidr::parse(s)).collect::<Result<Vec<_>>>();
+        Ok(Self { trusted: parse(&cfg.trusted_proxies)?, allow: parse(&cfg.allow)?, deny: parse(&cfg.deny)?, ipv6_prefix: cfg.ipv6_prefix })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|c| c.contains(ip))
+    }
+
+    /// Resolve the client address from the TCP peer and the raw `Forwarded` /
+    /// `X-Forwarded-For` header values. `Forwarded` is preferred when present.
+    pub fn resolve(&self, peer: IpAddr, forwarded: Option<&str>, x_forwarded_for: Option<&str>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let chain: Vec<Option<IpAddr>> = match (forwarded, x_forwarded_for) {
+            (Some(f), _) => forwarded_for(f),
+            (None, Some(x)) => x.split(',').map(parse_node).collect(),
+            (None, None) => return peer,
+        };
+        let mut client = peer;
+        for hop in chain.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // unparseable or obfuscated hop: stop at the last one we trust
+                None => break,
+            }
+        }
+        client
+    }
+
+    pub fn access(&self, ip: IpAddr) -> Access {
+        let ip = canonical(ip);
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            Access::Deny
+        } else if self.allow.iter().any(|c| c.contains(ip)) {
+            Access::Allow
+        } else {
+            Access::Limited
+        }
+    }
+
+    /// The address a client is counted under: IPv4 as is, IPv6 masked to `ipv6_prefix`.
+    pub fn group(&self, ip: IpAddr) -> IpAddr {
+        match canonical(ip) {
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - self.ipv6_prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+            v4 => v4,
+        }
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::from_config(&ClientConfig::default()).expect("default client config is valid")
+    }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// `for=` values of each `Forwarded` element, in order.
+fn forwarded_for(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (k, v) = pair.split_once('=')?;
+                k.trim().eq_ignore_ascii_case("for").then(|| v.trim().trim_matches('"'))
+            })
+        })
+        .map(|node| node.and_then(parse_node))
+        .collect()
+}
+
+/// Parse a forwarded node: `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']').and_then(|(ip, _)| ip.parse().ok());
+    }
+    node.rsplit_once(':').and_then(|(ip, _)| ip.parse::<std::net::Ipv4Addr>().ok()).map(IpAddr::V4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarding_headers_are_only_trusted_from_proxies() {
+        let clients = Clients::default();
+        let proxy = ip("172.18.0.5");
+        // direct client: headers ignored
+        assert_eq!(clients.resolve(ip("198.51.100.9"), None, Some("1.1.1.1")), ip("198.51.100.9"));
+        // via Traefik: rightmost untrusted hop, spoofed left entries ignored
+        assert_eq!(clients.resolve(proxy, None, Some("6.6.6.6, 203.0.113.4, 10.0.0.2")), ip("203.0.113.4"));
+        assert_eq!(clients.resolve(proxy, Some(r#"for=6.6.6.6, for="[2001:db8::17]:4711";proto=https"#), Some("9.9.9.9")), ip("2001:db8::17"));
+        assert_eq!(clients.resolve(proxy, Some("for=_hidden"), None), proxy);
+        assert_eq!(clients.resolve(proxy, None, Some("203.0.113.4:5555")), ip("203.0.113.4"));
+        assert_eq!(clients.resolve(proxy, None, None), proxy);
+    }
+
+    #[test]
+    fn ipv6_grouping_and_access_lists() {
+        let clients = Clients::from_config(&ClientConfig {
+            allow: vec!["192.0.2.10".into(), "198.51.100.0/24".into()],
+            deny: vec!["2001:db8:bad::/48".into(), "192.0.2.0/24".into()],
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        assert_eq!(clients.group(ip("2001:db8:1:2:aaaa::1")), clients.group(ip("2001:db8:1:2:bbbb::9")));
+        assert_ne!(clients.group(ip("2001:db8:1:2::1")), clients.group(ip("2001:db8:1:3::1")));
+        assert_eq!(clients.group(ip("::ffff:203.0.113.4")), ip("203.0.113.4"));
+        assert_eq!(clients.access(ip("2001:db8:bad:1::1")), Access::Deny);
+        assert_eq!(clients.access(ip("192.0.2.10")), Access::Deny, "deny wins");
+        assert_eq!(clients.access(ip("::ffff:192.0.2.77")), Access::Deny);
+        assert_eq!(clients.access(ip("198.51.100.3")), Access::Allow);
+        assert_eq!(clients.access(ip("203.0.113.4")), Access::Limited);
+    }
+}
@@ -1,73 +1,106 @@
-﻿use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::Instant;
-
-/// Simple token-bucket implementation used by the gateway.
-/// Buckets are created on first use and stored in-memory. This is an in-proc
-/// limiter suitable for single-host deployments. Persistent counters are kept
-/// in Storage separately (gateway is responsible for calling Storage::incr_rate_counter).
-pub struct TokenBucket {
-    capacity: f64,
-    tokens: f64,
-    refill_per_sec: f64,
-    last: Instant,
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub mod algorithms;
+pub mod client;
+pub mod policy;
+pub mod shared;
+pub mod store;
+
+pub use algorithms::{FixedWindow, Gcra, Kind, SlidingWindowLog, TokenBucket};
+pub use client::{Access, Clients};
+pub use policy::{Outcome, Policies, RequestInfo};
+pub use shared::Remote;
+pub use store::{Keyed, StoreLimits, StoreStats};
+
+/// Outcome of a rate check. `remaining` and `reset_after` describe the quota
+/// after this request, so callers can emit `RateLimit-*` headers either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Requests allowed per window/burst.
+    pub limit: u32,
+    /// Requests still allowed right now.
+    pub remaining: u32,
+    /// Time until the quota is fully restored.
+    pub reset_after: Duration,
+    /// When denied, how long to wait before the same request can succeed.
+    pub retry_after: Option<Duration>,
 }
 
-impl TokenBucket {
-    fn new(capacity: f64, refill_per_sec: f64) -> Self {
-        Self {
-            capacity,
-            tokens: capacity,
-            refill_per_sec,
-            last: Instant::now(),
-        }
-    }
+/// A rate limiting algorithm. Implementations keep their own per-key state
+/// (usually in a `Keyed` store) and must be safe to share between requests.
+pub trait Algorithm: Send + Sync {
+    /// Check and, if allowed, consume `cost` units for `key` at `now`.
+    fn check_at(&self, key: &str, cost: u32, now: Instant) -> Decision;
 
-    fn refill(&mut self) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last).as_secs_f64();
-        if elapsed > 0.0 {
-            self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
-            self.last = now;
-        }
-    }
+    /// Drop all per-key state.
+    fn reset(&self);
 
-    /// Try consume `amount` tokens. Returns true if tokens were available.
-    fn try_consume(&mut self, amount: f64) -> bool {
-        self.refill();
-        if self.tokens >= amount {
-            self.tokens -= amount;
-            true
-        } else {
-            false
-        }
-    }
+    /// Drop state idle at `now`. Returns how many keys were removed.
+    fn sweep(&self, now: Instant) -> usize;
+
+    fn stats(&self) -> StoreStats;
 }
 
-/// RateLimiter holds token buckets keyed by an arbitrary string (e.g. user or IP).
+/// Counters for one limiter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub keys: usize,
+    pub expired: u64,
+    pub evicted: u64,
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// RateLimiter applies one algorithm to requests keyed by an arbitrary string
+/// (e.g. user or IP). The state is in-proc; to share limits between several
+/// gateways, `Policies` charges one process's limiters over RPC (see `shared`).
+/// Persistent counters are kept in Storage separately (gateway is responsible
+/// for calling Storage::incr_rate_counter).
 pub struct RateLimiter {
-    buckets: Mutex<HashMap<String, TokenBucket>>,
-    capacity: f64,
-    refill_per_sec: f64,
+    algorithm: Box<dyn Algorithm>,
+    allowed: AtomicU64,
+    denied: AtomicU64,
 }
 
 impl RateLimiter {
-    /// Create a new RateLimiter with per-bucket capacity and refill rate (tokens/sec).
+    /// Create a token-bucket RateLimiter with per-bucket capacity and refill rate (tokens/sec).
     pub fn new(capacity: usize, refill_per_sec: f64) -> Self {
-        Self {
-            buckets: Mutex::new(HashMap::new()),
-            capacity: capacity as f64,
-            refill_per_sec,
-        }
+        Self::with_algorithm(TokenBucket::new(capacity as u32, refill_per_sec))
+    }
+
+    /// Create a RateLimiter backed by any algorithm.
+    pub fn with_algorithm(algorithm: impl Algorithm + 'static) -> Self {
+        Self { algorithm: Box::new(algorithm), allowed: AtomicU64::new(0), denied: AtomicU64::new(0) }
+    }
+
+    /// Check and consume a single unit for `key`.
+    pub fn check(&self, key: &str) -> Decision {
+        self.check_cost(key, 1)
+    }
+
+    /// Check and consume `cost` units for `key`.
+    pub fn check_cost(&self, key: &str, cost: u32) -> Decision {
+        let decision = self.algorithm.check_at(key, cost, Instant::now());
+        self.record(&decision);
+        decision
+    }
+
+    /// Consume `cost` units without counting a request (used for leases).
+    pub(crate) fn reserve(&self, key: &str, cost: u32) -> Decision {
+        self.algorithm.check_at(key, cost, Instant::now())
+    }
+
+    /// Count a decision made elsewhere (e.g. by the shared rate service).
+    pub(crate) fn record(&self, decision: &Decision) {
+        let counter = if decision.allowed { &self.allowed } else { &self.denied };
+        counter.fetch_add(1, Ordering::Relaxed);
     }
 
     /// Check and consume a single token for `key`. Returns true if allowed.
     pub fn allow(&self, key: &str) -> bool {
-        let mut map = self.buckets.lock().unwrap();
-        let bucket = map.entry(key.to_string()).or_insert_with(|| {
-            TokenBucket::new(self.capacity, self.refill_per_sec)
-        });
-        bucket.try_consume(1.0)
+        self.check(key).allowed
     }
 
     /// Convenience for tests/debugging.
@@ -75,10 +108,25 @@ impl RateLimiter {
         println!("rate limiter ready");
     }
 
-    /// Clear all in-memory token buckets (dev-only).
+    /// Drop idle per-key state. Returns how many keys were removed.
+    pub fn sweep(&self) -> usize {
+        self.algorithm.sweep(Instant::now())
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let store = self.algorithm.stats();
+        Metrics {
+            keys: store.keys,
+            expired: store.expired,
+            evicted: store.evicted,
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Clear all in-memory rate state (dev-only).
     /// Useful in development to reset rate limiting without restarting the process.
     pub fn clear_buckets(&self) {
-        let mut map = self.buckets.lock().unwrap();
-        map.clear();
+        self.algorithm.reset();
     }
 }
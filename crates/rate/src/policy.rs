@@ -0,0 +1,503 @@
+//! Declarative rate limit policies.
+//!
+//! A policy set has named *budgets* (an algorithm with a limit per period,
+//! each with its own per-key state) and an ordered list of *rules*. The first
+//! rule whose matchers all accept a request decides its budget, cost and key;
+//! a request no rule matches is not limited.
+//!
+//! ```json
+//! {
+//!   "budgets": {
+//!     "default":  { "algorithm": "token_bucket", "limit": 5, "period_secs": 5 },
+//!     "login":    { "algorithm": "sliding_window", "limit": 10, "period_secs": 300 }
+//!   },
+//!   "rules": [
+//!     { "path": "/healthz", "exempt": true },
+//!     { "path": "/api/auth/login", "methods": ["POST"], "budget": "login", "key": "ip" },
+//!     { "path": "/rooms/*/messages", "methods": ["POST"], "budget": "default", "cost": 2 },
+//!     { "budget": "default" }
+//!   ]
+//! }
+//! ```
+//!
+//! Path patterns match whole segments: `*` matches one segment and a trailing
+//! `**` matches any remainder (including nothing). Rules can also match on
+//! `methods`, `role`, `authenticated` and `ips` (addresses or CIDR blocks).
+//!
+//! An optional `clients` section configures how the client IP is found and
+//! grouped, and the allow/deny lists (see `client::ClientConfig`):
+//!
+//! ```json
+//! "clients": { "trusted_proxies": ["172.16.0.0/12"], "deny": ["192.0.2.0/24"], "ipv6_prefix": 64 }
+//! ```
+//!
+//! With several gateways, attach a `shared::Remote` so budgets are charged on
+//! one rate service instead of per process (see `shared`).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::client::{ClientConfig, Clients};
+use crate::shared::Remote;
+use crate::{Decision, Kind, Metrics, RateLimiter, StoreLimits};
+
+/// Built-in policies used when no policy file is configured.
+pub const DEFAULT_POLICIES: &str = r#"{
+  "budgets": {
+    "default":  { "algorithm": "token_bucket", "limit": 5, "period_secs": 5 },
+    "anon":     { "algorithm": "token_bucket", "limit": 5, "period_secs": 5 },
+    "login":    { "algorithm": "sliding_window", "limit": 10, "period_secs": 300 },
+    "refresh":  { "algorithm": "token_bucket", "limit": 30, "period_secs": 60 },
+    "messages": { "algorithm": "gcra", "limit": 30, "period_secs": 60 }
+  },
+  "rules": [
+    { "path": "/healthz", "exempt": true },
+    { "path": "/ws", "exempt": true },
+    { "path": "/api/frontend-logs", "exempt": true },
+    { "path": "/api/auth/me", "exempt": true },
+    { "path": "/api/auth/logout", "exempt": true },
+    { "path": "/api/auth/check_username", "exempt": true },
+    { "path": "/api/auth/login", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/login/mfa", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/signup", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/password/*", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/email/**", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/refresh", "methods": ["POST"], "budget": "refresh", "key": "ip" },
+    { "path": "/api/auth/oauth/*/*", "methods": ["GET"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/webauthn/login/*", "methods": ["POST"], "budget": "login", "key": "ip" },
+    { "path": "/api/auth/totp/*", "methods": ["POST"], "budget": "login" },
+    { "path": "/rooms/*/messages", "methods": ["POST"], "budget": "messages" },
+    { "authenticated": false, "budget": "anon", "key": "ip" },
+    { "budget": "default" }
+  ]
+}"#;
+
+/// On-disk policy format.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub budgets: HashMap<String, BudgetConfig>,
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub clients: ClientConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfig {
+    /// One of the `Kind::parse` names; defaults to "token_bucket".
+    #[serde(default)]
+    pub algorithm: Option<String>,
+    pub limit: u32,
+    pub period_secs: f64,
+    /// Most keys tracked at once; least recently used keys beyond it are evicted.
+    #[serde(default)]
+    pub max_keys: Option<usize>,
+    /// Extra units a gateway may lease per round trip in shared mode. Defaults
+    /// to a tenth of `limit`, so small budgets are always charged remotely.
+    #[serde(default)]
+    pub lease: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+    /// HTTP methods (any if empty).
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub authenticated: Option<bool>,
+    /// Client addresses or CIDR blocks (any if empty).
+    #[serde(default)]
+    pub ips: Vec<String>,
+    #[serde(default)]
+    pub exempt: bool,
+    #[serde(default)]
+    pub budget: Option<String>,
+    /// Units consumed per request (default 1).
+    #[serde(default)]
+    pub cost: Option<u32>,
+    #[serde(default)]
+    pub key: KeyBy,
+}
+
+/// What a budget is counted per.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyBy {
+    /// The authenticated user; anonymous requests fall back to the client IP.
+    #[default]
+    User,
+    Ip,
+    /// One counter shared by every request the rule matches.
+    Global,
+}
+
+/// The request attributes rules match on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestInfo<'a> {
+    pub path: &'a str,
+    pub method: &'a str,
+    pub user_id: Option<&'a str>,
+    pub role: Option<&'a str>,
+    /// Resolved client address (see `Clients::resolve`).
+    pub ip: Option<IpAddr>,
+}
+
+/// Result of checking a request against the policies.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub budget: String,
+    pub key: String,
+    pub decision: Decision,
+}
+
+/// An address block such as `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `addr/prefix`, or a bare address as a single-host block.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().with_context(|| format!("invalid address {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| anyhow!("invalid prefix in {:?}", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V4(net), IpAddr::V6(ip)) => ip.to_ipv4_mapped().is_some_and(|ip| prefix_eq(&net.octets(), &ip.octets(), self.prefix)),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let (full, rest) = ((bits / 8) as usize, bits % 8);
+    if a[..full] != b[..full] {
+        return false;
+    }
+    rest == 0 || (a[full] ^ b[full]) & (0xFFu8 << (8 - rest)) == 0
+}
+
+struct Rule {
+    path: Option<Vec<String>>,
+    methods: Vec<String>,
+    role: Option<String>,
+    authenticated: Option<bool>,
+    ips: Vec<Cidr>,
+    // None = exempt
+    budget: Option<String>,
+    cost: u32,
+    key: KeyBy,
+}
+
+impl Rule {
+    fn matches(&self, req: &RequestInfo) -> bool {
+        self.path.as_ref().is_none_or(|p| path_matches(p, req.path))
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(req.method)))
+            && self.role.as_deref().is_none_or(|r| req.role == Some(r))
+            && self.authenticated.is_none_or(|a| a == req.user_id.is_some())
+            && (self.ips.is_empty() || req.ip.is_some_and(|ip| self.ips.iter().any(|c| c.contains(ip))))
+    }
+}
+
+fn path_matches(pattern: &[String], path: &str) -> bool {
+    let mut segs = path.trim_end_matches('/').split('/').skip(1);
+    for (i, p) in pattern.iter().enumerate() {
+        if p == "**" && i == pattern.len() - 1 {
+            return true;
+        }
+        match segs.next() {
+            Some(s) if p == "*" || p == s => {}
+            _ => return false,
+        }
+    }
+    segs.next().is_none()
+}
+
+struct Budget {
+    limiter: RateLimiter,
+    limit: u32,
+    lease: u32,
+}
+
+/// Compiled policy set: one limiter per budget plus the ordered rules.
+pub struct Policies {
+    budgets: HashMap<String, Budget>,
+    rules: Vec<Rule>,
+    clients: Clients,
+    remote: Option<Remote>,
+}
+
+impl Policies {
+    /// The built-in `DEFAULT_POLICIES`.
+    pub fn defaults() -> Self {
+        Self::from_json(DEFAULT_POLICIES).expect("built-in rate policies are valid")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let cfg: PolicyConfig = serde_json::from_str(json).context("parsing rate policies")?;
+        Self::from_config(cfg)
+    }
+
+    /// Validate and compile a config: unknown algorithms, budgets, methods and
+    /// malformed CIDRs are errors.
+    pub fn from_config(cfg: PolicyConfig) -> Result<Self> {
+        let mut budgets = HashMap::new();
+        for (name, b) in cfg.budgets {
+            let kind = match b.algorithm.as_deref() {
+                Some(a) => Kind::parse(a).ok_or_else(|| anyhow!("budget {:?}: unknown algorithm {:?}", name, a))?,
+                None => Kind::TokenBucket,
+            };
+            if b.limit == 0 || b.period_secs.is_nan() || b.period_secs <= 0.0 {
+                bail!("budget {:?}: limit and period_secs must be positive", name);
+            }
+            let period = Duration::from_secs_f64(b.period_secs);
+            let mut store = StoreLimits::default();
+            if let Some(max_keys) = b.max_keys {
+                if max_keys == 0 {
+                    bail!("budget {:?}: max_keys must be positive", name);
+                }
+                store.max_keys = max_keys;
+            }
+            let limiter = RateLimiter::with_algorithm(kind.build_with(b.limit, period, store));
+            budgets.insert(name, Budget { limiter, limit: b.limit, lease: b.lease.unwrap_or(b.limit / 10) });
+        }
+
+        let mut rules = Vec::with_capacity(cfg.rules.len());
+        for (i, r) in cfg.rules.into_iter().enumerate() {
+            let budget = match (r.exempt, r.budget) {
+                (true, None) => None,
+                (false, Some(b)) if budgets.contains_key(&b) => Some(b),
+                (false, Some(b)) => bail!("rule {}: unknown budget {:?}", i, b),
+                (false, None) => bail!("rule {}: needs a budget or \"exempt\": true", i),
+                (true, Some(_)) => bail!("rule {}: an exempt rule cannot have a budget", i),
+            };
+            if let Some(m) = r.methods.iter().find(|m| m.is_empty() || !m.bytes().all(|b| b.is_ascii_alphabetic())) {
+                bail!("rule {}: invalid method {:?}", i, m);
+            }
+            let path = match r.path {
+                Some(p) if !p.starts_with('/') => bail!("rule {}: path {:?} must start with '/'", i, p),
+                Some(p) => Some(p.trim_end_matches('/').split('/').skip(1).map(str::to_string).collect()),
+                None => None,
+            };
+            let ips = r.ips.iter().map(|s| Cidr::parse(s)).collect::<Result<Vec<_>>>().with_context(|| format!("rule {}", i))?;
+            let cost = r.cost.unwrap_or(1);
+            if let Some(b) = &budget {
+                if cost == 0 || cost > budgets[b].limit {
+                    bail!("rule {}: cost must be between 1 and the limit of budget {:?}", i, b);
+                }
+            }
+            rules.push(Rule {
+                path,
+                methods: r.methods,
+                role: r.role,
+                authenticated: r.authenticated,
+                ips,
+                budget,
+                cost,
+                key: r.key,
+            });
+        }
+        let clients = Clients::from_config(&cfg.clients).context("clients")?;
+        Ok(Self { budgets, rules, clients, remote: None })
+    }
+
+    /// Charge budgets on a shared rate service instead of locally.
+    pub fn with_remote(mut self, remote: Remote) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Client IP resolution and allow/deny lists.
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Whether any rule matches on role (so callers can skip looking it up).
+    pub fn needs_role(&self) -> bool {
+        self.rules.iter().any(|r| r.role.is_some())
+    }
+
+    // First matching rule's budget, the key it counts under and the cost.
+    fn route(&self, req: &RequestInfo) -> Option<(&String, String, u32)> {
+        let rule = self.rules.iter().find(|r| r.matches(req))?;
+        let budget = rule.budget.as_ref()?;
+        // IPv6 clients share a key per prefix (a /64 by default)
+        let ip = || req.ip.map(|ip| format!("ip:{}", self.clients.group(ip))).unwrap_or_else(|| "anon".to_string());
+        let key = match rule.key {
+            KeyBy::User => req.user_id.map(|u| format!("user:{}", u)).unwrap_or_else(ip),
+            KeyBy::Ip => ip(),
+            KeyBy::Global => "global".to_string(),
+        };
+        Some((budget, key, rule.cost))
+    }
+
+    /// Charge a request against the first matching rule's budget in this
+    /// process. `None` if the request is exempt or matches no rule.
+    pub fn check(&self, req: &RequestInfo) -> Option<Outcome> {
+        let (budget, key, cost) = self.route(req)?;
+        let decision = self.budgets[budget].limiter.check_cost(&key, cost);
+        Some(Outcome { budget: budget.clone(), key, decision })
+    }
+
+    /// Like `check`, but charged on the shared rate service when a `Remote` is
+    /// attached. If the service can't be reached the local budget is used, so
+    /// each gateway still enforces the limit on its own.
+    pub async fn charge(&self, req: &RequestInfo<'_>) -> Option<Outcome> {
+        let Some(remote) = &self.remote else { return self.check(req) };
+        let (budget, key, cost) = self.route(req)?;
+        let b = &self.budgets[budget];
+        let decision = match remote.check(budget, &key, cost, b.lease).await {
+            Some(d) => {
+                b.limiter.record(&d);
+                d
+            }
+            None => b.limiter.check_cost(&key, cost),
+        };
+        Some(Outcome { budget: budget.clone(), key, decision })
+    }
+
+    /// Serve a lease on the rate service: charge `cost` for the request itself
+    /// and, if that is allowed, up to `extra` more units (at most half of what
+    /// is left) for the caller to spend locally. Returns the decision and the
+    /// number of extra units granted.
+    pub fn lease(&self, budget: &str, key: &str, cost: u32, extra: u32) -> Result<(Decision, u32)> {
+        let b = self.budgets.get(budget).ok_or_else(|| anyhow!("unknown budget {:?}", budget))?;
+        // the request comes off the network: costs no rule could have are refused
+        if cost == 0 || cost > b.limit {
+            bail!("cost {} is outside 1..={} for budget {:?}", cost, b.limit, budget);
+        }
+        let limiter = &b.limiter;
+        let decision = limiter.check_cost(key, cost);
+        let extra = extra.min(decision.remaining / 2);
+        if decision.allowed && extra > 0 {
+            let leased = limiter.reserve(key, extra);
+            if leased.allowed {
+                return Ok((leased, extra));
+            }
+        }
+        Ok((decision, 0))
+    }
+
+    /// Per-budget metrics, sorted by budget name.
+    pub fn metrics(&self) -> Vec<(String, Metrics)> {
+        let mut out: Vec<(String, Metrics)> = self.budgets.iter().map(|(name, b)| (name.clone(), b.limiter.metrics())).collect();
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    /// Drop idle state in every budget. Returns how many keys were removed.
+    pub fn sweep(&self) -> usize {
+        self.budgets.values().map(|b| b.limiter.sweep()).sum::<usize>() + self.remote.as_ref().map_or(0, Remote::sweep)
+    }
+
+    /// Clear the state of every budget (dev-only).
+    pub fn clear_buckets(&self) {
+        for b in self.budgets.values() {
+            b.limiter.clear_buckets();
+        }
+        if let Some(remote) = &self.remote {
+            remote.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req<'a>(method: &'a str, path: &'a str, user_id: Option<&'a str>) -> RequestInfo<'a> {
+        RequestInfo { path, method, user_id, role: None, ip: Some("203.0.113.7".parse().unwrap()) }
+    }
+
+    #[test]
+    fn first_matching_rule_picks_budget_and_key() {
+        let p = Policies::defaults();
+        assert!(p.check(&req("GET", "/healthz", None)).is_none());
+        assert!(p.check(&req("GET", "/ws", Some("u1"))).is_none());
+
+        let login = p.check(&req("POST", "/api/auth/login", Some("u1"))).unwrap();
+        assert_eq!((login.budget.as_str(), login.key.as_str()), ("login", "ip:203.0.113.7"));
+        let msg = p.check(&req("POST", "/rooms/general/messages", Some("u1"))).unwrap();
+        assert_eq!((msg.budget.as_str(), msg.key.as_str()), ("messages", "user:u1"));
+        assert_eq!(p.check(&req("GET", "/rooms/general/history", None)).unwrap().budget, "anon");
+        assert_eq!(p.check(&req("GET", "/rooms/general/history", Some("u1"))).unwrap().budget, "default");
+    }
+
+    #[test]
+    fn budgets_are_independent_and_costs_apply() {
+        let p = Policies::from_json(r#"{
+            "budgets": { "a": { "limit": 4, "period_secs": 60 }, "b": { "limit": 4, "period_secs": 60 } },
+            "rules": [
+                { "path": "/heavy/**", "budget": "a", "cost": 2 },
+                { "role": "admin", "budget": "b", "key": "global" },
+                { "ips": ["203.0.113.0/24"], "budget": "b" }
+            ]
+        }"#).unwrap();
+        let heavy = req("GET", "/heavy/x/y", Some("u1"));
+        assert!(p.check(&heavy).unwrap().decision.allowed);
+        assert!(p.check(&heavy).unwrap().decision.allowed);
+        assert!(!p.check(&heavy).unwrap().decision.allowed);
+        let a = p.metrics().into_iter().find(|(name, _)| name == "a").unwrap().1;
+        assert_eq!((a.keys, a.allowed, a.denied), (1, 2, 1));
+        // budget "b" is untouched by "a"
+        assert!(p.check(&req("GET", "/other", Some("u1"))).unwrap().decision.allowed);
+
+        let admin = RequestInfo { role: Some("admin"), ..req("GET", "/other", Some("u2")) };
+        assert_eq!(p.check(&admin).unwrap().key, "global");
+        assert!(p.needs_role());
+        let outside = RequestInfo { ip: Some("198.51.100.1".parse().unwrap()), ..req("GET", "/other", None) };
+        assert!(p.check(&outside).is_none());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        for bad in [
+            r#"{ "budgets": {}, "rules": [{ "budget": "missing" }] }"#,
+            r#"{ "budgets": { "a": { "algorithm": "nope", "limit": 1, "period_secs": 1 } }, "rules": [] }"#,
+            r#"{ "budgets": { "a": { "limit": 0, "period_secs": 1 } }, "rules": [] }"#,
+            r#"{ "budgets": { "a": { "limit": 1, "period_secs": 1 } }, "rules": [{ "budget": "a", "ips": ["10.0.0.0/33"] }] }"#,
+            r#"{ "budgets": { "a": { "limit": 1, "period_secs": 1 } }, "rules": [{ "budget": "a", "pth": "/" }] }"#,
+            r#"{ "budgets": {}, "rules": [{ "path": "/x" }] }"#,
+            r#"{ "budgets": { "a": { "limit": 5, "period_secs": 1 } }, "rules": [{ "budget": "a", "cost": 0 }] }"#,
+            r#"{ "budgets": { "a": { "limit": 5, "period_secs": 1 } }, "rules": [{ "budget": "a", "cost": 6 }] }"#,
+        ] {
+            assert!(Policies::from_json(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let v6 = Cidr::parse("2001:db8::/33").unwrap();
+        assert!(v6.contains("2001:db8:7fff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db8:8000::1".parse().unwrap()));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains("192.0.2.1".parse().unwrap()));
+    }
+}
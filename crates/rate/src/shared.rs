@@ -0,0 +1,296 @@
+//! Rate limits shared by several gateways.
+//!
+//! One process hosts the authoritative budgets and answers lease requests on a
+//! `bus::rpc` address (`serve`). Every other gateway loads the same policies and
+//! attaches a `Remote`, which `Policies::charge` uses instead of its local
+//! limiters.
+//!
+//! Addresses are ZeroMQ endpoints (`tcp://10.0.0.5:7790`, `ipc:///run/rate.sock`),
+//! so the gateways can be separate processes or hosts. `mem://` addresses use the
+//! in-process `bus::rpc` registry instead, for tests and single-process setups.
+//!
+//! To avoid a round trip per request the service may grant a few extra units
+//! along with an allowed request (see `Policies::lease`). The gateway spends
+//! them locally until they run out or the lease expires (`lease_ttl`). Leased
+//! units are already consumed on the service and unused ones are never
+//! refunded, so leasing can make a limit slightly stricter but never looser.
+//!
+//! If the service can't be reached the gateway falls back to its local limiters
+//! and retries the service after a short pause.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{Decision, Keyed, Policies};
+
+/// How long a gateway keeps using its local limiters after a failed call.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LeaseRequest {
+    budget: String,
+    key: String,
+    cost: u32,
+    extra: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LeaseReply {
+    Granted {
+        allowed: bool,
+        limit: u32,
+        remaining: u32,
+        reset_after_ms: u64,
+        retry_after_ms: Option<u64>,
+        /// Extra units leased to the caller.
+        extra: u32,
+    },
+    Error(String),
+}
+
+/// Answer lease requests for `policies` on `addr` (see the module docs).
+/// Requests made by the hosting process itself should go through the same
+/// `Policies` (without a `Remote`) so they share its budgets.
+pub fn serve(addr: &str, policies: Arc<Policies>) -> Result<()> {
+    let handler = move |req: Vec<u8>| {
+        let policies = Arc::clone(&policies);
+        async move { handle(&policies, &req) }
+    };
+    if in_process(addr) {
+        bus::rpc::bind_server(addr, handler)
+    } else {
+        bus::rpc::bind_server_zmq(addr, handler)
+    }
+}
+
+async fn request(addr: &str, payload: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    if in_process(addr) {
+        bus::rpc::req_once(addr, payload).await
+    } else {
+        bus::rpc::req_timeout_zmq(addr, payload, timeout).await
+    }
+}
+
+fn in_process(addr: &str) -> bool {
+    addr.starts_with("mem://")
+}
+
+fn handle(policies: &Policies, req: &[u8]) -> Vec<u8> {
+    let leased = serde_json::from_slice::<LeaseRequest>(req)
+        .map_err(anyhow::Error::from)
+        .and_then(|r| policies.lease(&r.budget, &r.key, r.cost, r.extra));
+    let reply = match leased {
+        Ok((d, extra)) => LeaseReply::Granted {
+            allowed: d.allowed,
+            limit: d.limit,
+            remaining: d.remaining,
+            reset_after_ms: d.reset_after.as_millis() as u64,
+            retry_after_ms: d.retry_after.map(|r| r.as_millis() as u64),
+            extra,
+        },
+        Err(e) => LeaseReply::Error(format!("{:#}", e)),
+    };
+    serde_json::to_vec(&reply).expect("lease reply serializes")
+}
+
+struct Lease {
+    units: u32,
+    expires: Instant,
+    // decision that came with the lease, used for headers while spending it
+    decision: Decision,
+}
+
+/// Client side of the shared rate service, holding this gateway's leases.
+pub struct Remote {
+    addr: String,
+    lease_ttl: Duration,
+    timeout: Duration,
+    leases: Keyed<Lease>,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Remote {
+    /// Talk to the rate service at `addr`, with 1s leases and a 250ms timeout.
+    pub fn new(addr: impl Into<String>) -> Self {
+        let lease_ttl = Duration::from_secs(1);
+        Self {
+            addr: addr.into(),
+            lease_ttl,
+            timeout: Duration::from_millis(250),
+            leases: Keyed::new(lease_ttl),
+            down_until: Mutex::new(None),
+        }
+    }
+
+    /// How long leased units may be spent locally.
+    pub fn with_lease_ttl(mut self, lease_ttl: Duration) -> Self {
+        self.lease_ttl = lease_ttl;
+        self.leases = Keyed::new(lease_ttl);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Charge `cost` for `key` in `budget`, from a live lease if possible,
+    /// otherwise on the service (asking for up to `extra` more units). `None`
+    /// if the service couldn't answer and the caller should decide locally.
+    pub(crate) async fn check(&self, budget: &str, key: &str, cost: u32, extra: u32) -> Option<Decision> {
+        let slot = format!("{}/{}", budget, key);
+        let now = Instant::now();
+        let leased = self.leases.with(&slot, now, || Lease::empty(now), |l| {
+            (now < l.expires && l.units >= cost).then(|| {
+                l.units -= cost;
+                Decision { remaining: l.decision.remaining + l.units, retry_after: None, ..l.decision }
+            })
+        });
+        if leased.is_some() {
+            return leased;
+        }
+        if self.down_until.lock().unwrap().is_some_and(|until| now < until) {
+            return None;
+        }
+
+        let req = LeaseRequest { budget: budget.to_string(), key: key.to_string(), cost, extra };
+        let payload = serde_json::to_vec(&req).expect("lease request serializes");
+        let reply = match tokio::time::timeout(self.timeout, request(&self.addr, &payload, self.timeout)).await {
+            Ok(Ok(bytes)) => serde_json::from_slice::<LeaseReply>(&bytes).map_err(anyhow::Error::from),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow::anyhow!("timed out after {:?}", self.timeout)),
+        };
+        match reply {
+            Ok(LeaseReply::Granted { allowed, limit, remaining, reset_after_ms, retry_after_ms, extra }) => {
+                let decision = Decision {
+                    allowed,
+                    limit,
+                    remaining,
+                    reset_after: Duration::from_millis(reset_after_ms),
+                    retry_after: retry_after_ms.map(Duration::from_millis),
+                };
+                if extra > 0 {
+                    let now = Instant::now();
+                    let expires = now + self.lease_ttl;
+                    self.leases.with(&slot, now, || Lease::empty(now), |l| *l = Lease { units: extra, expires, decision });
+                }
+                Some(Decision { remaining: remaining + extra, ..decision })
+            }
+            // the service is up but disagrees about the policies
+            Ok(LeaseReply::Error(e)) => {
+                tracing::warn!(budget, error = %e, "rate service refused lease");
+                None
+            }
+            Err(e) => {
+                tracing::warn!(addr = %self.addr, error = %e, "rate service unavailable, using local limits");
+                *self.down_until.lock().unwrap() = Some(Instant::now() + RETRY_AFTER_FAILURE);
+                None
+            }
+        }
+    }
+
+    /// Drop expired leases. Returns how many were removed.
+    pub(crate) fn sweep(&self) -> usize {
+        self.leases.sweep(Instant::now())
+    }
+
+    pub(crate) fn clear(&self) {
+        self.leases.clear();
+    }
+}
+
+impl Lease {
+    fn empty(now: Instant) -> Self {
+        let decision = Decision { allowed: false, limit: 0, remaining: 0, reset_after: Duration::ZERO, retry_after: None };
+        Self { units: 0, expires: now, decision }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestInfo;
+
+    const POLICY: &str = r#"{
+        "budgets": { "b": { "limit": 20, "period_secs": 3600, "lease": 5 } },
+        "rules": [{ "budget": "b", "key": "global" }]
+    }"#;
+
+    fn req() -> RequestInfo<'static> {
+        RequestInfo { path: "/x", method: "GET", ..RequestInfo::default() }
+    }
+
+    #[tokio::test]
+    async fn replicas_share_one_budget_with_leases() {
+        let addr = "mem://rate-shared-test";
+        let service = Arc::new(Policies::from_json(POLICY).unwrap());
+        serve(addr, Arc::clone(&service)).unwrap();
+        let replicas = [
+            Policies::from_json(POLICY).unwrap().with_remote(Remote::new(addr)),
+            Policies::from_json(POLICY).unwrap().with_remote(Remote::new(addr)),
+        ];
+
+        let mut allowed = 0;
+        for i in 0..40 {
+            if replicas[i % 2].charge(&req()).await.unwrap().decision.allowed {
+                allowed += 1;
+            }
+        }
+        assert!((15..=20).contains(&allowed), "allowed {}", allowed);
+        // the service only saw one request per lease
+        let calls = service.metrics()[0].1;
+        assert!(calls.allowed + calls.denied < 40, "{:?}", calls);
+        bus::rpc::unbind_server(addr);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicas_share_one_budget_over_tcp() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("tcp://127.0.0.1:{}", port);
+        let service = Arc::new(Policies::from_json(POLICY).unwrap());
+        serve(&addr, Arc::clone(&service)).unwrap();
+        let replicas = [
+            Policies::from_json(POLICY).unwrap().with_remote(Remote::new(addr.clone()).with_timeout(Duration::from_secs(2))),
+            Policies::from_json(POLICY).unwrap().with_remote(Remote::new(addr.clone()).with_timeout(Duration::from_secs(2))),
+        ];
+
+        let mut allowed = 0;
+        for i in 0..40 {
+            if replicas[i % 2].charge(&req()).await.unwrap().decision.allowed {
+                allowed += 1;
+            }
+        }
+        assert!((15..=20).contains(&allowed), "allowed {}", allowed);
+        // the requests were counted by the service, not by local fallbacks
+        let calls = service.metrics()[0].1;
+        assert!(calls.allowed >= 3 && calls.allowed + calls.denied < 40, "{:?}", calls);
+    }
+
+    #[test]
+    fn service_refuses_costs_outside_the_budget() {
+        let service = Policies::from_json(POLICY).unwrap();
+        let ask = |cost: u32| {
+            let req = serde_json::to_vec(&LeaseRequest { budget: "b".into(), key: "global".into(), cost, extra: 0 }).unwrap();
+            serde_json::from_slice::<LeaseReply>(&handle(&service, &req)).unwrap()
+        };
+        for cost in [0, 21, u32::MAX] {
+            assert!(matches!(ask(cost), LeaseReply::Error(_)), "cost {}", cost);
+        }
+        assert!(matches!(ask(20), LeaseReply::Granted { allowed: true, remaining: 0, .. }), "nothing was charged");
+    }
+
+    #[tokio::test]
+    async fn unreachable_service_falls_back_to_local_limits() {
+        let policy = r#"{ "budgets": { "b": { "limit": 2, "period_secs": 3600 } }, "rules": [{ "budget": "b" }] }"#;
+        let replica = Policies::from_json(policy).unwrap().with_remote(Remote::new("tcp://127.0.0.1:1"));
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            seen.push(replica.charge(&req()).await.unwrap().decision.allowed);
+        }
+        assert_eq!(seen, [true, true, false]);
+    }
+}
@@ -0,0 +1,204 @@
+//! Bounded per-key state shared by the algorithms.
+//!
+//! Keys are spread over independently locked shards. Within a shard entries are
+//! kept in least-recently-used order, which gives two kinds of removal:
+//! - *expiry*: an entry idle for longer than the store's `idle_ttl` is dropped.
+//!   Algorithms set `idle_ttl` to the time after which their state is
+//!   indistinguishable from a fresh key, so expiry never changes a decision.
+//! - *eviction*: when a shard is full, its least recently used entry makes room
+//!   for a new key. An evicted key starts over with a full quota, so
+//!   `max_keys` should comfortably exceed the number of concurrently active keys.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Size limits for a `Keyed` store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreLimits {
+    /// Maximum number of keys across all shards.
+    pub max_keys: usize,
+    /// Number of independently locked shards.
+    pub shards: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        Self { max_keys: 100_000, shards: 16 }
+    }
+}
+
+/// Counters describing a store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Keys currently held.
+    pub keys: usize,
+    /// Keys dropped after being idle for `idle_ttl`.
+    pub expired: u64,
+    /// Keys dropped to stay within `max_keys`.
+    pub evicted: u64,
+}
+
+struct Entry<S> {
+    state: S,
+    last_used: Instant,
+    tick: u64,
+}
+
+struct Shard<S> {
+    map: HashMap<String, Entry<S>>,
+    // tick of last use -> key, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// Sharded, size-bounded map from key to algorithm state.
+pub struct Keyed<S> {
+    shards: Vec<Mutex<Shard<S>>>,
+    hasher: RandomState,
+    per_shard: usize,
+    idle_ttl: Duration,
+    keys: AtomicUsize,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl<S> Keyed<S> {
+    /// A store with default limits whose entries expire after `idle_ttl` unused.
+    pub fn new(idle_ttl: Duration) -> Self {
+        Self::with_limits(idle_ttl, StoreLimits::default())
+    }
+
+    pub fn with_limits(idle_ttl: Duration, limits: StoreLimits) -> Self {
+        let shards = limits.shards.clamp(1, limits.max_keys.max(1));
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(Shard { map: HashMap::new(), order: BTreeMap::new(), tick: 0 })).collect(),
+            hasher: RandomState::new(),
+            per_shard: limits.max_keys.max(1).div_ceil(shards),
+            idle_ttl,
+            keys: AtomicUsize::new(0),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn idle_ttl(&self) -> Duration {
+        self.idle_ttl
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard<S>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// Run `f` on the state for `key` at `now`, creating it with `init` if missing.
+    pub fn with<R>(&self, key: &str, now: Instant, init: impl FnOnce() -> S, f: impl FnOnce(&mut S) -> R) -> R {
+        let mut guard = self.shard(key).lock().unwrap();
+        self.expire(&mut guard, now);
+        let Shard { map, order, tick } = &mut *guard;
+        *tick += 1;
+        match map.get_mut(key) {
+            Some(e) => {
+                order.remove(&e.tick);
+                e.tick = *tick;
+                e.last_used = e.last_used.max(now);
+            }
+            None => {
+                if map.len() >= self.per_shard {
+                    if let Some((_, lru)) = order.pop_first() {
+                        map.remove(&lru);
+                        self.keys.fetch_sub(1, Ordering::Relaxed);
+                        self.evicted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                map.insert(key.to_string(), Entry { state: init(), last_used: now, tick: *tick });
+                self.keys.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        order.insert(*tick, key.to_string());
+        f(&mut map.get_mut(key).expect("present above").state)
+    }
+
+    // Drop idle entries from the LRU end of a shard.
+    fn expire(&self, shard: &mut Shard<S>, now: Instant) -> usize {
+        let mut n = 0;
+        while let Some((&tick, key)) = shard.order.first_key_value() {
+            let idle = shard.map.get(key).is_none_or(|e| now.saturating_duration_since(e.last_used) >= self.idle_ttl);
+            if !idle {
+                break;
+            }
+            let key = key.clone();
+            shard.order.remove(&tick);
+            if shard.map.remove(&key).is_some() {
+                n += 1;
+            }
+        }
+        if n > 0 {
+            self.keys.fetch_sub(n, Ordering::Relaxed);
+            self.expired.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        n
+    }
+
+    /// Drop every entry idle at `now`. Returns how many were removed.
+    pub fn sweep(&self, now: Instant) -> usize {
+        self.shards.iter().map(|s| self.expire(&mut s.lock().unwrap(), now)).sum()
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut s = shard.lock().unwrap();
+            self.keys.fetch_sub(s.map.len(), Ordering::Relaxed);
+            s.map.clear();
+            s.order.clear();
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            keys: self.keys.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_keys_expire_and_full_shards_evict_lru() {
+        let t0 = Instant::now();
+        let store: Keyed<u32> = Keyed::with_limits(Duration::from_secs(10), StoreLimits { max_keys: 3, shards: 1 });
+        for k in ["a", "b", "c"] {
+            store.with(k, t0, || 0, |v| *v += 1);
+        }
+        // touch "a" so "b" is the least recently used
+        store.with("a", t0, || 0, |v| *v += 1);
+        store.with("d", t0, || 0, |v| *v += 1);
+        assert_eq!(store.stats(), StoreStats { keys: 3, expired: 0, evicted: 1 });
+        assert_eq!(store.with("a", t0, || 0, |v| *v), 2);
+        assert_eq!(store.with("b", t0, || 0, |v| *v), 0, "b was evicted");
+
+        assert_eq!(store.sweep(t0 + Duration::from_secs(10)), 3);
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.expired), (0, 3));
+    }
+
+    #[test]
+    fn keys_spread_over_shards() {
+        let t0 = Instant::now();
+        let store: Keyed<()> = Keyed::with_limits(Duration::from_secs(1), StoreLimits { max_keys: 1_000, shards: 8 });
+        for i in 0..500 {
+            store.with(&format!("k{}", i), t0, || (), |_| ());
+        }
+        assert_eq!(store.stats().keys, 500);
+        let used = store.shards.iter().filter(|s| !s.lock().unwrap().map.is_empty()).count();
+        assert!(used > 1);
+        store.clear();
+        assert_eq!(store.stats().keys, 0);
+    }
+}
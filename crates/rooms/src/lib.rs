@@ -3,6 +3,7 @@ use serde_json::Value;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use storage::{MessageRecord, Storage};
+use bus::envelope::Headers;
 use bus::pubsub::Publisher;
 
 /// Send a message to `room`.
@@ -19,7 +20,19 @@ pub fn send_message(
     storage: &Storage,
     publisher: &Publisher,
 ) -> Result<MessageRecord> {
-    tracing::info!(room = %room, "rooms::send_message called");
+    send_message_traced(room, body, storage, publisher, None)
+}
+
+/// Same as `send_message`, attaching the caller's W3C `traceparent` to the
+/// published envelope so subscribers can correlate with the originating request.
+pub fn send_message_traced(
+    room: &str,
+    body: Value,
+    storage: &Storage,
+    publisher: &Publisher,
+    traceparent: Option<&str>,
+) -> Result<MessageRecord> {
+    tracing::info!(room = %room, traceparent = ?traceparent, "rooms::send_message called");
     // timestamp
     let now_ms = SystemTime::now()
         .duration_since(UNIX_EPOCH)?
@@ -42,7 +55,8 @@ pub fn send_message(
     // publish to bus
     let topic = format!("room/{}", room);
     let bytes = serde_json::to_vec(&rec)?;
-    publisher.publish(&topic, &bytes)?;
+    let headers = Headers::json().with_traceparent(traceparent);
+    publisher.publish_with(&topic, &headers, &bytes)?;
 
     Ok(rec)
 }
@@ -18,6 +18,107 @@ pub struct MessageRecord {
     pub body: Value,
 }
 
+/// One live connection (tab, app instance) of a user. Times are unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceConnRecord {
+    pub user_id: String,
+    pub conn_id: String,
+    pub device: String,
+    pub connected_at: i64,
+    pub last_seen: i64,
+    /// Last user activity (as opposed to a liveness heartbeat); 0 if none yet.
+    #[serde(default)]
+    pub last_active: i64,
+    /// Rooms this connection is subscribed to.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+}
+
+/// User-chosen presence status and custom status text. Times are unix seconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PresenceStatusRecord {
+    /// Manual status: "online", "away", "busy" or "invisible".
+    pub status: String,
+    pub text: Option<String>,
+    pub text_expires_at: Option<i64>,
+    pub updated_at: i64,
+    /// Last status/text fanned out on `presence/*`, used to detect transitions.
+    #[serde(default)]
+    pub published: Option<String>,
+}
+
+/// One finished connection in a user's presence session log. Times are unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceSessionRecord {
+    pub user_id: String,
+    pub conn_id: String,
+    pub device: String,
+    pub started_at: i64,
+    pub ended_at: i64,
+}
+
+/// Failed sign-in attempts for one account or client IP. Times are unix seconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoginFailureRecord {
+    /// Consecutive failures since the last success or quiet period.
+    pub failures: u32,
+    pub last_failed_at: i64,
+    /// Backoff: no attempt is checked before this time.
+    pub next_attempt_at: i64,
+    /// Lockout: no attempt is checked before this time; 0 if not locked.
+    pub locked_until: i64,
+}
+
+/// One refresh token, stored under the SHA-256 of the token. Times are unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenRecord {
+    pub user_id: String,
+    /// Session (sign-in) this token belongs to, see `SessionRecord`.
+    pub session_id: String,
+    pub issued_at: i64,
+    pub expires_at: i64,
+    /// When it was exchanged for a new token; a token is good for one exchange.
+    #[serde(default)]
+    pub used_at: Option<i64>,
+}
+
+/// One sign-in on one device: the family of refresh tokens issued from it, each
+/// rotated into the next, and the access tokens carrying its id (`sid`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub user_id: String,
+    pub created_at: i64,
+    /// Last sign-in or refresh.
+    pub last_used_at: i64,
+    /// Expiry of the newest refresh token; the session can be dropped after this.
+    pub expires_at: i64,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Client address at the last sign-in or refresh.
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub revoked_at: Option<i64>,
+    #[serde(default)]
+    pub revoked_reason: Option<String>,
+}
+
+/// One security-relevant event (failed logins, lockouts, admin actions).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Unix seconds.
+    pub at: i64,
+    /// Event name, e.g. "login_failed" or "lockout_cleared".
+    pub event: String,
+    /// User who performed the action, if known.
+    pub actor: Option<String>,
+    /// Account, IP or resource the event is about.
+    pub subject: Option<String>,
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub detail: Value,
+}
+
 /// redb-backed Storage implementation.
 /// Messages are stored in a single table where the key is a lexicographically
 /// sortable composite string: "<room>/<server_ts:020>/<seq:020>" and the value
@@ -32,7 +133,19 @@ const MESSAGES_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("mes
 const SEQS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("seqs");
 const USERS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("users");
 const PRESENCE_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("presence");
+// Per-connection presence: key = "<user_id>/<conn_id>", value = JSON PresenceConnRecord
+const PRESENCE_CONNS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("presence_conns");
+// Manual presence status: key = user_id, value = JSON PresenceStatusRecord
+const PRESENCE_STATUS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("presence_status");
+// Presence session log: key = "<user_id>/<started_at:020>/<conn_id>", value = JSON PresenceSessionRecord
+const PRESENCE_SESSIONS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("presence_sessions");
+// Daily active users: key = "<YYYY-MM-DD>/<user_id>", value = empty
+const PRESENCE_DAILY_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("presence_daily");
 const RATE_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("rate");
+// Failed sign-ins: key = "account:<email>" or "ip:<addr>", value = JSON LoginFailureRecord
+const LOGIN_FAILURES_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("login_failures");
+// Audit log: key = "<unix_nanos:020>", value = JSON AuditRecord
+const AUDIT_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("audit");
 // Push subscriptions: key = endpoint, value = JSON { endpoint, keys: { p256dh, auth }, created_at }
 const PUSH_SUBS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("push_subs");
 // Secondary index: key = "<user_id>|<endpoint>", value = empty (or endpoint bytes for convenience)
@@ -41,7 +154,10 @@ const PUSH_SUBS_BY_USER_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition:
 // Additional tables for auth
 const CREDENTIALS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("credentials");
 const OAUTH_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("oauth");
+// Refresh tokens: key = sha256(token) hex, value = JSON RefreshTokenRecord
 const REFRESH_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("refresh");
+// Sessions: key = session_id, value = JSON SessionRecord
+const SESSIONS_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("sessions");
 
 // Transient / state tables for auth flows
 const WEBAUTHN_REG_STATE_TABLE: TableDefinition<&str, Vec<u8>> =
@@ -49,6 +165,11 @@ const WEBAUTHN_REG_STATE_TABLE: TableDefinition<&str, Vec<u8>> =
 const WEBAUTHN_AUTH_STATE_TABLE: TableDefinition<&str, Vec<u8>> =
     TableDefinition::new("webauthn_auth_state");
 const OAUTH_STATE_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("oauth_state");
+// Pending second-factor sign-ins: key = sha256(challenge token) hex, value = JSON { user_id, email, attempts, expires_at }
+const MFA_CHALLENGE_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("mfa_challenge");
+// Emailed single-use tokens (password reset, email verification): key = sha256(token) hex,
+// value = JSON { purpose, user_id, email, created_at, expires_at }
+const ACCOUNT_TOKEN_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("account_token");
 
 impl Storage {
     /// Create a storage instance rooted at `base_path` (will be created if missing).
@@ -70,17 +191,26 @@ impl Storage {
             let _ = write_txn.open_table(SEQS_TABLE)?;
             let _ = write_txn.open_table(USERS_TABLE)?;
             let _ = write_txn.open_table(PRESENCE_TABLE)?;
+            let _ = write_txn.open_table(PRESENCE_CONNS_TABLE)?;
+            let _ = write_txn.open_table(PRESENCE_STATUS_TABLE)?;
+            let _ = write_txn.open_table(PRESENCE_SESSIONS_TABLE)?;
+            let _ = write_txn.open_table(PRESENCE_DAILY_TABLE)?;
             let _ = write_txn.open_table(RATE_TABLE)?;
+            let _ = write_txn.open_table(LOGIN_FAILURES_TABLE)?;
+            let _ = write_txn.open_table(AUDIT_TABLE)?;
             let _ = write_txn.open_table(PUSH_SUBS_TABLE)?;
             let _ = write_txn.open_table(PUSH_SUBS_BY_USER_TABLE)?;
             // auth tables
             let _ = write_txn.open_table(CREDENTIALS_TABLE)?;
             let _ = write_txn.open_table(OAUTH_TABLE)?;
             let _ = write_txn.open_table(REFRESH_TABLE)?;
+            let _ = write_txn.open_table(SESSIONS_TABLE)?;
             // auth transient state tables
             let _ = write_txn.open_table(WEBAUTHN_REG_STATE_TABLE)?;
             let _ = write_txn.open_table(WEBAUTHN_AUTH_STATE_TABLE)?;
             let _ = write_txn.open_table(OAUTH_STATE_TABLE)?;
+            let _ = write_txn.open_table(MFA_CHALLENGE_TABLE)?;
+            let _ = write_txn.open_table(ACCOUNT_TOKEN_TABLE)?;
             write_txn.commit()?;
         }
 
@@ -314,7 +444,7 @@ impl Storage {
             // Use extract_if to remove entries older than cutoff. We can use extract_if which
             // returns an iterator of removed pairs; we don't need to collect them.
             // The predicate receives (&key, &value) where key is &str.
-            let mut extractor = table.extract_if(|_k, v| {
+            let extractor = table.extract_if(|_k, v| {
                 // In a write-context the value is provided as an owned Vec<u8>, so use as_slice().
                 let bytes = v.as_slice();
                 if bytes.is_empty() {
@@ -329,7 +459,7 @@ impl Storage {
             })?;
 
             // consume the iterator to perform removals
-            while let Some(removed_res) = extractor.next() {
+            for removed_res in extractor {
                 let _removed = removed_res?;
                 // noop; extractor performs removals as items are read
             }
@@ -444,7 +574,7 @@ impl Storage {
             {
                 let mut table = write_txn.open_table(CREDENTIALS_TABLE)?;
                 // Use extract_if to remove entries whose value JSON has "user_id" == user_id
-                let mut extractor = table.extract_if(|_k, v| {
+                let extractor = table.extract_if(|_k, v| {
                     let bytes = v.as_slice();
                     if bytes.is_empty() {
                         return false;
@@ -458,7 +588,7 @@ impl Storage {
                 })?;
 
                 // consume iterator to perform removals
-                while let Some(_removed_res) = extractor.next() {
+                for _removed_res in extractor {
                     // noop; extractor performs removals as items are read
                 }
             }
@@ -494,20 +624,37 @@ impl Storage {
         }
     }
  
-    /// Set presence for a user (timestamp ms). If `online` is true, mark online timestamp, else remove.
+    /// Read-modify-write the credentials of `email` in one transaction, so
+    /// concurrent updates (e.g. two uses of the same one-time code) can't both
+    /// succeed. The record is only written back when `f` returns `Ok`.
+    /// Returns `None` if there are no credentials for `email`.
+    pub fn update_credentials<T>(&self, email: &str, f: impl FnOnce(&mut Value) -> Result<T>) -> Result<Option<T>> {
+        let write_txn = self.db.begin_write()?;
+        let out;
+        {
+            let mut table = write_txn.open_table(CREDENTIALS_TABLE)?;
+            let current: Option<Value> = match table.get(email)? {
+                Some(v) => Some(serde_json::from_slice(v.value().as_slice())?),
+                None => None,
+            };
+            let Some(mut cred) = current else { return Ok(None) };
+            out = f(&mut cred)?;
+            table.insert(email, serde_json::to_vec(&cred)?)?;
+        }
+        write_txn.commit()?;
+        Ok(Some(out))
+    }
+
+    /// Set presence for a user. The row is kept when the user goes offline so
+    /// `last_seen` survives as a durable "last seen" timestamp.
     pub fn set_presence(&self, user_id: &str, online: bool, ts_ms: i64) -> Result<()> {
         let write_txn = self.db.begin_write()?;
         {
             let mut table = write_txn.open_table(PRESENCE_TABLE)?;
-            if online {
-                // store as JSON: { "last_seen": ts_ms }
-                let obj = serde_json::json!({ "last_seen": ts_ms });
-                let bytes = serde_json::to_vec(&obj)?;
-                table.insert(user_id, &bytes)?;
-            } else {
-                // Remove presence on offline for simplicity
-                table.remove(user_id)?;
-            }
+            // store as JSON: { "last_seen": ts_ms, "online": bool }
+            let obj = serde_json::json!({ "last_seen": ts_ms, "online": online });
+            let bytes = serde_json::to_vec(&obj)?;
+            table.insert(user_id, &bytes)?;
         }
         write_txn.commit()?;
         Ok(())
@@ -531,8 +678,8 @@ impl Storage {
         }
     }
 
-    /// List all presence entries (user_id, last_seen)
-    /// Iterates the PRESENCE_TABLE and returns a Vec of (user_id, last_seen).
+    /// List presence entries of users marked online (user_id, last_seen).
+    /// Rows written before the `online` flag existed count as online.
     pub fn list_presence(&self) -> Result<Vec<(String, i64)>> {
         let read_txn = self.db.begin_read()?;
         let table = read_txn.open_table(PRESENCE_TABLE)?;
@@ -547,7 +694,8 @@ impl Storage {
                 continue;
             }
             if let Ok(val) = serde_json::from_slice::<Value>(bytes.as_slice()) {
-                if let Some(n) = val.get("last_seen").and_then(|v| v.as_i64()) {
+                let online = val.get("online").and_then(|v| v.as_bool()).unwrap_or(true);
+                if let Some(n) = val.get("last_seen").and_then(|v| v.as_i64()).filter(|_| online) {
                     out.push((key, n));
                 }
             }
@@ -555,6 +703,177 @@ impl Storage {
         Ok(out)
     }
 
+    /// Insert or refresh a presence connection. Returns the user's connection count
+    /// afterwards and whether this connection is new, computed in the same transaction.
+    pub fn put_presence_conn(&self, rec: &PresenceConnRecord) -> Result<(usize, bool)> {
+        let prefix = format!("{}/", rec.user_id);
+        let key = format!("{}{}", prefix, rec.conn_id);
+        let bytes = serde_json::to_vec(rec)?;
+        let write_txn = self.db.begin_write()?;
+        let res;
+        {
+            let mut table = write_txn.open_table(PRESENCE_CONNS_TABLE)?;
+            let is_new = table.insert(key.as_str(), &bytes)?.is_none();
+            res = (count_prefix(&table, &prefix)?, is_new);
+        }
+        write_txn.commit()?;
+        Ok(res)
+    }
+
+    /// Remove a presence connection. Returns the user's remaining connection count
+    /// and whether the connection existed, computed in the same transaction.
+    pub fn remove_presence_conn(&self, user_id: &str, conn_id: &str) -> Result<(usize, bool)> {
+        let prefix = format!("{}/", user_id);
+        let key = format!("{}{}", prefix, conn_id);
+        let write_txn = self.db.begin_write()?;
+        let res;
+        {
+            let mut table = write_txn.open_table(PRESENCE_CONNS_TABLE)?;
+            let existed = table.remove(key.as_str())?.is_some();
+            res = (count_prefix(&table, &prefix)?, existed);
+        }
+        write_txn.commit()?;
+        Ok(res)
+    }
+
+    /// Remove every presence connection of a user. Returns how many were removed.
+    pub fn clear_presence_conns(&self, user_id: &str) -> Result<usize> {
+        let prefix = format!("{}/", user_id);
+        let write_txn = self.db.begin_write()?;
+        let mut removed = 0;
+        {
+            let mut table = write_txn.open_table(PRESENCE_CONNS_TABLE)?;
+            let extractor = table.extract_if(|k, _v| k.starts_with(prefix.as_str()))?;
+            for removed_res in extractor {
+                let _removed = removed_res?;
+                removed += 1;
+            }
+        }
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// List a user's live presence connections.
+    pub fn list_presence_conns_for_user(&self, user_id: &str) -> Result<Vec<PresenceConnRecord>> {
+        let prefix = format!("{}/", user_id);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PRESENCE_CONNS_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.range(prefix.as_str()..)? {
+            let (k, v) = pair?;
+            if !k.value().starts_with(prefix.as_str()) {
+                break;
+            }
+            if let Ok(rec) = serde_json::from_slice::<PresenceConnRecord>(v.value().as_slice()) {
+                out.push(rec);
+            }
+        }
+        Ok(out)
+    }
+
+    /// List every live presence connection.
+    pub fn list_presence_conns(&self) -> Result<Vec<PresenceConnRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PRESENCE_CONNS_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.iter()? {
+            let (_k, v) = pair?;
+            if let Ok(rec) = serde_json::from_slice::<PresenceConnRecord>(v.value().as_slice()) {
+                out.push(rec);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Store a user's manual presence status.
+    pub fn put_presence_status(&self, user_id: &str, rec: &PresenceStatusRecord) -> Result<()> {
+        let bytes = serde_json::to_vec(rec)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PRESENCE_STATUS_TABLE)?;
+            table.insert(user_id, &bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Get a user's manual presence status, if one was ever set.
+    pub fn get_presence_status(&self, user_id: &str) -> Result<Option<PresenceStatusRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PRESENCE_STATUS_TABLE)?;
+        match table.get(user_id)? {
+            Some(v) => Ok(serde_json::from_slice(v.value().as_slice()).ok()),
+            None => Ok(None),
+        }
+    }
+
+    /// Append a finished connection to a user's session log, dropping the oldest
+    /// entries beyond `keep`.
+    pub fn append_presence_session(&self, rec: &PresenceSessionRecord, keep: usize) -> Result<()> {
+        let prefix = format!("{}/", rec.user_id);
+        let key = format!("{}{:020}/{}", prefix, rec.started_at.max(0), rec.conn_id);
+        let bytes = serde_json::to_vec(rec)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PRESENCE_SESSIONS_TABLE)?;
+            table.insert(key.as_str(), &bytes)?;
+            let excess = count_prefix(&table, &prefix)?.saturating_sub(keep);
+            if excess > 0 {
+                let mut oldest: Vec<String> = Vec::with_capacity(excess);
+                for pair in table.range(prefix.as_str()..)?.take(excess) {
+                    let (k, _v) = pair?;
+                    oldest.push(k.value().to_string());
+                }
+                for k in oldest {
+                    table.remove(k.as_str())?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// A user's session log, newest first, at most `limit` entries.
+    pub fn list_presence_sessions(&self, user_id: &str, limit: usize) -> Result<Vec<PresenceSessionRecord>> {
+        let prefix = format!("{}/", user_id);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PRESENCE_SESSIONS_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.range(prefix.as_str()..)? {
+            let (k, v) = pair?;
+            if !k.value().starts_with(prefix.as_str()) {
+                break;
+            }
+            if let Ok(rec) = serde_json::from_slice::<PresenceSessionRecord>(v.value().as_slice()) {
+                out.push(rec);
+            }
+        }
+        out.reverse();
+        out.truncate(limit);
+        Ok(out)
+    }
+
+    /// Record a user as active on `day` ("YYYY-MM-DD"). Returns true if this is
+    /// the user's first activity that day.
+    pub fn mark_daily_active(&self, day: &str, user_id: &str) -> Result<bool> {
+        let key = format!("{}/{}", day, user_id);
+        let write_txn = self.db.begin_write()?;
+        let is_new;
+        {
+            let mut table = write_txn.open_table(PRESENCE_DAILY_TABLE)?;
+            is_new = table.insert(key.as_str(), Vec::new())?.is_none();
+        }
+        write_txn.commit()?;
+        Ok(is_new)
+    }
+
+    /// Number of distinct users active on `day` ("YYYY-MM-DD").
+    pub fn count_daily_active(&self, day: &str) -> Result<usize> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PRESENCE_DAILY_TABLE)?;
+        count_prefix(&table, &format!("{}/", day))
+    }
+
     /// Store or update a webauthn credential for a user.
     /// Key format: "<user_id>/<cred_id>"
     pub fn put_webauthn_cred(&self, user_id: &str, cred_id: &str, cred_json: &Value) -> Result<()> {
@@ -584,6 +903,33 @@ impl Storage {
         }
     }
 
+    /// Every webauthn credential of a user, as `(cred_id, credential)`.
+    pub fn list_webauthn_creds(&self, user_id: &str) -> Result<Vec<(String, Value)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(CREDENTIALS_TABLE)?;
+        let prefix = format!("{}/", user_id);
+        let mut out = Vec::new();
+        for pair in table.range(prefix.as_str()..)? {
+            let (k, v) = pair?;
+            let Some(cred_id) = k.value().strip_prefix(prefix.as_str()) else { break };
+            out.push((cred_id.to_string(), serde_json::from_slice(v.value().as_slice())?));
+        }
+        Ok(out)
+    }
+
+    /// Remove a webauthn credential. Returns whether it existed.
+    pub fn delete_webauthn_cred(&self, user_id: &str, cred_id: &str) -> Result<bool> {
+        let key = format!("{}/{}", user_id, cred_id);
+        let write_txn = self.db.begin_write()?;
+        let existed = {
+            let mut table = write_txn.open_table(CREDENTIALS_TABLE)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            removed
+        };
+        write_txn.commit()?;
+        Ok(existed)
+    }
+
     /// Transient state helpers for auth flows
     /// Store a webauthn registration pending state (key: "<user_id>/<reg_id>")
     pub fn put_webauthn_reg_state(&self, user_id: &str, reg_id: &str, state_json: &Value) -> Result<()> {
@@ -696,6 +1042,148 @@ impl Storage {
         Ok(())
     }
 
+    /// Drop OAuth sign-in states whose `expires_at` has passed (abandoned sign-ins).
+    pub fn purge_oauth_states(&self, now: i64) -> Result<usize> {
+        self.purge_expired_states(OAUTH_STATE_TABLE, now)
+    }
+
+    /// Drop webauthn registration and authentication states whose `expires_at` has passed.
+    pub fn purge_webauthn_states(&self, now: i64) -> Result<usize> {
+        Ok(self.purge_expired_states(WEBAUTHN_REG_STATE_TABLE, now)? + self.purge_expired_states(WEBAUTHN_AUTH_STATE_TABLE, now)?)
+    }
+
+    /// Store a pending second-factor challenge under `token_hash`.
+    pub fn put_mfa_challenge(&self, token_hash: &str, challenge_json: &Value) -> Result<()> {
+        let bytes = serde_json::to_vec(challenge_json)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(MFA_CHALLENGE_TABLE)?;
+            table.insert(token_hash, bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_mfa_challenge(&self, token_hash: &str) -> Result<Option<Value>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(MFA_CHALLENGE_TABLE)?;
+        match table.get(token_hash)? {
+            Some(v) => Ok(Some(serde_json::from_slice(v.value().as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read-modify-write the challenge under `token_hash` in one transaction,
+    /// like `update_credentials`, so concurrent answers can't both use the same
+    /// attempt. The record is only written back when `f` returns `Ok`.
+    /// Returns `None` if there is no such challenge.
+    pub fn update_mfa_challenge<T>(&self, token_hash: &str, f: impl FnOnce(&mut Value) -> Result<T>) -> Result<Option<T>> {
+        let write_txn = self.db.begin_write()?;
+        let out;
+        {
+            let mut table = write_txn.open_table(MFA_CHALLENGE_TABLE)?;
+            let current: Option<Value> = match table.get(token_hash)? {
+                Some(v) => Some(serde_json::from_slice(v.value().as_slice())?),
+                None => None,
+            };
+            let Some(mut challenge) = current else { return Ok(None) };
+            out = f(&mut challenge)?;
+            table.insert(token_hash, serde_json::to_vec(&challenge)?)?;
+        }
+        write_txn.commit()?;
+        Ok(Some(out))
+    }
+
+    pub fn delete_mfa_challenge(&self, token_hash: &str) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(MFA_CHALLENGE_TABLE)?;
+            table.remove(token_hash)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Drop second-factor challenges whose `expires_at` has passed.
+    pub fn purge_mfa_challenges(&self, now: i64) -> Result<usize> {
+        self.purge_expired_states(MFA_CHALLENGE_TABLE, now)
+    }
+
+    /// Store an emailed account token under `token_hash`.
+    pub fn put_account_token(&self, token_hash: &str, token_json: &Value) -> Result<()> {
+        let bytes = serde_json::to_vec(token_json)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(ACCOUNT_TOKEN_TABLE)?;
+            table.insert(token_hash, bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_account_token(&self, token_hash: &str) -> Result<Option<Value>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ACCOUNT_TOKEN_TABLE)?;
+        match table.get(token_hash)? {
+            Some(v) => Ok(Some(serde_json::from_slice(v.value().as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the account token under `token_hash` and return it, so exactly
+    /// one caller gets to use it.
+    pub fn take_account_token(&self, token_hash: &str) -> Result<Option<Value>> {
+        let write_txn = self.db.begin_write()?;
+        let taken;
+        {
+            let mut table = write_txn.open_table(ACCOUNT_TOKEN_TABLE)?;
+            taken = match table.remove(token_hash)? {
+                Some(v) => Some(serde_json::from_slice(v.value().as_slice())?),
+                None => None,
+            };
+        }
+        write_txn.commit()?;
+        Ok(taken)
+    }
+
+    /// Remove every account token of `user_id` issued for `purpose`. Returns how many.
+    pub fn delete_account_tokens(&self, user_id: &str, purpose: &str) -> Result<usize> {
+        let write_txn = self.db.begin_write()?;
+        let mut removed = 0;
+        {
+            let mut table = write_txn.open_table(ACCOUNT_TOKEN_TABLE)?;
+            table.retain(|_, v| {
+                let matches = serde_json::from_slice::<Value>(v.as_slice()).ok().is_some_and(|t| {
+                    t.get("user_id").and_then(|v| v.as_str()) == Some(user_id) && t.get("purpose").and_then(|v| v.as_str()) == Some(purpose)
+                });
+                removed += usize::from(matches);
+                !matches
+            })?;
+        }
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// Drop account tokens whose `expires_at` has passed.
+    pub fn purge_account_tokens(&self, now: i64) -> Result<usize> {
+        self.purge_expired_states(ACCOUNT_TOKEN_TABLE, now)
+    }
+
+    fn purge_expired_states(&self, def: TableDefinition<&str, Vec<u8>>, now: i64) -> Result<usize> {
+        let write_txn = self.db.begin_write()?;
+        let mut removed = 0;
+        {
+            let mut table = write_txn.open_table(def)?;
+            table.retain(|_, v| {
+                let keep = serde_json::from_slice::<Value>(v.as_slice()).ok().and_then(|s| s.get("expires_at").and_then(|v| v.as_i64())).is_some_and(|at| at > now);
+                removed += usize::from(!keep);
+                keep
+            })?;
+        }
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
     /// Link an OAuth provider subject to a user_id.
     /// Key format: "<provider>:<subject>" -> user_id (as bytes)
     pub fn link_oauth(&self, provider: &str, subject: &str, user_id: &str) -> Result<()> {
@@ -724,44 +1212,161 @@ impl Storage {
         }
     }
 
-    /// Create a refresh token record: key = token_id, value = JSON { user_id, expiry }
-    pub fn create_refresh_token(&self, token_id: &str, user_id: &str, expiry_unix: i64) -> Result<()> {
-        let obj = serde_json::json!({ "user_id": user_id, "expiry": expiry_unix });
-        let bytes = serde_json::to_vec(&obj)?;
+    /// Store a refresh token under `token_hash`.
+    pub fn create_refresh_token(&self, token_hash: &str, rec: &RefreshTokenRecord) -> Result<()> {
+        let bytes = serde_json::to_vec(rec)?;
         let write_txn = self.db.begin_write()?;
         {
             let mut table = write_txn.open_table(REFRESH_TABLE)?;
-            table.insert(token_id, &bytes)?;
+            table.insert(token_hash, bytes)?;
         }
         write_txn.commit()?;
         Ok(())
     }
 
-    /// Get refresh token record as JSON value.
-    pub fn get_refresh_token(&self, token_id: &str) -> Result<Option<Value>> {
+    /// Refresh token stored under `token_hash`. Records in an older format read as missing.
+    pub fn get_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>> {
         let read_txn = self.db.begin_read()?;
         let table = read_txn.open_table(REFRESH_TABLE)?;
-        match table.get(token_id)? {
-            Some(v) => {
-                let bytes = v.value();
-                let val = serde_json::from_slice(bytes.as_slice())?;
-                Ok(Some(val))
-            }
+        match table.get(token_hash)? {
+            Some(v) => Ok(serde_json::from_slice(v.value().as_slice()).ok()),
             None => Ok(None),
         }
     }
 
+    /// Mark a refresh token used at `now` and return it as it was before, so
+    /// exactly one caller sees `used_at == None` for a given token.
+    pub fn use_refresh_token(&self, token_hash: &str, now: i64) -> Result<Option<RefreshTokenRecord>> {
+        let write_txn = self.db.begin_write()?;
+        let prev;
+        {
+            let mut table = write_txn.open_table(REFRESH_TABLE)?;
+            prev = match table.get(token_hash)? {
+                Some(v) => serde_json::from_slice::<RefreshTokenRecord>(v.value().as_slice()).ok(),
+                None => None,
+            };
+            if let Some(rec) = prev.as_ref().filter(|r| r.used_at.is_none()) {
+                let used = RefreshTokenRecord { used_at: Some(now), ..rec.clone() };
+                table.insert(token_hash, serde_json::to_vec(&used)?)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(prev)
+    }
+
     /// Revoke a refresh token.
-    pub fn revoke_refresh_token(&self, token_id: &str) -> Result<()> {
+    pub fn revoke_refresh_token(&self, token_hash: &str) -> Result<()> {
         let write_txn = self.db.begin_write()?;
         {
             let mut table = write_txn.open_table(REFRESH_TABLE)?;
-            table.remove(token_id)?;
+            table.remove(token_hash)?;
         }
         write_txn.commit()?;
         Ok(())
     }
 
+    pub fn put_session(&self, session_id: &str, rec: &SessionRecord) -> Result<()> {
+        let bytes = serde_json::to_vec(rec)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+            table.insert(session_id, bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_session(&self, session_id: &str) -> Result<Option<SessionRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(SESSIONS_TABLE)?;
+        match table.get(session_id)? {
+            Some(v) => Ok(Some(serde_json::from_slice(v.value().as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sessions of `user_id` (including revoked ones not purged yet), oldest first.
+    pub fn list_sessions(&self, user_id: &str) -> Result<Vec<(String, SessionRecord)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(SESSIONS_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.iter()? {
+            let (k, v) = pair?;
+            if let Ok(rec) = serde_json::from_slice::<SessionRecord>(v.value().as_slice()) {
+                if rec.user_id == user_id {
+                    out.push((k.value().to_string(), rec));
+                }
+            }
+        }
+        out.sort_by_key(|(_, rec)| rec.created_at);
+        Ok(out)
+    }
+
+    /// Revoke a session at `now`, which invalidates its access and refresh
+    /// tokens. Returns false if it is unknown or already revoked.
+    pub fn revoke_session(&self, session_id: &str, reason: &str, now: i64) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        let revoked;
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+            let rec = match table.get(session_id)? {
+                Some(v) => Some(serde_json::from_slice::<SessionRecord>(v.value().as_slice())?),
+                None => None,
+            };
+            revoked = match rec.filter(|r| r.revoked_at.is_none()) {
+                Some(rec) => {
+                    let rec = SessionRecord { revoked_at: Some(now), revoked_reason: Some(reason.to_string()), ..rec };
+                    table.insert(session_id, serde_json::to_vec(&rec)?)?;
+                    true
+                }
+                None => false,
+            };
+        }
+        write_txn.commit()?;
+        Ok(revoked)
+    }
+
+    /// Revoke every live session of `user_id` except `keep`. Returns how many were revoked.
+    pub fn revoke_user_sessions(&self, user_id: &str, keep: Option<&str>, reason: &str, now: i64) -> Result<usize> {
+        let write_txn = self.db.begin_write()?;
+        let mut revoked = Vec::new();
+        {
+            let mut table = write_txn.open_table(SESSIONS_TABLE)?;
+            for pair in table.iter()? {
+                let (k, v) = pair?;
+                let Ok(rec) = serde_json::from_slice::<SessionRecord>(v.value().as_slice()) else { continue };
+                if rec.user_id == user_id && rec.revoked_at.is_none() && keep != Some(k.value()) {
+                    revoked.push((k.value().to_string(), rec));
+                }
+            }
+            for (id, rec) in &revoked {
+                let rec = SessionRecord { revoked_at: Some(now), revoked_reason: Some(reason.to_string()), ..rec.clone() };
+                table.insert(id.as_str(), serde_json::to_vec(&rec)?)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(revoked.len())
+    }
+
+    /// Drop refresh tokens and sessions that expired before `now`, plus token
+    /// records in an older format. Returns how many tokens were removed.
+    pub fn purge_refresh_tokens(&self, now: i64) -> Result<usize> {
+        let write_txn = self.db.begin_write()?;
+        let mut removed = 0;
+        {
+            let mut tokens = write_txn.open_table(REFRESH_TABLE)?;
+            tokens.retain(|_, v| {
+                let keep = serde_json::from_slice::<RefreshTokenRecord>(v.as_slice()).is_ok_and(|r| r.expires_at > now);
+                removed += usize::from(!keep);
+                keep
+            })?;
+            let mut sessions = write_txn.open_table(SESSIONS_TABLE)?;
+            sessions.retain(|_, v| serde_json::from_slice::<SessionRecord>(v.as_slice()).is_ok_and(|r| r.expires_at > now))?;
+        }
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
     /// Increment a rate counter by `delta`. Returns new value.
     pub fn incr_rate_counter(&self, key: &str, delta: u64) -> Result<u64> {
         let write_txn = self.db.begin_write()?;
@@ -782,7 +1387,7 @@ impl Storage {
             table.insert(key, &next.to_le_bytes().to_vec())?;
             drop(table);
             write_txn.commit()?;
-            return Ok(next);
+            Ok(next)
         }
     }
 
@@ -821,6 +1426,99 @@ impl Storage {
         Ok(out)
     }
 
+    /// Failed sign-in state for `key` ("account:<email>" or "ip:<addr>").
+    pub fn get_login_failures(&self, key: &str) -> Result<Option<LoginFailureRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(LOGIN_FAILURES_TABLE)?;
+        match table.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(v.value().as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the failed sign-in state of `key` with `f(current)` in one write
+    /// transaction, so concurrent failures are all counted. Returns the
+    /// previous and the new record.
+    pub fn update_login_failures(
+        &self,
+        key: &str,
+        f: impl FnOnce(Option<LoginFailureRecord>) -> LoginFailureRecord,
+    ) -> Result<(Option<LoginFailureRecord>, LoginFailureRecord)> {
+        let write_txn = self.db.begin_write()?;
+        let (prev, rec);
+        {
+            let mut table = write_txn.open_table(LOGIN_FAILURES_TABLE)?;
+            prev = match table.get(key)? {
+                Some(v) => serde_json::from_slice::<LoginFailureRecord>(v.value().as_slice()).ok(),
+                None => None,
+            };
+            rec = f(prev.clone());
+            table.insert(key, serde_json::to_vec(&rec)?)?;
+        }
+        write_txn.commit()?;
+        Ok((prev, rec))
+    }
+
+    /// Forget failed sign-ins for `key`. Returns true if there were any.
+    pub fn clear_login_failures(&self, key: &str) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        let removed;
+        {
+            let mut table = write_txn.open_table(LOGIN_FAILURES_TABLE)?;
+            removed = table.remove(key)?.is_some();
+        }
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// All tracked accounts and IPs with their failed sign-in state.
+    pub fn list_login_failures(&self) -> Result<Vec<(String, LoginFailureRecord)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(LOGIN_FAILURES_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.iter()? {
+            let (k, v) = pair?;
+            if let Ok(rec) = serde_json::from_slice::<LoginFailureRecord>(v.value().as_slice()) {
+                out.push((k.value().to_string(), rec));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Append an event to the audit log.
+    pub fn append_audit(&self, rec: &AuditRecord) -> Result<()> {
+        let bytes = serde_json::to_vec(rec)?;
+        let mut nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(AUDIT_TABLE)?;
+            // keys are time-ordered; step past events recorded in the same nanosecond
+            while table.get(format!("{:020}", nanos).as_str())?.is_some() {
+                nanos += 1;
+            }
+            table.insert(format!("{:020}", nanos).as_str(), bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Most recent audit events, newest first, at most `limit`.
+    pub fn list_audit(&self, limit: usize) -> Result<Vec<AuditRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(AUDIT_TABLE)?;
+        let mut out = Vec::new();
+        for pair in table.iter()?.rev() {
+            if out.len() >= limit {
+                break;
+            }
+            let (_k, v) = pair?;
+            if let Ok(rec) = serde_json::from_slice::<AuditRecord>(v.value().as_slice()) {
+                out.push(rec);
+            }
+        }
+        Ok(out)
+    }
+
     /// Reset a rate counter to zero.
     pub fn reset_rate_counter(&self, key: &str) -> Result<()> {
         let write_txn = self.db.begin_write()?;
@@ -834,9 +1532,139 @@ impl Storage {
     }
 }
 
+/// Count keys starting with `prefix` in a key-ordered table.
+fn count_prefix(table: &impl ReadableTable<&'static str, Vec<u8>>, prefix: &str) -> Result<usize> {
+    let mut n = 0;
+    for pair in table.range(prefix..)? {
+        let (k, _v) = pair?;
+        if !k.value().starts_with(prefix) {
+            break;
+        }
+        n += 1;
+    }
+    Ok(n)
+}
+
 // Simple convenience constructor for tests/dev
 impl Default for Storage {
     fn default() -> Self {
         Self::new("./data").expect("creating default storage")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Storage in a temporary directory, removed when the returned guard drops.
+    fn temp_storage() -> (tempfile::TempDir, Storage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        (dir, storage)
+    }
+
+    #[test]
+    fn concurrent_login_failures_are_all_counted() {
+        let (_dir, storage) = temp_storage();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        storage
+                            .update_login_failures("account:a@example.com", |prev| {
+                                let mut rec = prev.unwrap_or_default();
+                                rec.failures += 1;
+                                rec
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(storage.get_login_failures("account:a@example.com").unwrap().unwrap().failures, 40);
+        let (prev, rec) = storage.update_login_failures("account:a@example.com", |_| LoginFailureRecord::default()).unwrap();
+        assert_eq!((prev.map(|r| r.failures), rec.failures), (Some(40), 0));
+        assert!(storage.clear_login_failures("account:a@example.com").unwrap());
+        assert!(storage.get_login_failures("account:a@example.com").unwrap().is_none());
+    }
+
+    // Runs `f` on 8 threads at once and returns how many returned true.
+    fn race(f: impl Fn() -> bool + Sync) -> usize {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(&f)).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count()
+        })
+    }
+
+    #[test]
+    fn refresh_token_is_used_once() {
+        let (_dir, storage) = temp_storage();
+        let rec = RefreshTokenRecord { user_id: "u1".into(), session_id: "s1".into(), issued_at: 100, expires_at: 200, used_at: None };
+        storage.create_refresh_token("h1", &rec).unwrap();
+        assert_eq!(race(|| storage.use_refresh_token("h1", 150).unwrap().is_some_and(|r| r.used_at.is_none())), 1);
+        assert_eq!(storage.use_refresh_token("h1", 160).unwrap().unwrap().used_at, Some(150), "first use is kept");
+        assert!(storage.use_refresh_token("missing", 150).unwrap().is_none());
+    }
+
+    #[test]
+    fn revoking_user_sessions_keeps_the_current_one() {
+        let (_dir, storage) = temp_storage();
+        let session = |user_id: &str| SessionRecord {
+            user_id: user_id.into(),
+            created_at: 100,
+            last_used_at: 100,
+            expires_at: 200,
+            user_agent: None,
+            ip: None,
+            revoked_at: None,
+            revoked_reason: None,
+        };
+        for id in ["s1", "s2", "s3"] {
+            storage.put_session(id, &session("u1")).unwrap();
+        }
+        storage.put_session("other", &session("u2")).unwrap();
+        assert!(storage.revoke_session("s3", "signed out", 110).unwrap());
+
+        assert_eq!(storage.revoke_user_sessions("u1", Some("s1"), "password changed", 120).unwrap(), 1);
+        let s2 = storage.get_session("s2").unwrap().unwrap();
+        assert_eq!((s2.revoked_at, s2.revoked_reason.as_deref()), (Some(120), Some("password changed")));
+        assert_eq!(storage.get_session("s3").unwrap().unwrap().revoked_at, Some(110), "already revoked ones are left alone");
+        assert!(storage.get_session("s1").unwrap().unwrap().revoked_at.is_none());
+        assert!(storage.get_session("other").unwrap().unwrap().revoked_at.is_none());
+        assert_eq!(storage.revoke_user_sessions("u1", None, "signed out everywhere", 130).unwrap(), 1);
+    }
+
+    #[test]
+    fn credential_updates_are_serialized_and_failures_roll_back() {
+        let (_dir, storage) = temp_storage();
+        assert!(storage.update_credentials("a@example.com", |_| Ok(())).unwrap().is_none());
+        storage.put_credentials("a@example.com", &serde_json::json!({ "user_id": "u1", "codes": ["c1"] })).unwrap();
+        // a one-time code: every thread tries to remove it, only one finds it
+        let used = race(|| {
+            storage
+                .update_credentials("a@example.com", |cred| {
+                    let codes = cred["codes"].as_array_mut().unwrap();
+                    let found = codes.iter().any(|c| c == "c1");
+                    codes.retain(|c| c != "c1");
+                    Ok(found)
+                })
+                .unwrap()
+                .unwrap()
+        });
+        assert_eq!(used, 1);
+        let failed = storage.update_credentials::<()>("a@example.com", |cred| {
+            cred["user_id"] = "changed".into();
+            anyhow::bail!("rejected")
+        });
+        assert!(failed.is_err());
+        assert_eq!(storage.get_credentials("a@example.com").unwrap().unwrap()["user_id"], "u1", "not written back");
+    }
+
+    #[test]
+    fn account_token_is_taken_once() {
+        let (_dir, storage) = temp_storage();
+        storage.put_account_token("h1", &serde_json::json!({ "user_id": "u1", "purpose": "reset" })).unwrap();
+        assert_eq!(race(|| storage.take_account_token("h1").unwrap().is_some()), 1);
+        assert!(storage.take_account_token("h1").unwrap().is_none(), "gone once taken");
+    }
+}